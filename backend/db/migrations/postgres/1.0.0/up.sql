@@ -0,0 +1,22 @@
+CREATE TABLE users (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR NOT NULL,
+    surname VARCHAR NOT NULL
+);
+
+INSERT INTO
+    users (name, surname)
+VALUES
+    ('John', 'Doe');
+
+CREATE TABLE positions (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    latitude DOUBLE PRECISION NOT NULL,
+    longitude DOUBLE PRECISION NOT NULL,
+    source VARCHAR NOT NULL,
+    battery_level INTEGER NOT NULL,
+    sport_mode BOOLEAN NOT NULL,
+    time BIGINT NOT NULL,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
@@ -0,0 +1,12 @@
+CREATE TABLE audit_log (
+    id SERIAL PRIMARY KEY,
+    time BIGINT NOT NULL,
+    actor VARCHAR NOT NULL,
+    ip VARCHAR NOT NULL,
+    device VARCHAR NOT NULL,
+    action VARCHAR NOT NULL,
+    target VARCHAR NOT NULL,
+    details VARCHAR NOT NULL
+);
+
+CREATE INDEX audit_log_time ON audit_log (time);
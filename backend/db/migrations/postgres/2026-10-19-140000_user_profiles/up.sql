@@ -0,0 +1,4 @@
+ALTER TABLE users ADD COLUMN color VARCHAR;
+ALTER TABLE users ADD COLUMN phone VARCHAR;
+ALTER TABLE users ADD COLUMN timezone VARCHAR;
+ALTER TABLE users ADD COLUMN avatar_updated_at BIGINT;
@@ -0,0 +1,12 @@
+CREATE TABLE sharing_rules (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    name VARCHAR NOT NULL,
+    starts_at VARCHAR,
+    ends_at VARCHAR,
+    latitude DOUBLE PRECISION,
+    longitude DOUBLE PRECISION,
+    radius DOUBLE PRECISION,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+CREATE INDEX sharing_rules_user_id ON sharing_rules(user_id);
@@ -0,0 +1,13 @@
+CREATE TABLE device_status (
+    user_id INTEGER PRIMARY KEY NOT NULL,
+    last_fix BIGINT NOT NULL,
+    battery_level INTEGER NOT NULL,
+    source VARCHAR NOT NULL,
+    online BOOLEAN NOT NULL,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+-- Start from the latest known position of every user
+INSERT INTO device_status (user_id, last_fix, battery_level, source, online)
+SELECT DISTINCT ON (user_id) user_id, time, battery_level, source, FALSE
+FROM positions
+ORDER BY user_id, time DESC, id DESC;
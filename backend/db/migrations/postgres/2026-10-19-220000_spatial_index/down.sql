@@ -0,0 +1,2 @@
+DROP INDEX positions_time;
+DROP INDEX positions_location;
@@ -0,0 +1,4 @@
+-- Spatial index of the positions, with the built-in geometric types
+CREATE INDEX positions_location ON positions USING gist (point(longitude, latitude));
+
+CREATE INDEX positions_time ON positions (time);
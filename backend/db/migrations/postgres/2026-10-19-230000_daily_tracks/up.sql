@@ -0,0 +1,10 @@
+-- Simplified track of each user for each day of their timezone, kept beyond the positions retention
+CREATE TABLE daily_tracks (
+    user_id INTEGER NOT NULL,
+    day VARCHAR NOT NULL,
+    positions TEXT NOT NULL,
+    raw_count INTEGER NOT NULL,
+    last_time BIGINT NOT NULL,
+    PRIMARY KEY (user_id, day),
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
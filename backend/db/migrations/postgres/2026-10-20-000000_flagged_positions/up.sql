@@ -0,0 +1,2 @@
+-- Why a position is implausible, null if it is not
+ALTER TABLE positions ADD COLUMN flagged VARCHAR;
@@ -0,0 +1,14 @@
+-- Location of the cell towers, imported from the OpenCellID or Mozilla Location Service dumps,
+-- or cached from the Open Cell ID API until they expire
+CREATE TABLE cell_towers (
+    mcc INTEGER NOT NULL,
+    mnc INTEGER NOT NULL,
+    lac INTEGER NOT NULL,
+    cid BIGINT NOT NULL,
+    radio VARCHAR NOT NULL,
+    latitude DOUBLE PRECISION NOT NULL,
+    longitude DOUBLE PRECISION NOT NULL,
+    radius INTEGER NOT NULL,
+    expires_at BIGINT,
+    PRIMARY KEY (mcc, mnc, lac, cid)
+);
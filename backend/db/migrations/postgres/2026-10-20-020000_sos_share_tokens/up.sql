@@ -0,0 +1,3 @@
+-- Expiry of the share token handed out with an emergency, that is valid while the emergency is
+-- active only
+ALTER TABLE alerts ADD COLUMN share_expires_at BIGINT;
@@ -0,0 +1,10 @@
+CREATE TABLE auth_failures (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    time BIGINT NOT NULL,
+    ip VARCHAR NOT NULL,
+    credential VARCHAR NOT NULL,
+    path VARCHAR NOT NULL,
+    reason VARCHAR NOT NULL
+);
+
+CREATE INDEX auth_failures_time ON auth_failures (time);
@@ -0,0 +1,6 @@
+CREATE TABLE oidc_subjects (
+    subject VARCHAR PRIMARY KEY NOT NULL,
+    user_id INTEGER,
+    role VARCHAR NOT NULL,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE SET NULL
+);
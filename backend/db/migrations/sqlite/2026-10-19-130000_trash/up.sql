@@ -0,0 +1,9 @@
+CREATE TABLE trash (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    time BIGINT NOT NULL,
+    actor VARCHAR NOT NULL,
+    target VARCHAR NOT NULL,
+    content TEXT NOT NULL
+);
+
+CREATE INDEX trash_time ON trash (time);
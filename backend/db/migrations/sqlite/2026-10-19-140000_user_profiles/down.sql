@@ -0,0 +1,4 @@
+ALTER TABLE users DROP COLUMN avatar_updated_at;
+ALTER TABLE users DROP COLUMN timezone;
+ALTER TABLE users DROP COLUMN phone;
+ALTER TABLE users DROP COLUMN color;
@@ -0,0 +1,7 @@
+CREATE TABLE privacy_settings (
+    user_id INTEGER PRIMARY KEY NOT NULL,
+    precision VARCHAR NOT NULL DEFAULT 'exact',
+    paused_since BIGINT,
+    paused_until BIGINT,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
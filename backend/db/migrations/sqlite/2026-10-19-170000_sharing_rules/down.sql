@@ -0,0 +1 @@
+DROP TABLE sharing_rules;
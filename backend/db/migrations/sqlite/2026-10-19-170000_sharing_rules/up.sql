@@ -0,0 +1,12 @@
+CREATE TABLE sharing_rules (
+    id INTEGER PRIMARY KEY NOT NULL,
+    user_id INTEGER NOT NULL,
+    name VARCHAR NOT NULL,
+    starts_at VARCHAR,
+    ends_at VARCHAR,
+    latitude DOUBLE,
+    longitude DOUBLE,
+    radius DOUBLE,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+CREATE INDEX sharing_rules_user_id ON sharing_rules(user_id);
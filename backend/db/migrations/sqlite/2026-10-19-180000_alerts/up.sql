@@ -0,0 +1,16 @@
+CREATE TABLE alert_settings (
+    user_id INTEGER PRIMARY KEY NOT NULL,
+    battery_threshold INTEGER,
+    offline_after INTEGER,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+CREATE TABLE alerts (
+    id INTEGER PRIMARY KEY NOT NULL,
+    user_id INTEGER NOT NULL,
+    kind VARCHAR NOT NULL,
+    message VARCHAR NOT NULL,
+    raised_at BIGINT NOT NULL,
+    cleared_at BIGINT,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+CREATE INDEX alerts_user_id_kind ON alerts(user_id, kind);
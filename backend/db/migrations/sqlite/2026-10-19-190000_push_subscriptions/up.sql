@@ -0,0 +1,11 @@
+CREATE TABLE push_subscriptions (
+    id INTEGER PRIMARY KEY NOT NULL,
+    user_id INTEGER NOT NULL,
+    credential VARCHAR NOT NULL,
+    endpoint VARCHAR NOT NULL UNIQUE,
+    p256dh VARCHAR NOT NULL,
+    auth VARCHAR NOT NULL,
+    created_at BIGINT NOT NULL,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+CREATE INDEX push_subscriptions_user_id ON push_subscriptions(user_id);
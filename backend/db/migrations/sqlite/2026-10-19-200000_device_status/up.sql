@@ -0,0 +1,15 @@
+CREATE TABLE device_status (
+    user_id INTEGER PRIMARY KEY NOT NULL,
+    last_fix BIGINT NOT NULL,
+    battery_level INTEGER NOT NULL,
+    source VARCHAR NOT NULL,
+    online BOOLEAN NOT NULL,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+-- Start from the latest known position of every user
+INSERT INTO device_status (user_id, last_fix, battery_level, source, online)
+SELECT p.user_id, p.time, p.battery_level, p.source, FALSE
+FROM positions p
+WHERE p.id = (
+    SELECT id FROM positions WHERE user_id = p.user_id ORDER BY time DESC, id DESC LIMIT 1
+);
@@ -0,0 +1,2 @@
+-- The orphaned rows are not restored
+SELECT 1;
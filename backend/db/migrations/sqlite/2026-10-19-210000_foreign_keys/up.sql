@@ -0,0 +1,11 @@
+-- Remove the rows left behind by the deletions made while the foreign keys were not enforced
+DELETE FROM positions WHERE user_id NOT IN (SELECT id FROM users);
+DELETE FROM group_members WHERE user_id NOT IN (SELECT id FROM users)
+    OR group_id NOT IN (SELECT id FROM user_groups);
+DELETE FROM privacy_settings WHERE user_id NOT IN (SELECT id FROM users);
+DELETE FROM sharing_rules WHERE user_id NOT IN (SELECT id FROM users);
+DELETE FROM alert_settings WHERE user_id NOT IN (SELECT id FROM users);
+DELETE FROM alerts WHERE user_id NOT IN (SELECT id FROM users);
+DELETE FROM push_subscriptions WHERE user_id NOT IN (SELECT id FROM users);
+DELETE FROM device_status WHERE user_id NOT IN (SELECT id FROM users);
+UPDATE oidc_subjects SET user_id = NULL WHERE user_id NOT IN (SELECT id FROM users);
@@ -0,0 +1,5 @@
+DROP INDEX positions_time;
+DROP TRIGGER positions_rtree_delete;
+DROP TRIGGER positions_rtree_update;
+DROP TRIGGER positions_rtree_insert;
+DROP TABLE positions_rtree;
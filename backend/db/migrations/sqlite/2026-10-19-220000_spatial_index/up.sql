@@ -0,0 +1,29 @@
+-- Spatial index of the positions, kept up to date by triggers
+CREATE VIRTUAL TABLE positions_rtree USING rtree(
+    id,
+    min_lat, max_lat,
+    min_lon, max_lon
+);
+
+INSERT INTO positions_rtree
+SELECT id, latitude, latitude, longitude, longitude FROM positions;
+
+CREATE TRIGGER positions_rtree_insert AFTER INSERT ON positions
+BEGIN
+    INSERT INTO positions_rtree
+    VALUES (new.id, new.latitude, new.latitude, new.longitude, new.longitude);
+END;
+
+CREATE TRIGGER positions_rtree_update AFTER UPDATE OF id, latitude, longitude ON positions
+BEGIN
+    DELETE FROM positions_rtree WHERE id = old.id;
+    INSERT INTO positions_rtree
+    VALUES (new.id, new.latitude, new.latitude, new.longitude, new.longitude);
+END;
+
+CREATE TRIGGER positions_rtree_delete AFTER DELETE ON positions
+BEGIN
+    DELETE FROM positions_rtree WHERE id = old.id;
+END;
+
+CREATE INDEX positions_time ON positions (time);
@@ -0,0 +1,2 @@
+DROP INDEX alerts_cleared_at;
+ALTER TABLE alert_settings DROP COLUMN notifiers;
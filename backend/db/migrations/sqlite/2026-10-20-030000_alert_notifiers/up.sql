@@ -0,0 +1,5 @@
+-- Names of the notifiers the alerts of a user are sent through, separated by commas, all of them
+-- when null
+ALTER TABLE alert_settings ADD COLUMN notifiers VARCHAR;
+-- The cleared alerts are purged after the alert retention
+CREATE INDEX alerts_cleared_at ON alerts(cleared_at);
@@ -1,54 +1,170 @@
-use actix_web::error::ErrorForbidden;
+use actix_web::error::{ErrorForbidden, ErrorTooManyRequests};
 use actix_web::http::Method;
-use actix_web::{Error, dev::ServiceRequest};
+use actix_web::{Error, HttpMessage, HttpRequest, dev::ServiceRequest, web};
 use actix_web_httpauth::extractors::bearer::BearerAuth;
-use base64ct::{Base64, Encoding};
-use chacha20poly1305::aead::generic_array::GenericArray;
-use chacha20poly1305::consts::U12;
-use chacha20poly1305::{
-    ChaCha20Poly1305,
-    aead::{Aead, KeyInit},
-};
-use log::debug;
-use sha2::{Digest, Sha256};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::Duration;
+use subtle::ConstantTimeEq;
 use tokio::sync::Mutex;
 
+use crate::config::Config;
+use crate::db::DbConnection;
+use crate::errors::ServerError;
+use crate::ingester::IngesterHandle;
+use crate::keys::ShareKeySet;
+use crate::models::sos;
+use crate::notifiers::Notifiers;
+use crate::oidc::Oidc;
+use crate::rate_limit::{AuthLimiter, LOCKED_OUT, limiter_keys, record_failure};
+use crate::token::Claims;
+use crate::webpush::WebPush;
+
+type DbPool = r2d2::Pool<diesel::r2d2::ConnectionManager<DbConnection>>;
+
 pub struct AppConfig {
+    pub config: Config,
     pub bearer_token: String,
+    pub share_keys: ShareKeySet,
+    pub auth_limiter: AuthLimiter,
+    pub oidc: Option<Oidc>,
+    pub notifiers: Notifiers,
+    pub web_push: Option<WebPush>,
+    // how long deleted objects are kept in the trash, zero disables the trash
+    pub trash_purge_delay: Duration,
     pub open_cell_id_api_key: Option<String>,
-    pub user_last_update: Mutex<HashMap<i32, i64>>,
+    // where the web application is served, to build the share links
+    pub public_url: Option<String>,
     pub sport_mode_toggle_users: Mutex<Vec<i32>>,
+    // records the positions in batches, they are recorded one request at a time without it
+    pub ingester: Option<IngesterHandle>,
 }
 
 impl AppConfig {
     pub fn new(token: String, api_key: Option<String>) -> Self {
+        // A random share key, until the persisted one is set
+        let share_keys = ShareKeySet::new(&crate::utils::random_string());
         AppConfig {
+            config: Config::default(),
             bearer_token: token,
+            share_keys,
+            auth_limiter: AuthLimiter::default(),
+            oidc: None,
+            notifiers: Notifiers::default(),
+            web_push: None,
+            trash_purge_delay: DEFAULT_TRASH_PURGE_DELAY,
             open_cell_id_api_key: api_key,
-            user_last_update: Mutex::new(HashMap::new()),
+            public_url: None,
             sport_mode_toggle_users: Mutex::new(Vec::new()),
+            ingester: None,
         }
     }
+
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.trash_purge_delay = config.trash_purge_delay();
+        self.config = config;
+        self
+    }
+
+    pub fn with_share_keys(mut self, share_keys: ShareKeySet) -> Self {
+        self.share_keys = share_keys;
+        self
+    }
+
+    pub fn with_auth_limiter(mut self, auth_limiter: AuthLimiter) -> Self {
+        self.auth_limiter = auth_limiter;
+        self
+    }
+
+    pub fn with_oidc(mut self, oidc: Oidc) -> Self {
+        self.oidc = Some(oidc);
+        self
+    }
+
+    pub fn with_notifiers(mut self, notifiers: Notifiers) -> Self {
+        self.notifiers = notifiers;
+        self
+    }
+
+    pub fn with_web_push(mut self, web_push: WebPush) -> Self {
+        self.web_push = Some(web_push);
+        self
+    }
+
+    pub fn with_public_url(mut self, public_url: String) -> Self {
+        self.public_url = Some(public_url);
+        self
+    }
+
+    pub fn with_ingester(mut self, ingester: IngesterHandle) -> Self {
+        self.ingester = Some(ingester);
+        self
+    }
 }
 
-const SHARE_TOKEN_DURATION: u64 = 2 * 60 * 60;
+pub const DEFAULT_TRASH_PURGE_DELAY: Duration = Duration::from_secs(30 * 24 * 60 * 60);
 
 pub async fn share_validator(
     req: ServiceRequest,
     credentials: BearerAuth,
 ) -> Result<ServiceRequest, (Error, ServiceRequest)> {
-    let app_config = req
-        .app_data::<actix_web::web::Data<AppConfig>>()
-        .expect("Could not get token configuration");
-    if app_config.bearer_token == credentials.token() {
-        Ok(req)
-    } else {
-        Err((
+    match authenticate(req.request(), credentials.token()).await {
+        Ok(credential) if credential.is_admin() => {
+            req.extensions_mut().insert(credential);
+            Ok(req)
+        }
+        Ok(Credential::Share(_)) => Err((
             ErrorForbidden("a share token cannot be used to get a share token"),
             req,
-        ))
+        )),
+        Ok(_) => Err((ErrorForbidden("admin rights are required"), req)),
+        Err(e) => Err((e.into_error(ErrorForbidden), req)),
+    }
+}
+
+pub async fn admin_validator(
+    req: ServiceRequest,
+    credentials: BearerAuth,
+) -> Result<ServiceRequest, (Error, ServiceRequest)> {
+    match authenticate(req.request(), credentials.token()).await {
+        Ok(credential) if credential.is_admin() => {
+            req.extensions_mut().insert(credential);
+            Ok(req)
+        }
+        Ok(_) => Err((ErrorForbidden("admin rights are required"), req)),
+        Err(e) => Err((e.into_error(ErrorForbidden), req)),
+    }
+}
+
+// Admins and logged in users, the handlers must check that the user acts on its own data
+pub async fn session_validator(
+    req: ServiceRequest,
+    credentials: BearerAuth,
+) -> Result<ServiceRequest, (Error, ServiceRequest)> {
+    match authenticate(req.request(), credentials.token()).await {
+        Ok(credential @ (Credential::Main | Credential::Session { .. })) => {
+            req.extensions_mut().insert(credential);
+            Ok(req)
+        }
+        Ok(_) => Err((
+            ErrorForbidden("a share token cannot be used to alter settings"),
+            req,
+        )),
+        Err(e) => Err((e.into_error(ErrorForbidden), req)),
+    }
+}
+
+// Any valid credential, the handlers must check which users it can access
+pub async fn credential_validator(
+    req: ServiceRequest,
+    credentials: BearerAuth,
+) -> Result<ServiceRequest, (Error, ServiceRequest)> {
+    match authenticate(req.request(), credentials.token()).await {
+        Ok(credential) => {
+            req.extensions_mut().insert(credential);
+            Ok(req)
+        }
+        Err(e) => Err((e.into_error(ErrorForbidden), req)),
     }
 }
 
@@ -56,109 +172,224 @@ pub async fn validator(
     req: ServiceRequest,
     credentials: BearerAuth,
 ) -> Result<ServiceRequest, (Error, ServiceRequest)> {
-    let main_token = req
-        .app_data::<actix_web::web::Data<AppConfig>>()
-        .expect("Could not get token configuration")
-        .bearer_token
-        .to_owned();
-    if main_token == credentials.token() {
+    let credential = match authenticate(req.request(), credentials.token()).await {
+        Ok(credential) => credential,
+        Err(e) => return Err((e.into_error(ErrorForbidden), req)),
+    };
+    if credential.is_admin() {
+        req.extensions_mut().insert(credential);
         return Ok(req);
     }
-    // SHARE TOKEN SECTION : CHECK THE METHOD (GET ONLY ACCEPTED)
+    // SHARE TOKEN AND VIEWER SECTION : CHECK THE METHOD (GET ONLY ACCEPTED)
     if req.method() != Method::GET {
-        return Err((
-            ErrorForbidden("share token cannot be use to alter data"),
-            req,
-        ));
+        let reason = match credential {
+            Credential::Share(_) => "share token cannot be use to alter data",
+            _ => "a viewer cannot alter data",
+        };
+        return Err((ErrorForbidden(reason), req));
     }
-    let params = query_string_to_hashmap(req.query_string());
-    let user_id = params.get("user_id");
-    let (ok, reason) = check_share_token(credentials.token(), &main_token, user_id);
-    if ok {
-        Ok(req)
-    } else {
-        Err((ErrorForbidden(reason), req))
+    if let Credential::Share(id) = credential {
+        let params = query_string_to_hashmap(req.query_string());
+        if let Err(reason) = check_share_scope(id, params.get("user_id")) {
+            return Err((ErrorForbidden(reason), req));
+        }
     }
+    req.extensions_mut().insert(credential);
+    Ok(req)
+}
+
+// Roles granted to login sessions
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[repr(u8)]
+pub enum Role {
+    // same rights as the main token
+    Admin = 1,
+    // read only access
+    Viewer = 2,
 }
 
-pub fn check_share_token(
-    base64_token: &str,
-    main_token: &str,
-    user_id: Option<&String>,
-) -> (bool, &'static str) {
-    // TRY TO DECRYPT THE TOKEN
-    // Get the token as base64
-    debug!("Getting token, base64 token = {:?}", base64_token);
-    // Convert to &[u8]
-    let binary_token = match Base64::decode_vec(base64_token) {
-        Ok(val) => val,
-        Err(_) => {
-            return (false, "could not decode share token as base 64");
+impl TryFrom<u8> for Role {
+    type Error = &'static str;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Role::Admin),
+            2 => Ok(Role::Viewer),
+            _ => Err("unknown role"),
         }
-    };
-    debug!("Getting token, binary token = {:?}", binary_token);
-    // Derive the main token as a key
-    let mut hasher = Sha256::new();
-    hasher.update(main_token);
-    let key: [u8; 32] = hasher.finalize().into();
-    // Decipher the value of the token
-    let cipher = ChaCha20Poly1305::new(&key.into());
-    if binary_token.len() < 12 {
-        return (false, "Wrong token!");
     }
-    let nonce: GenericArray<_, U12> = GenericArray::clone_from_slice(&binary_token[..12]);
-    let data = match cipher.decrypt(&nonce, &binary_token[12..]) {
-        Ok(val) => val,
-        Err(_) => {
-            return (false, "could not decipher token data");
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Role::Admin => write!(f, "admin"),
+            Role::Viewer => write!(f, "viewer"),
         }
-    };
-    // Split the data to recover the time and the user id
-    let (time_data, id) = data.split_at(8);
-    // Convert it to a duration (since unix epoch, little endian)
-    let time_data: [u8; 8] = match time_data.try_into() {
-        Ok(val) => val,
-        Err(_) => {
-            return (false, "could not extract time from data");
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "admin" => Ok(Role::Admin),
+            "viewer" => Ok(Role::Viewer),
+            _ => Err("unknown role"),
         }
-    };
-    let token_time = u64::from_le_bytes(time_data);
-    let id: [u8; 2] = match id.try_into() {
-        Ok(val) => val,
-        Err(_) => {
-            return (false, "could not extract user id from data");
+    }
+}
+
+// A valid credential : the main token, a share token for the given user, or a login session
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Credential {
+    Main,
+    Share(u16),
+    Session { user_id: Option<i32>, role: Role },
+}
+
+impl Credential {
+    pub fn is_admin(&self) -> bool {
+        matches!(
+            self,
+            Credential::Main
+                | Credential::Session {
+                    role: Role::Admin,
+                    ..
+                }
+        )
+    }
+
+    // Whether the credential acts for the given user : admins act for everyone
+    pub fn owns(&self, uid: i32) -> bool {
+        match self {
+            Credential::Session {
+                user_id: Some(id), ..
+            } => *id == uid || self.is_admin(),
+            _ => self.is_admin(),
+        }
+    }
+}
+
+impl std::fmt::Display for Credential {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Credential::Main => write!(f, "main token"),
+            Credential::Share(id) => write!(f, "share token for user {}", id),
+            Credential::Session {
+                user_id: Some(id),
+                role,
+            } => write!(f, "{} session for user {}", role, id),
+            Credential::Session {
+                user_id: None,
+                role,
+            } => write!(f, "{} session", role),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    TooManyAttempts(Duration),
+    Invalid(&'static str),
+}
+
+impl AuthError {
+    // Convert to an HTTP error, using the given constructor for invalid credentials
+    pub fn into_error(self, invalid: fn(&'static str) -> Error) -> Error {
+        match self {
+            AuthError::TooManyAttempts(remaining) => ErrorTooManyRequests(format!(
+                "too many failed attempts, retry in {} seconds",
+                remaining.as_secs() + 1
+            )),
+            AuthError::Invalid(reason) => invalid(reason),
+        }
+    }
+}
+
+// Authenticate a presented token, counting the failed attempts per client IP and per credential
+pub async fn authenticate(req: &HttpRequest, token: &str) -> Result<Credential, AuthError> {
+    let app_config = req
+        .app_data::<actix_web::web::Data<AppConfig>>()
+        .expect("Could not get token configuration");
+    let (ip_key, credential_key) = limiter_keys(req, token);
+    let keys = [ip_key.as_str(), credential_key.as_str()];
+    if let Err(remaining) = app_config.auth_limiter.check(&keys) {
+        if app_config.auth_limiter.refusal(&keys) {
+            record_failure(req, token, LOCKED_OUT);
+        }
+        return Err(AuthError::TooManyAttempts(remaining));
+    }
+    if bool::from(token.as_bytes().ct_eq(app_config.bearer_token.as_bytes())) {
+        app_config.auth_limiter.success(&[&credential_key]);
+        return Ok(Credential::Main);
+    }
+    let claims = match crate::token::open(
+        &app_config.share_keys,
+        app_config.config.share_token_duration,
+        token,
+    ) {
+        Ok(claims) => claims,
+        Err(reason) => {
+            app_config.auth_limiter.failure(&keys);
+            record_failure(req, token, reason);
+            return Err(AuthError::Invalid(reason));
         }
     };
-    let id = u16::from_le_bytes(id);
-    // Get the current time
-    let time = match SystemTime::now().duration_since(UNIX_EPOCH) {
-        Ok(val) => val.as_secs(),
-        Err(_) => {
-            return (false, "could not get system time");
+    app_config.auth_limiter.success(&[&credential_key]);
+    Ok(match claims {
+        Claims::Emergency {
+            user_id,
+            expires_at,
+        } => {
+            // the emergency share token is given up as soon as the emergency is cleared
+            if !emergency_in_force(req, user_id.into(), expires_at).await {
+                return Err(AuthError::Invalid("the emergency is over"));
+            }
+            Credential::Share(user_id)
+        }
+        Claims::Share { user_id, .. } | Claims::Expiring { user_id, .. } => {
+            Credential::Share(user_id)
         }
+        Claims::Session { user_id, role, .. } => Credential::Session { user_id, role },
+    })
+}
+
+async fn emergency_in_force(req: &HttpRequest, uid: i32, expires_at: u64) -> bool {
+    let Some(pool) = req.app_data::<web::Data<DbPool>>().cloned() else {
+        return false;
     };
-    // Check that no more than 2 hours passed since the creation of the token
-    if time > token_time + SHARE_TOKEN_DURATION {
-        return (false, "token is expired");
-    }
-    // Check the user id
+    let in_force = web::block(move || -> Result<bool, ServerError> {
+        let mut conn = pool.get()?;
+        Ok(sos::is_in_force(&mut conn, uid, expires_at)?)
+    })
+    .await;
+    matches!(in_force, Ok(Ok(true)))
+}
+
+// Check that a share token for the given user is used to access this user only
+pub fn check_share_scope(id: u16, user_id: Option<&String>) -> Result<(), &'static str> {
     if let Some(user_id) = user_id.map(|x| x.parse::<u16>().unwrap_or(0))
         && user_id != id
     {
-        return (false, "user ids don't match");
+        return Err("user ids don't match");
     }
-    (true, "")
+    Ok(())
 }
 
 #[macro_export]
 macro_rules! create_app {
     ($pool:expr, $app_config:expr, $positions_server_tx:expr) => {{
         use actix_cors::Cors;
-        use actix_web::dev::Service;
         use actix_web::{App, HttpResponse, error::InternalError, middleware, web, web::Data};
         use actix_web_httpauth::middleware::HttpAuthentication;
-        use $crate::app::query_string_to_hashmap;
-        use $crate::models::{position, sport_mode, user};
+        use $crate::models::{
+            alert, audit, device_status, group, oidc_subject, position, privacy, push_subscription,
+            sharing_rule, sos, spatial, sport_mode, track, trash, user,
+        };
+        use $crate::oidc;
         use $crate::positions_handler::count;
         use $crate::positions_handler::positions_ws_handler;
         use $crate::token;
@@ -167,7 +398,7 @@ macro_rules! create_app {
             .app_data(Data::new($pool.clone()))
             .app_data(
                 web::JsonConfig::default()
-                    .limit(4096)
+                    .limit($app_config.config.json_limit)
                     .error_handler(|err, _req| {
                         InternalError::from_response(err, HttpResponse::Conflict().finish()).into()
                     }),
@@ -181,75 +412,23 @@ macro_rules! create_app {
                     .wrap(HttpAuthentication::bearer($crate::app::validator))
                     .service(user::read_all)
                     .service(user::read)
+                    .service(user::read_avatar)
+                    .service(user::upload_avatar)
+                    .service(user::delete_avatar)
                     .service(user::create)
                     .service(user::update)
                     .service(user::delete_all)
                     .service(user::delete),
             )
-            .service(
-                web::resource("/api/positions/ws")
-                    .route(web::get().to(positions_ws_handler))
-                    .wrap_fn(
-                        |req,
-                         srv|
-                         -> std::pin::Pin<
-                            Box<
-                                dyn std::future::Future<
-                                        Output = Result<
-                                            actix_web::dev::ServiceResponse,
-                                            actix_web::Error,
-                                        >,
-                                    >,
-                            >,
-                        > {
-                            let reference_token = req
-                                .app_data::<web::Data<AppConfig>>()
-                                .map(|data| &data.bearer_token);
-                            if let Some(ref_token) = reference_token {
-                                // Parse the query string into a HashMap
-                                let params = query_string_to_hashmap(req.query_string());
-                                // Get the value of a specific parameter
-                                let query_token = match params.get("token").cloned().ok_or("") {
-                                    Ok(v) => v,
-                                    Err(_) => {
-                                        return Box::pin(async {
-                                            Err(actix_web::error::ErrorUnauthorized(
-                                                "could not parse query",
-                                            ))
-                                        });
-                                    }
-                                };
-                                let query_token =
-                                    urlencoding::decode(&query_token).unwrap_or_default();
-                                if &query_token == ref_token {
-                                    return srv.call(req);
-                                }
-                                // SHARE TOKEN SECTION
-                                let query_user_id = params.get("user_id");
-                                let (ok, reason) = $crate::app::check_share_token(
-                                    &query_token,
-                                    ref_token,
-                                    query_user_id,
-                                );
-                                if ok {
-                                    return srv.call(req);
-                                } else {
-                                    return Box::pin(async move {
-                                        Err(actix_web::error::ErrorUnauthorized(reason))
-                                    });
-                                }
-                            }
-                            Box::pin(async {
-                                Err(actix_web::error::ErrorUnauthorized("Wrong token!"))
-                            })
-                        },
-                    ),
-            )
+            .service(web::resource("/api/positions/ws").route(web::get().to(positions_ws_handler)))
             .service(
                 web::scope("/api/positions")
                     .wrap(HttpAuthentication::bearer($crate::app::validator))
                     .route("/ws_count", web::get().to(count))
                     .service(position::read_filter)
+                    .service(position::read_latest)
+                    .service(spatial::read_area)
+                    .service(track::read_tracks)
                     .service(position::read)
                     .service(position::create)
                     .service(position::update)
@@ -257,17 +436,103 @@ macro_rules! create_app {
                     .service(position::delete)
                     .service(position::create_from_cid),
             )
+            .service(
+                web::scope("/api/status")
+                    .wrap(HttpAuthentication::bearer($crate::app::validator))
+                    .service(device_status::read_all)
+                    .service(device_status::read),
+            )
             .service(
                 web::scope("/api/sport-mode")
                     .wrap(HttpAuthentication::bearer($crate::app::validator))
                     .service(sport_mode::toggle_sport_mode),
             )
+            .service(
+                web::scope("/api/alerts")
+                    .wrap(HttpAuthentication::bearer($crate::app::validator))
+                    .service(alert::read_all),
+            )
+            .service(
+                web::scope("/api/alert-settings")
+                    .wrap(HttpAuthentication::bearer($crate::app::session_validator))
+                    .service(alert::read_settings)
+                    .service(alert::update_settings),
+            )
+            .service(
+                web::scope("/api/push")
+                    .wrap(HttpAuthentication::bearer(
+                        $crate::app::credential_validator,
+                    ))
+                    .service(push_subscription::read_public_key)
+                    .service(push_subscription::read_all)
+                    .service(push_subscription::create)
+                    .service(push_subscription::delete),
+            )
+            .service(
+                web::scope("/api/sos")
+                    .wrap(HttpAuthentication::bearer($crate::app::session_validator))
+                    .service(sos::trigger)
+                    .service(sos::clear),
+            )
+            .service(
+                web::scope("/api/privacy")
+                    .wrap(HttpAuthentication::bearer($crate::app::session_validator))
+                    .service(privacy::read)
+                    .service(privacy::update)
+                    .service(sharing_rule::read_all)
+                    .service(sharing_rule::create)
+                    .service(sharing_rule::delete),
+            )
+            .service(
+                web::scope("/api/groups")
+                    .wrap(HttpAuthentication::bearer($crate::app::admin_validator))
+                    .service(group::read_all)
+                    .service(group::read)
+                    .service(group::create)
+                    .service(group::update)
+                    .service(group::delete)
+                    .service(group::read_members)
+                    .service(group::add_member)
+                    .service(group::remove_member),
+            )
+            .service(
+                web::scope("/api/trash")
+                    .wrap(HttpAuthentication::bearer($crate::app::admin_validator))
+                    .service(trash::read_all)
+                    .service(trash::restore)
+                    .service(trash::purge),
+            )
+            .service(
+                web::scope("/api/backups")
+                    .wrap(HttpAuthentication::bearer($crate::app::admin_validator))
+                    .service($crate::backup::read_all)
+                    .service($crate::backup::create),
+            )
+            .service(
+                web::scope("/api/audit")
+                    .wrap(HttpAuthentication::bearer($crate::app::admin_validator))
+                    .service(audit::read_all),
+            )
+            .service(
+                web::scope("/api/oidc/subjects")
+                    .wrap(HttpAuthentication::bearer($crate::app::admin_validator))
+                    .service(oidc_subject::read_all)
+                    .service(oidc_subject::create)
+                    .service(oidc_subject::delete),
+            )
+            .service(
+                web::scope("/api/oidc")
+                    .service(oidc::login)
+                    .service(oidc::callback),
+            )
             .service(
                 web::scope("/api/token")
                     .wrap(HttpAuthentication::bearer($crate::app::share_validator))
                     .service(token::get),
             )
-            .service(actix_files::Files::new("/", "./web").index_file("index.html"))
+            .service(
+                actix_files::Files::new("/", &$app_config.config.web_dir).index_file("index.html"),
+            )
     }};
 }
 
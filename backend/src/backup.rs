@@ -0,0 +1,359 @@
+//! Online backups of the SQLite database, and their restoration.
+//!
+//! A backup is taken with `VACUUM INTO`, which copies a consistent snapshot of the database while
+//! the server keeps running. A restoration replaces the database file, so it is done from the
+//! command line with the server stopped.
+
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use actix_web::{HttpRequest, HttpResponse, get, post, web};
+use chrono::DateTime;
+use diesel::{
+    SqliteConnection, migration::MigrationSource, prelude::*, sql_query, sql_types::Text,
+    sqlite::Sqlite,
+};
+use diesel_migrations::MigrationHarness;
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use log::{error, info};
+use serde::Serialize;
+
+use crate::{
+    app::AppConfig,
+    config::Config,
+    db::{DbConnection, SQLITE_MIGRATIONS},
+    errors::{ServerError, io_error},
+    models::audit::NewAuditEntry,
+    utils::now,
+};
+
+type DbPool = r2d2::Pool<diesel::r2d2::ConnectionManager<DbConnection>>;
+
+const PREFIX: &str = "tesou-";
+const EXTENSION: &str = ".sqlite";
+const COMPRESSED_EXTENSION: &str = ".sqlite.gz";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Backup {
+    pub name: String,
+    pub size: u64,
+    pub time: i64,
+}
+
+impl Backup {
+    fn read(path: &Path) -> Result<Backup, ServerError> {
+        let metadata = fs::metadata(path).map_err(|e| io_error(path, e))?;
+        Ok(Backup {
+            name: path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+            size: metadata.len(),
+            time: metadata
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or(0, |d| d.as_millis() as i64),
+        })
+    }
+}
+
+fn timestamp() -> String {
+    DateTime::from_timestamp_millis(now())
+        .unwrap_or_default()
+        .format("%Y%m%dT%H%M%S%3fZ")
+        .to_string()
+}
+
+// Take a backup in the backup directory, and remove the oldest ones beyond the number to keep
+pub fn take(conn: &mut DbConnection, config: &Config) -> Result<Backup, ServerError> {
+    let DbConnection::Sqlite(conn) = conn else {
+        return Err(ServerError::Other(
+            "backups are only supported on SQLite, use pg_dump for PostgreSQL".to_owned(),
+        ));
+    };
+    let dir = Path::new(&config.backup_dir);
+    fs::create_dir_all(dir).map_err(|e| io_error(dir, e))?;
+    let path = dir.join(format!("{}{}{}", PREFIX, timestamp(), EXTENSION));
+    sql_query("VACUUM INTO ?")
+        .bind::<Text, _>(path.to_string_lossy())
+        .execute(conn)?;
+    let path = match config.backup_compress {
+        true => {
+            let compressed = path.with_extension(&COMPRESSED_EXTENSION[1..]);
+            compress(&path, &compressed).map_err(|e| io_error(&compressed, e))?;
+            fs::remove_file(&path).map_err(|e| io_error(&path, e))?;
+            compressed
+        }
+        false => path,
+    };
+    let backup = Backup::read(&path)?;
+    rotate(dir, config.backup_keep)?;
+    Ok(backup)
+}
+
+fn compress(from: &Path, to: &Path) -> io::Result<()> {
+    let mut encoder = GzEncoder::new(fs::File::create(to)?, Compression::default());
+    io::copy(&mut fs::File::open(from)?, &mut encoder)?;
+    encoder.finish()?.sync_all()
+}
+
+// The backups of the directory, newest first
+pub fn list(dir: &Path) -> Result<Vec<Backup>, ServerError> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(io_error(dir, e)),
+    };
+    let mut paths = Vec::new();
+    for entry in entries {
+        let path = entry.map_err(|e| io_error(dir, e))?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if name.starts_with(PREFIX)
+            && (name.ends_with(EXTENSION) || name.ends_with(COMPRESSED_EXTENSION))
+        {
+            paths.push(path);
+        }
+    }
+    // The names are timestamped
+    paths.sort();
+    paths.reverse();
+    paths.iter().map(|p| Backup::read(p)).collect()
+}
+
+fn rotate(dir: &Path, keep: usize) -> Result<(), ServerError> {
+    if keep == 0 {
+        return Ok(());
+    }
+    for backup in list(dir)?.into_iter().skip(keep) {
+        let path = dir.join(backup.name);
+        fs::remove_file(&path).map_err(|e| io_error(&path, e))?;
+    }
+    Ok(())
+}
+
+pub async fn watch(pool: DbPool, config: Config) {
+    let period = Duration::from_secs(config.backup_interval);
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        let (pool, config) = (pool.clone(), config.clone());
+        let backup = web::block(move || take(&mut *pool.get()?, &config)).await;
+        match backup.map_err(ServerError::from).and_then(|b| b) {
+            Ok(backup) => info!("Backup taken: {}", backup.name),
+            Err(e) => error!("scheduled backup failed: {}", e),
+        }
+    }
+}
+
+#[derive(QueryableByName)]
+struct IntegrityCheck {
+    #[diesel(sql_type = Text)]
+    integrity_check: String,
+}
+
+// Check that a database is sound and that this version knows all its migrations, returns the
+// number of migrations to apply on it
+fn validate(path: &Path) -> Result<usize, String> {
+    let mut conn =
+        SqliteConnection::establish(&path.to_string_lossy()).map_err(|e| e.to_string())?;
+    let check = sql_query("PRAGMA integrity_check")
+        .load::<IntegrityCheck>(&mut conn)
+        .map_err(|e| format!("not a SQLite database: {}", e))?;
+    if check.iter().any(|c| c.integrity_check != "ok") {
+        return Err(format!(
+            "the database is corrupted: {}",
+            check
+                .iter()
+                .map(|c| c.integrity_check.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+    let applied: HashSet<String> = conn
+        .applied_migrations()
+        .map_err(|e| format!("not a tesou database: {}", e))?
+        .iter()
+        .map(|v| v.to_string())
+        .collect();
+    if applied.is_empty() {
+        return Err("not a tesou database: no migration was applied".to_owned());
+    }
+    let known: HashSet<String> = MigrationSource::<Sqlite>::migrations(&SQLITE_MIGRATIONS)
+        .map_err(|e| e.to_string())?
+        .iter()
+        .map(|m| m.name().version().to_string())
+        .collect();
+    let mut unknown: Vec<&String> = applied.difference(&known).collect();
+    if !unknown.is_empty() {
+        unknown.sort();
+        return Err(format!(
+            "the database comes from a newer version, with the migrations {:?}",
+            unknown
+        ));
+    }
+    Ok(known.len() - applied.len())
+}
+
+// Replace the database with a backup, keeping the replaced one aside, the server must be stopped
+pub fn restore(config: &Config, file: &Path, out: &mut impl Write) -> Result<(), ServerError> {
+    let url = &config.database_url;
+    if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+        return Err(ServerError::Other(
+            "restores are only supported on SQLite, use pg_restore for PostgreSQL".to_owned(),
+        ));
+    }
+    let database = Path::new(url);
+    let restored = PathBuf::from(format!("{}.restore", url));
+    let copy = || -> io::Result<u64> {
+        let mut reader: Box<dyn io::Read> = match file.to_string_lossy().ends_with(".gz") {
+            true => Box::new(GzDecoder::new(fs::File::open(file)?)),
+            false => Box::new(fs::File::open(file)?),
+        };
+        let mut writer = fs::File::create(&restored)?;
+        let size = io::copy(&mut reader, &mut writer)?;
+        writer.sync_all()?;
+        Ok(size)
+    };
+    copy().map_err(|e| io_error(file, e))?;
+    let pending = match validate(&restored) {
+        Ok(pending) => pending,
+        Err(e) => {
+            let _ = fs::remove_file(&restored);
+            return Err(ServerError::Other(format!("{}: {}", file.display(), e)));
+        }
+    };
+
+    // Keep the replaced database, with its journal, until the restored one is checked
+    if database.exists() {
+        let previous = format!("{}.before-restore-{}", url, timestamp());
+        for suffix in ["", "-wal", "-shm"] {
+            let from = PathBuf::from(format!("{}{}", url, suffix));
+            if from.exists() {
+                let to = PathBuf::from(format!("{}{}", previous, suffix));
+                fs::rename(&from, &to).map_err(|e| io_error(&from, e))?;
+            }
+        }
+        writeln!(out, "Replaced database moved to {}", previous)?;
+    }
+    fs::rename(&restored, database).map_err(|e| io_error(database, e))?;
+    writeln!(out, "Restored {} to {}", file.display(), url)?;
+    if pending > 0 {
+        writeln!(
+            out,
+            "{} migrations will be applied at the next start",
+            pending
+        )?;
+    }
+    Ok(())
+}
+
+#[get("")]
+pub async fn read_all(cfg: web::Data<AppConfig>) -> Result<HttpResponse, ServerError> {
+    let dir = PathBuf::from(&cfg.config.backup_dir);
+    let backups = web::block(move || list(&dir)).await??;
+    Ok(HttpResponse::Ok().json(backups))
+}
+
+#[post("")]
+pub async fn create(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    cfg: web::Data<AppConfig>,
+) -> Result<HttpResponse, ServerError> {
+    let audit = NewAuditEntry::new(&req, "backups.create");
+    let mut conn = pool.get()?;
+    if let DbConnection::Postgresql(_) = *conn {
+        return Ok(HttpResponse::NotImplemented()
+            .body("backups are only supported on SQLite, use pg_dump for PostgreSQL"));
+    }
+    let config = cfg.config.clone();
+    let backup = web::block(move || -> Result<Backup, ServerError> {
+        let backup = take(&mut conn, &config)?;
+        audit
+            .target(format!("backups/{}", backup.name))
+            .details(&serde_json::json!({ "size": backup.size }))
+            .insert(&mut conn)?;
+        Ok(backup)
+    })
+    .await??;
+    Ok(HttpResponse::Created().json(backup))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_and_restore() {
+        let dir = std::env::temp_dir().join(format!("tesou_restore_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let database = dir.join("db.sqlite").to_string_lossy().into_owned();
+        let config = Config {
+            database_url: database.clone(),
+            backup_dir: dir.join("backups").to_string_lossy().into_owned(),
+            ..Config::default()
+        };
+        let count_users = |conn: &mut DbConnection| {
+            crate::schema::users::table
+                .count()
+                .get_result::<i64>(conn)
+                .unwrap()
+        };
+
+        let mut conn = DbConnection::establish(&database).unwrap();
+        conn.run_migrations().unwrap();
+        let users = count_users(&mut conn);
+        let backup = take(&mut conn, &config).unwrap();
+        diesel::delete(crate::schema::users::table)
+            .execute(&mut conn)
+            .unwrap();
+        drop(conn);
+
+        let mut out = Vec::new();
+        let file = Path::new(&config.backup_dir).join(&backup.name);
+        restore(&config, &file, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with(&format!(
+            "Replaced database moved to {}.before-restore-",
+            database
+        )));
+        let mut conn = DbConnection::establish(&database).unwrap();
+        assert_eq!(count_users(&mut conn), users);
+
+        // A database with unknown migrations is rejected, and the current one is left in place
+        diesel::sql_query(
+            "INSERT INTO __diesel_schema_migrations (version) VALUES ('29991231000000')",
+        )
+        .execute(&mut conn)
+        .unwrap();
+        let config = Config {
+            backup_compress: false,
+            ..config
+        };
+        let backup = take(&mut conn, &config).unwrap();
+        assert!(backup.name.ends_with(".sqlite"));
+        let file = Path::new(&config.backup_dir).join(&backup.name);
+        let err = restore(&config, &file, &mut Vec::new()).unwrap_err();
+        assert!(err.to_string().ends_with(
+            r#"the database comes from a newer version, with the migrations ["29991231000000"]"#
+        ));
+        assert!(!Path::new(&format!("{}.restore", database)).exists());
+
+        // ... as is a file that is not a database
+        let file = dir.join("garbage.sqlite");
+        fs::write(&file, "not a database").unwrap();
+        let err = restore(&config, &file, &mut Vec::new()).unwrap_err();
+        assert!(err.to_string().contains("not a SQLite database"));
+        assert_eq!(count_users(&mut conn), users);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
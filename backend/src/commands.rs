@@ -0,0 +1,655 @@
+//! Administration commands, run on the server host against the database without going through the
+//! HTTP API.
+
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use chrono::{DateTime, SecondsFormat};
+use clap::{Subcommand, ValueEnum};
+use diesel::prelude::*;
+use flate2::read::GzDecoder;
+
+use crate::{
+    backup,
+    config::{Config, MAX_SHARE_TOKEN_DURATION},
+    db::DbConnection,
+    errors::{ServerError, io_error},
+    keys::ShareKeySet,
+    models::{
+        audit::NewAuditEntry,
+        cell_tower, device_status,
+        plausibility::{Fix, Mode, Plausibility, Track},
+        position::{NewPosition, Position, filter_positions},
+        track,
+        user::{self, NewUser, User},
+    },
+    schema::{positions, users},
+    token::{self, Claims},
+    utils::now,
+};
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the server (the default)
+    Serve,
+    /// Apply the pending database migrations
+    Migrate,
+    /// Take a backup of the SQLite database in the backup directory
+    Backup,
+    /// Replace the SQLite database with a backup, the server must be stopped
+    Restore { file: PathBuf },
+    /// Manage the users
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Manage the share tokens
+    #[command(subcommand)]
+    Token(TokenCommand),
+    /// Manage the positions
+    #[command(subcommand)]
+    Positions(PositionsCommand),
+    /// Manage the cell towers used to locate the Cell Id positions
+    #[command(subcommand)]
+    Cells(CellsCommand),
+    /// Export the positions of a user
+    Export {
+        #[arg(long)]
+        user: i32,
+        #[arg(long, value_enum, default_value_t = Format::Gpx)]
+        format: Format,
+        /// Output file [default: the standard output]
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Import positions of a user, as exported
+    Import {
+        #[arg(long)]
+        user: i32,
+        /// File format [default: from the file extension]
+        #[arg(long, value_enum)]
+        format: Option<Format>,
+        file: PathBuf,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum UserCommand {
+    /// Add a user, and print its id
+    Add {
+        #[arg(long)]
+        name: String,
+        #[arg(long)]
+        surname: String,
+    },
+    /// List the users
+    List,
+    /// Remove a user, moving it to the trash
+    Remove {
+        id: i32,
+        /// Also remove its positions and settings
+        #[arg(long)]
+        cascade: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum TokenCommand {
+    /// Issue a share token, giving a read only access to the positions of a user
+    Issue {
+        #[arg(long)]
+        user: u16,
+        /// Validity in seconds, a year at most [default: the share token duration]
+        #[arg(long)]
+        ttl: Option<u64>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum PositionsCommand {
+    /// Delete the positions recorded before a date
+    Purge {
+        /// RFC 3339 date, or milliseconds since the epoch
+        #[arg(long, value_parser = parse_time)]
+        before: i64,
+        /// Only purge the positions of this user
+        #[arg(long)]
+        user: Option<i32>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CellsCommand {
+    /// Load the cell towers of an OpenCellID or Mozilla Location Service CSV dump, gzipped or not
+    Import {
+        /// Only import the cells of this mobile country code, can be repeated
+        #[arg(long)]
+        mcc: Vec<i32>,
+        file: PathBuf,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Format {
+    Gpx,
+    Json,
+}
+
+fn parse_time(value: &str) -> Result<i64, String> {
+    if let Ok(millis) = value.parse::<i64>() {
+        return Ok(millis);
+    }
+    DateTime::parse_from_rfc3339(value)
+        .map(|t| t.timestamp_millis())
+        .map_err(|e| {
+            format!(
+                "{} is neither a RFC 3339 date nor a timestamp: {}",
+                value, e
+            )
+        })
+}
+
+// Run an administration command, the share keys being needed to issue tokens
+pub fn run(
+    command: Command,
+    config: &Config,
+    share_keys: &ShareKeySet,
+    conn: &mut DbConnection,
+    out: &mut impl Write,
+) -> Result<(), ServerError> {
+    match command {
+        Command::Serve | Command::Restore { .. } => {
+            unreachable!("run without a database connection")
+        }
+        Command::Migrate => {
+            let applied = conn.run_migrations().map_err(ServerError::Other)?;
+            writeln!(out, "Applied {} migrations", applied.len())?;
+            for version in applied {
+                writeln!(out, "{}", version)?;
+            }
+        }
+        Command::Backup => {
+            let backup = backup::take(conn, config)?;
+            NewAuditEntry::local("backups.create")
+                .target(format!("backups/{}", backup.name))
+                .details(&serde_json::json!({ "size": backup.size }))
+                .insert(conn)?;
+            writeln!(
+                out,
+                "{}",
+                Path::new(&config.backup_dir).join(backup.name).display()
+            )?;
+        }
+        Command::User(UserCommand::Add { name, surname }) => {
+            let mut o = NewUser {
+                name,
+                surname,
+                color: None,
+                phone: None,
+                timezone: None,
+            };
+            o.trim();
+            o.validate().map_err(ServerError::Other)?;
+            let created_o = user::insert(conn, &o, NewAuditEntry::local("users.create"))?;
+            writeln!(out, "{}", created_o.id)?;
+        }
+        Command::User(UserCommand::List) => {
+            for u in users::table.order(users::id.asc()).load::<User>(conn)? {
+                writeln!(out, "{}\t{}\t{}", u.id, u.name, u.surname)?;
+            }
+        }
+        Command::User(UserCommand::Remove { id, cascade }) => {
+            user::delete_object(
+                conn,
+                id,
+                cascade,
+                config,
+                NewAuditEntry::local("users.delete"),
+            )?;
+            writeln!(out, "Deleted user {}", id)?;
+        }
+        Command::Token(TokenCommand::Issue { user, ttl }) => {
+            users::table.find(i32::from(user)).first::<User>(conn)?;
+            let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            let claims = match ttl {
+                // bounded as the share token duration of the configuration
+                Some(ttl) if !(1..=MAX_SHARE_TOKEN_DURATION).contains(&ttl) => {
+                    return Err(ServerError::Other(format!(
+                        "the ttl must be between 1 and {} seconds",
+                        MAX_SHARE_TOKEN_DURATION
+                    )));
+                }
+                Some(ttl) => Claims::Expiring {
+                    expires_at: time + ttl,
+                    user_id: user,
+                },
+                None => Claims::Share {
+                    issued_at: time,
+                    user_id: user,
+                },
+            };
+            let token = token::seal(share_keys, &claims)?;
+            NewAuditEntry::local("token.issue")
+                .target(format!("users/{}", user))
+                .details(&serde_json::json!({
+                    "key_id": format!("{:02x?}", share_keys.current_id()),
+                    "kind": if ttl.is_some() { "expiring" } else { "share" },
+                    "ttl": ttl,
+                }))
+                .insert(conn)?;
+            writeln!(out, "{}", token)?;
+        }
+        Command::Positions(PositionsCommand::Purge { before, user }) => {
+            let deleted = conn.transaction(|conn| -> Result<usize, ServerError> {
+                let mut query = positions::table
+                    .filter(positions::time.lt(before))
+                    .into_boxed();
+                if let Some(uid) = user {
+                    query = query.filter(positions::user_id.eq(uid));
+                }
+                let purged = query.load::<Position>(conn)?;
+                for chunk in purged.chunks(1000) {
+                    diesel::delete(positions::table)
+                        .filter(positions::id.eq_any(chunk.iter().map(|p| p.id)))
+                        .execute(conn)?;
+                }
+                // the purged positions are forgotten by the daily tracks as well
+                track::forget(conn, &purged)?;
+                Ok(purged.len())
+            })?;
+            NewAuditEntry::local("positions.purge")
+                .target(user.map_or("positions".to_owned(), |uid| format!("users/{}", uid)))
+                .details(&serde_json::json!({ "before": before, "deleted": deleted }))
+                .insert(conn)?;
+            writeln!(out, "Deleted {} positions", deleted)?;
+        }
+        Command::Cells(CellsCommand::Import { mcc, file }) => {
+            let f = File::open(&file).map_err(|e| io_error(&file, e))?;
+            let reader: Box<dyn BufRead> = match file.extension().is_some_and(|e| e == "gz") {
+                true => Box::new(BufReader::new(GzDecoder::new(f))),
+                false => Box::new(BufReader::new(f)),
+            };
+            let (imported, skipped) = conn.immediate_transaction(|conn| {
+                let (imported, skipped) = cell_tower::import(conn, reader, &mcc)
+                    .map_err(|e| ServerError::Other(format!("{}: {}", file.display(), e)))?;
+                NewAuditEntry::local("cell_towers.import")
+                    .target("cell_towers")
+                    .details(&serde_json::json!({
+                        "file": file.display().to_string(),
+                        "imported": imported,
+                        "skipped": skipped,
+                    }))
+                    .insert(conn)?;
+                Ok::<_, ServerError>((imported, skipped))
+            })?;
+            writeln!(
+                out,
+                "Imported {} cell towers, skipped {}",
+                imported, skipped
+            )?;
+        }
+        Command::Export {
+            user,
+            format,
+            output,
+        } => {
+            let u = users::table.find(user).first::<User>(conn)?;
+            let objects = positions::table
+                .filter(positions::user_id.eq(user))
+                .order(positions::time.asc())
+                .load::<Position>(conn)?;
+            let content = match format {
+                Format::Gpx => to_gpx(&u, &objects),
+                Format::Json => serde_json::to_string_pretty(&objects)?,
+            };
+            match output {
+                Some(path) => std::fs::write(&path, content).map_err(|e| io_error(&path, e))?,
+                None => out.write_all(content.as_bytes())?,
+            }
+        }
+        Command::Import { user, format, file } => {
+            let format = match format {
+                Some(format) => format,
+                None if file.extension().is_some_and(|e| e == "json") => Format::Json,
+                None => Format::Gpx,
+            };
+            let content = std::fs::read_to_string(&file).map_err(|e| io_error(&file, e))?;
+            let mut objects = match format {
+                Format::Gpx => from_gpx(&content),
+                Format::Json => {
+                    serde_json::from_str::<Vec<NewPosition>>(&content).map_err(|e| e.to_string())
+                }
+            }
+            .map_err(|e| ServerError::Other(format!("{}: {}", file.display(), e)))?;
+            let total = objects.len();
+            let oldest = now() - config.position_retention().as_millis() as i64;
+            let imported = conn.immediate_transaction(|conn| -> Result<usize, ServerError> {
+                users::table.find(user).first::<User>(conn)?;
+                let existing: HashSet<i64> = positions::table
+                    .filter(positions::user_id.eq(user))
+                    .select(positions::time)
+                    .load::<i64>(conn)?
+                    .into_iter()
+                    .collect();
+                // Skip the positions already recorded, or that would be purged at once
+                objects.retain(|p| p.time > oldest && !existing.contains(&p.time));
+                objects.sort_by_key(|p| p.time);
+                for p in objects.iter_mut() {
+                    p.user_id = user;
+                }
+                let mut objects =
+                    filter_positions(objects, None, Some(user), config.minimum_time_gap);
+                // Flag or reject the positions that can't be right, as when they are posted, each
+                // one being compared to the plausible fix recorded or imported before it
+                let plausibility = Plausibility::from_config(config);
+                let fixes: Vec<Fix> = positions::table
+                    .filter(positions::user_id.eq(user))
+                    .filter(positions::flagged.is_null())
+                    .order(positions::time.asc())
+                    .load::<Position>(conn)?
+                    .iter()
+                    .map(Fix::from)
+                    .collect();
+                let mut fixes = fixes.into_iter().peekable();
+                let mut track = Track::default();
+                objects.retain_mut(|p| {
+                    while let Some(fix) = fixes.next_if(|f| f.time < p.time) {
+                        track.reset(fix);
+                    }
+                    p.flagged = track.check(&plausibility, Fix::from(&*p));
+                    p.flagged.is_none() || plausibility.mode != Mode::Reject
+                });
+                if objects.is_empty() {
+                    return Ok(0);
+                }
+                crate::insert_batch!(conn, positions::table, &objects)?;
+                // The device status follows the newest plausible position
+                let newest = positions::table
+                    .filter(positions::user_id.eq(user))
+                    .filter(positions::flagged.is_null())
+                    .order(positions::time.desc())
+                    .first::<Position>(conn)
+                    .optional()?;
+                if let Some(newest) = newest
+                    && device_status::last_fix(conn, user)?.is_none_or(|t| t < newest.time)
+                {
+                    device_status::record(conn, &newest)?;
+                }
+                NewAuditEntry::local("positions.import")
+                    .target(format!("users/{}", user))
+                    .details(&serde_json::json!({
+                        "imported": objects.len(),
+                        "flagged": objects.iter().filter(|p| p.flagged.is_some()).count(),
+                    }))
+                    .insert(conn)?;
+                Ok(objects.len())
+            })?;
+            writeln!(
+                out,
+                "Imported {} positions, skipped {}",
+                imported,
+                total - imported
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn unescape(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+// Write the positions as a GPX track
+fn to_gpx(u: &User, objects: &[Position]) -> String {
+    let mut gpx = String::from(concat!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+        "<gpx version=\"1.1\" creator=\"tesou\" xmlns=\"http://www.topografix.com/GPX/1/1\">\n",
+        "  <trk>\n"
+    ));
+    gpx.push_str(&format!(
+        "    <name>{}</name>\n    <trkseg>\n",
+        escape(&format!("{} {}", u.name, u.surname))
+    ));
+    for p in objects {
+        let time = DateTime::from_timestamp_millis(p.time)
+            .unwrap_or_default()
+            .to_rfc3339_opts(SecondsFormat::Millis, true);
+        gpx.push_str(&format!(
+            "      <trkpt lat=\"{}\" lon=\"{}\"><time>{}</time><src>{}</src></trkpt>\n",
+            p.latitude,
+            p.longitude,
+            time,
+            escape(&p.source)
+        ));
+    }
+    gpx.push_str("    </trkseg>\n  </trk>\n</gpx>\n");
+    gpx
+}
+
+// Read the track points of a GPX file, which must have a time
+fn from_gpx(content: &str) -> Result<Vec<NewPosition>, String> {
+    let attribute = |tag: &str, name: &str| -> Option<String> {
+        ['"', '\''].iter().find_map(|quote| {
+            let prefix = format!(" {}={}", name, quote);
+            let start = tag.find(&prefix)? + prefix.len();
+            let end = tag[start..].find(*quote)?;
+            Some(tag[start..start + end].to_owned())
+        })
+    };
+    let element = |point: &str, name: &str| -> Option<String> {
+        let start = point.find(&format!("<{}>", name))? + name.len() + 2;
+        let end = point[start..].find(&format!("</{}>", name))?;
+        Some(unescape(point[start..start + end].trim()))
+    };
+    let mut objects = Vec::new();
+    for point in content.split("<trkpt").skip(1) {
+        let point = &point[..point.find("</trkpt>").unwrap_or(point.len())];
+        let tag = &point[..point.find('>').unwrap_or(point.len())];
+        let coordinate = |name| {
+            attribute(tag, name)
+                .and_then(|v| v.parse::<f64>().ok())
+                .ok_or_else(|| format!("track point without a valid {}", name))
+        };
+        let time = element(point, "time").ok_or("track point without a time")?;
+        objects.push(NewPosition {
+            user_id: 0,
+            latitude: coordinate("lat")?,
+            longitude: coordinate("lon")?,
+            source: element(point, "src").unwrap_or_else(|| "GPX".to_owned()),
+            battery_level: 0,
+            sport_mode: false,
+            time: parse_time(&time)?,
+            flagged: None,
+        });
+    }
+    Ok(objects)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gpx() {
+        let u = User {
+            id: 1,
+            name: "Jane".to_owned(),
+            surname: "O'Neil & co".to_owned(),
+            color: None,
+            phone: None,
+            timezone: None,
+            avatar_updated_at: None,
+        };
+        let position = |time, source: &str| Position {
+            id: 1,
+            user_id: 1,
+            latitude: 45.74846,
+            longitude: -4.84671,
+            source: source.to_owned(),
+            battery_level: 50,
+            sport_mode: false,
+            time,
+            flagged: None,
+        };
+        let gpx = to_gpx(
+            &u,
+            &[
+                position(1_800_000_000_123, "GPS"),
+                position(1_800_000_060_000, "<Cell Id>"),
+            ],
+        );
+        assert!(gpx.contains("<name>Jane O&apos;Neil &amp; co</name>"));
+        assert!(gpx.contains(
+            r#"<trkpt lat="45.74846" lon="-4.84671"><time>2027-01-15T08:00:00.123Z</time><src>GPS</src></trkpt>"#
+        ));
+        let read = from_gpx(&gpx).unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[0].latitude, 45.74846);
+        assert_eq!(read[0].longitude, -4.84671);
+        assert_eq!(read[0].time, 1_800_000_000_123);
+        assert_eq!(read[1].source, "<Cell Id>");
+
+        // Other writers use single quotes and offsets, and may omit the source
+        let read = from_gpx(
+            "<trkseg><trkpt lon='2.5' lat='48.1'>\n<ele>35</ele>\n<time>2027-01-15T09:00:00+01:00</time>\n</trkpt></trkseg>",
+        )
+        .unwrap();
+        assert_eq!(read[0].latitude, 48.1);
+        assert_eq!(read[0].longitude, 2.5);
+        assert_eq!(read[0].time, 1_800_000_000_000);
+        assert_eq!(read[0].source, "GPX");
+        assert_eq!(
+            from_gpx(r#"<trkpt lat="48.1" lon="2.5"></trkpt>"#).unwrap_err(),
+            "track point without a time"
+        );
+        assert_eq!(
+            from_gpx(r#"<trkpt lat="north" lon="2.5"><time>1</time></trkpt>"#).unwrap_err(),
+            "track point without a valid lat"
+        );
+    }
+
+    #[test]
+    fn test_issue_and_import() {
+        let mut conn = DbConnection::establish(":memory:").unwrap();
+        conn.run_migrations().unwrap();
+        let config = Config::default();
+        let share_keys = ShareKeySet::new("secret");
+        let mut run_with = |command| {
+            let mut out = Vec::new();
+            run(command, &config, &share_keys, &mut conn, &mut out)
+                .map(|_| String::from_utf8(out).unwrap())
+        };
+        let user: u16 = run_with(Command::User(UserCommand::Add {
+            name: "Kim".to_owned(),
+            surname: "Imported".to_owned(),
+        }))
+        .unwrap()
+        .trim()
+        .parse()
+        .unwrap();
+
+        // The tokens with a custom validity are not emergency ones, and are bounded
+        let issue = |ttl| Command::Token(TokenCommand::Issue { user, ttl });
+        let token = run_with(issue(Some(60))).unwrap();
+        assert!(matches!(
+            token::open(&share_keys, 0, token.trim()),
+            Ok(Claims::Expiring { user_id, .. }) if user_id == user
+        ));
+        for ttl in [0, MAX_SHARE_TOKEN_DURATION + 1, u64::MAX] {
+            assert_eq!(
+                run_with(issue(Some(ttl))).unwrap_err().to_string(),
+                "Error: the ttl must be between 1 and 31536000 seconds"
+            );
+        }
+
+        // The imported positions are checked for plausibility
+        let time = now() - 60 * 60 * 1000;
+        let file = std::env::temp_dir().join(format!("tesou-import-{}.json", time));
+        std::fs::write(
+            &file,
+            serde_json::to_string(
+                &[
+                    (45.75, 4.85, 0),
+                    (48.85, 2.35, 60_000),
+                    (45.76, 4.85, 120_000),
+                ]
+                .map(|(latitude, longitude, t)| NewPosition {
+                    user_id: 0,
+                    latitude,
+                    longitude,
+                    source: "GPS".to_owned(),
+                    time: time + t,
+                    ..Default::default()
+                }),
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let output = run_with(Command::Import {
+            user: user.into(),
+            format: None,
+            file: file.clone(),
+        })
+        .unwrap();
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(output, "Imported 3 positions, skipped 0\n");
+        let flagged = positions::table
+            .order(positions::time.asc())
+            .select(positions::flagged)
+            .load::<Option<String>>(&mut conn)
+            .unwrap();
+        assert!(flagged[0].is_none() && flagged[2].is_none());
+        assert!(
+            flagged[1]
+                .as_deref()
+                .is_some_and(|reason| reason.starts_with("implausible speed"))
+        );
+
+        // The purged positions are removed from the daily tracks
+        track::update_tracks(&mut conn, 0.0).unwrap();
+        let mut out = Vec::new();
+        let purge = Command::Positions(PositionsCommand::Purge {
+            before: time + 90_000,
+            user: None,
+        });
+        run(
+            purge,
+            &config,
+            &ShareKeySet::new("secret"),
+            &mut conn,
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "Deleted 2 positions\n");
+        let tracks = crate::schema::daily_tracks::table
+            .select(crate::schema::daily_tracks::positions)
+            .load::<String>(&mut conn)
+            .unwrap()
+            .concat();
+        assert!(tracks.contains("45.76") && !tracks.contains("45.75"));
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("1800000000000"), Ok(1_800_000_000_000));
+        assert_eq!(parse_time("2027-01-15T08:00:00Z"), Ok(1_800_000_000_000));
+        assert!(parse_time("yesterday").is_err());
+    }
+}
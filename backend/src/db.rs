@@ -0,0 +1,94 @@
+//! Database connection, either to SQLite or to PostgreSQL according to the database url.
+
+use diesel::{
+    PgConnection, SqliteConnection,
+    connection::{AnsiTransactionManager, TransactionManager},
+    prelude::*,
+};
+use diesel_migrations::{EmbeddedMigrations, MigrationHarness};
+
+pub const SQLITE_MIGRATIONS: EmbeddedMigrations = embed_migrations!("db/migrations/sqlite");
+pub const POSTGRES_MIGRATIONS: EmbeddedMigrations = embed_migrations!("db/migrations/postgres");
+
+// The urls that are not PostgreSQL ones (postgres://...) are SQLite database paths
+#[derive(diesel::MultiConnection)]
+pub enum DbConnection {
+    Postgresql(PgConnection),
+    Sqlite(SqliteConnection),
+}
+
+impl DbConnection {
+    pub fn backend_name(&self) -> &'static str {
+        match self {
+            DbConnection::Postgresql(_) => "PostgreSQL",
+            DbConnection::Sqlite(_) => "SQLite",
+        }
+    }
+
+    // Apply the pending migrations, returns the versions applied
+    pub fn run_migrations(&mut self) -> Result<Vec<String>, String> {
+        let migrations = match self {
+            DbConnection::Postgresql(_) => POSTGRES_MIGRATIONS,
+            DbConnection::Sqlite(_) => SQLITE_MIGRATIONS,
+        };
+        self.run_pending_migrations(migrations)
+            .map(|versions| versions.iter().map(|v| v.to_string()).collect())
+            .map_err(|e| e.to_string())
+    }
+
+    // Run a transaction that takes the SQLite write lock at once, so that a read followed by a
+    // write cannot interleave with another writer, or a plain transaction on PostgreSQL
+    pub fn immediate_transaction<T, E, F>(&mut self, f: F) -> Result<T, E>
+    where
+        F: FnOnce(&mut Self) -> Result<T, E>,
+        E: From<diesel::result::Error>,
+    {
+        match self {
+            DbConnection::Sqlite(conn) => {
+                AnsiTransactionManager::begin_transaction_sql(conn, "BEGIN IMMEDIATE")?
+            }
+            DbConnection::Postgresql(_) => return self.transaction(f),
+        }
+        match f(self) {
+            Ok(value) => {
+                <Self as Connection>::TransactionManager::commit_transaction(self)?;
+                Ok(value)
+            }
+            Err(e) => {
+                <Self as Connection>::TransactionManager::rollback_transaction(self)?;
+                Err(e)
+            }
+        }
+    }
+}
+
+// Insert a row, replacing the one with the same key if any, as the SQLite REPLACE does
+#[macro_export]
+macro_rules! replace_into {
+    ($conn:expr, $table:expr, $key:expr, $values:expr) => {
+        match &mut *$conn {
+            $crate::db::DbConnection::Sqlite(conn) => {
+                diesel::replace_into($table).values($values).execute(conn)
+            }
+            $crate::db::DbConnection::Postgresql(conn) => conn.transaction(|conn| {
+                diesel::delete($table.filter($key)).execute(conn)?;
+                diesel::insert_into($table).values($values).execute(conn)
+            }),
+        }
+    };
+}
+
+// Insert several rows at once, which each backend does its own way
+#[macro_export]
+macro_rules! insert_batch {
+    ($conn:expr, $table:expr, $values:expr) => {
+        match &mut *$conn {
+            $crate::db::DbConnection::Sqlite(conn) => {
+                diesel::insert_into($table).values($values).execute(conn)
+            }
+            $crate::db::DbConnection::Postgresql(conn) => {
+                diesel::insert_into($table).values($values).execute(conn)
+            }
+        }
+    };
+}
@@ -1,24 +1,59 @@
 use std::time::Duration;
 
-use diesel::{SqliteConnection, connection::SimpleConnection};
+use diesel::connection::SimpleConnection;
 
+use crate::{config::Config, db::DbConnection};
+
+// Options of the SQLite connections, PostgreSQL always enforces the foreign keys and waits for locks
 #[derive(Debug)]
 pub struct ConnectionOptions {
     pub enable_foreign_keys: bool,
     pub busy_timeout: Option<Duration>,
+    // the write-ahead log lets the readers go on while writing, it persists in the database file
+    pub enable_wal: bool,
+    pub synchronous: Option<String>,
+    pub cache_size_kib: Option<u64>,
+    pub mmap_size: Option<u64>,
+}
+
+impl ConnectionOptions {
+    pub fn from_config(config: &Config) -> Self {
+        ConnectionOptions {
+            enable_foreign_keys: config.foreign_keys,
+            busy_timeout: Some(Duration::from_secs(30)),
+            enable_wal: config.sqlite_wal,
+            synchronous: Some(config.sqlite_synchronous.to_uppercase()),
+            cache_size_kib: Some(config.sqlite_cache_size),
+            mmap_size: Some(config.sqlite_mmap_size),
+        }
+    }
 }
 
-impl diesel::r2d2::CustomizeConnection<SqliteConnection, diesel::r2d2::Error>
-    for ConnectionOptions
-{
-    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), diesel::r2d2::Error> {
+impl diesel::r2d2::CustomizeConnection<DbConnection, diesel::r2d2::Error> for ConnectionOptions {
+    fn on_acquire(&self, conn: &mut DbConnection) -> Result<(), diesel::r2d2::Error> {
+        let DbConnection::Sqlite(conn) = conn else {
+            return Ok(());
+        };
         (|| {
-            if self.enable_foreign_keys {
-                conn.batch_execute("PRAGMA foreign_keys = ON;")?;
-            }
             if let Some(d) = self.busy_timeout {
                 conn.batch_execute(&format!("PRAGMA busy_timeout = {};", d.as_millis()))?;
             }
+            if self.enable_wal {
+                conn.batch_execute("PRAGMA journal_mode = WAL;")?;
+            }
+            if let Some(synchronous) = &self.synchronous {
+                conn.batch_execute(&format!("PRAGMA synchronous = {};", synchronous))?;
+            }
+            if let Some(cache_size) = self.cache_size_kib {
+                // a negative size is in KiB rather than in pages
+                conn.batch_execute(&format!("PRAGMA cache_size = -{};", cache_size))?;
+            }
+            if let Some(mmap_size) = self.mmap_size {
+                conn.batch_execute(&format!("PRAGMA mmap_size = {};", mmap_size))?;
+            }
+            if self.enable_foreign_keys {
+                conn.batch_execute("PRAGMA foreign_keys = ON;")?;
+            }
             Ok(())
         })()
         .map_err(diesel::r2d2::Error::QueryError)
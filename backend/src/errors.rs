@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::time::SystemTimeError;
 
 use actix_web::HttpResponse;
@@ -14,6 +15,8 @@ pub enum ServerError {
     DieselNotFound,
     DieselDatabaseError(String),
     Image(String),
+    // The children of an object that is deleted without cascading, with their count by table
+    Dependents(BTreeMap<&'static str, usize>),
 }
 
 impl std::fmt::Display for ServerError {
@@ -26,6 +29,14 @@ impl std::fmt::Display for ServerError {
             ServerError::DieselDatabaseError(m) => write!(f, "{}", m),
             ServerError::Image(m) => write!(f, "Image error: {}", m),
             ServerError::Other(m) => write!(f, "Error: {}", m),
+            ServerError::Dependents(d) => write!(
+                f,
+                "the object has dependents, that must be deleted along with it: {}",
+                d.iter()
+                    .map(|(table, count)| format!("{} {}", count, table))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
         }
     }
 }
@@ -45,6 +56,10 @@ impl ResponseError for ServerError {
                 log::info!("{}", m);
                 HttpResponse::NotFound().body(m.clone())
             }
+            ServerError::Dependents(d) => HttpResponse::Conflict().json(serde_json::json!({
+                "message": "the object has dependents, delete it with ?cascade=true to delete them too",
+                "dependents": d,
+            })),
         }
     }
 }
@@ -79,6 +94,11 @@ impl From<std::io::Error> for ServerError {
     }
 }
 
+// Errors of file operations, which name the file
+pub fn io_error(path: &std::path::Path, err: std::io::Error) -> ServerError {
+    ServerError::Other(format!("{}: {}", path.display(), err))
+}
+
 impl From<PayloadError> for ServerError {
     fn from(err: PayloadError) -> ServerError {
         ServerError::Image(err.to_string())
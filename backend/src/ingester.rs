@@ -0,0 +1,139 @@
+//! Batched recording of the positions posted by the devices.
+//!
+//! With the sport mode on, every device posts a fix each second. Rather than a transaction and a
+//! pool checkout per request, the [`Ingester`] takes the requests waiting in its queue, up to the
+//! batch size, and records them in a single transaction on a single connection. The requests
+//! arriving while a batch is written make up the next one, so that the batches grow with the load
+//! without delaying the lone requests.
+
+use std::time::Duration;
+
+use actix_web::web;
+use diesel::QueryResult;
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::{Instant, timeout_at},
+};
+
+use crate::{
+    config::Config,
+    db::DbConnection,
+    models::{
+        plausibility::Plausibility,
+        position::{Ingested, NewPosition, record_batch},
+    },
+};
+
+type DbPool = r2d2::Pool<diesel::r2d2::ConnectionManager<DbConnection>>;
+
+// positions posted by a device, and where to send the outcome of their recording
+struct Request {
+    positions: Vec<NewPosition>,
+    res_tx: oneshot::Sender<QueryResult<Ingested>>,
+}
+
+// call and spawn [`run`](Self::run) to start recording the positions
+pub struct Ingester {
+    pool: DbPool,
+    batch_size: usize,
+    // how long to wait for more requests once one is received
+    window: Duration,
+    minimum_time_gap: i64,
+    retention: Duration,
+    plausibility: Plausibility,
+    req_rx: mpsc::Receiver<Request>,
+}
+
+impl Ingester {
+    pub fn new(pool: DbPool, config: &Config) -> (Self, IngesterHandle) {
+        // the senders wait while a few batches are queued
+        let (req_tx, req_rx) = mpsc::channel(4 * config.ingest_batch_size);
+        (
+            Ingester {
+                pool,
+                batch_size: config.ingest_batch_size,
+                window: Duration::from_millis(config.ingest_batch_window),
+                minimum_time_gap: config.minimum_time_gap,
+                retention: config.position_retention(),
+                plausibility: Plausibility::from_config(config),
+                req_rx,
+            },
+            IngesterHandle { req_tx },
+        )
+    }
+
+    pub async fn run(mut self) {
+        while let Some(request) = self.req_rx.recv().await {
+            let mut batch = vec![request];
+            let deadline = Instant::now() + self.window;
+            while batch.len() < self.batch_size {
+                match self.req_rx.try_recv() {
+                    Ok(request) => batch.push(request),
+                    Err(_) if self.window.is_zero() => break,
+                    Err(_) => match timeout_at(deadline, self.req_rx.recv()).await {
+                        Ok(Some(request)) => batch.push(request),
+                        _ => break,
+                    },
+                }
+            }
+            self.write(batch).await;
+        }
+    }
+
+    async fn write(&self, batch: Vec<Request>) {
+        let (positions, res_txs): (Vec<_>, Vec<_>) =
+            batch.into_iter().map(|r| (r.positions, r.res_tx)).unzip();
+        let pool = self.pool.clone();
+        let (minimum_time_gap, retention, plausibility) =
+            (self.minimum_time_gap, self.retention, self.plausibility);
+        let results = web::block(move || {
+            let mut conn = pool
+                .get()
+                .map_err(|e| diesel::result::Error::QueryBuilderError(e.into()))?;
+            record_batch(
+                &mut conn,
+                positions,
+                minimum_time_gap,
+                retention,
+                plausibility,
+            )
+        })
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|results| results.map_err(|e| e.to_string()));
+        match results {
+            Ok(results) => {
+                for (res_tx, result) in res_txs.into_iter().zip(results) {
+                    // the request may have been dropped in the meantime
+                    let _ = res_tx.send(result);
+                }
+            }
+            Err(e) => {
+                log::error!("couldn't record a batch of positions: {}", e);
+                for res_tx in res_txs {
+                    let _ = res_tx.send(Err(diesel::result::Error::RollbackTransaction));
+                }
+            }
+        }
+    }
+}
+
+// handle to the [`Ingester`], that can be cloned and shared
+#[derive(Clone)]
+pub struct IngesterHandle {
+    req_tx: mpsc::Sender<Request>,
+}
+
+impl IngesterHandle {
+    // Record the positions posted by a device
+    pub async fn record(&self, positions: Vec<NewPosition>) -> QueryResult<Ingested> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.req_tx
+            .send(Request { positions, res_tx })
+            .await
+            .map_err(|_| diesel::result::Error::BrokenTransactionManager)?;
+        res_rx
+            .await
+            .map_err(|_| diesel::result::Error::BrokenTransactionManager)?
+    }
+}
@@ -0,0 +1,247 @@
+//! Share token signing keys.
+//!
+//! Share tokens are sealed with a key set that is independent from the main bearer token, so
+//! that either one can be rotated without touching the other. Each key is derived from an
+//! operator provided secret, or from one generated on first start, with HKDF-SHA256, and carries a
+//! short key id that is embedded in the tokens it seals. After a rotation, the previous key is
+//! still accepted until a given time, so that the share tokens already handed out keep working
+//! until they expire.
+
+use chacha20poly1305::{
+    ChaCha20Poly1305,
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload, generic_array::GenericArray},
+};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+use crate::errors::ServerError;
+
+// version of the binary token layout : version (1) | key id (4) | nonce (12) | ciphertext
+pub const TOKEN_VERSION: u8 = 1;
+const KEY_ID_LEN: usize = 4;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = 1 + KEY_ID_LEN;
+
+const CIPHER_KEY_INFO: &[u8] = b"tesou share token cipher key";
+const KEY_ID_INFO: &[u8] = b"tesou share token key id";
+
+pub type KeyId = [u8; KEY_ID_LEN];
+
+pub struct ShareKey {
+    id: KeyId,
+    cipher: ChaCha20Poly1305,
+}
+
+impl ShareKey {
+    // Derive a cipher key and its id from a secret
+    pub fn derive(secret: &str) -> Self {
+        let hk = Hkdf::<Sha256>::new(None, secret.as_bytes());
+        let mut key = [0u8; 32];
+        hk.expand(CIPHER_KEY_INFO, &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        let mut id = [0u8; KEY_ID_LEN];
+        hk.expand(KEY_ID_INFO, &mut id)
+            .expect("4 bytes is a valid HKDF-SHA256 output length");
+        ShareKey {
+            id,
+            cipher: ChaCha20Poly1305::new(&key.into()),
+        }
+    }
+}
+
+pub struct ShareKeySet {
+    current: ShareKey,
+    previous: Option<(ShareKey, u64)>,
+}
+
+impl ShareKeySet {
+    pub fn new(current_secret: &str) -> Self {
+        ShareKeySet {
+            current: ShareKey::derive(current_secret),
+            previous: None,
+        }
+    }
+
+    // Derive the keys from the configured secret, or from the key file, generated the first time.
+    // They are rotated by moving the secret to share_token_previous_key, accepted until the unix
+    // time share_token_previous_valid_until
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        let share_keys = match &config.share_token_key {
+            Some(secret) => ShareKeySet::new(secret),
+            None => ShareKeySet::new(&load_or_generate_secret(Path::new(
+                &config.share_token_key_file,
+            ))?),
+        };
+        // the validation of the configuration makes sure the time is given with the key
+        match (
+            &config.share_token_previous_key,
+            config.share_token_previous_valid_until,
+        ) {
+            (Some(previous_key), Some(valid_until)) => {
+                Ok(share_keys.with_previous(previous_key, valid_until))
+            }
+            _ => Ok(share_keys),
+        }
+    }
+
+    // Keep accepting the tokens sealed with the previous key until the given unix time
+    pub fn with_previous(mut self, previous_secret: &str, valid_until: u64) -> Self {
+        self.previous = Some((ShareKey::derive(previous_secret), valid_until));
+        self
+    }
+
+    pub fn current_id(&self) -> KeyId {
+        self.current.id
+    }
+
+    // Seal a payload with the current key
+    pub fn seal(&self, payload: &[u8]) -> Result<Vec<u8>, ServerError> {
+        let mut token = Vec::with_capacity(HEADER_LEN + NONCE_LEN + payload.len() + 16);
+        token.push(TOKEN_VERSION);
+        token.extend_from_slice(&self.current.id);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng); // 96-bits; unique per message
+        let ciphertext = self.current.cipher.encrypt(
+            &nonce,
+            Payload {
+                msg: payload,
+                aad: &token[..HEADER_LEN],
+            },
+        )?;
+        token.extend_from_slice(&nonce);
+        token.extend_from_slice(&ciphertext);
+        Ok(token)
+    }
+
+    // Open a token sealed with the current key, or with the previous key if still in its grace period
+    pub fn open(&self, token: &[u8]) -> Result<Vec<u8>, &'static str> {
+        if token.len() < HEADER_LEN + NONCE_LEN {
+            return Err("Wrong token!");
+        }
+        if token[0] != TOKEN_VERSION {
+            return Err("unsupported token version");
+        }
+        let kid: KeyId = token[1..HEADER_LEN]
+            .try_into()
+            .map_err(|_| "could not extract key id from token")?;
+        let key = if kid == self.current.id {
+            &self.current
+        } else {
+            match &self.previous {
+                Some((previous, valid_until)) if previous.id == kid => {
+                    if unix_time() > *valid_until {
+                        return Err("token key has been retired");
+                    }
+                    previous
+                }
+                _ => return Err("unknown token key"),
+            }
+        };
+        let nonce = GenericArray::from_slice(&token[HEADER_LEN..HEADER_LEN + NONCE_LEN]);
+        key.cipher
+            .decrypt(
+                nonce,
+                Payload {
+                    msg: &token[HEADER_LEN + NONCE_LEN..],
+                    aad: &token[..HEADER_LEN],
+                },
+            )
+            .map_err(|_| "could not decipher token data")
+    }
+}
+
+// Load the secret from a file, generating it the first time
+fn load_or_generate_secret(path: &Path) -> Result<String, String> {
+    match std::fs::read_to_string(path) {
+        Ok(secret) if !secret.trim().is_empty() => Ok(secret.trim().to_owned()),
+        Ok(_) => Err(format!("{} is empty", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let secret = crate::utils::random_string();
+            std::fs::write(path, &secret).map_err(|e| e.to_string())?;
+            Ok(secret)
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_rotation() {
+        let old_keys = ShareKeySet::new("old secret");
+        let token = old_keys.seal(b"payload").unwrap();
+        assert_eq!(token[0], TOKEN_VERSION);
+        assert_eq!(token[1..5], old_keys.current_id());
+        assert_eq!(old_keys.open(&token).unwrap(), b"payload");
+
+        // Derivation is deterministic, and distinct secrets give distinct key ids
+        assert_eq!(
+            ShareKey::derive("old secret").id,
+            ShareKey::derive("old secret").id
+        );
+        assert_ne!(
+            ShareKey::derive("old secret").id,
+            ShareKey::derive("new secret").id
+        );
+
+        // A rotated key set still opens the old tokens until the given time
+        let rotated = ShareKeySet::new("new secret").with_previous("old secret", unix_time() + 60);
+        assert_eq!(rotated.open(&token).unwrap(), b"payload");
+        let new_token = rotated.seal(b"payload").unwrap();
+        assert_eq!(new_token[1..5], rotated.current_id());
+        assert_eq!(rotated.open(&new_token).unwrap(), b"payload");
+
+        // ... but not after it
+        let retired = ShareKeySet::new("new secret").with_previous("old secret", unix_time() - 1);
+        assert_eq!(retired.open(&token), Err("token key has been retired"));
+
+        // Without the previous key, the old tokens are unknown
+        let new_keys = ShareKeySet::new("new secret");
+        assert_eq!(new_keys.open(&token), Err("unknown token key"));
+
+        // A tampered header is rejected
+        let mut tampered = new_token.clone();
+        tampered[0] = 2;
+        assert_eq!(new_keys.open(&tampered), Err("unsupported token version"));
+        let mut tampered = new_token.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert_eq!(
+            new_keys.open(&tampered),
+            Err("could not decipher token data")
+        );
+
+        // A too short token is rejected
+        assert_eq!(new_keys.open(&[1, 2, 3]), Err("Wrong token!"));
+    }
+
+    #[test]
+    fn test_generated_secret() {
+        let path = std::env::temp_dir().join(format!("tesou_share_key_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        // The secret is generated once, then read back
+        let secret = load_or_generate_secret(&path).unwrap();
+        assert_eq!(secret.len(), 48);
+        assert_eq!(load_or_generate_secret(&path).unwrap(), secret);
+        std::fs::write(&path, "").unwrap();
+        assert!(
+            load_or_generate_secret(&path)
+                .unwrap_err()
+                .ends_with("is empty")
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
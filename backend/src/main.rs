@@ -4,23 +4,37 @@ extern crate diesel;
 extern crate diesel_migrations;
 
 use std::env;
+use std::path::Path;
 
 use actix_web::HttpServer;
 use actix_web::web::Data;
-use diesel::prelude::*;
+use clap::Parser;
 use diesel::r2d2::{self, ConnectionManager};
-use diesel_migrations::{EmbeddedMigrations, MigrationHarness};
 use positions_server::PositionsServer;
 use tokio::{spawn, try_join};
 
 use crate::app::AppConfig;
+use crate::commands::Command;
+use crate::config::{Cli, Config};
+use crate::db::DbConnection;
+use crate::keys::ShareKeySet;
 
 mod app;
+mod backup;
+mod commands;
+mod config;
+mod db;
 mod db_options;
 mod errors;
+mod ingester;
+mod keys;
 mod models;
+mod notifiers;
+mod oidc;
 mod positions_handler;
 mod positions_server;
+mod pubsub;
+mod rate_limit;
 mod schema;
 #[cfg(test)]
 pub mod tester;
@@ -28,11 +42,25 @@ pub mod tester;
 mod tests;
 mod token;
 mod utils;
+mod webpush;
 
 use log::info;
 
+type DbPool = r2d2::Pool<ConnectionManager<DbConnection>>;
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    // Load the configuration, from the file, the environment and the command line
+    let cli = Cli::parse();
+    let config = Config::load(&cli).unwrap_or_else(|e| {
+        eprintln!("invalid configuration: {}", e);
+        std::process::exit(2);
+    });
+    if cli.print_config {
+        print!("{}", config.redacted().to_toml());
+        return Ok(());
+    }
+
     if env::var("RUST_LOG").is_err() {
         // TODO: Audit that the environment access only happens in single-threaded code.
         unsafe { env::set_var("RUST_LOG", "info") };
@@ -40,40 +68,192 @@ async fn main() -> std::io::Result<()> {
 
     env_logger::init();
 
-    // create the db folder if it doesn't already exist
-    std::fs::create_dir_all("db").expect("failed creating db folder");
+    match cli.command {
+        None | Some(Command::Serve) => serve(config).await,
+        Some(command) => {
+            run_command(command, config);
+            Ok(())
+        }
+    }
+}
+
+fn create_pool(config: &Config) -> DbPool {
+    // create the folders of the database and of the generated keys if they don't already exist
+    let sqlite = !config.database_url.starts_with("postgres");
+    for file in [
+        Some(&config.share_token_key_file),
+        Some(&config.vapid_private_key_file),
+        Some(&config.database_url).filter(|_| sqlite),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        if let Some(dir) = Path::new(file)
+            .parent()
+            .filter(|d| !d.as_os_str().is_empty())
+        {
+            std::fs::create_dir_all(dir).expect("failed creating db folder");
+        }
+    }
 
-    // set up database connection pool
-    let manager = ConnectionManager::<SqliteConnection>::new("db/db.sqlite");
-    let pool = r2d2::Pool::builder()
-        .connection_customizer(Box::new(db_options::ConnectionOptions {
-            enable_foreign_keys: false,
-            busy_timeout: Some(std::time::Duration::from_secs(30)),
-        }))
+    // set up database connection pool, to a SQLite file unless a PostgreSQL url is given
+    let manager = ConnectionManager::<DbConnection>::new(&config.database_url);
+    r2d2::Pool::builder()
+        .connection_customizer(Box::new(db_options::ConnectionOptions::from_config(config)))
         .build(manager)
-        .expect("failed to create pool.");
-    pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("db/migrations");
-    pool.get()
-        .expect("couldn't get db connection from pool")
-        .run_pending_migrations(MIGRATIONS)
-        .expect("couldn't run migrations");
+        .expect("failed to create pool.")
+}
+
+// Run an administration command, exiting with a non zero status on error
+fn run_command(command: Command, config: Config) {
+    let result = match command {
+        // The database must not be opened while it is replaced
+        Command::Restore { file } => backup::restore(&config, &file, &mut std::io::stdout().lock()),
+        command => {
+            let pool = create_pool(&config);
+            let mut conn = pool.get().expect("couldn't get db connection from pool");
+            if !matches!(command, Command::Migrate) {
+                conn.run_migrations().expect("couldn't run migrations");
+            }
+            let share_keys =
+                ShareKeySet::from_config(&config).expect("couldn't set up the share token keys");
+            commands::run(
+                command,
+                &config,
+                &share_keys,
+                &mut conn,
+                &mut std::io::stdout().lock(),
+            )
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn serve(config: Config) -> std::io::Result<()> {
+    let pool = create_pool(&config);
+    let mut conn = pool.get().expect("couldn't get db connection from pool");
+    conn.run_migrations().expect("couldn't run migrations");
+    info!("Database backend: {}", conn.backend_name());
+    drop(conn);
 
     // Set up authorization token
     let app_config = AppConfig::new(
-        env::var("TOKEN").unwrap_or_else(|_| -> String {
+        config.token.clone().unwrap_or_else(|| {
             let token = crate::utils::random_string();
             info!("Authorization token: {}", token);
             token
         }),
-        env::var("API_KEY").ok(),
+        config.api_key.clone(),
+    )
+    .with_config(config);
+
+    // Set up share token keys, independent from the authorization token (rotate by moving
+    // share_token_key, or the generated key, to share_token_previous_key)
+    let share_keys =
+        ShareKeySet::from_config(&app_config.config).expect("couldn't set up the share token keys");
+    info!("Share token key id: {:02x?}", share_keys.current_id());
+    let app_config = app_config.with_share_keys(share_keys);
+    // Record the positions in batches
+    let (ingester, ingester_handle) =
+        crate::ingester::Ingester::new(pool.clone(), &app_config.config);
+    spawn(ingester.run());
+    let app_config = app_config.with_ingester(ingester_handle);
+
+    // Set up brute force protection
+    let auth_limiter = crate::rate_limit::AuthLimiter::new(
+        app_config.config.auth_max_failures,
+        app_config.config.auth_lockout(),
+        std::time::Duration::from_secs(60 * 60),
+    );
+    let app_config = app_config.with_auth_limiter(auth_limiter);
+    // Set up OpenID Connect login if configured
+    let app_config = match crate::oidc::OidcConfig::from_config(&app_config.config) {
+        Some(oidc_config) => {
+            info!("OpenID Connect login enabled with {}", oidc_config.issuer);
+            app_config.with_oidc(crate::oidc::Oidc::new(oidc_config))
+        }
+        None => app_config,
+    };
+
+    // Set up the public url of the web application, used in the emergency share links
+    let app_config = match app_config.config.public_url.clone() {
+        Some(public_url) => app_config.with_public_url(public_url),
+        None => app_config,
+    };
+
+    // Set up Web Push, with a VAPID key generated on first start unless given
+    let vapid_keys = match &app_config.config.vapid_private_key {
+        Some(key) => crate::webpush::VapidKeys::from_base64(key),
+        None => crate::webpush::VapidKeys::load_or_generate(Path::new(
+            &app_config.config.vapid_private_key_file,
+        )),
+    }
+    .expect("couldn't set up the VAPID key");
+    let web_push = crate::webpush::WebPush::new(
+        vapid_keys,
+        app_config.config.vapid_subject.clone(),
+        pool.clone(),
+    );
+    info!("VAPID public key: {}", web_push.public_key());
+
+    // Set up the alerts notifiers
+    let mut notifiers = crate::notifiers::Notifiers::from_config(&app_config.config);
+    notifiers.push(Box::new(web_push.clone()));
+    let notifiers = crate::notifiers::Notifiers::new(notifiers).with_emergency_contacts(
+        crate::notifiers::split_names(&app_config.config.alert_emergency_contacts),
     );
+    info!("Alerts notifiers: {:?}", notifiers.names());
+    let app_config = app_config.with_notifiers(notifiers).with_web_push(web_push);
+
     // Data should be constructed outside the HttpServer::new closure if shared, potentially mutable state is desired...
     let app_config = Data::new(app_config);
-    let bind = "0.0.0.0:8080";
+    let bind = app_config.config.bind.clone();
 
     // Positions server
     let (positions_server, server_tx) = PositionsServer::new();
     let positions_server = spawn(positions_server.run());
+    // Share the broadcasts with the other instances if configured
+    let server_tx = match &app_config.config.pubsub_url {
+        Some(url) => {
+            let pubsub = crate::pubsub::Postgres::connect(url, server_tx.clone())
+                .await
+                .expect("couldn't connect to the pub/sub backend");
+            server_tx.with_pubsub(std::sync::Arc::new(pubsub))
+        }
+        None => server_tx,
+    };
+    info!("Positions broadcast backend: {}", server_tx.pubsub_name());
+
+    // Scheduled backups
+    if app_config.config.backup_interval > 0 {
+        info!(
+            "Backups every {} seconds in {}",
+            app_config.config.backup_interval, app_config.config.backup_dir
+        );
+        spawn(crate::backup::watch(
+            pool.clone(),
+            app_config.config.clone(),
+        ));
+    }
+
+    // Daily tracks, kept beyond the positions retention
+    if app_config.config.track_interval > 0 {
+        spawn(crate::models::track::watch(
+            pool.clone(),
+            app_config.config.clone(),
+        ));
+    }
+
+    // Offline devices alerts
+    spawn(crate::models::alert::watch_offline(
+        pool.clone(),
+        app_config.clone(),
+        server_tx.clone(),
+        app_config.config.alert_check_interval(),
+    ));
 
     // Start HTTP server
     let http_server = HttpServer::new(move || create_app!(pool, &app_config, &server_tx))
@@ -0,0 +1,389 @@
+use std::time::Duration;
+
+use actix_web::{HttpRequest, HttpResponse, get, put, web};
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    app::AppConfig,
+    db::DbConnection,
+    errors::ServerError,
+    models::{
+        audit::NewAuditEntry, device_status, group::request_visibility, position::Position,
+        privacy::check_owner, trash, user::User,
+    },
+    notifiers::{Notification, Notifiers, split_names},
+    positions_server::PositionsServerHandle,
+    schema::{alert_settings, alerts, users},
+    utils::now,
+};
+
+type DbPool = r2d2::Pool<ConnectionManager<DbConnection>>;
+
+pub const BATTERY_LOW: &str = "battery_low";
+pub const OFFLINE: &str = "offline";
+pub const SOS: &str = "sos";
+
+// An alert is active until its condition clears, and is not raised again meanwhile
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, Insertable)]
+#[diesel(table_name = alerts)]
+pub struct Alert {
+    pub id: i32,
+    pub user_id: i32,
+    pub kind: String,
+    pub message: String,
+    pub raised_at: i64,
+    pub cleared_at: Option<i64>,
+    // expiry of the emergency share token, in seconds since the epoch
+    #[serde(skip)]
+    pub share_expires_at: Option<i64>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = alerts)]
+struct NewAlert<'a> {
+    user_id: i32,
+    kind: &'a str,
+    message: &'a str,
+    raised_at: i64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Queryable, Insertable, AsChangeset)]
+#[diesel(table_name = alert_settings, treat_none_as_null = true)]
+pub struct AlertSettings {
+    #[serde(default)]
+    pub user_id: i32,
+    // raise an alert when the battery level drops below this percentage
+    pub battery_threshold: Option<i32>,
+    // raise an alert when no position arrived for this many minutes
+    pub offline_after: Option<i32>,
+    // names of the notifiers the alerts are sent through, separated by commas, all of them if none
+    pub notifiers: Option<String>,
+}
+
+impl AlertSettings {
+    fn validate(&self) -> Result<(), String> {
+        if self
+            .battery_threshold
+            .is_some_and(|t| !(1..=100).contains(&t))
+        {
+            return Err("battery threshold must be between 1 and 100".to_owned());
+        }
+        if self.offline_after.is_some_and(|m| m < 1) {
+            return Err("offline delay must be at least one minute".to_owned());
+        }
+        Ok(())
+    }
+}
+
+fn load_settings(conn: &mut DbConnection, uid: i32) -> QueryResult<Option<AlertSettings>> {
+    alert_settings::table
+        .find(uid)
+        .first::<AlertSettings>(conn)
+        .optional()
+}
+
+// The notifiers the alerts of a user are sent through, all of them if none
+pub(crate) fn notifier_targets(conn: &mut DbConnection, uid: i32) -> QueryResult<Option<String>> {
+    Ok(load_settings(conn, uid)?.and_then(|s| s.notifiers))
+}
+
+pub(crate) fn user_name(conn: &mut DbConnection, uid: i32) -> QueryResult<String> {
+    let user = users::table.find(uid).first::<User>(conn)?;
+    Ok(format!("{} {}", user.name, user.surname))
+}
+
+// Raise an alert, unless the same one is already active
+pub(crate) fn raise(
+    conn: &mut DbConnection,
+    uid: i32,
+    kind: &str,
+    message: &str,
+    now: i64,
+) -> QueryResult<Option<Alert>> {
+    let active = alerts::table
+        .filter(alerts::user_id.eq(uid))
+        .filter(alerts::kind.eq(kind))
+        .filter(alerts::cleared_at.is_null())
+        .count()
+        .get_result::<i64>(conn)?;
+    if active > 0 {
+        return Ok(None);
+    }
+    diesel::insert_into(alerts::table)
+        .values(&NewAlert {
+            user_id: uid,
+            kind,
+            message,
+            raised_at: now,
+        })
+        .execute(conn)?;
+    alerts::table
+        .order(alerts::id.desc())
+        .first::<Alert>(conn)
+        .map(Some)
+}
+
+// Clear the active alert of a kind, if any
+pub(crate) fn clear(
+    conn: &mut DbConnection,
+    uid: i32,
+    kind: &str,
+    now: i64,
+) -> QueryResult<Option<Alert>> {
+    let active = alerts::table
+        .filter(alerts::user_id.eq(uid))
+        .filter(alerts::kind.eq(kind))
+        .filter(alerts::cleared_at.is_null())
+        .first::<Alert>(conn)
+        .optional()?;
+    if let Some(mut alert) = active {
+        diesel::update(alerts::table.find(alert.id))
+            .set(alerts::cleared_at.eq(now))
+            .execute(conn)?;
+        alert.cleared_at = Some(now);
+        return Ok(Some(alert));
+    }
+    Ok(None)
+}
+
+// Evaluate the alerts of a user on a new position, returns the raised and cleared ones
+pub fn check_position(conn: &mut DbConnection, position: &Position) -> QueryResult<Vec<Alert>> {
+    let uid = position.user_id;
+    let now = now();
+    let mut changed = Vec::new();
+    changed.extend(clear(conn, uid, OFFLINE, now)?);
+    let threshold = load_settings(conn, uid)?.and_then(|s| s.battery_threshold);
+    match threshold {
+        // a negative battery level is unknown
+        Some(threshold) if (0..threshold).contains(&position.battery_level) => {
+            let message = format!(
+                "Battery of {} is low: {}%",
+                user_name(conn, uid)?,
+                position.battery_level
+            );
+            changed.extend(raise(conn, uid, BATTERY_LOW, &message, now)?);
+        }
+        Some(_) if position.battery_level < 0 => {}
+        _ => changed.extend(clear(conn, uid, BATTERY_LOW, now)?),
+    }
+    Ok(changed)
+}
+
+// Raise the offline alerts of the users that did not send a position for too long
+pub fn check_offline(conn: &mut DbConnection, now: i64) -> QueryResult<Vec<Alert>> {
+    let settings = alert_settings::table
+        .filter(alert_settings::offline_after.is_not_null())
+        .load::<AlertSettings>(conn)?;
+    if settings.is_empty() {
+        return Ok(Vec::new());
+    }
+    let last_fixes = device_status::last_fixes(conn)?;
+    let mut changed = Vec::new();
+    for s in settings {
+        let (Some(last), Some(minutes)) = (last_fixes.get(&s.user_id), s.offline_after) else {
+            continue;
+        };
+        if now - last > i64::from(minutes) * 60 * 1000 {
+            let message = format!(
+                "No position received from {} for {} minutes",
+                user_name(conn, s.user_id)?,
+                (now - last) / 60 / 1000
+            );
+            changed.extend(raise(conn, s.user_id, OFFLINE, &message, now)?);
+        }
+    }
+    Ok(changed)
+}
+
+// Delete the alerts cleared for longer than the retention
+pub fn purge_expired(conn: &mut DbConnection, retention: Duration) -> QueryResult<usize> {
+    if retention.is_zero() {
+        return Ok(0);
+    }
+    let limit = now() - i64::try_from(retention.as_millis()).unwrap_or(i64::MAX / 2);
+    diesel::delete(alerts::table)
+        .filter(alerts::cleared_at.lt(limit))
+        .execute(conn)
+}
+
+// Send the changed alerts over the WebSocket, and notify the raised ones through the notifiers
+// chosen by their users
+pub async fn deliver(
+    changed: Vec<Alert>,
+    pool: &DbPool,
+    notifiers: &Notifiers,
+    positions_server: &PositionsServerHandle,
+) -> Result<(), ServerError> {
+    for alert in changed {
+        positions_server
+            .send_message(
+                alert.user_id.try_into()?,
+                serde_json::to_string(&serde_json::json!({ "alert": alert }))?,
+            )
+            .await;
+        if alert.cleared_at.is_none() {
+            let mut conn = pool.get()?;
+            let uid = alert.user_id;
+            let targets = web::block(move || notifier_targets(&mut conn, uid)).await??;
+            notifiers.only(targets.as_deref()).dispatch(Notification {
+                user_id: alert.user_id,
+                title: match alert.kind.as_str() {
+                    BATTERY_LOW => "Battery low".to_owned(),
+                    OFFLINE => "Device offline".to_owned(),
+                    SOS => "Emergency".to_owned(),
+                    kind => kind.to_owned(),
+                },
+                message: alert.message.clone(),
+                payload: serde_json::to_value(&alert)?,
+            });
+        }
+    }
+    Ok(())
+}
+
+pub async fn run_offline_check(
+    pool: &DbPool,
+    cfg: &AppConfig,
+    positions_server: &PositionsServerHandle,
+    now: i64,
+) -> Result<(), ServerError> {
+    let mut conn = pool.get()?;
+    let retention = cfg.config.alert_retention();
+    let auth_failure_retention = cfg.config.auth_failure_retention();
+    let trash_purge_delay = cfg.trash_purge_delay;
+    let config = cfg.config.clone();
+    let changed = web::block(move || -> Result<Vec<Alert>, ServerError> {
+        purge_expired(&mut conn, retention)?;
+        crate::rate_limit::purge_expired(&mut conn, auth_failure_retention)?;
+        // the trash is purged here too, with the avatars of the users it no longer holds
+        if !trash_purge_delay.is_zero() {
+            trash::purge_expired(&mut conn, trash_purge_delay)?;
+        }
+        trash::purge_avatars(&mut conn, &config)?;
+        device_status::check_online(&mut conn, now)?;
+        Ok(check_offline(&mut conn, now)?)
+    })
+    .await??;
+    deliver(changed, pool, &cfg.notifiers, positions_server).await
+}
+
+// Check periodically for the devices that went offline
+pub async fn watch_offline(
+    pool: DbPool,
+    cfg: web::Data<AppConfig>,
+    positions_server: PositionsServerHandle,
+    period: Duration,
+) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        if let Err(e) = run_offline_check(&pool, &cfg, &positions_server, now()).await {
+            log::error!("offline alerts check failed: {}", e);
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AlertsParams {
+    user_id: Option<i32>,
+    #[serde(default)]
+    active: bool,
+}
+
+#[get("")]
+pub async fn read_all(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    params: web::Query<AlertsParams>,
+) -> Result<HttpResponse, ServerError> {
+    let visibility = request_visibility(&req, &pool).await?;
+    let mut conn = pool.get()?;
+    let params = params.into_inner();
+    let objects = web::block(move || {
+        let mut query = alerts::table.order(alerts::id.desc()).into_boxed();
+        if let Some(uid) = params.user_id {
+            query = query.filter(alerts::user_id.eq(uid));
+        }
+        if params.active {
+            query = query.filter(alerts::cleared_at.is_null());
+        }
+        query.load::<Alert>(&mut conn)
+    })
+    .await??;
+    // Alerts stay private outside the groups
+    let objects: Vec<Alert> = objects
+        .into_iter()
+        .filter(|a| visibility.allows(a.user_id))
+        .collect();
+    Ok(HttpResponse::Ok().json(objects))
+}
+
+#[get("/{uid}")]
+pub async fn read_settings(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    uid: web::Path<i32>,
+) -> Result<HttpResponse, ServerError> {
+    let uid = *uid;
+    if let Some(resp) = check_owner(&req, uid) {
+        return Ok(resp);
+    }
+    let mut conn = pool.get()?;
+    let settings = web::block(move || -> QueryResult<AlertSettings> {
+        users::table.find(uid).first::<User>(&mut conn)?;
+        Ok(load_settings(&mut conn, uid)?.unwrap_or(AlertSettings {
+            user_id: uid,
+            ..Default::default()
+        }))
+    })
+    .await??;
+    Ok(HttpResponse::Ok().json(settings))
+}
+
+#[put("/{uid}")]
+pub async fn update_settings(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    cfg: web::Data<AppConfig>,
+    uid: web::Path<i32>,
+    o: web::Json<AlertSettings>,
+) -> Result<HttpResponse, ServerError> {
+    let uid = *uid;
+    if let Some(resp) = check_owner(&req, uid) {
+        return Ok(resp);
+    }
+    let mut settings = o.into_inner();
+    settings.user_id = uid;
+    if let Err(e) = settings.validate() {
+        return Ok(HttpResponse::BadRequest().body(e));
+    }
+    let names = cfg.notifiers.names();
+    if let Some(unknown) = settings
+        .notifiers
+        .iter()
+        .flat_map(|n| split_names(n))
+        .find(|n| !names.contains(&n.as_str()))
+    {
+        return Ok(HttpResponse::BadRequest().body(format!("unknown notifier: {}", unknown)));
+    }
+    let audit = NewAuditEntry::new(&req, "alert_settings.update");
+    let mut conn = pool.get()?;
+    let settings = web::block(move || -> Result<AlertSettings, ServerError> {
+        users::table.find(uid).first::<User>(&mut conn)?;
+        crate::replace_into!(
+            conn,
+            alert_settings::table,
+            alert_settings::user_id.eq(uid),
+            &settings
+        )?;
+        audit
+            .target(format!("users/{}", uid))
+            .details(&settings)
+            .insert(&mut conn)?;
+        Ok(settings)
+    })
+    .await??;
+    Ok(HttpResponse::Ok().json(settings))
+}
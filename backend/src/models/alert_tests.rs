@@ -0,0 +1,376 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use actix_web::{App, HttpResponse, HttpServer, web};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+    sync::mpsc,
+};
+
+use crate::{
+    app::AppConfig,
+    create_app,
+    models::alert::{purge_expired, run_offline_check},
+    notifiers::{Notifier, Notifiers, Ntfy, Smtp, Webhook},
+    positions_server::PositionsServerHandle,
+    utils::now,
+};
+
+type Received = Arc<Mutex<Vec<String>>>;
+
+// Mock SMTP relay, keeping the data of the received mails
+async fn mock_smtp(listener: TcpListener, received: Received) {
+    while let Ok((stream, _)) = listener.accept().await {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        writer.write_all(b"220 mock ESMTP\r\n").await.unwrap();
+        let mut data: Option<String> = None;
+        while let Ok(Some(line)) = lines.next_line().await {
+            let reply: &[u8] = match &mut data {
+                Some(d) if line == "." => {
+                    received.lock().unwrap().push(d.clone());
+                    data = None;
+                    b"250 queued\r\n"
+                }
+                Some(d) => {
+                    d.push_str(&line);
+                    d.push('\n');
+                    continue;
+                }
+                None if line.starts_with("EHLO") => b"250-mock\r\n250 8BITMIME\r\n",
+                None if line == "DATA" => {
+                    data = Some(String::new());
+                    b"354 go ahead\r\n"
+                }
+                None if line == "QUIT" => {
+                    writer.write_all(b"221 bye\r\n").await.unwrap();
+                    break;
+                }
+                None => b"250 ok\r\n",
+            };
+            writer.write_all(reply).await.unwrap();
+        }
+    }
+}
+
+pub async fn alert_test(
+    pool: &r2d2::Pool<diesel::r2d2::ConnectionManager<crate::db::DbConnection>>,
+    position_server_handle: &PositionsServerHandle,
+) {
+    use crate::{do_test, do_test_extract_id};
+    use actix_web::{
+        http::{Method, StatusCode},
+        test,
+    };
+
+    // Mock webhook and ntfy endpoints
+    let received: Received = Arc::default();
+    let http_received = web::Data::new(received.clone());
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    let http_server = HttpServer::new(move || {
+        App::new().app_data(http_received.clone()).route(
+            "/{notifier}",
+            web::post().to(
+                |received: web::Data<Received>, req: actix_web::HttpRequest, body: String| async move {
+                    let title = req
+                        .headers()
+                        .get("Title")
+                        .map(|t| t.to_str().unwrap().to_owned())
+                        .unwrap_or_default();
+                    received
+                        .lock()
+                        .unwrap()
+                        .push(format!("{} {} {}", req.path(), title, body));
+                    HttpResponse::Ok().finish()
+                },
+            ),
+        )
+    })
+    .workers(1)
+    .listen(listener)
+    .unwrap()
+    .run();
+    let http_server_handle = http_server.handle();
+    actix_web::rt::spawn(http_server);
+
+    // Mock SMTP relay
+    let smtp_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let relay = smtp_listener.local_addr().unwrap().to_string();
+    let smtp = actix_web::rt::spawn(mock_smtp(smtp_listener, received.clone()));
+
+    let notifiers: Vec<Box<dyn Notifier>> = vec![
+        Box::new(Webhook {
+            url: format!("{}/webhook", base_url),
+        }),
+        Box::new(Ntfy {
+            url: format!("{}/ntfy", base_url),
+            token: None,
+        }),
+        Box::new(Smtp {
+            relay,
+            from: "tesou@localhost".to_owned(),
+            to: vec!["family@localhost".to_owned()],
+        }),
+    ];
+    let app_config = web::Data::new(
+        AppConfig::new("0101".to_string(), None).with_notifiers(Notifiers::new(notifiers)),
+    );
+    let mut app = test::init_service(create_app!(pool, &app_config, position_server_handle)).await;
+
+    // Wait for the notifications sent in the background
+    let wait_notifications = |count: usize| {
+        let received = received.clone();
+        async move {
+            for _ in 0..100 {
+                if received.lock().unwrap().len() >= count {
+                    break;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            }
+            let notifications = received.lock().unwrap().clone();
+            assert_eq!(notifications.len(), count);
+            notifications
+        }
+    };
+
+    let uid = do_test_extract_id!(
+        app,
+        Method::POST,
+        "/api/users",
+        r#"{"name":"Frank","surname":"Alerted"}"#,
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    let (ws_tx, mut ws_rx) = mpsc::unbounded_channel();
+    let conn_id = position_server_handle
+        .connect(ws_tx, uid.try_into().unwrap(), true)
+        .await;
+
+    // Set up the alerts
+    let settings_uri = format!("/api/alert-settings/{}", uid);
+    do_test!(
+        app,
+        Method::GET,
+        &settings_uri,
+        "",
+        StatusCode::OK,
+        format!(r#"{{"user_id":{},"battery_threshold":null"#, uid)
+    );
+    do_test!(
+        app,
+        Method::PUT,
+        &settings_uri,
+        r#"{"battery_threshold":120,"offline_after":5}"#,
+        StatusCode::BAD_REQUEST,
+        "battery threshold must be between 1 and 100"
+    );
+    do_test!(
+        app,
+        Method::PUT,
+        &settings_uri,
+        r#"{"battery_threshold":20,"offline_after":5}"#,
+        StatusCode::OK,
+        format!(
+            r#"{{"user_id":{},"battery_threshold":20,"offline_after":5,"notifiers":null}}"#,
+            uid
+        )
+    );
+
+    // A low battery raises an alert once, until the battery is charged again
+    let post_position = |battery_level: i32, time: i64| {
+        format!(
+            r#"[{{"user_id":{},"latitude":45.74846,"longitude":4.84671,"source":"GPS","battery_level":{},"sport_mode":false,"time":{}}}]"#,
+            uid, battery_level, time
+        )
+    };
+    let start = now() - 60000;
+    do_test!(
+        app,
+        Method::POST,
+        "/api/positions",
+        &post_position(50, start),
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    do_test!(
+        app,
+        Method::POST,
+        "/api/positions",
+        &post_position(15, start + 10000),
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    do_test!(
+        app,
+        Method::POST,
+        "/api/positions",
+        &post_position(10, start + 20000),
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    let notifications = wait_notifications(3).await;
+    assert!(notifications.iter().any(|n| n.starts_with("/webhook")
+        && n.contains(r#""kind":"battery_low""#)
+        && n.contains("Battery of Frank Alerted is low: 15%")));
+    assert!(
+        notifications
+            .iter()
+            .any(|n| n == "/ntfy Battery low Battery of Frank Alerted is low: 15%")
+    );
+    assert!(
+        notifications
+            .iter()
+            .any(|n| n.contains("Subject: Battery low")
+                && n.contains("To: <family@localhost>")
+                && n.contains("Battery of Frank Alerted is low: 15%"))
+    );
+    do_test!(
+        app,
+        Method::GET,
+        &format!("/api/alerts?user_id={}&active=true", uid),
+        "",
+        StatusCode::OK,
+        r#"[{"id":"#
+    );
+    do_test!(
+        app,
+        Method::POST,
+        "/api/positions",
+        &post_position(80, start + 30000),
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    do_test!(
+        app,
+        Method::GET,
+        &format!("/api/alerts?user_id={}&active=true", uid),
+        "",
+        StatusCode::OK,
+        "[]"
+    );
+
+    // The alerts are sent over the WebSocket when raised and when cleared
+    let mut ws_alerts = Vec::new();
+    while let Ok(msg) = ws_rx.try_recv() {
+        if msg.starts_with(r#"{"alert":"#) {
+            ws_alerts.push(msg);
+        }
+    }
+    assert_eq!(ws_alerts.len(), 2);
+    assert!(ws_alerts[0].contains(r#""cleared_at":null"#));
+    assert!(!ws_alerts[1].contains(r#""cleared_at":null"#));
+
+    // The alerts are sent through the notifiers chosen by the user only
+    do_test!(
+        app,
+        Method::PUT,
+        &settings_uri,
+        r#"{"battery_threshold":20,"offline_after":5,"notifiers":"ntfy, pigeon"}"#,
+        StatusCode::BAD_REQUEST,
+        "unknown notifier: pigeon"
+    );
+    do_test!(
+        app,
+        Method::PUT,
+        &settings_uri,
+        r#"{"battery_threshold":20,"offline_after":5,"notifiers":"ntfy"}"#,
+        StatusCode::OK,
+        "{"
+    );
+
+    // No position for too long raises an offline alert, once
+    run_offline_check(
+        pool,
+        &app_config,
+        position_server_handle,
+        now() + 10 * 60000,
+    )
+    .await
+    .unwrap();
+    let notifications = wait_notifications(4).await;
+    do_test!(
+        app,
+        Method::GET,
+        &format!("/api/status/{}", uid),
+        "",
+        StatusCode::OK,
+        format!(r#"{{"user_id":{},"last_fix":{},"#, uid, start + 30000)
+    );
+    let body = do_test!(
+        app,
+        Method::GET,
+        &format!("/api/status/{}", uid),
+        "",
+        StatusCode::OK,
+        "{"
+    );
+    assert!(body.ends_with(r#""online":false}"#));
+    assert!(
+        notifications
+            .iter()
+            .any(|n| n.starts_with("/ntfy Device offline No position received from Frank Alerted"))
+    );
+    assert!(
+        !notifications
+            .iter()
+            .any(|n| n.contains("Device offline") && !n.starts_with("/ntfy"))
+    );
+    run_offline_check(
+        pool,
+        &app_config,
+        position_server_handle,
+        now() + 20 * 60000,
+    )
+    .await
+    .unwrap();
+    do_test!(
+        app,
+        Method::POST,
+        "/api/positions",
+        &post_position(80, start + 40000),
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    wait_notifications(4).await;
+    let body = do_test!(
+        app,
+        Method::GET,
+        &format!("/api/alerts?user_id={}", uid),
+        "",
+        StatusCode::OK,
+        "["
+    );
+    assert_eq!(body.matches(r#""kind":"offline""#).count(), 1);
+    assert_eq!(body.matches(r#""cleared_at":null"#).count(), 0);
+
+    // The cleared alerts are purged after the retention
+    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    let mut conn = pool.get().unwrap();
+    assert_eq!(purge_expired(&mut conn, Duration::ZERO).unwrap(), 0);
+    assert!(purge_expired(&mut conn, Duration::from_millis(5)).unwrap() >= 2);
+    do_test!(
+        app,
+        Method::GET,
+        &format!("/api/alerts?user_id={}", uid),
+        "",
+        StatusCode::OK,
+        "[]"
+    );
+
+    // Clean up
+    position_server_handle.disconnect(conn_id);
+    do_test!(
+        app,
+        Method::DELETE,
+        &format!("/api/users/{}?cascade=true", uid),
+        "",
+        StatusCode::OK,
+        ""
+    );
+    http_server_handle.stop(true).await;
+    smtp.abort();
+}
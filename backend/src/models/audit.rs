@@ -0,0 +1,139 @@
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, get, web};
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    app::Credential, db::DbConnection, errors::ServerError, rate_limit::client_ip,
+    schema::audit_log, utils::now,
+};
+
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable)]
+pub struct AuditEntry {
+    pub id: i32,
+    pub time: i64,
+    pub actor: String,
+    pub ip: String,
+    pub device: String,
+    pub action: String,
+    pub target: String,
+    pub details: String,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = audit_log)]
+pub struct NewAuditEntry {
+    pub time: i64,
+    pub actor: String,
+    pub ip: String,
+    pub device: String,
+    pub action: String,
+    pub target: String,
+    pub details: String,
+}
+
+type DbPool = r2d2::Pool<ConnectionManager<DbConnection>>;
+
+impl NewAuditEntry {
+    // Prepare an audit entry for the given request, telling who did it and from where
+    pub fn new(req: &HttpRequest, action: &str) -> Self {
+        let actor = match req.extensions().get::<Credential>() {
+            Some(credential) => credential.to_string(),
+            None => "anonymous".to_owned(),
+        };
+        let device = req
+            .headers()
+            .get("X-Device")
+            .or_else(|| req.headers().get("User-Agent"))
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.chars().take(200).collect())
+            .unwrap_or_default();
+        NewAuditEntry {
+            time: now(),
+            actor,
+            ip: client_ip(req),
+            device,
+            action: action.to_owned(),
+            target: String::new(),
+            details: String::new(),
+        }
+    }
+
+    // Prepare an audit entry for an action run from the command line, on the server itself
+    pub fn local(action: &str) -> Self {
+        NewAuditEntry {
+            time: now(),
+            actor: "command line".to_owned(),
+            ip: String::new(),
+            device: String::new(),
+            action: action.to_owned(),
+            target: String::new(),
+            details: String::new(),
+        }
+    }
+
+    pub fn target(mut self, target: impl Into<String>) -> Self {
+        self.target = target.into();
+        self
+    }
+
+    pub fn details(mut self, details: &impl Serialize) -> Self {
+        self.details = serde_json::to_string(details).unwrap_or_default();
+        self
+    }
+
+    pub fn insert(&self, conn: &mut DbConnection) -> QueryResult<usize> {
+        diesel::insert_into(audit_log::table)
+            .values(self)
+            .execute(conn)
+    }
+
+    // Record the entry out of a blocking context
+    pub async fn record(self, pool: &DbPool) -> Result<(), ServerError> {
+        let mut conn = pool.get()?;
+        web::block(move || self.insert(&mut conn)).await??;
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    pub action: Option<String>,
+    pub actor: Option<String>,
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+#[get("")]
+pub async fn read_all(
+    pool: web::Data<DbPool>,
+    query: web::Query<AuditQuery>,
+) -> Result<HttpResponse, ServerError> {
+    let mut conn = pool.get()?;
+    let query = query.into_inner();
+    let entries = web::block(move || {
+        use crate::schema::audit_log::dsl::*;
+        let mut q = audit_log.into_boxed();
+        if let Some(a) = query.action {
+            q = q.filter(action.eq(a));
+        }
+        if let Some(a) = query.actor {
+            q = q.filter(actor.eq(a));
+        }
+        if let Some(s) = query.since {
+            q = q.filter(time.ge(s));
+        }
+        if let Some(u) = query.until {
+            q = q.filter(time.le(u));
+        }
+        q.order(id.desc())
+            .limit(query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT))
+            .load::<AuditEntry>(&mut conn)
+    })
+    .await??;
+    Ok(HttpResponse::Ok().json(entries))
+}
@@ -0,0 +1,137 @@
+use crate::{
+    app::AppConfig, create_app, models::audit::AuditEntry, positions_server::PositionsServerHandle,
+};
+
+pub async fn audit_test(
+    pool: &r2d2::Pool<diesel::r2d2::ConnectionManager<crate::db::DbConnection>>,
+    app_config: &actix_web::web::Data<AppConfig>,
+    position_server_handle: &PositionsServerHandle,
+) {
+    use crate::{do_test, do_test_extract_id};
+    use actix_web::{
+        http::{Method, StatusCode},
+        test,
+    };
+
+    let mut app = test::init_service(create_app!(pool, app_config, position_server_handle)).await;
+
+    // Create, update and delete a user
+    let user_id = do_test_extract_id!(
+        app,
+        Method::POST,
+        "/api/users",
+        r#"{"name":"Audited","surname":"User"}"#,
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    do_test!(
+        app,
+        Method::PUT,
+        &format!("/api/users/{}", user_id),
+        &format!(
+            r#"{{"id":{},"name":"Audited","surname":"Patched"}}"#,
+            user_id
+        ),
+        StatusCode::OK,
+        "{\"id\""
+    );
+
+    // Toggle the sport mode and issue a share token
+    do_test!(
+        app,
+        Method::POST,
+        &format!("/api/sport-mode/toggle/{}", user_id),
+        "",
+        StatusCode::OK,
+        ""
+    );
+    let share_token = do_test!(
+        app,
+        Method::GET,
+        &format!("/api/token?user_id={}", user_id),
+        "",
+        StatusCode::OK,
+        ""
+    );
+
+    // Wipe the positions
+    do_test!(
+        app,
+        Method::POST,
+        "/api/positions",
+        &format!(
+            r#"[{{"user_id":{},"latitude":45.74846,"longitude":4.84671,"source":"GPS","battery_level":50,"sport_mode":false}}]"#,
+            user_id
+        ),
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    do_test!(
+        app,
+        Method::DELETE,
+        "/api/positions?confirm=positions",
+        "",
+        StatusCode::OK,
+        "Deleted all objects"
+    );
+    do_test!(
+        app,
+        Method::DELETE,
+        &format!("/api/users/{}?cascade=true", user_id),
+        "",
+        StatusCode::OK,
+        ""
+    );
+
+    // Get the audit trail, most recent first
+    let body = do_test!(
+        app,
+        Method::GET,
+        "/api/audit?limit=6",
+        "",
+        StatusCode::OK,
+        "["
+    );
+    let entries: Vec<AuditEntry> = serde_json::from_str(&body).unwrap();
+    let actions: Vec<&str> = entries.iter().map(|e| e.action.as_str()).collect();
+    assert_eq!(
+        actions,
+        vec![
+            "users.delete",
+            "positions.delete_all",
+            "token.issue",
+            "sport_mode.toggle",
+            "users.update",
+            "users.create"
+        ]
+    );
+    assert!(entries.iter().all(|e| e.actor == "main token"));
+    assert_eq!(entries[0].target, format!("users/{}", user_id));
+    assert!(
+        entries[1]
+            .details
+            .starts_with(r#"{"deleted":1,"trash_id":"#)
+    );
+    assert!(entries[4].details.contains("Patched"));
+
+    // Filter the audit trail
+    let body = do_test!(
+        app,
+        Method::GET,
+        "/api/audit?action=positions.delete_all&limit=1",
+        "",
+        StatusCode::OK,
+        "["
+    );
+    let entries: Vec<AuditEntry> = serde_json::from_str(&body).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].target, "positions");
+
+    // A share token cannot read the audit trail
+    let req = test::TestRequest::get()
+        .insert_header(("Authorization", format!("Bearer {share_token}")))
+        .uri("/api/audit")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 403);
+}
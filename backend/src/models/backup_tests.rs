@@ -0,0 +1,90 @@
+use std::fs;
+
+use actix_web::web;
+
+use crate::{
+    app::{AppConfig, Role},
+    backup::list,
+    config::Config,
+    create_app,
+    db::DbConnection,
+    positions_server::PositionsServerHandle,
+    token::{self, Claims},
+};
+
+pub async fn backup_test(
+    pool: &r2d2::Pool<diesel::r2d2::ConnectionManager<DbConnection>>,
+    position_server_handle: &PositionsServerHandle,
+) {
+    use crate::do_test;
+    use actix_web::{
+        http::{Method, StatusCode},
+        test,
+    };
+
+    let dir = std::env::temp_dir().join(format!("tesou_backups_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let app_config = web::Data::new(
+        AppConfig::new("0101".to_string(), None).with_config(Config {
+            backup_dir: dir.to_string_lossy().into_owned(),
+            backup_keep: 2,
+            ..Config::default()
+        }),
+    );
+    let mut app = test::init_service(create_app!(pool, &app_config, position_server_handle)).await;
+
+    do_test!(app, Method::GET, "/api/backups", "", StatusCode::OK, "[]");
+
+    // Backups require the admin rights
+    let viewer_session = token::seal(
+        &app_config.share_keys,
+        &Claims::Session {
+            expires_at: u64::MAX / 2,
+            user_id: None,
+            role: Role::Viewer,
+        },
+    )
+    .unwrap();
+    for method in [Method::GET, Method::POST] {
+        let req = test::TestRequest::with_uri("/api/backups")
+            .method(method)
+            .insert_header(("Authorization", format!("Bearer {}", viewer_session)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(test::read_body(resp).await, "admin rights are required");
+    }
+
+    if let DbConnection::Postgresql(_) = *pool.get().unwrap() {
+        do_test!(
+            app,
+            Method::POST,
+            "/api/backups",
+            "",
+            StatusCode::NOT_IMPLEMENTED,
+            "backups are only supported on SQLite"
+        );
+        return;
+    }
+
+    // Backups are compressed, and only the newest are kept
+    for _ in 0..3 {
+        let body = do_test!(
+            app,
+            Method::POST,
+            "/api/backups",
+            "",
+            StatusCode::CREATED,
+            r#"{"name":"tesou-"#
+        );
+        assert!(body.contains(r#".sqlite.gz","size":"#));
+    }
+    let backups = list(&dir).unwrap();
+    assert_eq!(backups.len(), 2);
+    let body = do_test!(app, Method::GET, "/api/backups", "", StatusCode::OK, "[");
+    assert!(body.starts_with(&format!(r#"[{{"name":"{}""#, backups[0].name)));
+    let body = do_test!(app, Method::GET, "/api/audit", "", StatusCode::OK, "[");
+    assert!(body.contains(&format!("backups/{}", backups[0].name)));
+
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::io::BufRead;
+
+use diesel::prelude::*;
+use diesel::upsert::excluded;
+
+use crate::{
+    db::DbConnection,
+    errors::ServerError,
+    models::position::CellId,
+    schema::cell_towers::{self, dsl::*},
+};
+
+// Rows written at once, well below the bind parameters limit of both backends
+const CHUNK_SIZE: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq, Queryable, Insertable)]
+#[diesel(table_name = cell_towers)]
+pub struct CellTower {
+    pub mcc: i32,
+    pub mnc: i32,
+    pub lac: i32,
+    pub cid: i64,
+    pub radio: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    // estimated range, in meters
+    pub radius: i32,
+    // when a cell located by Open Cell ID must be located again, the imported cells never expire
+    pub expires_at: Option<i64>,
+}
+
+impl CellTower {
+    pub fn is_fresh(&self, time: i64) -> bool {
+        self.expires_at.is_none_or(|t| t > time)
+    }
+
+    // Read a line of the OpenCellID or Mozilla Location Service dumps, that share their columns:
+    // radio,mcc,net,area,cell,unit,lon,lat,range,samples,changeable,created,updated,averageSignal
+    fn from_csv(line: &str) -> Option<CellTower> {
+        let fields: Vec<&str> = line.trim_end().split(',').collect();
+        if fields.len() < 9 {
+            return None;
+        }
+        let tower = CellTower {
+            radio: fields[0].to_owned(),
+            mcc: fields[1].parse().ok()?,
+            mnc: fields[2].parse().ok()?,
+            lac: fields[3].parse().ok()?,
+            cid: fields[4].parse().ok()?,
+            longitude: fields[6].parse().ok()?,
+            latitude: fields[7].parse().ok()?,
+            radius: fields[8].parse().ok()?,
+            expires_at: None,
+        };
+        ((-90.0..=90.0).contains(&tower.latitude) && (-180.0..=180.0).contains(&tower.longitude))
+            .then_some(tower)
+    }
+}
+
+// Get the tower of a cell, even if expired
+pub fn locate(conn: &mut DbConnection, cell_id: &CellId) -> QueryResult<Option<CellTower>> {
+    let (Ok(cell_mcc), Ok(cell_mnc)) = (cell_id.mcc.parse::<i32>(), cell_id.mnc.parse::<i32>())
+    else {
+        return Ok(None);
+    };
+    cell_towers
+        .find((cell_mcc, cell_mnc, cell_id.lac, cell_id.cid))
+        .first::<CellTower>(conn)
+        .optional()
+}
+
+// Insert the towers, replacing the ones of the same cells
+pub fn upsert(conn: &mut DbConnection, towers: &[CellTower]) -> QueryResult<usize> {
+    let mut written = 0;
+    for chunk in towers.chunks(CHUNK_SIZE) {
+        // a statement can't update a row twice, the last tower of a cell wins
+        let chunk: HashMap<_, _> = chunk
+            .iter()
+            .map(|t| ((t.mcc, t.mnc, t.lac, t.cid), t))
+            .collect();
+        let chunk: Vec<&CellTower> = chunk.into_values().collect();
+        written += match conn {
+            DbConnection::Sqlite(conn) => diesel::replace_into(cell_towers::table)
+                .values(chunk)
+                .execute(conn)?,
+            DbConnection::Postgresql(conn) => diesel::insert_into(cell_towers::table)
+                .values(chunk)
+                .on_conflict((mcc, mnc, lac, cid))
+                .do_update()
+                .set((
+                    radio.eq(excluded(radio)),
+                    latitude.eq(excluded(latitude)),
+                    longitude.eq(excluded(longitude)),
+                    radius.eq(excluded(radius)),
+                    expires_at.eq(excluded(expires_at)),
+                ))
+                .execute(conn)?,
+        };
+    }
+    Ok(written)
+}
+
+// Load a dump, keeping the cells of the given countries only if any, and get how many lines were
+// imported and skipped
+pub fn import(
+    conn: &mut DbConnection,
+    reader: impl BufRead,
+    mccs: &[i32],
+) -> Result<(usize, usize), ServerError> {
+    let (mut imported, mut skipped) = (0, 0);
+    let mut towers = Vec::with_capacity(CHUNK_SIZE);
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if index == 0 && line.starts_with("radio,") {
+            continue;
+        }
+        match CellTower::from_csv(&line) {
+            Some(t) if mccs.is_empty() || mccs.contains(&t.mcc) => towers.push(t),
+            _ => skipped += 1,
+        }
+        if towers.len() == CHUNK_SIZE {
+            upsert(conn, &towers)?;
+            imported += towers.len();
+            towers.clear();
+        }
+    }
+    upsert(conn, &towers)?;
+    imported += towers.len();
+    Ok((imported, skipped))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_csv() {
+        assert_eq!(
+            CellTower::from_csv(
+                "LTE,208,1,28000,12345678,0,4.8357,45.764,1200,42,1,1459814400,1700000000,0\r\n"
+            ),
+            Some(CellTower {
+                mcc: 208,
+                mnc: 1,
+                lac: 28000,
+                cid: 12345678,
+                radio: "LTE".to_owned(),
+                latitude: 45.764,
+                longitude: 4.8357,
+                radius: 1200,
+                expires_at: None,
+            })
+        );
+        // the header, truncated lines and impossible coordinates are skipped
+        for line in [
+            "radio,mcc,net,area,cell,unit,lon,lat,range,samples,changeable,created,updated,averageSignal",
+            "GSM,208,1,1,1,0,4.8357",
+            "GSM,208,1,1,1,0,4.8357,95.0,100,1,1,0,0,0",
+            "",
+        ] {
+            assert_eq!(CellTower::from_csv(line), None);
+        }
+    }
+}
@@ -1,10 +1,10 @@
 #[macro_export]
 macro_rules! crud_use {
     () => {
-        use actix_web::{HttpResponse, delete, get, post, put, web};
+        use actix_web::{HttpMessage, HttpRequest, HttpResponse, delete, get, post, put, web};
         use diesel::prelude::*;
         use diesel::r2d2::ConnectionManager;
-        type DbPool = r2d2::Pool<ConnectionManager<SqliteConnection>>;
+        type DbPool = r2d2::Pool<ConnectionManager<$crate::db::DbConnection>>;
     };
 }
 
@@ -46,27 +46,39 @@ macro_rules! crud_read_all {
 #[macro_export]
 macro_rules! crud_create {
     ($inmodel:ty, $outmodel:ty, $table:tt, $( $parent_model:ty, $parent_table:tt, $parent_table_id:tt ),* ) => {
+        // Insert an object, whose parents must exist, and record it in the audit log
+        pub fn insert(
+            conn: &mut $crate::db::DbConnection,
+            o: &$inmodel,
+            audit: $crate::models::audit::NewAuditEntry,
+        ) -> Result<$outmodel, ServerError> {
+            $(
+                // Check that parent for our object exists
+                $crate::schema::$parent_table::dsl::$parent_table.find(o.$parent_table_id).first::<$parent_model>(conn)?;
+            )*
+            use $crate::schema::$table::dsl::*;
+            diesel::insert_into($table)
+                .values(o)
+                .execute(conn)?;
+            let o = $table.order(id.desc()).first::<$outmodel>(conn)?;
+            audit.target(format!("{}/{}", stringify!($table), o.id)).details(&o).insert(conn)?;
+            Ok(o)
+        }
+
         #[post("")]
         pub async fn create(
+            req: HttpRequest,
             pool: web::Data<DbPool>,
             mut o: web::Json<$inmodel>,
         ) -> Result<HttpResponse, ServerError> {
+            o.trim();
+            if let Err(reason) = o.validate() {
+                return Ok(HttpResponse::BadRequest().body(reason));
+            }
+            let audit = $crate::models::audit::NewAuditEntry::new(&req, concat!(stringify!($table), ".create"));
             let mut conn = pool.get()?;
-            let created_o: Result<$outmodel, ServerError> = web::block(move || {
-                $(
-                    // Check that parent for our object exists
-                    $crate::schema::$parent_table::dsl::$parent_table.find(o.$parent_table_id).first::<$parent_model>(&conn)?;
-                )*
-                use $crate::schema::$table::dsl::*;
-                o.trim();
-                diesel::insert_into($table)
-                    .values(&*o)
-                    .execute(&mut conn)?;
-                let o = $table.order(id.desc()).first::<$outmodel>(&mut conn)?;
-                Ok(o)
-            })
-            .await?;
-                Ok(HttpResponse::Created().json(created_o?))
+            let created_o = web::block(move || insert(&mut conn, &o, audit)).await??;
+            Ok(HttpResponse::Created().json(created_o))
         }
     };
 }
@@ -74,12 +86,22 @@ macro_rules! crud_create {
 #[macro_export]
 macro_rules! crud_update {
     ($model:ty, $table:tt, $( $parent_model:ty, $parent_table:tt, $parent_table_id:tt ),*) => {
+        $crate::crud_update!($model => $model, $table, $( $parent_model, $parent_table, $parent_table_id ),*);
+    };
+    // The changes can be given by a dedicated model, to leave out the fields that are not sent
+    ($inmodel:ty => $model:ty, $table:tt, $( $parent_model:ty, $parent_table:tt, $parent_table_id:tt ),*) => {
         #[put("/{oid}")]
         pub async fn update(
+            req: HttpRequest,
             pool: web::Data<DbPool>,
-            mut o: web::Json<$model>,
+            mut o: web::Json<$inmodel>,
             oid: web::Path<i32>,
         ) -> Result<HttpResponse, ServerError> {
+            o.trim();
+            if let Err(reason) = o.validate() {
+                return Ok(HttpResponse::BadRequest().body(reason));
+            }
+            let audit = $crate::models::audit::NewAuditEntry::new(&req, concat!(stringify!($table), ".update"));
             let mut conn = pool.get()?;
             let put_o: Result<$model, ServerError> = web::block(move || {
                 $(
@@ -87,13 +109,13 @@ macro_rules! crud_update {
                     $crate::schema::$parent_table::dsl::$parent_table.find(o.$parent_table_id).first::<$parent_model>(&mut conn)?;
                 )*
                 use $crate::schema::$table::dsl::*;
-                o.trim();
 
                 diesel::update($table)
                     .filter(id.eq(*oid))
                     .set(&*o)
                     .execute(&mut conn)?;
                 let o = $table.filter(id.eq(*oid)).first::<$model>(&mut conn)?;
+                audit.target(format!("{}/{}", stringify!($table), *oid)).details(&o).insert(&mut conn)?;
                 Ok(o)
             })
             .await?;
@@ -104,25 +126,72 @@ macro_rules! crud_update {
 
 #[macro_export]
 macro_rules! crud_delete {
-    ($model:ty, $table:tt) => {
+    ($model:ty, $table:tt $(, $child_model:ty, $child_table:tt, $child_parent_id:tt )* $(; $on_delete:path )?) => {
+        // Move an object and its children to the trash, and record it in the audit log, an object
+        // with children is only deleted if cascading is asked for. The optional hook is called
+        // with the deleted objects, to update what was derived from them
+        pub fn delete_object(
+            conn: &mut $crate::db::DbConnection,
+            oid: i32,
+            cascade: bool,
+            config: &$crate::config::Config,
+            audit: $crate::models::audit::NewAuditEntry,
+        ) -> Result<(), ServerError> {
+            let purge_delay = config.trash_purge_delay();
+            conn.transaction::<_, ServerError, _>(|conn| {
+                use $crate::schema::$table::dsl::*;
+                let deleted_o = $table.filter(id.eq(oid)).load::<$model>(conn)?;
+                if deleted_o.is_empty() {
+                    return Err(ServerError::DieselNotFound);
+                }
+                let mut content = serde_json::Map::new();
+                content.insert(stringify!($table).to_owned(), serde_json::to_value(&deleted_o)?);
+                #[allow(unused_mut)]
+                let mut dependents = std::collections::BTreeMap::new();
+                $(
+                    // Move the children to the trash along with their parent
+                    let children = $crate::schema::$child_table::table
+                        .filter($crate::schema::$child_table::$child_parent_id.eq(oid))
+                        .load::<$child_model>(conn)?;
+                    if !children.is_empty() {
+                        dependents.insert(stringify!($child_table), children.len());
+                    }
+                    content.insert(stringify!($child_table).to_owned(), serde_json::to_value(&children)?);
+                )*
+                if !cascade && !dependents.is_empty() {
+                    return Err(ServerError::Dependents(dependents));
+                }
+                $(
+                    diesel::delete($crate::schema::$child_table::table)
+                        .filter($crate::schema::$child_table::$child_parent_id.eq(oid))
+                        .execute(conn)?;
+                )*
+                diesel::delete($table).filter(id.eq(oid)).execute(conn)?;
+                $( $on_delete(conn, config, &deleted_o)?; )?
+                let target = format!("{}/{}", stringify!($table), oid);
+                let trash_id = $crate::models::trash::put(conn, purge_delay, &audit.actor, &target, &content.into())?;
+                audit
+                    .target(target)
+                    .details(&serde_json::json!({ "trash_id": trash_id, "dependents": dependents }))
+                    .insert(conn)?;
+                Ok(())
+            })
+        }
+
         #[delete("/{oid}")]
         pub async fn delete(
+            req: HttpRequest,
             pool: web::Data<DbPool>,
+            cfg: web::Data<$crate::app::AppConfig>,
             oid: web::Path<i32>,
         ) -> Result<HttpResponse, ServerError> {
+            let audit = $crate::models::audit::NewAuditEntry::new(&req, concat!(stringify!($table), ".delete"));
+            let cascade = $crate::app::query_string_to_hashmap(req.query_string())
+                .get("cascade")
+                .is_some_and(|v| v == "true");
             let mut conn = pool.get()?;
             let oid = *oid;
-            web::block(move || {
-                use $crate::schema::$table::dsl::*;
-                let deleted = diesel::delete($table)
-                    .filter(id.eq(oid))
-                    .execute(&mut conn)?;
-                match deleted {
-                    0 => Err(diesel::result::Error::NotFound),
-                    _ => Ok(deleted),
-                }
-            })
-            .await??;
+            web::block(move || delete_object(&mut conn, oid, cascade, &cfg.config, audit)).await??;
             Ok(HttpResponse::Ok().body(format!("Deleted object with id: {}", oid)))
         }
     };
@@ -130,17 +199,58 @@ macro_rules! crud_delete {
 
 #[macro_export]
 macro_rules! crud_delete_all {
-    ($model:ty, $table:tt) => {
+    ($model:ty, $table:tt $(, $child_model:ty, $child_table:tt )* $(; $on_delete:path )?) => {
         #[delete("")]
-        pub async fn delete_all(pool: web::Data<DbPool>) -> Result<HttpResponse, ServerError> {
+        pub async fn delete_all(
+            req: HttpRequest,
+            pool: web::Data<DbPool>,
+            cfg: web::Data<$crate::app::AppConfig>,
+        ) -> Result<HttpResponse, ServerError> {
+            // Wiping a whole table requires admin rights and an explicit confirmation
+            if !req.extensions().get::<$crate::app::Credential>().is_some_and(|c| c.is_admin()) {
+                return Ok(HttpResponse::Forbidden().body("admin rights are required"));
+            }
+            let confirmation = $crate::app::query_string_to_hashmap(req.query_string())
+                .remove("confirm")
+                .or_else(|| {
+                    req.headers()
+                        .get("X-Confirm-Delete")
+                        .and_then(|v| v.to_str().ok())
+                        .map(str::to_owned)
+                });
+            if confirmation.as_deref() != Some(stringify!($table)) {
+                return Ok(HttpResponse::BadRequest().body(concat!(
+                    "deleting all ", stringify!($table), " must be confirmed with ?confirm=",
+                    stringify!($table), " or the X-Confirm-Delete header"
+                )));
+            }
+            let audit = $crate::models::audit::NewAuditEntry::new(&req, concat!(stringify!($table), ".delete_all"));
+            let purge_delay = cfg.trash_purge_delay;
             let mut conn = pool.get()?;
             web::block(move || {
-                use $crate::schema::$table::dsl::*;
-                let deleted = diesel::delete($table).execute(&mut conn)?;
-                match deleted {
-                    0 => Err(diesel::result::Error::NotFound),
-                    _ => Ok(deleted),
-                }
+                conn.transaction::<_, ServerError, _>(|conn| {
+                    use $crate::schema::$table::dsl::*;
+                    let deleted_o = $table.load::<$model>(conn)?;
+                    if deleted_o.is_empty() {
+                        return Err(ServerError::DieselNotFound);
+                    }
+                    let mut content = serde_json::Map::new();
+                    content.insert(stringify!($table).to_owned(), serde_json::to_value(&deleted_o)?);
+                    $(
+                        // Move the children to the trash along with their parents
+                        let children = $crate::schema::$child_table::table.load::<$child_model>(conn)?;
+                        diesel::delete($crate::schema::$child_table::table).execute(conn)?;
+                        content.insert(stringify!($child_table).to_owned(), serde_json::to_value(&children)?);
+                    )*
+                    let deleted = diesel::delete($table).execute(conn)?;
+                    $( $on_delete(conn, &cfg.config, &deleted_o)?; )?
+                    let trash_id = $crate::models::trash::put(conn, purge_delay, &audit.actor, stringify!($table), &content.into())?;
+                    audit
+                        .target(stringify!($table))
+                        .details(&serde_json::json!({ "deleted": deleted, "trash_id": trash_id }))
+                        .insert(conn)?;
+                    Ok(deleted)
+                })
             })
             .await??;
             Ok(HttpResponse::Ok().body("Deleted all objects"))
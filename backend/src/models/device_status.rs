@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, get, web};
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    app::Credential,
+    db::DbConnection,
+    errors::ServerError,
+    models::{
+        group::request_visibility,
+        position::Position,
+        privacy::{self, Policy, Served},
+    },
+    schema::{alert_settings, device_status, positions},
+    utils::now,
+};
+
+type DbPool = r2d2::Pool<ConnectionManager<DbConnection>>;
+
+// Delay without any position after which a device is offline, unless its offline alert is sooner
+pub const OFFLINE_AFTER: i64 = 15 * 60 * 1000;
+
+// Last known state of the device of a user, kept across restarts
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, Insertable, AsChangeset)]
+#[diesel(table_name = device_status)]
+pub struct DeviceStatus {
+    pub user_id: i32,
+    // time of the most recent position
+    pub last_fix: i64,
+    pub battery_level: i32,
+    pub source: String,
+    pub online: bool,
+}
+
+// A status as served to a credential : while the position of the user is hidden, so is when its
+// device was last seen
+#[derive(Debug, Serialize)]
+pub struct ServedStatus {
+    pub user_id: i32,
+    pub last_fix: Option<i64>,
+    pub battery_level: i32,
+    pub source: String,
+    pub online: Option<bool>,
+    // why the last fix is hidden
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<&'static str>,
+}
+
+impl ServedStatus {
+    fn new(
+        credential: Option<&Credential>,
+        policy: Option<&Policy>,
+        status: DeviceStatus,
+        last_position: Option<Position>,
+        now: i64,
+    ) -> Self {
+        let hidden = match last_position {
+            Some(position) => match privacy::serve(credential, policy, position, now) {
+                Served::Hidden { status, .. } => Some(status),
+                Served::Position(_) => None,
+            },
+            // without the position, only the pause is known
+            None if !credential.is_some_and(|c| c.owns(status.user_id))
+                && policy
+                    .and_then(|p| p.settings.as_ref())
+                    .is_some_and(|s| s.is_paused(now)) =>
+            {
+                Some("sharing paused")
+            }
+            None => None,
+        };
+        ServedStatus {
+            user_id: status.user_id,
+            last_fix: hidden.is_none().then_some(status.last_fix),
+            battery_level: status.battery_level,
+            source: status.source,
+            online: hidden.is_none().then_some(status.online),
+            status: hidden,
+        }
+    }
+}
+
+// A share token gives access to the status of its user only
+fn in_share_scope(credential: Option<&Credential>, uid: i32) -> bool {
+    !matches!(credential, Some(Credential::Share(id)) if i32::from(*id) != uid)
+}
+
+// Get the positions the statuses were recorded from, by user
+fn last_positions(
+    conn: &mut DbConnection,
+    statuses: &[DeviceStatus],
+) -> QueryResult<HashMap<i32, Position>> {
+    let fixes: HashMap<i32, i64> = statuses.iter().map(|s| (s.user_id, s.last_fix)).collect();
+    Ok(positions::table
+        .filter(positions::user_id.eq_any(fixes.keys()))
+        .filter(positions::time.eq_any(fixes.values()))
+        .filter(positions::flagged.is_null())
+        .load::<Position>(conn)?
+        .into_iter()
+        .filter(|p| fixes.get(&p.user_id) == Some(&p.time))
+        .map(|p| (p.user_id, p))
+        .collect())
+}
+
+pub fn last_fix(conn: &mut DbConnection, uid: i32) -> QueryResult<Option<i64>> {
+    device_status::table
+        .find(uid)
+        .select(device_status::last_fix)
+        .first::<i64>(conn)
+        .optional()
+}
+
+pub fn last_fixes(conn: &mut DbConnection) -> QueryResult<HashMap<i32, i64>> {
+    Ok(device_status::table
+        .select((device_status::user_id, device_status::last_fix))
+        .load::<(i32, i64)>(conn)?
+        .into_iter()
+        .collect())
+}
+
+// Update the status with the newest position of the user
+pub fn record(conn: &mut DbConnection, position: &Position) -> QueryResult<()> {
+    crate::replace_into!(
+        conn,
+        device_status::table,
+        device_status::user_id.eq(position.user_id),
+        &DeviceStatus {
+            user_id: position.user_id,
+            last_fix: position.time,
+            battery_level: position.battery_level,
+            source: position.source.clone(),
+            online: true,
+        }
+    )?;
+    Ok(())
+}
+
+// Set offline the devices that did not send a position for too long, returns their users
+pub fn check_online(conn: &mut DbConnection, now: i64) -> QueryResult<Vec<i32>> {
+    let offline_after: HashMap<i32, i64> = alert_settings::table
+        .select((alert_settings::user_id, alert_settings::offline_after))
+        .load::<(i32, Option<i32>)>(conn)?
+        .into_iter()
+        .filter_map(|(uid, minutes)| Some((uid, i64::from(minutes?) * 60 * 1000)))
+        .collect();
+    let statuses = device_status::table
+        .filter(device_status::online.eq(true))
+        .load::<DeviceStatus>(conn)?;
+    let offline: Vec<i32> = statuses
+        .into_iter()
+        .filter(|s| {
+            let delay = offline_after
+                .get(&s.user_id)
+                .map_or(OFFLINE_AFTER, |d| (*d).min(OFFLINE_AFTER));
+            now - s.last_fix > delay
+        })
+        .map(|s| s.user_id)
+        .collect();
+    if !offline.is_empty() {
+        diesel::update(device_status::table)
+            .filter(device_status::user_id.eq_any(&offline))
+            .set(device_status::online.eq(false))
+            .execute(conn)?;
+    }
+    Ok(offline)
+}
+
+#[get("")]
+pub async fn read_all(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, ServerError> {
+    let visibility = request_visibility(&req, &pool).await?;
+    let credential = req.extensions().get::<Credential>().copied();
+    let mut conn = pool.get()?;
+    let (objects, mut last_positions, policies) = web::block(move || {
+        // Statuses stay private outside the groups
+        let objects: Vec<DeviceStatus> = device_status::table
+            .order(device_status::user_id.asc())
+            .load::<DeviceStatus>(&mut conn)?
+            .into_iter()
+            .filter(|s| {
+                visibility.allows(s.user_id) && in_share_scope(credential.as_ref(), s.user_id)
+            })
+            .collect();
+        let last_positions = last_positions(&mut conn, &objects)?;
+        QueryResult::Ok((objects, last_positions, privacy::load_all(&mut conn)?))
+    })
+    .await??;
+    let now = now();
+    let objects: Vec<ServedStatus> = objects
+        .into_iter()
+        .map(|s| {
+            let (policy, last_position) =
+                (policies.get(&s.user_id), last_positions.remove(&s.user_id));
+            ServedStatus::new(credential.as_ref(), policy, s, last_position, now)
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(objects))
+}
+
+#[get("/{uid}")]
+pub async fn read(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    uid: web::Path<i32>,
+) -> Result<HttpResponse, ServerError> {
+    let uid = *uid;
+    let credential = req.extensions().get::<Credential>().copied();
+    if !in_share_scope(credential.as_ref(), uid) {
+        return Ok(HttpResponse::Forbidden().body("user ids don't match"));
+    }
+    if !request_visibility(&req, &pool).await?.allows(uid) {
+        return Err(ServerError::DieselNotFound);
+    }
+    let mut conn = pool.get()?;
+    let (object, mut last_positions, policy) = web::block(move || {
+        let object = device_status::table
+            .find(uid)
+            .first::<DeviceStatus>(&mut conn)?;
+        let last_positions = last_positions(&mut conn, std::slice::from_ref(&object))?;
+        QueryResult::Ok((object, last_positions, privacy::load(&mut conn, uid)?))
+    })
+    .await??;
+    let last_position = last_positions.remove(&uid);
+    Ok(HttpResponse::Ok().json(ServedStatus::new(
+        credential.as_ref(),
+        Some(&policy),
+        object,
+        last_position,
+        now(),
+    )))
+}
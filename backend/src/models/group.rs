@@ -0,0 +1,204 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    app::Credential,
+    crud_create, crud_delete, crud_read, crud_read_all, crud_update, crud_use,
+    db::DbConnection,
+    errors::ServerError,
+    models::{audit::NewAuditEntry, user::User},
+    schema::{group_members, user_groups},
+};
+
+macro_rules! trim {
+    () => {
+        fn trim(&mut self) -> &Self {
+            self.name = self.name.trim().to_string();
+            self
+        }
+
+        fn validate(&self) -> Result<(), String> {
+            match self.name.is_empty() {
+                true => Err("group name must not be empty".to_owned()),
+                false => Ok(()),
+            }
+        }
+    };
+}
+
+#[derive(
+    Debug, Clone, Serialize, Deserialize, Queryable, Insertable, AsChangeset, Identifiable,
+)]
+#[diesel(table_name = user_groups)]
+pub struct Group {
+    pub id: i32,
+    pub name: String,
+}
+impl Group {
+    trim!();
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Insertable)]
+#[diesel(table_name = user_groups)]
+pub struct NewGroup {
+    pub name: String,
+}
+impl NewGroup {
+    trim!();
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, Insertable)]
+#[diesel(table_name = group_members)]
+pub struct GroupMember {
+    pub group_id: i32,
+    pub user_id: i32,
+}
+
+crud_use!();
+crud_read_all!(Group, user_groups);
+crud_read!(Group, user_groups);
+crud_create!(NewGroup, Group, user_groups,);
+crud_update!(Group, user_groups,);
+crud_delete!(Group, user_groups, GroupMember, group_members, group_id);
+
+// Users a credential can see
+#[derive(Debug, Clone, PartialEq)]
+pub enum Visibility {
+    All,
+    Users(HashSet<i32>),
+}
+
+impl Visibility {
+    pub fn allows(&self, uid: i32) -> bool {
+        match self {
+            Visibility::All => true,
+            Visibility::Users(users) => users.contains(&uid),
+        }
+    }
+}
+
+// Admins see everyone, share tokens and sessions see their own user and the members of its groups
+pub fn visibility(
+    conn: &mut DbConnection,
+    credential: Option<&Credential>,
+) -> QueryResult<Visibility> {
+    let uid = match credential {
+        Some(c) if c.is_admin() => return Ok(Visibility::All),
+        Some(Credential::Share(id)) => Some(i32::from(*id)),
+        Some(Credential::Session { user_id, .. }) => *user_id,
+        _ => None,
+    };
+    let Some(uid) = uid else {
+        return Ok(Visibility::Users(HashSet::new()));
+    };
+    let groups = group_members::table
+        .filter(group_members::user_id.eq(uid))
+        .select(group_members::group_id)
+        .load::<i32>(conn)?;
+    let mut users: HashSet<i32> = group_members::table
+        .filter(group_members::group_id.eq_any(groups))
+        .select(group_members::user_id)
+        .load::<i32>(conn)?
+        .into_iter()
+        .collect();
+    users.insert(uid);
+    Ok(Visibility::Users(users))
+}
+
+// Get the users visible to the credential of an authenticated request
+pub async fn request_visibility(
+    req: &HttpRequest,
+    pool: &DbPool,
+) -> Result<Visibility, ServerError> {
+    let credential = req.extensions().get::<Credential>().copied();
+    if credential.is_some_and(|c| c.is_admin()) {
+        return Ok(Visibility::All);
+    }
+    let mut conn = pool.get()?;
+    Ok(web::block(move || visibility(&mut conn, credential.as_ref())).await??)
+}
+
+#[get("/{gid}/members")]
+pub async fn read_members(
+    pool: web::Data<DbPool>,
+    gid: web::Path<i32>,
+) -> Result<HttpResponse, ServerError> {
+    let mut conn = pool.get()?;
+    let members = web::block(move || {
+        use crate::schema::users::dsl::*;
+        user_groups::table.find(*gid).first::<Group>(&mut conn)?;
+        users
+            .inner_join(group_members::table)
+            .filter(group_members::group_id.eq(*gid))
+            .select(crate::schema::users::all_columns)
+            .order(name.asc())
+            .load::<User>(&mut conn)
+    })
+    .await??;
+    Ok(HttpResponse::Ok().json(members))
+}
+
+#[post("/{gid}/members/{uid}")]
+pub async fn add_member(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    path: web::Path<(i32, i32)>,
+) -> Result<HttpResponse, ServerError> {
+    let audit = NewAuditEntry::new(&req, "group_members.create");
+    let (gid, uid) = path.into_inner();
+    let mut conn = pool.get()?;
+    let member = web::block(move || -> Result<GroupMember, ServerError> {
+        // Check that the group and the user exist
+        user_groups::table.find(gid).first::<Group>(&mut conn)?;
+        crate::schema::users::table
+            .find(uid)
+            .first::<User>(&mut conn)?;
+        let member = GroupMember {
+            group_id: gid,
+            user_id: uid,
+        };
+        crate::replace_into!(
+            conn,
+            group_members::table,
+            group_members::group_id
+                .eq(gid)
+                .and(group_members::user_id.eq(uid)),
+            &member
+        )?;
+        audit
+            .target(format!("user_groups/{}/members/{}", gid, uid))
+            .insert(&mut conn)?;
+        Ok(member)
+    })
+    .await??;
+    Ok(HttpResponse::Created().json(member))
+}
+
+#[delete("/{gid}/members/{uid}")]
+pub async fn remove_member(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    path: web::Path<(i32, i32)>,
+) -> Result<HttpResponse, ServerError> {
+    let audit = NewAuditEntry::new(&req, "group_members.delete");
+    let (gid, uid) = path.into_inner();
+    let mut conn = pool.get()?;
+    web::block(move || {
+        let deleted = diesel::delete(group_members::table.find((gid, uid))).execute(&mut conn)?;
+        match deleted {
+            0 => Err(diesel::result::Error::NotFound),
+            _ => {
+                audit
+                    .target(format!("user_groups/{}/members/{}", gid, uid))
+                    .insert(&mut conn)?;
+                Ok(deleted)
+            }
+        }
+    })
+    .await??;
+    Ok(HttpResponse::Ok().body(format!(
+        "Removed user with id: {} from group with id: {}",
+        uid, gid
+    )))
+}
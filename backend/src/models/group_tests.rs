@@ -0,0 +1,263 @@
+use crate::{
+    app::{AppConfig, Role},
+    create_app,
+    positions_server::PositionsServerHandle,
+    token::{self, Claims},
+};
+
+pub async fn group_test(
+    pool: &r2d2::Pool<diesel::r2d2::ConnectionManager<crate::db::DbConnection>>,
+    app_config: &actix_web::web::Data<AppConfig>,
+    position_server_handle: &PositionsServerHandle,
+) {
+    use crate::{do_test, do_test_extract_id};
+    use actix_web::{
+        http::{Method, StatusCode},
+        test,
+    };
+
+    let mut app = test::init_service(create_app!(pool, app_config, position_server_handle)).await;
+
+    // Get a response body with a given token
+    macro_rules! get_with {
+        ($token:expr, $uri:expr, $expected_status_code:expr) => {{
+            let req = test::TestRequest::get()
+                .insert_header(("Authorization", format!("Bearer {}", $token)))
+                .uri(&$uri)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), $expected_status_code);
+            let body = test::read_body(resp).await;
+            std::str::from_utf8(&body).unwrap().to_string()
+        }};
+    }
+
+    // Create three users, and a position for the last one
+    let alice = do_test_extract_id!(
+        app,
+        Method::POST,
+        "/api/users",
+        r#"{"name":"Alice","surname":"Grouped"}"#,
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    let bob = do_test_extract_id!(
+        app,
+        Method::POST,
+        "/api/users",
+        r#"{"name":"Bob","surname":"Grouped"}"#,
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    let carol = do_test_extract_id!(
+        app,
+        Method::POST,
+        "/api/users",
+        r#"{"name":"Carol","surname":"Grouped"}"#,
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    let carol_position = do_test_extract_id!(
+        app,
+        Method::POST,
+        "/api/positions",
+        &format!(
+            r#"[{{"user_id":{},"latitude":45.74846,"longitude":4.84671,"source":"GPS","battery_level":50,"sport_mode":false}}]"#,
+            carol
+        ),
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+
+    // Put Alice and Bob in a family, and Carol in a club
+    do_test!(
+        app,
+        Method::POST,
+        "/api/groups",
+        r#"{"name":"  "}"#,
+        StatusCode::BAD_REQUEST,
+        "group name must not be empty"
+    );
+    let family = do_test_extract_id!(
+        app,
+        Method::POST,
+        "/api/groups",
+        r#"{"name":"Family"}"#,
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    let club = do_test_extract_id!(
+        app,
+        Method::POST,
+        "/api/groups",
+        r#"{"name":"Hiking club"}"#,
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    for (group, user) in [(family, alice), (family, bob), (club, carol)] {
+        do_test!(
+            app,
+            Method::POST,
+            &format!("/api/groups/{}/members/{}", group, user),
+            "",
+            StatusCode::CREATED,
+            format!(r#"{{"group_id":{},"user_id":{}}}"#, group, user)
+        );
+    }
+    do_test!(
+        app,
+        Method::POST,
+        &format!("/api/groups/{}/members/{}", family, carol + 100),
+        "",
+        StatusCode::NOT_FOUND,
+        "Item not found"
+    );
+    let body = do_test!(
+        app,
+        Method::GET,
+        &format!("/api/groups/{}/members", family),
+        "",
+        StatusCode::OK,
+        format!(r#"[{{"id":{},"name":"Alice""#, alice)
+    );
+    assert!(body.contains("Bob") && !body.contains("Carol"));
+
+    // A share token for Alice only sees her family
+    let share_token = do_test!(
+        app,
+        Method::GET,
+        &format!("/api/token?user_id={}", alice),
+        "",
+        StatusCode::OK,
+        ""
+    );
+    let body = get_with!(share_token, "/api/users", StatusCode::OK);
+    assert!(body.contains("Alice") && body.contains("Bob") && !body.contains("Carol"));
+    get_with!(
+        share_token,
+        format!("/api/users/{}", carol),
+        StatusCode::NOT_FOUND
+    );
+    get_with!(share_token, "/api/groups", StatusCode::FORBIDDEN);
+
+    // ... as well as a viewer session for Alice
+    let session = |user_id| {
+        token::seal(
+            &app_config.share_keys,
+            &Claims::Session {
+                expires_at: u64::MAX / 2,
+                user_id,
+                role: Role::Viewer,
+            },
+        )
+        .unwrap()
+    };
+    let alice_session = session(Some(alice));
+    let body = get_with!(alice_session, "/api/users", StatusCode::OK);
+    assert!(body.contains("Alice") && body.contains("Bob") && !body.contains("Carol"));
+    get_with!(alice_session, format!("/api/users/{}", bob), StatusCode::OK);
+    get_with!(
+        alice_session,
+        format!("/api/positions?user_id={}", bob),
+        StatusCode::OK
+    );
+
+    // Positions stay private outside the group
+    let body = get_with!(
+        alice_session,
+        format!("/api/positions?user_id={}", carol),
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(body, "user is outside of your groups");
+    get_with!(
+        alice_session,
+        format!("/api/positions/{}", carol_position),
+        StatusCode::NOT_FOUND
+    );
+    let req = test::TestRequest::get()
+        .uri(&format!(
+            "/api/positions/ws?user_id={}&token={}",
+            carol,
+            urlencoding::encode(&alice_session)
+        ))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // A session without user sees nobody
+    let body = get_with!(session(None), "/api/users", StatusCode::OK);
+    assert_eq!(body, "[]");
+
+    // Removing Bob from the family hides him
+    do_test!(
+        app,
+        Method::DELETE,
+        &format!("/api/groups/{}/members/{}", family, bob),
+        "",
+        StatusCode::OK,
+        format!(
+            "Removed user with id: {} from group with id: {}",
+            bob, family
+        )
+    );
+    let body = get_with!(alice_session, "/api/users", StatusCode::OK);
+    assert!(body.contains("Alice") && !body.contains("Bob"));
+
+    // Deleting a group deletes its memberships, and restoring it brings them back
+    do_test!(
+        app,
+        Method::DELETE,
+        &format!("/api/groups/{}?cascade=true", club),
+        "",
+        StatusCode::OK,
+        format!("Deleted object with id: {}", club)
+    );
+    do_test!(
+        app,
+        Method::GET,
+        &format!("/api/groups/{}/members", club),
+        "",
+        StatusCode::NOT_FOUND,
+        "Item not found"
+    );
+    let body = do_test!(app, Method::GET, "/api/trash", "", StatusCode::OK, "[");
+    let entries: Vec<crate::models::trash::TrashEntry> = serde_json::from_str(&body).unwrap();
+    do_test!(
+        app,
+        Method::POST,
+        &format!("/api/trash/{}/restore", entries[0].id),
+        "",
+        StatusCode::OK,
+        "Restored 2 objects"
+    );
+    do_test!(
+        app,
+        Method::GET,
+        &format!("/api/groups/{}/members", club),
+        "",
+        StatusCode::OK,
+        format!(r#"[{{"id":{},"name":"Carol""#, carol)
+    );
+
+    // Clean up
+    for id in [family, club] {
+        do_test!(
+            app,
+            Method::DELETE,
+            &format!("/api/groups/{}?cascade=true", id),
+            "",
+            StatusCode::OK,
+            ""
+        );
+    }
+    for id in [alice, bob, carol] {
+        do_test!(
+            app,
+            Method::DELETE,
+            &format!("/api/users/{}?cascade=true", id),
+            "",
+            StatusCode::OK,
+            ""
+        );
+    }
+}
@@ -1,13 +1,45 @@
+pub(crate) mod alert;
+pub(crate) mod audit;
+pub(crate) mod cell_tower;
 pub(crate) mod crud;
+pub(crate) mod device_status;
+pub(crate) mod group;
+pub(crate) mod oidc_subject;
+pub(crate) mod plausibility;
 pub(crate) mod position;
+pub(crate) mod privacy;
+pub(crate) mod push_subscription;
+pub(crate) mod sharing_rule;
+pub(crate) mod sos;
+pub(crate) mod spatial;
 pub(crate) mod sport_mode;
+pub(crate) mod track;
+pub(crate) mod trash;
 pub(crate) mod user;
 
+#[cfg(test)]
+pub(crate) mod alert_tests;
+#[cfg(test)]
+pub(crate) mod audit_tests;
+#[cfg(test)]
+pub(crate) mod backup_tests;
+#[cfg(test)]
+pub(crate) mod group_tests;
 #[cfg(test)]
 pub(crate) mod position_tests;
 #[cfg(test)]
 pub(crate) mod position_ws_tests;
 #[cfg(test)]
+pub(crate) mod privacy_tests;
+#[cfg(test)]
+pub(crate) mod push_tests;
+#[cfg(test)]
 pub(crate) mod user_tests;
 #[cfg(test)]
+pub(crate) mod sos_tests;
+#[cfg(test)]
 pub(crate) mod sport_mode_tests;
+#[cfg(test)]
+pub(crate) mod track_tests;
+#[cfg(test)]
+pub(crate) mod trash_tests;
@@ -0,0 +1,115 @@
+use actix_web::{HttpRequest, HttpResponse, delete, get, post, web};
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use serde::{Deserialize, Serialize};
+
+use crate::{app::Role, db::DbConnection, errors::ServerError, schema::oidc_subjects};
+
+type DbPool = r2d2::Pool<ConnectionManager<DbConnection>>;
+
+// Maps an identity provider subject to a tesou user and role
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, Insertable)]
+#[diesel(table_name = oidc_subjects)]
+pub struct OidcSubject {
+    pub subject: String,
+    pub user_id: Option<i32>,
+    pub role: String,
+}
+
+impl OidcSubject {
+    fn trim(&mut self) -> &Self {
+        self.subject = self.subject.trim().to_string();
+        self.role = self.role.trim().to_lowercase();
+        self
+    }
+
+    pub fn role(&self) -> Result<Role, &'static str> {
+        self.role.parse()
+    }
+}
+
+pub fn find(conn: &mut DbConnection, sub: &str) -> QueryResult<Option<OidcSubject>> {
+    use crate::schema::oidc_subjects::dsl::*;
+    oidc_subjects
+        .filter(subject.eq(sub))
+        .first::<OidcSubject>(conn)
+        .optional()
+}
+
+#[get("")]
+pub async fn read_all(pool: web::Data<DbPool>) -> Result<HttpResponse, ServerError> {
+    let mut conn = pool.get()?;
+    let objects = web::block(move || {
+        use crate::schema::oidc_subjects::dsl::*;
+        oidc_subjects
+            .order(subject.asc())
+            .load::<OidcSubject>(&mut conn)
+    })
+    .await??;
+    Ok(HttpResponse::Ok().json(objects))
+}
+
+// Create or replace the mapping of a subject
+#[post("")]
+pub async fn create(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    mut o: web::Json<OidcSubject>,
+) -> Result<HttpResponse, ServerError> {
+    o.trim();
+    if o.role().is_err() {
+        return Ok(HttpResponse::BadRequest().body("role must be admin or viewer"));
+    }
+    let audit = crate::models::audit::NewAuditEntry::new(&req, "oidc_subjects.create");
+    let mut conn = pool.get()?;
+    let created_o: Result<OidcSubject, ServerError> = web::block(move || {
+        if let Some(uid) = o.user_id {
+            // Check that the user exists
+            crate::schema::users::dsl::users
+                .find(uid)
+                .first::<crate::models::user::User>(&mut conn)?;
+        }
+        crate::replace_into!(
+            conn,
+            oidc_subjects::table,
+            oidc_subjects::subject.eq(&o.subject),
+            &*o
+        )?;
+        audit
+            .target(format!("oidc_subjects/{}", o.subject))
+            .details(&*o)
+            .insert(&mut conn)?;
+        Ok(o.into_inner())
+    })
+    .await?;
+    Ok(HttpResponse::Created().json(created_o?))
+}
+
+#[delete("/{sub}")]
+pub async fn delete(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    sub: web::Path<String>,
+) -> Result<HttpResponse, ServerError> {
+    let audit = crate::models::audit::NewAuditEntry::new(&req, "oidc_subjects.delete");
+    let mut conn = pool.get()?;
+    let sub = sub.into_inner();
+    let deleted_sub = sub.clone();
+    web::block(move || {
+        use crate::schema::oidc_subjects::dsl::*;
+        let deleted = diesel::delete(oidc_subjects)
+            .filter(subject.eq(&sub))
+            .execute(&mut conn)?;
+        match deleted {
+            0 => Err(diesel::result::Error::NotFound),
+            _ => {
+                audit
+                    .target(format!("oidc_subjects/{}", sub))
+                    .insert(&mut conn)?;
+                Ok(deleted)
+            }
+        }
+    })
+    .await??;
+    Ok(HttpResponse::Ok().body(format!("Deleted subject: {}", deleted_sub)))
+}
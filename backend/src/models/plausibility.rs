@@ -0,0 +1,223 @@
+use crate::{
+    config::Config,
+    models::{
+        position::{NewPosition, Position},
+        sharing_rule::distance,
+    },
+};
+
+// What is done with the positions that can't be right
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Off,
+    // record them with the reason why
+    Flag,
+    Reject,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Plausibility {
+    pub mode: Mode,
+    // in meters per second
+    pub max_speed: f64,
+}
+
+// Number of implausible fixes in a row, agreeing with each other, after which the user is taken to
+// have really moved that fast, as with a device turned off in a plane
+pub const REANCHOR_FIXES: usize = 3;
+// Time after which a plausible fix is too old to tell the speed of the next ones, in milliseconds
+pub const MAX_LOOK_BACK: i64 = 3600 * 1000;
+
+impl Default for Plausibility {
+    fn default() -> Self {
+        Plausibility::from_config(&Config::default())
+    }
+}
+
+// Where and when a user was
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fix {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub time: i64,
+}
+
+impl From<&Position> for Fix {
+    fn from(p: &Position) -> Self {
+        Fix {
+            latitude: p.latitude,
+            longitude: p.longitude,
+            time: p.time,
+        }
+    }
+}
+
+impl From<&NewPosition> for Fix {
+    fn from(p: &NewPosition) -> Self {
+        Fix {
+            latitude: p.latitude,
+            longitude: p.longitude,
+            time: p.time,
+        }
+    }
+}
+
+impl Plausibility {
+    pub fn from_config(config: &Config) -> Self {
+        Plausibility {
+            mode: match config.plausibility.to_lowercase().as_str() {
+                "off" => Mode::Off,
+                "reject" => Mode::Reject,
+                _ => Mode::Flag,
+            },
+            max_speed: config.max_speed,
+        }
+    }
+
+    // Get why a position can't be right, given the previous plausible fix of its user
+    pub fn check(&self, position: Fix, previous: Option<Fix>) -> Option<String> {
+        if self.mode == Mode::Off {
+            return None;
+        }
+        let (latitude, longitude) = (position.latitude, position.longitude);
+        if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+            return Some("coordinates out of range".to_owned());
+        }
+        if latitude.abs() < 1e-6 && longitude.abs() < 1e-6 {
+            return Some("null island coordinates".to_owned());
+        }
+        let previous = previous?;
+        let meters = distance(previous.latitude, previous.longitude, latitude, longitude);
+        // the fixes of the same second are a second apart at most
+        let seconds = ((position.time - previous.time).abs() as f64 / 1000.0).max(1.0);
+        let speed = meters / seconds;
+        (speed > self.max_speed).then(|| {
+            format!(
+                "implausible speed of {:.0} km/h since the previous fix",
+                speed * 3.6
+            )
+        })
+    }
+}
+
+// The fixes a user's next position is compared to : the last plausible one, and the implausible
+// ones since that agree with each other
+#[derive(Debug, Clone, Default)]
+pub struct Track {
+    anchor: Option<Fix>,
+    pending: Vec<Fix>,
+}
+
+impl Track {
+    pub fn new(anchor: Option<Fix>) -> Self {
+        Track {
+            anchor,
+            pending: Vec::new(),
+        }
+    }
+
+    // Start over from a plausible fix
+    pub fn reset(&mut self, anchor: Fix) {
+        self.anchor = Some(anchor);
+        self.pending.clear();
+    }
+
+    // Get why a fix can't be right, and follow it
+    pub fn check(&mut self, plausibility: &Plausibility, fix: Fix) -> Option<String> {
+        let anchor = self
+            .anchor
+            .filter(|a| (fix.time - a.time).abs() <= MAX_LOOK_BACK);
+        let Some(reason) = plausibility.check(fix, anchor) else {
+            self.reset(fix);
+            return None;
+        };
+        // only the fixes too far from the anchor may become the next one
+        if plausibility.check(fix, None).is_some() {
+            return Some(reason);
+        }
+        if self
+            .pending
+            .last()
+            .is_some_and(|last| plausibility.check(fix, Some(*last)).is_some())
+        {
+            self.pending.clear();
+        }
+        self.pending.push(fix);
+        if self.pending.len() >= REANCHOR_FIXES {
+            self.reset(fix);
+            return None;
+        }
+        Some(reason)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let position = |latitude, longitude, time| Fix {
+            latitude,
+            longitude,
+            time,
+        };
+        let flag = Plausibility::default();
+        let lyon = position(45.75, 4.85, 0);
+        assert_eq!(flag.check(position(45.75, 4.85, 0), None), None);
+        assert_eq!(
+            flag.check(position(91.0, 4.85, 0), None).as_deref(),
+            Some("coordinates out of range")
+        );
+        assert_eq!(
+            flag.check(position(45.75, -180.5, 0), Some(lyon))
+                .as_deref(),
+            Some("coordinates out of range")
+        );
+        assert_eq!(
+            flag.check(position(0.0, 0.0, 0), None).as_deref(),
+            Some("null island coordinates")
+        );
+        // a kilometer in a minute is fine, not in a second
+        assert_eq!(flag.check(position(45.759, 4.85, 60_000), Some(lyon)), None);
+        assert_eq!(
+            flag.check(position(45.759, 4.85, 1000), Some(lyon))
+                .as_deref(),
+            Some("implausible speed of 3603 km/h since the previous fix")
+        );
+        // the order of the fixes does not matter
+        assert!(
+            flag.check(position(45.759, 4.85, -1000), Some(lyon))
+                .is_some()
+        );
+        let off = Plausibility {
+            mode: Mode::Off,
+            ..flag
+        };
+        assert_eq!(off.check(position(0.0, 0.0, 0), None), None);
+    }
+
+    #[test]
+    fn test_track() {
+        let fix = |latitude, time| Fix {
+            latitude,
+            longitude: 4.85,
+            time,
+        };
+        let flag = Plausibility::default();
+        let mut track = Track::new(Some(fix(45.75, 0)));
+        assert_eq!(track.check(&flag, fix(45.751, 60_000)), None);
+        // a jump to Paris is implausible, until the fixes there agree with each other
+        assert!(track.check(&flag, fix(48.85, 120_000)).is_some());
+        assert!(track.check(&flag, fix(45.752, 180_000)).is_none());
+        assert!(track.check(&flag, fix(48.85, 240_000)).is_some());
+        assert!(track.check(&flag, fix(48.851, 300_000)).is_some());
+        assert!(track.check(&flag, fix(91.0, 310_000)).is_some());
+        assert_eq!(track.check(&flag, fix(48.852, 360_000)), None);
+        assert_eq!(track.check(&flag, fix(48.853, 420_000)), None);
+        assert!(track.check(&flag, fix(45.75, 480_000)).is_some());
+        // an old fix does not tell the speed
+        let mut track = Track::new(Some(fix(45.75, 0)));
+        assert_eq!(track.check(&flag, fix(48.85, MAX_LOOK_BACK + 1)), None);
+    }
+}
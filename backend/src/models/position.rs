@@ -1,22 +1,35 @@
-use actix_web::HttpRequest;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    app::AppConfig,
-    crud_delete, crud_delete_all, crud_read, crud_update, crud_use,
+    app::{AppConfig, Credential},
+    config::Config,
+    crud_delete, crud_delete_all, crud_update, crud_use,
+    db::DbConnection,
     errors::ServerError,
-    models::user::User,
+    models::{
+        alert,
+        cell_tower::{self, CellTower},
+        device_status,
+        group::request_visibility,
+        plausibility::{Fix, Mode, Plausibility, REANCHOR_FIXES, Track},
+        privacy,
+        track::Simplification,
+        user::User,
+    },
     positions_server::PositionsServerHandle,
     schema::positions::{self, dsl::*},
+    utils::now,
 };
 
-const MINIMUM_TIME_GAP: i64 = 1000;
-
 macro_rules! trim {
     () => {
         fn trim(&mut self) -> &Self {
             self
         }
+
+        fn validate(&self) -> Result<(), String> {
+            Ok(())
+        }
     };
 }
 
@@ -41,6 +54,9 @@ pub struct Position {
     pub battery_level: i32,
     pub sport_mode: bool,
     pub time: i64,
+    // why the position is implausible
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flagged: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Insertable, Default, PartialEq)]
@@ -55,43 +71,38 @@ pub struct NewPosition {
     pub sport_mode: bool,
     #[serde(default = "now")]
     pub time: i64,
+    #[serde(skip)]
+    pub flagged: Option<String>,
 }
 fn default_source() -> String {
     "GPS".to_string()
 }
-fn now() -> i64 {
-    match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
-        Ok(n) => std::time::Duration::as_millis(&n)
-            .try_into()
-            .unwrap_or_default(),
-        Err(_) => 0,
-    }
-}
 impl Position {
     trim!();
 }
 
 crud_use!();
 
-fn filter_positions(
+pub(crate) fn filter_positions(
     pos_vec: Vec<NewPosition>,
     reference: Option<i64>,
     uid: Option<i32>,
+    minimum_time_gap: i64,
 ) -> Vec<NewPosition> {
     if let Some(filter_user_id) = uid {
         let mut filtered_positions = Vec::new();
         let mut last_time = None;
         for position in pos_vec {
             if position.user_id == filter_user_id {
-                // Check if the time difference with the reference is greater than or equal to MINIMUM_TIME_GAP
+                // Check if the time difference with the reference is greater than or equal to the minimum time gap
                 if reference.is_none()
-                    || (position.time - reference.unwrap()).abs() >= MINIMUM_TIME_GAP
+                    || (position.time - reference.unwrap()).abs() >= minimum_time_gap
                 {
-                    // Check if there's a preceding position and its time difference is greater than MINIMUM_TIME_GAP
+                    // Check if there's a preceding position and its time difference is greater than the minimum time gap
                     let pos_time = position.time;
                     if let Some(prev_time) = last_time {
                         let diff: i64 = pos_time - prev_time;
-                        if diff.abs() >= MINIMUM_TIME_GAP {
+                        if diff.abs() >= minimum_time_gap {
                             filtered_positions.push(position);
                         }
                     } else {
@@ -108,22 +119,139 @@ fn filter_positions(
     pos_vec // Return the original vector untouched
 }
 
-macro_rules! update_last_timestamp {
-    ($hm:tt, $created_o:tt) => {
-        let t = $hm.entry($created_o.user_id).or_insert($created_o.time);
-        *t = $created_o.time;
-    };
-}
-
 macro_rules! delete_old_positions {
-    ($connection:tt) => {
-        // keep only the positions of the last 24 hours
+    ($connection:expr, $retention:expr) => {
+        // keep only the positions of the retention period
         diesel::delete(positions)
-            .filter(time.le(now() - 24 * 60 * 60 * 1000))
-            .execute(&mut $connection)?;
+            .filter(time.le(now() - $retention.as_millis() as i64))
+            .execute($connection)?;
     };
 }
 
+// A recorded position, with what is needed to share it
+pub struct Recorded {
+    pub position: Position,
+    pub policy: privacy::Policy,
+    pub alerts: Vec<alert::Alert>,
+}
+
+// Outcome of the recording of the positions posted by a device
+pub enum Ingested {
+    Recorded(Recorded),
+    // all the positions were too close to the last fix
+    Duplicate,
+    // all the positions were implausible, for this reason
+    Rejected(String),
+    // all the positions were recorded as implausible, the newest is given : they are not shared
+    Flagged(Position),
+}
+
+// Record the positions posted by a device
+fn record(
+    conn: &mut DbConnection,
+    o: Vec<NewPosition>,
+    minimum_time_gap: i64,
+    plausibility: Plausibility,
+) -> QueryResult<Ingested> {
+    let uid = o[0].user_id;
+    // Check that parent for our object exists
+    crate::schema::users::dsl::users
+        .find(uid)
+        .first::<User>(conn)?;
+    // Filter the positions : remove those that have a timestamp too close to the last fix or too close together
+    let o = filter_positions(
+        o,
+        device_status::last_fix(conn, uid)?,
+        Some(uid),
+        minimum_time_gap,
+    );
+    if o.is_empty() {
+        return Ok(Ingested::Duplicate);
+    }
+    // Flag or reject the positions that can't be right, given the last plausible fix and the
+    // implausible ones since
+    let anchor = positions
+        .filter(user_id.eq(uid))
+        .filter(flagged.is_null())
+        .order((time.desc(), id.desc()))
+        .first::<Position>(conn)
+        .optional()?
+        .map(|p| Fix::from(&p));
+    let mut since = positions
+        .filter(user_id.eq(uid))
+        .filter(flagged.is_not_null())
+        .filter(time.gt(anchor.map_or(i64::MIN, |a| a.time)))
+        .order((time.desc(), id.desc()))
+        .limit(REANCHOR_FIXES as i64)
+        .load::<Position>(conn)?;
+    since.reverse();
+    let mut track = Track::new(anchor);
+    for p in &since {
+        track.check(&plausibility, Fix::from(p));
+    }
+    let mut rejected = None;
+    let o: Vec<NewPosition> = o
+        .into_iter()
+        .filter_map(|mut p| {
+            p.flagged = track.check(&plausibility, Fix::from(&p));
+            match &p.flagged {
+                None => {}
+                Some(reason) if plausibility.mode == Mode::Reject => {
+                    rejected = Some(reason.clone());
+                    return None;
+                }
+                Some(_) => {}
+            }
+            Some(p)
+        })
+        .collect();
+    if let Some(reason) = rejected.filter(|_| o.is_empty()) {
+        return Ok(Ingested::Rejected(reason));
+    }
+    crate::insert_batch!(conn, positions, &(*o))?;
+    if o.iter().all(|p| p.flagged.is_some()) {
+        let o = positions
+            .filter(user_id.eq(uid))
+            .filter(flagged.is_not_null())
+            .order((time.desc(), id.desc()))
+            .first::<Position>(conn)?;
+        return Ok(Ingested::Flagged(o));
+    }
+    // the implausible positions are kept out of the status, the alerts and the broadcast
+    let o = positions
+        .filter(user_id.eq(uid))
+        .filter(flagged.is_null())
+        .order((time.desc(), id.desc()))
+        .first::<Position>(conn)?;
+    device_status::record(conn, &o)?;
+    let policy = privacy::load(conn, uid)?;
+    let alerts = alert::check_position(conn, &o)?;
+    Ok(Ingested::Recorded(Recorded {
+        position: o,
+        policy,
+        alerts,
+    }))
+}
+
+// Record the positions posted by several devices in a single transaction, the writers being
+// serialized so that the last fixes are current when filtering. Each request is recorded in its
+// own savepoint, so that its failure does not affect the others.
+pub fn record_batch(
+    conn: &mut DbConnection,
+    batch: Vec<Vec<NewPosition>>,
+    minimum_time_gap: i64,
+    retention: std::time::Duration,
+    plausibility: Plausibility,
+) -> QueryResult<Vec<QueryResult<Ingested>>> {
+    conn.immediate_transaction(|conn| {
+        delete_old_positions!(conn, retention);
+        Ok(batch
+            .into_iter()
+            .map(|o| conn.transaction(|conn| record(conn, o, minimum_time_gap, plausibility)))
+            .collect())
+    })
+}
+
 #[post("")]
 pub async fn create(
     pool: web::Data<DbPool>,
@@ -143,37 +271,45 @@ pub async fn create(
         }
         sport_mode_toggle_users.retain(|&x| x != uid);
     }
-    let mut hm = cfg.user_last_update.lock().await;
-    // Filter the positions : remove those that have a timestamp too close to the last update or too close together
-    let o = filter_positions(o, hm.get(&uid).copied(), Some(uid));
-    if o.is_empty() {
-        return Ok(HttpResponse::Conflict()
-            .body("there is already a recorded position in the same second"));
-    }
-    let mut conn = pool.get()?;
-    match web::block(move || {
-        // Check that parent for our object exists
-        crate::schema::users::dsl::users
-            .find(o[0].user_id)
-            .first::<User>(&mut conn)?;
-        delete_old_positions!(conn);
-        diesel::insert_into(positions)
-            .values(&(*o))
-            .execute(&mut conn)?;
-        let o = positions
-            .filter(user_id.eq(uid))
-            .order(time.desc())
-            .first::<Position>(&mut conn)?;
-        Ok(o)
-    })
-    .await?
-    {
-        Ok(created_o) => {
-            update_last_timestamp!(hm, created_o);
+    drop(sport_mode_toggle_users);
+    let recorded = match &cfg.ingester {
+        Some(ingester) => ingester.record(o).await,
+        None => {
+            let minimum_time_gap = cfg.config.minimum_time_gap;
+            let retention = cfg.config.position_retention();
+            let plausibility = Plausibility::from_config(&cfg.config);
+            let mut conn = pool.get()?;
+            web::block(move || {
+                record_batch(
+                    &mut conn,
+                    vec![o],
+                    minimum_time_gap,
+                    retention,
+                    plausibility,
+                )
+            })
+            .await?
+            .and_then(|mut results| results.remove(0))
+        }
+    };
+    match recorded {
+        Ok(Ingested::Duplicate) => Ok(HttpResponse::Conflict()
+            .body("there is already a recorded position in the same second")),
+        Ok(Ingested::Rejected(reason)) => Ok(HttpResponse::UnprocessableEntity().body(reason)),
+        Ok(Ingested::Flagged(flagged_o)) => Ok(HttpResponse::Created().json(flagged_o)),
+        Ok(Ingested::Recorded(Recorded {
+            position: created_o,
+            policy,
+            alerts,
+        })) => {
+            alert::deliver(alerts, &pool, &cfg.notifiers, &ws_data).await?;
+            // the listeners other than the owner get the position as shared by its privacy policy
+            let shared_o = privacy::serve(None, Some(&policy), created_o.clone(), now());
             ws_data
-                .send_message(
+                .send_private_message(
                     created_o.user_id.try_into()?,
                     serde_json::to_string(&created_o)?,
+                    Some(serde_json::to_string(&shared_o)?),
                 )
                 .await;
             Ok(HttpResponse::Created().json(created_o))
@@ -188,7 +324,61 @@ pub async fn create(
     }
 }
 
-crud_read!(Position, positions);
+#[get("/{oid}")]
+pub async fn read(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    oid: web::Path<i32>,
+) -> Result<HttpResponse, ServerError> {
+    let visibility = request_visibility(&req, &pool).await?;
+    let credential = req.extensions().get::<Credential>().copied();
+    let mut conn = pool.get()?;
+    let (object, policy) = web::block(move || {
+        let object = positions.filter(id.eq(*oid)).first::<Position>(&mut conn)?;
+        let policy = privacy::load(&mut conn, object.user_id)?;
+        QueryResult::Ok((object, policy))
+    })
+    .await??;
+    // Positions stay private outside the groups
+    if !visibility.allows(object.user_id) {
+        return Err(ServerError::DieselNotFound);
+    }
+    let object = privacy::serve(credential.as_ref(), Some(&policy), object, now());
+    Ok(HttpResponse::Ok().json(object))
+}
+
+// Latest position of every visible user, or the reason why it is hidden
+#[get("/latest")]
+pub async fn read_latest(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, ServerError> {
+    let visibility = request_visibility(&req, &pool).await?;
+    let credential = req.extensions().get::<Credential>().copied();
+    let mut conn = pool.get()?;
+    let (objects, policies) = web::block(move || {
+        // the implausible positions are left out, for the last plausible ones
+        let objects = positions
+            .filter(flagged.is_null())
+            .order((user_id.asc(), time.desc(), id.desc()))
+            .load::<Position>(&mut conn)?;
+        let policies = privacy::load_all(&mut conn)?;
+        QueryResult::Ok((objects, policies))
+    })
+    .await??;
+    let now = now();
+    let mut last_user_id = None;
+    let mut latest = Vec::new();
+    for object in objects {
+        if !visibility.allows(object.user_id) || last_user_id == Some(object.user_id) {
+            continue;
+        }
+        last_user_id = Some(object.user_id);
+        let policy = policies.get(&object.user_id);
+        latest.push(privacy::serve(credential.as_ref(), policy, object, now));
+    }
+    Ok(HttpResponse::Ok().json(latest))
+}
 
 #[derive(Deserialize)]
 pub struct Params {
@@ -200,15 +390,40 @@ pub async fn read_filter(
     req: HttpRequest,
     pool: web::Data<DbPool>,
 ) -> Result<HttpResponse, ServerError> {
-    let mut conn = pool.get()?;
     let params = web::Query::<Params>::from_query(req.query_string());
+    let simplification = match web::Query::<Simplification>::from_query(req.query_string()) {
+        Ok(s) => s.into_inner(),
+        Err(e) => return Ok(HttpResponse::BadRequest().body(e.to_string())),
+    };
+    if let Err(e) = simplification.validate() {
+        return Ok(HttpResponse::BadRequest().body(e));
+    }
+    if let Ok(p) = &params
+        && !request_visibility(&req, &pool).await?.allows(p.user_id)
+    {
+        return Ok(HttpResponse::Forbidden().body("user is outside of your groups"));
+    }
+    let credential = req.extensions().get::<Credential>().copied();
+    let mut conn = pool.get()?;
     let object = match params {
         Ok(p) => {
             web::block(move || {
-                positions
+                let objects = positions
                     .filter(user_id.eq(p.user_id))
                     .order(id.asc())
-                    .load::<Position>(&mut conn)
+                    .load::<Position>(&mut conn)?;
+                let policy = privacy::load(&mut conn, p.user_id)?;
+                let now = now();
+                // Exact positions are only served to their owner, the hidden ones are left out
+                QueryResult::Ok(
+                    objects
+                        .into_iter()
+                        .filter_map(|o| {
+                            privacy::serve(credential.as_ref(), Some(&policy), o, now).position()
+                        })
+                        .collect::<Vec<Position>>(),
+                )
+                .map(|objects| simplification.apply(objects))
             })
             .await?
         }
@@ -228,8 +443,14 @@ pub async fn read_filter(
 }
 
 crud_update!(Position, positions, User, users, user_id);
-crud_delete_all!(Position, positions);
-crud_delete!(Position, positions);
+// The deleted positions are removed from the daily tracks too
+crud_delete_all!(Position, positions; forget_tracks);
+crud_delete!(Position, positions; forget_tracks);
+
+// The daily tracks are updated without the deleted positions
+fn forget_tracks(conn: &mut DbConnection, _: &Config, deleted: &[Position]) -> QueryResult<()> {
+    crate::models::track::forget(conn, deleted)
+}
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CellId {
@@ -243,6 +464,74 @@ pub struct CellId {
     pub battery_level: i32,
 }
 
+// Android reports the unknown cell identity fields as the largest integer
+const CELL_UNAVAILABLE: i64 = i32::MAX as i64;
+// CDMA base station coordinates are in quarters of arc second
+const CDMA_UNITS_PER_DEGREE: f64 = 4.0 * 3600.0;
+
+impl CellId {
+    fn validate(&self) -> Result<(), String> {
+        // the largest cell and area identities of each network type
+        let (max_cid, max_lac) = match self.network_type.as_str() {
+            "CDMA" => {
+                return match self.coordinates() {
+                    Some(_) => Ok(()),
+                    None => Err("CDMA base station coordinates are unavailable".to_owned()),
+                };
+            }
+            "GSM" => (0xFFFF, 0xFFFF),
+            "WCDMA" | "TDSCDMA" | "LTE" => (0xFFF_FFFF, 0xFFFF),
+            "NR" => (0xF_FFFF_FFFF, 0xFF_FFFF),
+            t => return Err(format!("unknown network type: {}", t)),
+        };
+        let digits = |s: &str, lengths: &[usize]| {
+            lengths.contains(&s.len()) && s.bytes().all(|b| b.is_ascii_digit())
+        };
+        if !digits(&self.mcc, &[3]) || self.mcc == "000" {
+            return Err(format!("mcc must be three digits, got {}", self.mcc));
+        }
+        if !digits(&self.mnc, &[2, 3]) {
+            return Err(format!("mnc must be two or three digits, got {}", self.mnc));
+        }
+        if self.cid == CELL_UNAVAILABLE || i64::from(self.lac) == CELL_UNAVAILABLE {
+            return Err(format!(
+                "the cell identity is unavailable on {}",
+                self.network_type
+            ));
+        }
+        if !(0..=max_cid).contains(&self.cid) {
+            return Err(format!(
+                "cid must be between 0 and {} on {}, got {}",
+                max_cid, self.network_type, self.cid
+            ));
+        }
+        if !(0..=max_lac).contains(&self.lac) {
+            return Err(format!(
+                "lac must be between 0 and {} on {}, got {}",
+                max_lac, self.network_type, self.lac
+            ));
+        }
+        Ok(())
+    }
+
+    // Coordinates of the base station, in degrees, if the network gives them
+    fn coordinates(&self) -> Option<(f64, f64)> {
+        let max_lat = (90.0 * CDMA_UNITS_PER_DEGREE) as i64;
+        let max_long = (180.0 * CDMA_UNITS_PER_DEGREE) as i64;
+        (self.network_type == "CDMA"
+            && self.lat != CELL_UNAVAILABLE
+            && self.long != CELL_UNAVAILABLE
+            && (-max_lat..=max_lat).contains(&self.lat)
+            && (-max_long..=max_long).contains(&self.long))
+        .then(|| {
+            (
+                self.lat as f64 / CDMA_UNITS_PER_DEGREE,
+                self.long as f64 / CDMA_UNITS_PER_DEGREE,
+            )
+        })
+    }
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct OpenCellIdResponse {
@@ -273,8 +562,11 @@ pub async fn create_from_cid(
     uid: web::Path<i32>,
     cell_id: web::Json<CellId>,
     cfg: web::Data<AppConfig>,
+    ws_data: web::Data<PositionsServerHandle>,
 ) -> Result<HttpResponse, ServerError> {
-    let mut hm = cfg.user_last_update.lock().await;
+    if let Err(e) = cell_id.validate() {
+        return Ok(HttpResponse::UnprocessableEntity().body(e));
+    }
     let mut o = NewPosition {
         user_id: *uid,
         latitude: 0.0,
@@ -283,40 +575,93 @@ pub async fn create_from_cid(
         time: now(),
         battery_level: cell_id.battery_level,
         sport_mode: false,
+        flagged: None,
     };
-    if let Some(last_update) = hm.get(&o.user_id)
-        && (o.time - last_update).abs() < MINIMUM_TIME_GAP
-    {
+    let minimum_time_gap = cfg.config.minimum_time_gap;
+    let retention = cfg.config.position_retention();
+    let cell_cache_ttl = cfg.config.cell_cache_ttl as i64 * 1000;
+    let coordinates = cell_id.coordinates();
+    let mut conn = pool.get()?;
+    let cell = cell_id.clone();
+    let (last_fix, tower) = web::block(move || -> Result<_, ServerError> {
+        let tower = match coordinates {
+            Some(_) => None,
+            None => cell_tower::locate(&mut conn, &cell)?,
+        };
+        Ok((device_status::last_fix(&mut conn, *uid)?, tower))
+    })
+    .await??;
+    if coordinates.is_none() && tower.is_none() && cfg.open_cell_id_api_key.is_none() {
+        return Ok(HttpResponse::UnprocessableEntity().body(format!(
+            "{} cell not in the cell towers, and there is no Open Cell ID API key to locate it",
+            cell_id.network_type
+        )));
+    }
+    // Check the duplicates before looking the cell up
+    if last_fix.is_some_and(|t| (o.time - t).abs() < minimum_time_gap) {
         return Ok(HttpResponse::Conflict()
             .body("there is already a recorded position in the same second"));
     }
-    if cell_id.lat != -1 {
-        o.latitude = (cell_id.lat / 1296000) as f64;
-        o.longitude = (cell_id.long / 2592000) as f64;
-    } else if let Some(api_key) = &cfg.open_cell_id_api_key {
-        // Get the cell location from open Cell Id database
-        let ocid_resp = get_resp(&cell_id, api_key).await?;
-        // Create position from those informations
-        o.latitude = ocid_resp.lat;
-        o.longitude = ocid_resp.lon;
-    };
+    match (coordinates, tower, &cfg.open_cell_id_api_key) {
+        (Some((lat, lon)), _, _) => (o.latitude, o.longitude) = (lat, lon),
+        (None, Some(t), _) if t.is_fresh(o.time) => {
+            (o.latitude, o.longitude) = (t.latitude, t.longitude)
+        }
+        (None, tower, Some(api_key)) => match get_resp(&cell_id, api_key).await {
+            Ok(ocid_resp) => {
+                (o.latitude, o.longitude) = (ocid_resp.lat, ocid_resp.lon);
+                if cell_cache_ttl > 0 {
+                    let cached = CellTower {
+                        mcc: ocid_resp.mcc,
+                        mnc: ocid_resp.mnc,
+                        lac: cell_id.lac,
+                        cid: cell_id.cid,
+                        radio: ocid_resp.radio,
+                        latitude: ocid_resp.lat,
+                        longitude: ocid_resp.lon,
+                        radius: ocid_resp.range,
+                        expires_at: Some(o.time + cell_cache_ttl),
+                    };
+                    let mut conn = pool.get()?;
+                    web::block(move || cell_tower::upsert(&mut conn, &[cached])).await??;
+                }
+            }
+            // An outdated location is better than none
+            Err(e) => match tower {
+                Some(t) => {
+                    log::warn!("{}, using the expired location of the cell", e);
+                    (o.latitude, o.longitude) = (t.latitude, t.longitude);
+                }
+                None => return Err(e),
+            },
+        },
+        (None, Some(t), None) => (o.latitude, o.longitude) = (t.latitude, t.longitude),
+        (None, None, None) => unreachable!("no location checked above"),
+    }
+    let plausibility = Plausibility::from_config(&cfg.config);
     let mut conn = pool.get()?;
     match web::block(move || {
-        // Check that parent for our object exists
-        crate::schema::users::dsl::users
-            .find(o.user_id)
-            .first::<User>(&mut conn)?;
-        delete_old_positions!(conn);
-        diesel::insert_into(positions)
-            .values(o)
-            .execute(&mut conn)?;
-        let o = positions.order(id.desc()).first::<Position>(&mut conn)?;
-        Ok(o)
+        record_batch(
+            &mut conn,
+            vec![vec![o]],
+            minimum_time_gap,
+            retention,
+            plausibility,
+        )
     })
     .await?
+    .and_then(|mut results| results.remove(0))
     {
-        Ok(created_o) => {
-            update_last_timestamp!(hm, created_o);
+        Ok(Ingested::Duplicate) => Ok(HttpResponse::Conflict()
+            .body("there is already a recorded position in the same second")),
+        Ok(Ingested::Rejected(reason)) => Ok(HttpResponse::UnprocessableEntity().body(reason)),
+        Ok(Ingested::Flagged(flagged_o)) => Ok(HttpResponse::Created().json(flagged_o)),
+        Ok(Ingested::Recorded(Recorded {
+            position: created_o,
+            alerts,
+            ..
+        })) => {
+            alert::deliver(alerts, &pool, &cfg.notifiers, &ws_data).await?;
             Ok(HttpResponse::Created().json(created_o))
         }
         Err(e) => match e {
@@ -330,26 +675,30 @@ pub async fn create_from_cid(
 }
 
 async fn get_resp(cell_id: &CellId, api_key: &str) -> Result<OpenCellIdResponse, ServerError> {
-    // Request latitude and longitude from OpenCellId
+    // Request latitude and longitude from OpenCellId, without logging the key
+    log::info!(
+        "Locating the {} cell {}-{}-{}-{} with Open Cell ID",
+        cell_id.network_type,
+        cell_id.mcc,
+        cell_id.mnc,
+        cell_id.lac,
+        cell_id.cid
+    );
     let url = format!(
         "https://opencellid.org/cell/get?key={}&mcc={}&mnc={}&lac={}&cellid={}&format=json",
         api_key, cell_id.mcc, cell_id.mnc, cell_id.lac, cell_id.cid
     );
-    log::info!(
-        "Creating position from open cell id database with url {}",
-        url
-    );
     match reqwest::get(url).await {
         Ok(res) => match res.json().await {
             Ok(v) => Ok(v),
             Err(e) => Err(ServerError::Other(format!(
                 "Cell not found in Open Cell ID Database: {}",
-                e
+                e.without_url()
             ))),
         },
         Err(e) => Err(ServerError::Other(format!(
             "Open Cell ID did not respond: {}",
-            e
+            e.without_url()
         ))),
     }
 }
@@ -406,7 +755,7 @@ mod tests {
 
         // Test case 1: Filtering by user_id 2 and reference 2500
         let uid = Some(2);
-        let filtered_positions_1 = filter_positions(vec_pos.clone(), reference, uid);
+        let filtered_positions_1 = filter_positions(vec_pos.clone(), reference, uid, 1000);
         assert_eq!(
             filtered_positions_1,
             vec![
@@ -425,29 +774,29 @@ mod tests {
 
         // Test case 2: Filtering by user_id 3 and reference 5000
         let uid = Some(3);
-        let filtered_positions_2 = filter_positions(vec_pos.clone(), reference, uid);
+        let filtered_positions_2 = filter_positions(vec_pos.clone(), reference, uid, 1000);
         assert_eq!(filtered_positions_2, vec![]);
 
         // Test case 3: No filtering (uid = None)
         let uid = None;
-        let filtered_positions_3 = filter_positions(vec_pos.clone(), reference, uid);
+        let filtered_positions_3 = filter_positions(vec_pos.clone(), reference, uid, 1000);
         assert_eq!(filtered_positions_3, vec_pos);
 
         // Test case 4: Empty positions vector
         let empty_positions = Vec::new();
         let uid = Some(2);
-        let filtered_positions_4 = filter_positions(empty_positions.clone(), reference, uid);
+        let filtered_positions_4 = filter_positions(empty_positions.clone(), reference, uid, 1000);
         assert_eq!(filtered_positions_4, Vec::new());
 
         // Test case 5: Empty positions vector (uid = None)
         let uid = None;
-        let filtered_positions_5 = filter_positions(empty_positions.clone(), reference, uid);
+        let filtered_positions_5 = filter_positions(empty_positions.clone(), reference, uid, 1000);
         assert_eq!(filtered_positions_5, empty_positions);
 
         // Test case 6: Filtering by user_id 2 and no reference
         let uid = Some(2);
         let reference = None;
-        let filtered_positions_1 = filter_positions(vec_pos.clone(), reference, uid);
+        let filtered_positions_1 = filter_positions(vec_pos.clone(), reference, uid, 1000);
         assert_eq!(
             filtered_positions_1,
             vec![
@@ -469,4 +818,116 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_cell_id() {
+        let cell = |network_type: &str, cid, lac| CellId {
+            network_type: network_type.to_owned(),
+            mcc: "208".to_owned(),
+            mnc: "01".to_owned(),
+            cid,
+            lac,
+            lat: -1,
+            long: -1,
+            battery_level: 50,
+        };
+        // The CDMA base stations give their coordinates, in quarters of arc second
+        let cdma = CellId {
+            mcc: "no_mcc".to_owned(),
+            mnc: "no_mnc".to_owned(),
+            lat: 658_857,
+            long: 69_810,
+            ..cell("CDMA", -1, -1)
+        };
+        assert_eq!(cdma.validate(), Ok(()));
+        let (lat, lon) = cdma.coordinates().unwrap();
+        assert!((lat - 45.754).abs() < 1e-3 && (lon - 4.848).abs() < 1e-3);
+        let south_west = CellId {
+            lat: -1_296_000,
+            long: -2_592_000,
+            ..cdma.clone()
+        };
+        assert_eq!(south_west.coordinates(), Some((-90.0, -180.0)));
+        for (lat, long) in [(CELL_UNAVAILABLE, 69_810), (1_296_001, 0), (0, -2_592_001)] {
+            let cdma = CellId {
+                lat,
+                long,
+                ..cdma.clone()
+            };
+            assert_eq!(cdma.coordinates(), None);
+            assert_eq!(
+                cdma.validate(),
+                Err("CDMA base station coordinates are unavailable".to_owned())
+            );
+        }
+
+        // The other networks are located by their identities, that are checked
+        for (network_type, max_cid, max_lac) in [
+            ("GSM", 65_535, 65_535),
+            ("WCDMA", 268_435_455, 65_535),
+            ("TDSCDMA", 268_435_455, 65_535),
+            ("LTE", 268_435_455, 65_535),
+            ("NR", 68_719_476_735, 16_777_215),
+        ] {
+            let valid = cell(network_type, max_cid, max_lac);
+            assert_eq!(valid.validate(), Ok(()));
+            assert_eq!(valid.coordinates(), None);
+            assert_eq!(
+                cell(network_type, max_cid + 1, 0).validate(),
+                Err(format!(
+                    "cid must be between 0 and {} on {}, got {}",
+                    max_cid,
+                    network_type,
+                    max_cid + 1
+                ))
+            );
+            assert!(cell(network_type, 0, max_lac + 1).validate().is_err());
+            // unavailable identities
+            assert!(cell(network_type, -1, 0).validate().is_err());
+            assert!(cell(network_type, 0, -1).validate().is_err());
+            assert_eq!(
+                cell(network_type, CELL_UNAVAILABLE, 0).validate(),
+                Err(format!(
+                    "the cell identity is unavailable on {}",
+                    network_type
+                ))
+            );
+        }
+        assert_eq!(
+            CellId {
+                mcc: "no_mcc".to_owned(),
+                ..cell("LTE", 1, 1)
+            }
+            .validate(),
+            Err("mcc must be three digits, got no_mcc".to_owned())
+        );
+        assert!(
+            CellId {
+                mcc: "000".to_owned(),
+                ..cell("LTE", 1, 1)
+            }
+            .validate()
+            .is_err()
+        );
+        assert_eq!(
+            CellId {
+                mnc: "1".to_owned(),
+                ..cell("GSM", 1, 1)
+            }
+            .validate(),
+            Err("mnc must be two or three digits, got 1".to_owned())
+        );
+        assert!(
+            CellId {
+                mnc: "001".to_owned(),
+                ..cell("GSM", 1, 1)
+            }
+            .validate()
+            .is_ok()
+        );
+        assert_eq!(
+            cell("no_type", 1, 1).validate(),
+            Err("unknown network type: no_type".to_owned())
+        );
+    }
 }
@@ -1,4 +1,14 @@
-use crate::{app::AppConfig, create_app, positions_server::PositionsServerHandle};
+use crate::{
+    app::AppConfig,
+    config::Config,
+    create_app,
+    models::{
+        cell_tower::{self, CellTower},
+        plausibility::Plausibility,
+        position::{Ingested, NewPosition, record_batch},
+    },
+    positions_server::PositionsServerHandle,
+};
 
 impl std::fmt::Display for crate::models::position::Position {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -18,7 +28,7 @@ impl std::fmt::Display for crate::models::position::Position {
 }
 
 pub async fn position_test(
-    pool: &r2d2::Pool<diesel::r2d2::ConnectionManager<diesel::SqliteConnection>>,
+    pool: &r2d2::Pool<diesel::r2d2::ConnectionManager<crate::db::DbConnection>>,
     app_config: &actix_web::web::Data<AppConfig>,
     position_server_handle: &PositionsServerHandle,
 ) {
@@ -87,6 +97,42 @@ pub async fn position_test(
         "there is already a recorded position in the same second"
     );
 
+    // The last fix is kept in the database, so the duplicates are still rejected after a restart
+    let restarted_config = actix_web::web::Data::new(AppConfig::new("0101".to_string(), None));
+    let mut restarted_app =
+        test::init_service(create_app!(pool, &restarted_config, position_server_handle)).await;
+    do_test!(
+        restarted_app,
+        Method::POST,
+        "/api/positions",
+        &format!(
+            r#"[{{"user_id":{},"latitude":45.74846,"longitude":4.84671,"source":"GPS","battery_level":50,"sport_mode":false}}]"#,
+            user_id
+        ),
+        StatusCode::CONFLICT,
+        "there is already a recorded position in the same second"
+    );
+
+    // Get the device status
+    do_test!(
+        app,
+        Method::GET,
+        &format!("/api/status/{}", user_id),
+        "",
+        StatusCode::OK,
+        format!(r#"{{"user_id":{},"last_fix":"#, user_id)
+    );
+    let body = do_test!(app, Method::GET, "/api/status", "", StatusCode::OK, "[");
+    assert!(body.contains(r#""battery_level":50,"source":"GPS","online":true}"#));
+    do_test!(
+        app,
+        Method::GET,
+        &format!("/api/status/{}", user_id + 1),
+        "",
+        StatusCode::NOT_FOUND,
+        "Item not found"
+    );
+
     // Get a position
     do_test!(
         app,
@@ -123,7 +169,8 @@ pub async fn position_test(
             source: "GPS".to_string(),
             battery_level: 50,
             sport_mode: false,
-            time: 0
+            time: 0,
+            flagged: None,
         },
         StatusCode::OK,
         format!(
@@ -206,7 +253,7 @@ pub async fn position_test(
     do_test!(
         app,
         Method::DELETE,
-        "/api/positions",
+        "/api/positions?confirm=positions",
         "",
         StatusCode::OK,
         "Deleted all objects"
@@ -243,9 +290,582 @@ pub async fn position_test(
     do_test!(
         app,
         Method::DELETE,
-        "/api/positions",
+        "/api/positions?confirm=positions",
         "",
         StatusCode::OK,
         "Deleted all objects"
     );
+
+    // The positions of several devices are recorded in a single transaction, each request apart
+    let position = |uid, t| NewPosition {
+        user_id: uid,
+        latitude: 45.74846,
+        longitude: 4.84671,
+        source: "GPS".to_owned(),
+        battery_level: 50,
+        sport_mode: true,
+        time: t,
+        flagged: None,
+    };
+    let t = crate::utils::now();
+    let results = record_batch(
+        &mut pool.get().unwrap(),
+        vec![
+            vec![position(user_id, t)],
+            vec![position(user_id + 1, t)],
+            vec![position(user_id, t + 500)],
+            vec![position(user_id, t + 1000)],
+        ],
+        1000,
+        std::time::Duration::from_secs(24 * 60 * 60),
+        Plausibility::default(),
+    )
+    .unwrap();
+    assert!(matches!(&results[0], Ok(Ingested::Recorded(r)) if r.position.time == t));
+    // an unknown user does not fail the batch
+    assert!(matches!(results[1], Err(diesel::result::Error::NotFound)));
+    // the previous request of the batch is the last fix of the next one
+    assert!(matches!(results[2], Ok(Ingested::Duplicate)));
+    assert!(matches!(&results[3], Ok(Ingested::Recorded(r)) if r.position.time == t + 1000));
+
+    // Concurrent requests are batched by the ingester, and all recorded
+    let responses = futures::future::join_all((1..=20).map(|i| {
+        let req = test::TestRequest::post()
+            .uri("/api/positions")
+            .insert_header(("Authorization", "Bearer 0101"))
+            .set_json(vec![position(user_id, t + 1000 + i * 1000)])
+            .to_request();
+        test::call_service(&app, req)
+    }))
+    .await;
+    assert!(responses.iter().all(|r| r.status() == StatusCode::CREATED));
+    let body = do_test!(
+        app,
+        Method::GET,
+        &format!("/api/positions?user_id={}", user_id),
+        "",
+        StatusCode::OK,
+        "["
+    );
+    assert_eq!(body.matches(r#""sport_mode":true"#).count(), 22);
+
+    // The SQLite connections are tuned for the frequent writes
+    if let crate::db::DbConnection::Sqlite(conn) = &mut *pool.get().unwrap() {
+        use diesel::{RunQueryDsl, sql_types::Text};
+        #[derive(diesel::QueryableByName)]
+        struct JournalMode {
+            #[diesel(sql_type = Text)]
+            journal_mode: String,
+        }
+        let mode = diesel::sql_query("PRAGMA journal_mode")
+            .get_result::<JournalMode>(conn)
+            .unwrap();
+        assert_eq!(mode.journal_mode, "wal");
+    }
+
+    // Query the positions inside an area, over a time range
+    do_test!(
+        app,
+        Method::DELETE,
+        "/api/positions?confirm=positions",
+        "",
+        StatusCode::OK,
+        "Deleted all objects"
+    );
+    let other_id = do_test_extract_id!(
+        app,
+        Method::POST,
+        "/api/users",
+        r#"{"name":"Other","surname":"User"}"#,
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    // near the school at 8:30 and 8:45, far from it at 8:50, and near it again at 10:00
+    let school = (45.75, 4.85);
+    let eight = crate::utils::now() - 3 * 3600 * 1000;
+    let at = |minutes: i64| eight + minutes * 60 * 1000;
+    let id = do_test_extract_id!(
+        app,
+        Method::POST,
+        "/api/positions",
+        &format!(
+            r#"[{{"user_id":{u},"latitude":45.7501,"longitude":4.8501,"source":"GPS","battery_level":50,"sport_mode":false,"time":{}}},{{"user_id":{u},"latitude":45.7502,"longitude":4.8502,"source":"GPS","battery_level":50,"sport_mode":false,"time":{}}},{{"user_id":{u},"latitude":45.9,"longitude":4.85,"source":"GPS","battery_level":50,"sport_mode":false,"time":{}}},{{"user_id":{u},"latitude":45.75,"longitude":4.85,"source":"GPS","battery_level":50,"sport_mode":false,"time":{}}}]"#,
+            at(30),
+            at(45),
+            at(50),
+            at(120),
+            u = user_id
+        ),
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    // the latest position is returned
+    let (first, second) = (id - 3, id - 2);
+    do_test!(
+        app,
+        Method::POST,
+        "/api/positions",
+        &format!(
+            r#"[{{"user_id":{},"latitude":45.7545,"longitude":4.85,"source":"GPS","battery_level":50,"sport_mode":false,"time":{}}}]"#,
+            other_id,
+            at(20)
+        ),
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    let area = |radius: u32, query: &str| {
+        format!(
+            "/api/positions/area?lat={}&lon={}&radius={}&from={}&to={}{}",
+            school.0,
+            school.1,
+            radius,
+            at(0),
+            at(60),
+            query
+        )
+    };
+    // the other user is 500 m away from the school, and the last position is out of the range
+    let body = do_test!(
+        app,
+        Method::GET,
+        &area(200, ""),
+        "",
+        StatusCode::OK,
+        format!(r#"[{{"id":{},"user_id":{}"#, second, user_id)
+    );
+    assert_eq!(body.matches(r#""id":"#).count(), 2);
+    let body = do_test!(
+        app,
+        Method::GET,
+        &area(200, "&latest=true"),
+        "",
+        StatusCode::OK,
+        format!(r#"[{{"id":{},"user_id":{}"#, second, user_id)
+    );
+    assert_eq!(body.matches(r#""id":"#).count(), 1);
+    let body = do_test!(app, Method::GET, &area(1000, ""), "", StatusCode::OK, "[");
+    assert_eq!(body.matches(r#""id":"#).count(), 3);
+    let body = do_test!(
+        app,
+        Method::GET,
+        &area(1000, &format!("&user_id={}", other_id)),
+        "",
+        StatusCode::OK,
+        format!(r#"[{{"id":{},"user_id":{}"#, id + 1, other_id)
+    );
+    assert_eq!(body.matches(r#""id":"#).count(), 1);
+    let body = do_test!(
+        app,
+        Method::GET,
+        "/api/positions/area?min_lat=45.7&min_lon=4.8&max_lat=46&max_lon=4.9",
+        "",
+        StatusCode::OK,
+        format!(r#"[{{"id":{},"user_id":{}"#, id, user_id)
+    );
+    assert_eq!(body.matches(r#""id":"#).count(), 5);
+    // they come a page at a time
+    let body = do_test!(
+        app,
+        Method::GET,
+        &area(1000, "&limit=2"),
+        "",
+        StatusCode::OK,
+        format!(r#"[{{"id":{},"user_id":{}"#, second, user_id)
+    );
+    assert!(body.contains(&format!(r#"{{"id":{},"user_id":{}"#, first, user_id)));
+    assert_eq!(body.matches(r#""id":"#).count(), 2);
+    let body = do_test!(
+        app,
+        Method::GET,
+        &format!(
+            "/api/positions/area?lat={}&lon={}&radius=1000&from={}&to={}&before_id={}&limit=2",
+            school.0,
+            school.1,
+            at(0),
+            at(30),
+            first
+        ),
+        "",
+        StatusCode::OK,
+        format!(r#"[{{"id":{},"user_id":{}"#, id + 1, other_id)
+    );
+    assert_eq!(body.matches(r#""id":"#).count(), 1);
+    do_test!(
+        app,
+        Method::GET,
+        "/api/positions/area?min_lat=46&min_lon=4.8&max_lat=45.7&max_lon=4.9",
+        "",
+        StatusCode::BAD_REQUEST,
+        "min_lat and max_lat must be ordered latitudes"
+    );
+    do_test!(
+        app,
+        Method::GET,
+        "/api/positions/area?lat=45.75&lon=4.85",
+        "",
+        StatusCode::BAD_REQUEST,
+        "the area must be given"
+    );
+    // The spatial index follows the updates and the deletions of the positions
+    do_test!(
+        app,
+        Method::PUT,
+        &format!("/api/positions/{}", first),
+        &format!(
+            r#"{{"id":{},"user_id":{},"latitude":48.85,"longitude":2.35,"source":"GPS","battery_level":50,"sport_mode":false,"time":{}}}"#,
+            first,
+            user_id,
+            at(30)
+        ),
+        StatusCode::OK,
+        "{\"id\""
+    );
+    do_test!(
+        app,
+        Method::DELETE,
+        &format!("/api/positions/{}", second),
+        "",
+        StatusCode::OK,
+        "Deleted object"
+    );
+    do_test!(app, Method::GET, &area(200, ""), "", StatusCode::OK, "[]");
+    do_test!(
+        app,
+        Method::GET,
+        "/api/positions/area?lat=48.85&lon=2.35&radius=10",
+        "",
+        StatusCode::OK,
+        format!(r#"[{{"id":{},"user_id":{}"#, first, user_id)
+    );
+
+    // The implausible positions are flagged, and compared to the last plausible one
+    let t = crate::utils::now();
+    let post = |latitude: f64, longitude: f64, time: i64| {
+        format!(
+            r#"[{{"user_id":{},"latitude":{},"longitude":{},"source":"Cell Id (LTE)","battery_level":50,"sport_mode":false,"time":{}}}]"#,
+            user_id, latitude, longitude, time
+        )
+    };
+    let plausible_id = do_test_extract_id!(
+        app,
+        Method::POST,
+        "/api/positions",
+        &post(45.75, 4.85, t + 60_000),
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    let (ws_tx, mut ws_rx) = tokio::sync::mpsc::unbounded_channel();
+    let conn_id = position_server_handle
+        .connect(ws_tx, user_id.try_into().unwrap(), true)
+        .await;
+    let body = do_test!(
+        app,
+        Method::POST,
+        "/api/positions",
+        &post(0.0, 0.0, t + 120_000),
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    assert!(body.ends_with(r#""flagged":"null island coordinates"}"#));
+    let body = do_test!(
+        app,
+        Method::POST,
+        "/api/positions",
+        &post(48.85, 2.35, t + 180_000),
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    assert!(body.contains(r#""flagged":"implausible speed of "#));
+    // neither broadcast nor taken as the last fix of the device
+    assert!(ws_rx.try_recv().is_err());
+    do_test!(
+        app,
+        Method::GET,
+        &format!("/api/status/{}", user_id),
+        "",
+        StatusCode::OK,
+        format!(r#"{{"user_id":{},"last_fix":{},"#, user_id, t + 60_000)
+    );
+    // and left out of the areas
+    let body = do_test!(
+        app,
+        Method::GET,
+        "/api/positions/area?lat=48.85&lon=2.35&radius=10",
+        "",
+        StatusCode::OK,
+        format!(r#"[{{"id":{},"user_id":{}"#, first, user_id)
+    );
+    assert_eq!(body.matches(r#""id":"#).count(), 1);
+    // the last plausible position is the latest one
+    let body = do_test!(
+        app,
+        Method::GET,
+        "/api/positions/latest",
+        "",
+        StatusCode::OK,
+        "["
+    );
+    assert!(body.contains(&format!(
+        r#"{{"id":{},"user_id":{},"#,
+        plausible_id, user_id
+    )));
+    assert!(!body.contains("flagged"));
+    let body = do_test!(
+        app,
+        Method::POST,
+        "/api/positions",
+        &post(45.76, 4.85, t + 240_000),
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    assert!(!body.contains("flagged"));
+    assert_eq!(ws_rx.try_recv().unwrap(), body);
+    position_server_handle.disconnect(conn_id);
+
+    // They can be rejected instead
+    let reject_config = actix_web::web::Data::new(
+        AppConfig::new("0101".to_string(), None).with_config(Config {
+            plausibility: "reject".to_owned(),
+            ..Default::default()
+        }),
+    );
+    let mut reject_app =
+        test::init_service(create_app!(pool, &reject_config, position_server_handle)).await;
+    do_test!(
+        reject_app,
+        Method::POST,
+        "/api/positions",
+        &post(91.0, 4.85, t + 300_000),
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "coordinates out of range"
+    );
+    // along with the plausible positions
+    do_test!(
+        reject_app,
+        Method::POST,
+        "/api/positions",
+        &format!(
+            "[{},{}]",
+            &post(0.0, 0.0, t + 300_000)[1..].trim_end_matches(']'),
+            &post(45.77, 4.85, t + 360_000)[1..].trim_end_matches(']')
+        ),
+        StatusCode::CREATED,
+        format!(
+            r#"{{"id":{},"user_id":{},"latitude":45.77,"#,
+            plausible_id + 4,
+            user_id
+        )
+    );
+
+    // The CDMA cells are located by their coordinates, the others by the cell towers or Open Cell ID
+    let cell_user_id = do_test_extract_id!(
+        app,
+        Method::POST,
+        "/api/users",
+        r#"{"name":"Cell","surname":"User"}"#,
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    let cell = |network_type: &str, lat: i64, long: i64| {
+        format!(
+            r#"{{"network_type":"{}","mcc":"208","mnc":"01","cid":1234,"lac":56,"lat":{},"long":{},"battery_level":40}}"#,
+            network_type, lat, long
+        )
+    };
+    let body = do_test!(
+        app,
+        Method::POST,
+        &format!("/api/positions/cid/{}", cell_user_id),
+        &cell("CDMA", 658_857, 69_810),
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    assert!(body.contains(&format!(
+        r#""user_id":{},"latitude":45.7539583"#,
+        cell_user_id
+    )));
+    do_test!(
+        app,
+        Method::POST,
+        &format!("/api/positions/cid/{}", cell_user_id),
+        &cell("CDMA", 2_147_483_647, 2_147_483_647),
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "CDMA base station coordinates are unavailable"
+    );
+    do_test!(
+        app,
+        Method::POST,
+        &format!("/api/positions/cid/{}", cell_user_id),
+        &cell("LTE", -1, -1),
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "LTE cell not in the cell towers, and there is no Open Cell ID API key to locate it"
+    );
+    do_test!(
+        app,
+        Method::POST,
+        &format!("/api/positions/cid/{}", cell_user_id),
+        &cell("LTE", -1, -1).replace(r#""mcc":"208""#, r#""mcc":"no_mcc""#),
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "mcc must be three digits, got no_mcc"
+    );
+    do_test!(
+        app,
+        Method::POST,
+        &format!("/api/positions/cid/{}", cell_user_id),
+        &cell("no_type", -1, -1),
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "unknown network type: no_type"
+    );
+
+    // The cells are found in the imported dumps, and the expired Open Cell ID locations are used
+    // as a last resort
+    let mut conn = pool.get().unwrap();
+    let dump = "radio,mcc,net,area,cell,unit,lon,lat,range,samples,changeable,created,updated,averageSignal
+LTE,208,1,56,2345,0,4.8357,45.764,1200,42,1,1459814400,1700000000,0
+GSM,208,1,56,not_a_cell,0,4.8357,45.764,1200,42,1,1459814400,1700000000,0
+GSM,310,260,56,1234,0,-122.4194,37.7749,800,12,1,1459814400,1700000000,0
+";
+    assert_eq!(
+        cell_tower::import(&mut conn, dump.as_bytes(), &[208]).unwrap(),
+        (1, 2)
+    );
+    let expired = CellTower {
+        mcc: 208,
+        mnc: 1,
+        lac: 56,
+        cid: 5678,
+        radio: "LTE".to_owned(),
+        latitude: 45.75,
+        longitude: 4.85,
+        radius: 1000,
+        expires_at: Some(1),
+    };
+    assert_eq!(cell_tower::upsert(&mut conn, &[expired]).unwrap(), 1);
+    // one user per cell, as a user can't have two positions in the same second
+    let imported_user_id = do_test_extract_id!(
+        app,
+        Method::POST,
+        "/api/users",
+        r#"{"name":"Imported","surname":"Tower"}"#,
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    let expired_user_id = do_test_extract_id!(
+        app,
+        Method::POST,
+        "/api/users",
+        r#"{"name":"Expired","surname":"Tower"}"#,
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    for (tower_user_id, cid, latitude) in [
+        (imported_user_id, 2345, "45.764"),
+        (expired_user_id, 5678, "45.75"),
+    ] {
+        let body = do_test!(
+            app,
+            Method::POST,
+            &format!("/api/positions/cid/{}", tower_user_id),
+            &cell("LTE", -1, -1).replace("1234", &cid.to_string()),
+            StatusCode::CREATED,
+            "{\"id\""
+        );
+        assert!(body.contains(&format!(
+            r#""user_id":{},"latitude":{},"#,
+            tower_user_id, latitude
+        )));
+    }
+    do_test!(
+        app,
+        Method::POST,
+        &format!("/api/positions/cid/{}", cell_user_id),
+        &cell("GSM", -1, -1).replace("1234", "4321"),
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "GSM cell not in the cell towers"
+    );
+
+    // The identities are checked against the limits of each network type, the unavailable ones
+    // being reported as i32::MAX
+    let identity = |network_type: &str, cid: i64, lac: i64| {
+        format!(
+            r#"{{"network_type":"{}","mcc":"208","mnc":"01","cid":{},"lac":{},"lat":-1,"long":-1,"battery_level":40}}"#,
+            network_type, cid, lac
+        )
+    };
+    for (network_type, max_cid, max_lac) in [
+        ("GSM", 65_535, 65_535),
+        ("WCDMA", 268_435_455, 65_535),
+        ("TDSCDMA", 268_435_455, 65_535),
+        ("NR", 68_719_476_735, 16_777_215),
+    ] {
+        let tower = CellTower {
+            mcc: 208,
+            mnc: 1,
+            lac: max_lac as i32,
+            cid: max_cid,
+            radio: network_type.to_owned(),
+            latitude: 45.76,
+            longitude: 4.84,
+            radius: 1000,
+            expires_at: None,
+        };
+        assert_eq!(cell_tower::upsert(&mut conn, &[tower]).unwrap(), 1);
+        let body = do_test!(
+            app,
+            Method::POST,
+            "/api/users",
+            &format!(r#"{{"name":"{}","surname":"Cell"}}"#, network_type),
+            StatusCode::CREATED,
+            "{\"id\""
+        );
+        // one user per network type, as a user can't have two positions in the same second
+        let network_user_id =
+            serde_json::from_str::<serde_json::Value>(&body).unwrap()["id"].clone();
+        let uri = format!("/api/positions/cid/{}", network_user_id);
+        let body = do_test!(
+            app,
+            Method::POST,
+            &uri,
+            &identity(network_type, max_cid, max_lac),
+            StatusCode::CREATED,
+            "{\"id\""
+        );
+        assert!(body.contains(r#""latitude":45.76,"#));
+        do_test!(
+            app,
+            Method::POST,
+            &uri,
+            &identity(network_type, max_cid + 1, max_lac),
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!(
+                "cid must be between 0 and {} on {}, got {}",
+                max_cid,
+                network_type,
+                max_cid + 1
+            )
+        );
+        do_test!(
+            app,
+            Method::POST,
+            &uri,
+            &identity(network_type, max_cid, max_lac + 1),
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!(
+                "lac must be between 0 and {} on {}, got {}",
+                max_lac,
+                network_type,
+                max_lac + 1
+            )
+        );
+        for (cid, lac) in [(2_147_483_647, 0), (0, 2_147_483_647)] {
+            do_test!(
+                app,
+                Method::POST,
+                &uri,
+                &identity(network_type, cid, lac),
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("the cell identity is unavailable on {}", network_type)
+            );
+        }
+    }
 }
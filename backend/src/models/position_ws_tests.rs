@@ -2,12 +2,13 @@ use std::{ops::Add, time::Duration};
 
 use crate::{
     app::AppConfig,
+    config::Config,
     create_app,
     models::{
         position::Position,
         user::{NewUser, User},
     },
-    positions_handler::CLIENT_TIMEOUT,
+    positions_handler::client_timeout,
     positions_server::PositionsServerHandle,
 };
 use actix_web::web::Bytes;
@@ -33,18 +34,23 @@ macro_rules! next_text_message {
 }
 
 pub async fn position_ws_test(
-    pool: &r2d2::Pool<diesel::r2d2::ConnectionManager<diesel::SqliteConnection>>,
+    pool: &r2d2::Pool<diesel::r2d2::ConnectionManager<crate::db::DbConnection>>,
     app_config: &actix_web::web::Data<AppConfig>,
     position_server_handle: &PositionsServerHandle,
 ) {
+    // Ping the clients often, so that they time out quickly
+    let config = Config {
+        heartbeat_interval: 3,
+        ..Default::default()
+    };
+    let client_timeout = client_timeout(config.heartbeat_interval());
+    let app_config = actix_web::web::Data::new(
+        AppConfig::new(app_config.bearer_token.clone(), None).with_config(config),
+    );
     let pool = pool.clone();
-    let app_config = app_config.clone();
     let position_server_handle = position_server_handle.clone();
     let app = actix_test::start(move || create_app!(&pool, &app_config, &position_server_handle));
 
-    // TODO: Audit that the environment access only happens in single-threaded code.
-    unsafe { std::env::set_var("HEARTBEAT_INTERVAL", "3") };
-
     // Create an user
     let user_id = create_user(&app).await;
 
@@ -107,7 +113,7 @@ pub async fn position_ws_test(
         .unwrap();
     // Create a position
     app.post("/api/positions").bearer_auth("0101").content_type("application/json").send_body(format!(
-        r#"[{{"user_id":{},"latitude":45.12345,"longitude":4.84671,"source":"GPS","battery_level":50,"sport_mode":false}}]"#,
+        r#"[{{"user_id":{},"latitude":45.74812345,"longitude":4.84671,"source":"GPS","battery_level":50,"sport_mode":false}}]"#,
         user_id
     )).await.unwrap().json::<Position>().await.unwrap();
 
@@ -116,13 +122,13 @@ pub async fn position_ws_test(
 
     // Check that both connexions get the new position
     let response = next_text_message!(connection);
-    assert!(response.contains("12345"));
+    assert!(response.contains("45.74812345"));
 
     let response = next_text_message!(connection2);
-    assert!(response.contains("12345"));
+    assert!(response.contains("45.74812345"));
 
     // Wait for connexions timeout
-    sleep(CLIENT_TIMEOUT.add(Duration::from_secs(2))).await;
+    sleep(client_timeout.add(Duration::from_secs(2))).await;
 
     // Check that connexions were closed
     loop {
@@ -134,7 +140,7 @@ pub async fn position_ws_test(
     // Check that no clients are connected anymore
     test_connected(&app, 0).await;
 
-    app.delete("/api/positions")
+    app.delete("/api/positions?confirm=positions")
         .bearer_auth("0101")
         .send()
         .await
@@ -178,6 +184,7 @@ async fn create_user(app: &actix_test::TestServer) -> i32 {
         .send_json(&NewUser {
             name: "user".to_owned(),
             surname: "user".to_owned(),
+            ..Default::default()
         })
         .await
         .unwrap()
@@ -0,0 +1,444 @@
+use std::collections::HashMap;
+
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, get, put, web};
+use chrono_tz::Tz;
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    app::Credential,
+    db::DbConnection,
+    errors::ServerError,
+    models::{audit::NewAuditEntry, position::Position, sharing_rule::SharingRule, user::User},
+    schema::{privacy_settings, sharing_rules, users},
+    utils::now,
+};
+
+type DbPool = r2d2::Pool<ConnectionManager<DbConnection>>;
+
+const KM_PER_DEGREE: f64 = 111.32;
+
+// How precisely a user shares its location with the others
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Precision {
+    #[default]
+    Exact,
+    // snapped to a 1 km grid
+    Km,
+    // snapped to a 10 km grid
+    City,
+}
+
+impl Precision {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Precision::Exact => "exact",
+            Precision::Km => "km",
+            Precision::City => "city",
+        }
+    }
+
+    fn cell_km(&self) -> Option<f64> {
+        match self {
+            Precision::Exact => None,
+            Precision::Km => Some(1.0),
+            Precision::City => Some(10.0),
+        }
+    }
+}
+
+impl std::str::FromStr for Precision {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exact" => Ok(Precision::Exact),
+            "km" => Ok(Precision::Km),
+            "city" => Ok(Precision::City),
+            _ => Err("unknown precision"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, Insertable, AsChangeset)]
+#[diesel(table_name = privacy_settings, treat_none_as_null = true)]
+pub struct PrivacySettings {
+    pub user_id: i32,
+    pub precision: String,
+    // positions recorded between these times are not shared
+    pub paused_since: Option<i64>,
+    pub paused_until: Option<i64>,
+}
+
+impl PrivacySettings {
+    fn default_for(user_id: i32) -> Self {
+        PrivacySettings {
+            user_id,
+            precision: Precision::Exact.as_str().to_owned(),
+            paused_since: None,
+            paused_until: None,
+        }
+    }
+
+    pub fn precision(&self) -> Precision {
+        self.precision.parse().unwrap_or_default()
+    }
+
+    pub fn is_paused(&self, now: i64) -> bool {
+        self.paused_until.is_some_and(|until| now < until)
+    }
+
+    // While paused nothing is shared, afterwards the positions recorded during the pause stay hidden
+    fn hides(&self, time: i64, now: i64) -> bool {
+        match (self.paused_since, self.paused_until) {
+            (Some(since), Some(until)) => now < until || (since <= time && time <= until),
+            _ => false,
+        }
+    }
+}
+
+// Everything deciding how a user's positions are shared with the others
+#[derive(Debug, Clone)]
+pub struct Policy {
+    pub settings: Option<PrivacySettings>,
+    pub rules: Vec<SharingRule>,
+    pub timezone: Tz,
+}
+
+// A position as served to a viewer, or only the reason why it is hidden
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum Served {
+    Position(Position),
+    Hidden { user_id: i32, status: &'static str },
+}
+
+impl Served {
+    pub fn position(self) -> Option<Position> {
+        match self {
+            Served::Position(position) => Some(position),
+            Served::Hidden { .. } => None,
+        }
+    }
+}
+
+impl Policy {
+    // Size of the grid the shared positions are snapped to, in km, if they are blurred
+    pub fn blur_km(&self) -> Option<f64> {
+        self.settings
+            .as_ref()
+            .and_then(|settings| settings.precision().cell_km())
+    }
+
+    // Get a position as shared with the others
+    pub fn apply(&self, mut position: Position, now: i64) -> Served {
+        let hidden = |status| Served::Hidden {
+            user_id: position.user_id,
+            status,
+        };
+        if let Some(settings) = &self.settings
+            && settings.hides(position.time, now)
+        {
+            return hidden("sharing paused");
+        }
+        for rule in &self.rules {
+            if let Some(status) = rule.hides(&position, now, self.timezone) {
+                return hidden(status);
+            }
+        }
+        if let Some(cell_km) = self.blur_km() {
+            (position.latitude, position.longitude) =
+                snap(position.latitude, position.longitude, cell_km);
+        }
+        Served::Position(position)
+    }
+}
+
+// Snap coordinates to the center of a grid cell of the given size
+fn snap(latitude: f64, longitude: f64, cell_km: f64) -> (f64, f64) {
+    let lat_step = cell_km / KM_PER_DEGREE;
+    let lat = (((latitude / lat_step).floor() + 0.5) * lat_step).clamp(-90.0, 90.0);
+    // the meridians get closer towards the poles
+    let lon_step = lat_step / lat.to_radians().cos().max(0.01);
+    let lon = ((longitude / lon_step).floor() + 0.5) * lon_step;
+    (lat, (lon + 180.0).rem_euclid(360.0) - 180.0)
+}
+
+fn load_settings(conn: &mut DbConnection, uid: i32) -> QueryResult<Option<PrivacySettings>> {
+    privacy_settings::table
+        .find(uid)
+        .first::<PrivacySettings>(conn)
+        .optional()
+}
+
+fn parse_timezone(timezone: Option<String>) -> Tz {
+    timezone.and_then(|t| t.parse().ok()).unwrap_or(Tz::UTC)
+}
+
+pub fn load(conn: &mut DbConnection, uid: i32) -> QueryResult<Policy> {
+    let timezone = users::table
+        .find(uid)
+        .select(users::timezone)
+        .first::<Option<String>>(conn)
+        .optional()?
+        .flatten();
+    Ok(Policy {
+        settings: load_settings(conn, uid)?,
+        rules: sharing_rules::table
+            .filter(sharing_rules::user_id.eq(uid))
+            .load::<SharingRule>(conn)?,
+        timezone: parse_timezone(timezone),
+    })
+}
+
+pub fn load_all(conn: &mut DbConnection) -> QueryResult<HashMap<i32, Policy>> {
+    let mut policies: HashMap<i32, Policy> = users::table
+        .select((users::id, users::timezone))
+        .load::<(i32, Option<String>)>(conn)?
+        .into_iter()
+        .map(|(uid, timezone)| {
+            let policy = Policy {
+                settings: None,
+                rules: Vec::new(),
+                timezone: parse_timezone(timezone),
+            };
+            (uid, policy)
+        })
+        .collect();
+    for settings in privacy_settings::table.load::<PrivacySettings>(conn)? {
+        if let Some(policy) = policies.get_mut(&settings.user_id) {
+            policy.settings = Some(settings);
+        }
+    }
+    for rule in sharing_rules::table.load::<SharingRule>(conn)? {
+        if let Some(policy) = policies.get_mut(&rule.user_id) {
+            policy.rules.push(rule);
+        }
+    }
+    Ok(policies)
+}
+
+// Get a position as served to a credential : its owner and the admin sessions get the exact data.
+// The main token is shared by the web client whoever uses it, so the privacy settings apply to it
+// as to any other viewer.
+pub fn serve(
+    credential: Option<&Credential>,
+    policy: Option<&Policy>,
+    position: Position,
+    now: i64,
+) -> Served {
+    if credential.is_some_and(|c| !matches!(c, Credential::Main) && c.owns(position.user_id)) {
+        return Served::Position(position);
+    }
+    match policy {
+        Some(policy) => policy.apply(position, now),
+        None => Served::Position(position),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PrivacyUpdate {
+    #[serde(default)]
+    pub precision: Precision,
+    pub paused_until: Option<i64>,
+}
+
+// Get a forbidden response if the request does not come from the user or an admin
+pub(crate) fn check_owner(req: &HttpRequest, uid: i32) -> Option<HttpResponse> {
+    match req.extensions().get::<Credential>() {
+        Some(c) if c.owns(uid) => None,
+        _ => Some(HttpResponse::Forbidden().body("only the user or an admin can do that")),
+    }
+}
+
+#[get("/{uid}")]
+pub async fn read(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    uid: web::Path<i32>,
+) -> Result<HttpResponse, ServerError> {
+    let uid = *uid;
+    if let Some(resp) = check_owner(&req, uid) {
+        return Ok(resp);
+    }
+    let mut conn = pool.get()?;
+    let settings = web::block(move || -> QueryResult<PrivacySettings> {
+        crate::schema::users::table
+            .find(uid)
+            .first::<User>(&mut conn)?;
+        Ok(load_settings(&mut conn, uid)?.unwrap_or_else(|| PrivacySettings::default_for(uid)))
+    })
+    .await??;
+    Ok(HttpResponse::Ok().json(settings))
+}
+
+#[put("/{uid}")]
+pub async fn update(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    uid: web::Path<i32>,
+    o: web::Json<PrivacyUpdate>,
+) -> Result<HttpResponse, ServerError> {
+    let uid = *uid;
+    if let Some(resp) = check_owner(&req, uid) {
+        return Ok(resp);
+    }
+    let audit = NewAuditEntry::new(&req, "privacy_settings.update");
+    let mut conn = pool.get()?;
+    let settings = web::block(move || -> Result<PrivacySettings, ServerError> {
+        crate::schema::users::table
+            .find(uid)
+            .first::<User>(&mut conn)?;
+        let previous =
+            load_settings(&mut conn, uid)?.unwrap_or_else(|| PrivacySettings::default_for(uid));
+        let now = now();
+        let (paused_since, paused_until) = match (previous.is_paused(now), o.paused_until) {
+            // extend or shorten the current pause
+            (true, Some(until)) => (previous.paused_since, Some(until.max(now))),
+            // resume now, still hiding what was recorded during the pause
+            (true, None) => (previous.paused_since, Some(now)),
+            (false, Some(until)) if until > now => (Some(now), Some(until)),
+            // keep the last pause window
+            (false, _) => (previous.paused_since, previous.paused_until),
+        };
+        let settings = PrivacySettings {
+            user_id: uid,
+            precision: o.precision.as_str().to_owned(),
+            paused_since,
+            paused_until,
+        };
+        crate::replace_into!(
+            conn,
+            privacy_settings::table,
+            privacy_settings::user_id.eq(uid),
+            &settings
+        )?;
+        audit
+            .target(format!("users/{}", uid))
+            .details(&settings)
+            .insert(&mut conn)?;
+        Ok(settings)
+    })
+    .await??;
+    Ok(HttpResponse::Ok().json(settings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(time: i64) -> Position {
+        Position {
+            id: 1,
+            user_id: 1,
+            latitude: 45.74846,
+            longitude: 4.84671,
+            source: "GPS".to_owned(),
+            battery_level: 50,
+            sport_mode: false,
+            time,
+            flagged: None,
+        }
+    }
+
+    #[test]
+    fn test_snap() {
+        let (lat, lon) = snap(45.74846, 4.84671, 1.0);
+        assert!((lat - 45.74846).abs() < 1.0 / KM_PER_DEGREE);
+        assert!((lon - 4.84671).abs() < 1.0 / KM_PER_DEGREE / 45f64.to_radians().cos());
+        // neighbouring points fall in the same cell
+        assert_eq!(snap(45.74846, 4.84671, 10.0), snap(45.74900, 4.84700, 10.0));
+        // the cells are centered
+        let (lat, _) = snap(0.0001, 0.0001, 1.0);
+        assert!((lat - 0.5 / KM_PER_DEGREE).abs() < 1e-9);
+        // longitudes wrap around
+        let (_, lon) = snap(0.0, 179.999, 10.0);
+        assert!((-180.0..=180.0).contains(&lon));
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut policy = Policy {
+            settings: Some(PrivacySettings::default_for(1)),
+            rules: Vec::new(),
+            timezone: Tz::UTC,
+        };
+        let apply = |policy: &Policy, time, now| policy.apply(position(time), now).position();
+        assert_eq!(apply(&policy, 100, 1000).unwrap().latitude, 45.74846);
+
+        let settings = policy.settings.as_mut().unwrap();
+        settings.precision = "city".to_owned();
+        let fuzzed = apply(&policy, 100, 1000).unwrap();
+        assert_ne!(fuzzed.latitude, 45.74846);
+        assert_ne!(fuzzed.longitude, 4.84671);
+
+        // During a pause nothing is shared, afterwards only the pause window is hidden
+        let settings = policy.settings.as_mut().unwrap();
+        settings.paused_since = Some(200);
+        settings.paused_until = Some(300);
+        assert!(apply(&policy, 100, 250).is_none());
+        assert!(apply(&policy, 100, 1000).is_some());
+        assert!(apply(&policy, 250, 1000).is_none());
+        assert!(apply(&policy, 350, 1000).is_some());
+
+        // Hidden positions are served as a status
+        assert_eq!(
+            serde_json::to_string(&policy.apply(position(250), 1000)).unwrap(),
+            r#"{"user_id":1,"status":"sharing paused"}"#
+        );
+        policy.rules.push(SharingRule {
+            id: 1,
+            user_id: 1,
+            name: "home".to_owned(),
+            starts_at: None,
+            ends_at: None,
+            latitude: Some(45.74846),
+            longitude: Some(4.84671),
+            radius: Some(100.0),
+        });
+        assert_eq!(
+            serde_json::to_string(&policy.apply(position(350), 1000)).unwrap(),
+            r#"{"user_id":1,"status":"hidden by zone"}"#
+        );
+
+        // The owner and the admin sessions still get the exact data, not the main token
+        let owner = Credential::Session {
+            user_id: Some(1),
+            role: crate::app::Role::Viewer,
+        };
+        let other = Credential::Share(2);
+        assert_eq!(
+            serve(Some(&owner), Some(&policy), position(250), 1000)
+                .position()
+                .unwrap()
+                .latitude,
+            45.74846
+        );
+        let admin = Credential::Session {
+            user_id: None,
+            role: crate::app::Role::Admin,
+        };
+        assert!(
+            serve(Some(&admin), Some(&policy), position(250), 1000)
+                .position()
+                .is_some()
+        );
+        assert!(
+            serve(Some(&Credential::Main), Some(&policy), position(250), 1000)
+                .position()
+                .is_none()
+        );
+        assert!(
+            serve(Some(&other), Some(&policy), position(250), 1000)
+                .position()
+                .is_none()
+        );
+        assert!(
+            serve(Some(&other), None, position(250), 1000)
+                .position()
+                .is_some()
+        );
+    }
+}
@@ -0,0 +1,440 @@
+use crate::{
+    app::{AppConfig, Role},
+    create_app,
+    models::sharing_rule::SharingRule,
+    positions_server::PositionsServerHandle,
+    token::{self, Claims},
+    utils::now,
+};
+
+pub async fn privacy_test(
+    pool: &r2d2::Pool<diesel::r2d2::ConnectionManager<crate::db::DbConnection>>,
+    app_config: &actix_web::web::Data<AppConfig>,
+    position_server_handle: &PositionsServerHandle,
+) {
+    use crate::{do_test, do_test_extract_id};
+    use actix_web::{
+        http::{Method, StatusCode},
+        test,
+    };
+
+    let mut app = test::init_service(create_app!(pool, app_config, position_server_handle)).await;
+
+    // Send a request with a given token
+    macro_rules! call_with {
+        ($token:expr, $method:expr, $uri:expr, $payload:expr, $expected_status_code:expr) => {{
+            let req = test::TestRequest::default()
+                .method($method)
+                .insert_header(("Authorization", format!("Bearer {}", $token)))
+                .insert_header(("Content-Type", "application/json"))
+                .uri(&$uri)
+                .set_payload($payload)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), $expected_status_code);
+            let body = test::read_body(resp).await;
+            std::str::from_utf8(&body).unwrap().to_string()
+        }};
+    }
+
+    // Create Dave with a position, and Erin in the same group
+    let dave = do_test_extract_id!(
+        app,
+        Method::POST,
+        "/api/users",
+        r#"{"name":"Dave","surname":"Private"}"#,
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    let erin = do_test_extract_id!(
+        app,
+        Method::POST,
+        "/api/users",
+        r#"{"name":"Erin","surname":"Private"}"#,
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    let group = do_test_extract_id!(
+        app,
+        Method::POST,
+        "/api/groups",
+        r#"{"name":"Neighbours"}"#,
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    do_test!(
+        app,
+        Method::POST,
+        &format!("/api/groups/{}/members/{}", group, dave),
+        "",
+        StatusCode::CREATED,
+        ""
+    );
+    do_test!(
+        app,
+        Method::POST,
+        &format!("/api/groups/{}/members/{}", group, erin),
+        "",
+        StatusCode::CREATED,
+        ""
+    );
+    let position = do_test_extract_id!(
+        app,
+        Method::POST,
+        "/api/positions",
+        &format!(
+            r#"[{{"user_id":{},"latitude":45.74846,"longitude":4.84671,"source":"GPS","battery_level":50,"sport_mode":false,"time":{}}}]"#,
+            dave,
+            now() - 60000
+        ),
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+
+    let session = |user_id| {
+        token::seal(
+            &app_config.share_keys,
+            &Claims::Session {
+                expires_at: u64::MAX / 2,
+                user_id: Some(user_id),
+                role: Role::Viewer,
+            },
+        )
+        .unwrap()
+    };
+    let dave_session = session(dave);
+    let erin_session = session(erin);
+    // A share token for Dave is how Dave's positions are shared with anyone
+    let share_token = do_test!(
+        app,
+        Method::GET,
+        &format!("/api/token?user_id={}", dave),
+        "",
+        StatusCode::OK,
+        ""
+    );
+    let settings_uri = format!("/api/privacy/{}", dave);
+    let dave_positions_uri = format!("/api/positions?user_id={}", dave);
+
+    // The settings default to exact positions, and only their owner can change them
+    call_with!(dave_session, Method::GET, settings_uri, "", StatusCode::OK);
+    let body = call_with!(
+        erin_session,
+        Method::PUT,
+        settings_uri,
+        r#"{"precision":"city"}"#,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(body, "only the user or an admin can do that");
+    call_with!(
+        share_token,
+        Method::PUT,
+        settings_uri,
+        r#"{"precision":"city"}"#,
+        StatusCode::FORBIDDEN
+    );
+    call_with!(
+        dave_session,
+        Method::PUT,
+        settings_uri,
+        r#"{"precision":"unknown"}"#,
+        StatusCode::CONFLICT
+    );
+    let body = call_with!(
+        dave_session,
+        Method::PUT,
+        settings_uri,
+        r#"{"precision":"km"}"#,
+        StatusCode::OK
+    );
+    assert!(body.contains(r#""precision":"km""#));
+
+    // The others get fuzzed positions, the main token of the web client too, and the owner the
+    // exact ones
+    for token in [&erin_session, &share_token, &"0101".to_owned()] {
+        let body = call_with!(token, Method::GET, dave_positions_uri, "", StatusCode::OK);
+        assert!(body.contains("\"latitude\":45.7") && !body.contains("45.74846"));
+        let body = call_with!(
+            token,
+            Method::GET,
+            "/api/positions/latest",
+            "",
+            StatusCode::OK
+        );
+        assert!(body.contains(&format!("\"user_id\":{}", dave)) && !body.contains("45.74846"));
+    }
+    let body = call_with!(
+        dave_session,
+        Method::GET,
+        dave_positions_uri,
+        "",
+        StatusCode::OK
+    );
+    assert!(body.contains("45.74846"));
+
+    // The areas match the fuzzed positions, never the exact ones
+    let body = call_with!(
+        erin_session,
+        Method::GET,
+        dave_positions_uri,
+        "",
+        StatusCode::OK
+    );
+    let served: serde_json::Value = serde_json::from_str(&body).unwrap();
+    let (lat, lon) = (
+        served[0]["latitude"].as_f64().unwrap(),
+        served[0]["longitude"].as_f64().unwrap(),
+    );
+    let area_uri = |lat: f64, lon: f64| {
+        format!(
+            "/api/positions/area?lat={}&lon={}&radius=50&user_id={}",
+            lat, lon, dave
+        )
+    };
+    let body = call_with!(
+        erin_session,
+        Method::GET,
+        area_uri(lat, lon),
+        "",
+        StatusCode::OK
+    );
+    assert!(body.contains(&format!(r#""id":{}"#, position)) && !body.contains("45.74846"));
+    let body = call_with!(
+        erin_session,
+        Method::GET,
+        area_uri(45.74846, 4.84671),
+        "",
+        StatusCode::OK
+    );
+    assert_eq!(body, "[]");
+    let body = call_with!(
+        dave_session,
+        Method::GET,
+        area_uri(45.74846, 4.84671),
+        "",
+        StatusCode::OK
+    );
+    assert!(body.contains("45.74846"));
+    // and a share token only gets its own user, whatever the query
+    do_test!(
+        app,
+        Method::POST,
+        "/api/positions",
+        &format!(
+            r#"[{{"user_id":{},"latitude":{},"longitude":{},"source":"GPS","battery_level":50,"sport_mode":false,"time":{}}}]"#,
+            erin,
+            lat,
+            lon,
+            now() - 60000
+        ),
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    let body = call_with!(
+        share_token,
+        Method::GET,
+        format!("/api/positions/area?lat={}&lon={}&radius=50", lat, lon),
+        "",
+        StatusCode::OK
+    );
+    assert!(body.contains(&format!(r#""user_id":{}"#, dave)));
+    assert!(!body.contains(&format!(r#""user_id":{}"#, erin)));
+
+    // A share token gets the status of its own user only
+    let body = call_with!(share_token, Method::GET, "/api/status", "", StatusCode::OK);
+    assert!(body.starts_with(&format!(r#"[{{"user_id":{},"last_fix":"#, dave)));
+    assert_eq!(body.matches(r#""user_id""#).count(), 1);
+    let body = call_with!(
+        share_token,
+        Method::GET,
+        format!("/api/status/{}", erin),
+        "",
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(body, "user ids don't match");
+
+    // Nothing is shared while paused
+    call_with!(
+        dave_session,
+        Method::PUT,
+        settings_uri,
+        format!(r#"{{"precision":"km","paused_until":{}}}"#, now() + 3600000),
+        StatusCode::OK
+    );
+    let body = call_with!(
+        erin_session,
+        Method::GET,
+        dave_positions_uri,
+        "",
+        StatusCode::OK
+    );
+    assert_eq!(body, "[]");
+    let body = call_with!(
+        erin_session,
+        Method::GET,
+        "/api/positions/latest",
+        "",
+        StatusCode::OK
+    );
+    assert!(body.contains(&format!(
+        r#"{{"user_id":{},"status":"sharing paused"}}"#,
+        dave
+    )));
+    // and neither is when the device was last seen
+    let body = call_with!(
+        erin_session,
+        Method::GET,
+        format!("/api/status/{}", dave),
+        "",
+        StatusCode::OK
+    );
+    assert!(body.contains(r#""last_fix":null"#) && body.contains(r#""online":null"#));
+    assert!(body.contains(r#""status":"sharing paused""#));
+    let body = call_with!(
+        dave_session,
+        Method::GET,
+        format!("/api/status/{}", dave),
+        "",
+        StatusCode::OK
+    );
+    assert!(body.contains(r#""online":true"#) && !body.contains("status"));
+    do_test!(
+        app,
+        Method::POST,
+        "/api/positions",
+        &format!(
+            r#"[{{"user_id":{},"latitude":46.0,"longitude":5.0,"source":"GPS","battery_level":40,"sport_mode":false}}]"#,
+            dave
+        ),
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+
+    // After resuming, what was recorded during the pause stays hidden
+    call_with!(
+        dave_session,
+        Method::PUT,
+        settings_uri,
+        r#"{"precision":"exact","paused_until":null}"#,
+        StatusCode::OK
+    );
+    let body = call_with!(
+        erin_session,
+        Method::GET,
+        dave_positions_uri,
+        "",
+        StatusCode::OK
+    );
+    assert!(body.contains("45.74846") && !body.contains("46.0"));
+    let body = call_with!(
+        dave_session,
+        Method::GET,
+        dave_positions_uri,
+        "",
+        StatusCode::OK
+    );
+    assert!(body.contains("45.74846") && body.contains("46.0"));
+
+    // Rules are managed by their owner only, and validated
+    let rules_uri = format!("/api/privacy/{}/rules", dave);
+    call_with!(
+        erin_session,
+        Method::POST,
+        rules_uri,
+        r#"{"name":"work","latitude":45.74846,"longitude":4.84671,"radius":200}"#,
+        StatusCode::FORBIDDEN
+    );
+    let body = call_with!(
+        dave_session,
+        Method::POST,
+        rules_uri,
+        r#"{"name":"night","starts_at":"22:00"}"#,
+        StatusCode::BAD_REQUEST
+    );
+    assert!(body.starts_with("a rule must have either"));
+    let body = call_with!(
+        dave_session,
+        Method::POST,
+        rules_uri,
+        r#"{"name":"work","latitude":45.74846,"longitude":4.84671,"radius":200}"#,
+        StatusCode::CREATED
+    );
+    let zone: SharingRule = serde_json::from_str(&body).unwrap();
+
+    // Positions inside the zone are hidden from the others, not from the owner
+    let body = call_with!(
+        erin_session,
+        Method::GET,
+        dave_positions_uri,
+        "",
+        StatusCode::OK
+    );
+    assert_eq!(body, "[]");
+    let body = call_with!(
+        dave_session,
+        Method::GET,
+        dave_positions_uri,
+        "",
+        StatusCode::OK
+    );
+    assert!(body.contains("45.74846"));
+    call_with!(
+        dave_session,
+        Method::DELETE,
+        format!("{}/{}", rules_uri, zone.id),
+        "",
+        StatusCode::OK
+    );
+
+    // Nothing is shared during a scheduled window, which is evaluated in the user timezone
+    let hhmm = |offset: i64| {
+        chrono::DateTime::from_timestamp_millis(now() + offset)
+            .unwrap()
+            .format("%H:%M")
+            .to_string()
+    };
+    call_with!(
+        dave_session,
+        Method::POST,
+        rules_uri,
+        format!(
+            r#"{{"name":"now","starts_at":"{}","ends_at":"{}"}}"#,
+            hhmm(-3600000),
+            hhmm(3600000)
+        ),
+        StatusCode::CREATED
+    );
+    let body = call_with!(
+        erin_session,
+        Method::GET,
+        format!("/api/positions/{}", position),
+        "",
+        StatusCode::OK
+    );
+    assert_eq!(
+        body,
+        format!(r#"{{"user_id":{},"status":"hidden by schedule"}}"#, dave)
+    );
+    let body = call_with!(dave_session, Method::GET, rules_uri, "", StatusCode::OK);
+    assert!(body.contains(r#""name":"now""#) && !body.contains("work"));
+
+    // Clean up
+    do_test!(
+        app,
+        Method::DELETE,
+        &format!("/api/groups/{}?cascade=true", group),
+        "",
+        StatusCode::OK,
+        ""
+    );
+    for id in [dave, erin] {
+        do_test!(
+            app,
+            Method::DELETE,
+            &format!("/api/users/{}?cascade=true", id),
+            "",
+            StatusCode::OK,
+            ""
+        );
+    }
+}
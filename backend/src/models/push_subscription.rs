@@ -0,0 +1,225 @@
+use std::net::IpAddr;
+
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, delete, get, post, web};
+use base64ct::{Base64UrlUnpadded, Encoding};
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    app::{AppConfig, Credential},
+    db::DbConnection,
+    errors::ServerError,
+    models::{audit::NewAuditEntry, group::request_visibility, user::User},
+    schema::push_subscriptions,
+    utils::now,
+};
+
+type DbPool = r2d2::Pool<ConnectionManager<DbConnection>>;
+
+// A browser subscribed to the notifications about a user, through the credential it uses
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, Insertable)]
+#[diesel(table_name = push_subscriptions)]
+pub struct PushSubscription {
+    pub id: i32,
+    pub user_id: i32,
+    pub credential: String,
+    pub endpoint: String,
+    pub p256dh: String,
+    pub auth: String,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = push_subscriptions)]
+struct NewPushSubscription {
+    user_id: i32,
+    credential: String,
+    endpoint: String,
+    p256dh: String,
+    auth: String,
+    created_at: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SubscriptionKeys {
+    pub p256dh: String,
+    pub auth: String,
+}
+
+// The PushSubscription JSON of the browsers, and the user to get the notifications of
+#[derive(Debug, Deserialize)]
+pub struct SubscriptionRequest {
+    pub user_id: i32,
+    pub endpoint: String,
+    pub keys: SubscriptionKeys,
+}
+
+impl SubscriptionRequest {
+    fn validate(&self) -> Result<(), String> {
+        let url = match reqwest::Url::parse(&self.endpoint) {
+            Ok(url) if url.scheme() == "https" => url,
+            _ => return Err("the endpoint must be an https url".to_owned()),
+        };
+        // the push services are on the internet, the server must not be made to call its network
+        let host = url.host_str().unwrap_or_default();
+        let local = match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+            Ok(ip) => !is_public(ip),
+            Err(_) => host.is_empty() || host == "localhost" || host.ends_with(".localhost"),
+        };
+        if local {
+            return Err("the endpoint must not be a local address".to_owned());
+        }
+        let decode = |v: &str| Base64UrlUnpadded::decode_vec(v.trim_end_matches('=')).ok();
+        if decode(&self.keys.p256dh)
+            .is_none_or(|k| k.len() != 65 || p256::PublicKey::from_sec1_bytes(&k).is_err())
+        {
+            return Err("p256dh must be an uncompressed P-256 public key".to_owned());
+        }
+        if decode(&self.keys.auth).is_none_or(|k| k.len() != 16) {
+            return Err("auth must be a 16 bytes secret".to_owned());
+        }
+        Ok(())
+    }
+}
+
+// Whether an address is reachable from the internet, and not in a loopback, link-local or private
+// network
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let shared = ip.octets()[0] == 100 && (ip.octets()[1] & 0xc0) == 64;
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || shared)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local())
+            }
+        },
+    }
+}
+
+fn credential(req: &HttpRequest) -> Credential {
+    // unwrap: the credential is always set by the validator
+    *req.extensions().get::<Credential>().unwrap()
+}
+
+#[get("/vapid-public-key")]
+pub async fn read_public_key(cfg: web::Data<AppConfig>) -> HttpResponse {
+    match &cfg.web_push {
+        Some(web_push) => HttpResponse::Ok().body(web_push.public_key()),
+        None => HttpResponse::NotFound().body("web push is not configured"),
+    }
+}
+
+// The subscriptions made with the request credential, or all of them for the admins
+#[get("/subscriptions")]
+pub async fn read_all(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, ServerError> {
+    let credential = credential(&req);
+    let mut conn = pool.get()?;
+    let objects = web::block(move || {
+        let mut query = push_subscriptions::table
+            .order(push_subscriptions::id.asc())
+            .into_boxed();
+        if !credential.is_admin() {
+            query = query.filter(push_subscriptions::credential.eq(credential.to_string()));
+        }
+        query.load::<PushSubscription>(&mut conn)
+    })
+    .await??;
+    Ok(HttpResponse::Ok().json(objects))
+}
+
+#[post("/subscriptions")]
+pub async fn create(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    o: web::Json<SubscriptionRequest>,
+) -> Result<HttpResponse, ServerError> {
+    if let Err(e) = o.validate() {
+        return Ok(HttpResponse::BadRequest().body(e));
+    }
+    // A share token is given away, the notifications are for the users of the application
+    if let Credential::Share(_) = credential(&req) {
+        return Ok(HttpResponse::Forbidden().body("share tokens can't subscribe to notifications"));
+    }
+    // Notifications stay private outside the groups
+    if !request_visibility(&req, &pool).await?.allows(o.user_id) {
+        return Ok(HttpResponse::Forbidden().body("user is outside of your groups"));
+    }
+    let audit = NewAuditEntry::new(&req, "push_subscriptions.create");
+    let o = o.into_inner();
+    let new_o = NewPushSubscription {
+        user_id: o.user_id,
+        credential: credential(&req).to_string(),
+        endpoint: o.endpoint,
+        p256dh: o.keys.p256dh,
+        auth: o.keys.auth,
+        created_at: now(),
+    };
+    let mut conn = pool.get()?;
+    let created_o = web::block(move || -> Result<PushSubscription, ServerError> {
+        crate::schema::users::table
+            .find(new_o.user_id)
+            .first::<User>(&mut conn)?;
+        // a browser subscribing again replaces its previous subscription
+        crate::replace_into!(
+            conn,
+            push_subscriptions::table,
+            push_subscriptions::endpoint.eq(&new_o.endpoint),
+            &new_o
+        )?;
+        let created_o = push_subscriptions::table
+            .filter(push_subscriptions::endpoint.eq(&new_o.endpoint))
+            .first::<PushSubscription>(&mut conn)?;
+        audit
+            .target(format!("push_subscriptions/{}", created_o.id))
+            .details(&serde_json::json!({ "user_id": created_o.user_id }))
+            .insert(&mut conn)?;
+        Ok(created_o)
+    })
+    .await??;
+    Ok(HttpResponse::Created().json(created_o))
+}
+
+#[delete("/subscriptions/{oid}")]
+pub async fn delete(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    oid: web::Path<i32>,
+) -> Result<HttpResponse, ServerError> {
+    let credential = credential(&req);
+    let audit = NewAuditEntry::new(&req, "push_subscriptions.delete");
+    let oid = *oid;
+    let mut conn = pool.get()?;
+    web::block(move || -> Result<(), ServerError> {
+        let mut query = diesel::delete(push_subscriptions::table)
+            .filter(push_subscriptions::id.eq(oid))
+            .into_boxed();
+        // only the admins can remove the subscriptions of the others
+        if !credential.is_admin() {
+            query = query.filter(push_subscriptions::credential.eq(credential.to_string()));
+        }
+        if query.execute(&mut conn)? == 0 {
+            return Err(ServerError::DieselNotFound);
+        }
+        audit
+            .target(format!("push_subscriptions/{}", oid))
+            .insert(&mut conn)?;
+        Ok(())
+    })
+    .await??;
+    Ok(HttpResponse::Ok().body(format!("Deleted object with id: {}", oid)))
+}
@@ -0,0 +1,357 @@
+use std::sync::{Arc, Mutex};
+
+use actix_web::{App, HttpRequest, HttpResponse, HttpServer, web};
+use base64ct::{Base64UrlUnpadded, Encoding};
+use p256::{SecretKey, elliptic_curve::sec1::ToEncodedPoint};
+
+use crate::{
+    app::{AppConfig, Role},
+    create_app,
+    notifiers::Notifiers,
+    positions_server::PositionsServerHandle,
+    token::{self, Claims},
+    utils::now,
+    webpush::{
+        VapidKeys, WebPush,
+        tests::{decrypt, verify_authorization},
+    },
+};
+
+// Path, Authorization header and body of the received messages
+type Received = Arc<Mutex<Vec<(String, String, Vec<u8>)>>>;
+
+pub async fn push_test(
+    pool: &r2d2::Pool<diesel::r2d2::ConnectionManager<crate::db::DbConnection>>,
+    position_server_handle: &PositionsServerHandle,
+) {
+    use crate::{do_test, do_test_extract_id};
+    use actix_web::{
+        http::{Method, StatusCode},
+        test,
+    };
+
+    // Mock push service, that forgot the subscriptions under /gone
+    let received: Received = Arc::default();
+    let http_received = web::Data::new(received.clone());
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    let http_server = HttpServer::new(move || {
+        App::new().app_data(http_received.clone()).route(
+            "/{subscription}",
+            web::post().to(
+                |received: web::Data<Received>, req: HttpRequest, body: web::Bytes| async move {
+                    assert_eq!(req.headers().get("Content-Encoding").unwrap(), "aes128gcm");
+                    assert!(req.headers().contains_key("TTL"));
+                    let authorization = req
+                        .headers()
+                        .get("Authorization")
+                        .map(|a| a.to_str().unwrap().to_owned())
+                        .unwrap_or_default();
+                    received.lock().unwrap().push((
+                        req.path().to_owned(),
+                        authorization,
+                        body.to_vec(),
+                    ));
+                    match req.path() {
+                        "/gone" => HttpResponse::Gone().finish(),
+                        _ => HttpResponse::Created().finish(),
+                    }
+                },
+            ),
+        )
+    })
+    .workers(1)
+    .listen(listener)
+    .unwrap()
+    .run();
+    let http_server_handle = http_server.handle();
+    actix_web::rt::spawn(http_server);
+
+    let web_push = WebPush::new(
+        VapidKeys::generate(),
+        "mailto:admin@localhost".to_owned(),
+        pool.clone(),
+    );
+    let app_config = web::Data::new(
+        AppConfig::new("0101".to_string(), None)
+            .with_notifiers(Notifiers::new(vec![Box::new(web_push.clone())]))
+            .with_web_push(web_push.clone()),
+    );
+    let mut app = test::init_service(create_app!(pool, &app_config, position_server_handle)).await;
+
+    // Send a request with a given token
+    macro_rules! call_with {
+        ($token:expr, $method:expr, $uri:expr, $payload:expr, $expected_status_code:expr) => {{
+            let req = test::TestRequest::default()
+                .method($method)
+                .insert_header(("Authorization", format!("Bearer {}", $token)))
+                .insert_header(("Content-Type", "application/json"))
+                .uri(&$uri)
+                .set_payload($payload)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), $expected_status_code);
+            let body = test::read_body(resp).await;
+            std::str::from_utf8(&body).unwrap().to_string()
+        }};
+    }
+
+    let grace: i32 = do_test_extract_id!(
+        app,
+        Method::POST,
+        "/api/users",
+        r#"{"name":"Grace","surname":"Pushed"}"#,
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    let henry = do_test_extract_id!(
+        app,
+        Method::POST,
+        "/api/users",
+        r#"{"name":"Henry","surname":"Outsider"}"#,
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    let henry_session = token::seal(
+        &app_config.share_keys,
+        &Claims::Session {
+            expires_at: u64::MAX / 2,
+            user_id: Some(henry),
+            role: Role::Viewer,
+        },
+    )
+    .unwrap();
+    let grace_session = token::seal(
+        &app_config.share_keys,
+        &Claims::Session {
+            expires_at: u64::MAX / 2,
+            user_id: Some(grace),
+            role: Role::Viewer,
+        },
+    )
+    .unwrap();
+    let share_token = do_test!(
+        app,
+        Method::GET,
+        &format!("/api/token?user_id={}", grace),
+        "",
+        StatusCode::OK,
+        ""
+    );
+
+    // The browsers get the application server key with any credential
+    let public_key = call_with!(
+        share_token,
+        Method::GET,
+        "/api/push/vapid-public-key",
+        "",
+        StatusCode::OK
+    );
+    assert_eq!(public_key, web_push.public_key());
+
+    // The browser keys
+    let ua_secret = SecretKey::from_slice(&[7; 32]).unwrap();
+    let auth_secret = [5; 16];
+    let subscription = |endpoint: &str, p256dh: &[u8], auth: &[u8]| {
+        format!(
+            r#"{{"user_id":{},"endpoint":"{}","keys":{{"p256dh":"{}","auth":"{}"}}}}"#,
+            grace,
+            endpoint,
+            Base64UrlUnpadded::encode_string(p256dh),
+            Base64UrlUnpadded::encode_string(auth)
+        )
+    };
+    let p256dh = ua_secret.public_key().to_encoded_point(false);
+    let p256dh = p256dh.as_bytes();
+
+    // Subscriptions are validated, and limited to the visible users
+    for endpoint in ["not an url", "http://push.example.com/ok"] {
+        do_test!(
+            app,
+            Method::POST,
+            "/api/push/subscriptions",
+            &subscription(endpoint, p256dh, &auth_secret),
+            StatusCode::BAD_REQUEST,
+            "the endpoint must be an https url"
+        );
+    }
+    for endpoint in [
+        "https://localhost/ok",
+        "https://127.0.0.1/ok",
+        "https://10.1.2.3/ok",
+        "https://169.254.169.254/ok",
+        "https://[::1]/ok",
+        "https://[fd00::1]/ok",
+        "https://[::ffff:192.168.1.1]/ok",
+    ] {
+        do_test!(
+            app,
+            Method::POST,
+            "/api/push/subscriptions",
+            &subscription(endpoint, p256dh, &auth_secret),
+            StatusCode::BAD_REQUEST,
+            "the endpoint must not be a local address"
+        );
+    }
+    do_test!(
+        app,
+        Method::POST,
+        "/api/push/subscriptions",
+        &subscription("https://push.example.com/ok", &p256dh[1..], &auth_secret),
+        StatusCode::BAD_REQUEST,
+        "p256dh must be an uncompressed P-256 public key"
+    );
+    do_test!(
+        app,
+        Method::POST,
+        "/api/push/subscriptions",
+        &subscription("https://push.example.com/ok", p256dh, &auth_secret[1..]),
+        StatusCode::BAD_REQUEST,
+        "auth must be a 16 bytes secret"
+    );
+    let body = call_with!(
+        henry_session,
+        Method::POST,
+        "/api/push/subscriptions",
+        subscription("https://push.example.com/ok", p256dh, &auth_secret),
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(body, "user is outside of your groups");
+    let body = call_with!(
+        share_token,
+        Method::POST,
+        "/api/push/subscriptions",
+        subscription("https://push.example.com/ok", p256dh, &auth_secret),
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(body, "share tokens can't subscribe to notifications");
+
+    // A browser subscribing again replaces its subscription
+    for _ in 0..2 {
+        call_with!(
+            grace_session,
+            Method::POST,
+            "/api/push/subscriptions",
+            subscription("https://push.example.com/ok", p256dh, &auth_secret),
+            StatusCode::CREATED
+        );
+    }
+    let gone_id: i32 = do_test_extract_id!(
+        app,
+        Method::POST,
+        "/api/push/subscriptions",
+        &subscription("https://push.example.com/gone", p256dh, &auth_secret),
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    let body = call_with!(
+        grace_session,
+        Method::GET,
+        "/api/push/subscriptions",
+        "",
+        StatusCode::OK
+    );
+    assert_eq!(body.matches(r#""endpoint":"#).count(), 1);
+    assert!(body.contains("/ok"));
+
+    // The mock push service is local, which the subscriptions can't be
+    {
+        use crate::schema::push_subscriptions::dsl::*;
+        use diesel::prelude::*;
+        let mut conn = pool.get().unwrap();
+        for path in ["/ok", "/gone"] {
+            diesel::update(push_subscriptions)
+                .filter(endpoint.eq(format!("https://push.example.com{}", path)))
+                .set(endpoint.eq(format!("{}{}", base_url, path)))
+                .execute(&mut conn)
+                .unwrap();
+        }
+    }
+
+    // A low battery alert is pushed to the subscriptions of the user
+    do_test!(
+        app,
+        Method::PUT,
+        &format!("/api/alert-settings/{}", grace),
+        r#"{"battery_threshold":20,"offline_after":null}"#,
+        StatusCode::OK,
+        ""
+    );
+    do_test!(
+        app,
+        Method::POST,
+        "/api/positions",
+        &format!(
+            r#"[{{"user_id":{},"latitude":45.74846,"longitude":4.84671,"source":"GPS","battery_level":10,"sport_mode":false,"time":{}}}]"#,
+            grace,
+            now() - 60000
+        ),
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    // Wait for the subscription the push service forgot to be removed
+    let mut subscriptions = String::new();
+    for _ in 0..100 {
+        subscriptions = do_test!(
+            app,
+            Method::GET,
+            "/api/push/subscriptions",
+            "",
+            StatusCode::OK,
+            "["
+        );
+        if !subscriptions.contains(&format!(r#""id":{},"#, gone_id)) {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert!(!subscriptions.contains(&format!(r#""id":{},"#, gone_id)));
+    let messages = received.lock().unwrap().clone();
+    assert_eq!(messages.len(), 2);
+    for (path, authorization, body) in &messages {
+        let claims = verify_authorization(authorization, &public_key);
+        assert_eq!(claims["aud"], base_url);
+        assert_eq!(claims["sub"], "mailto:admin@localhost");
+        let message = decrypt(&ua_secret, &auth_secret, body);
+        let message: serde_json::Value = serde_json::from_slice(&message).unwrap();
+        assert_eq!(message["user_id"], grace);
+        assert_eq!(message["title"], "Battery low");
+        assert_eq!(message["message"], "Battery of Grace Pushed is low: 10%");
+        assert!(path == "/ok" || path == "/gone");
+    }
+
+    // Only their creator or an admin removes the subscriptions
+    let ok_id = subscriptions
+        .split(r#"{"id":"#)
+        .find(|s| s.contains("/ok"))
+        .and_then(|s| s.split(',').next())
+        .unwrap()
+        .to_owned();
+    call_with!(
+        henry_session,
+        Method::DELETE,
+        format!("/api/push/subscriptions/{}", ok_id),
+        "",
+        StatusCode::NOT_FOUND
+    );
+    call_with!(
+        grace_session,
+        Method::DELETE,
+        format!("/api/push/subscriptions/{}", ok_id),
+        "",
+        StatusCode::OK
+    );
+
+    // Clean up
+    for uid in [grace, henry] {
+        do_test!(
+            app,
+            Method::DELETE,
+            &format!("/api/users/{}?cascade=true", uid),
+            "",
+            StatusCode::OK,
+            ""
+        );
+    }
+    http_server_handle.stop(true).await;
+}
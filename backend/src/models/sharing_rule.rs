@@ -0,0 +1,336 @@
+use actix_web::{HttpRequest, HttpResponse, delete, get, post, web};
+use chrono::{DateTime, Timelike};
+use chrono_tz::Tz;
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::DbConnection,
+    errors::ServerError,
+    models::{audit::NewAuditEntry, position::Position, privacy::check_owner, user::User},
+    schema::sharing_rules,
+};
+
+type DbPool = r2d2::Pool<ConnectionManager<DbConnection>>;
+
+pub(crate) const EARTH_RADIUS_METERS: f64 = 6_371_000.0;
+
+// Automatic rule hiding a user's positions, either on a daily schedule or inside a zone
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, Insertable)]
+#[diesel(table_name = sharing_rules)]
+pub struct SharingRule {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    // daily window in the user timezone, as HH:MM, that may wrap around midnight
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub starts_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ends_at: Option<String>,
+    // zone center and radius in meters
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latitude: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub longitude: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub radius: Option<f64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Insertable)]
+#[diesel(table_name = sharing_rules)]
+pub struct NewSharingRule {
+    #[serde(skip)]
+    pub user_id: i32,
+    pub name: String,
+    pub starts_at: Option<String>,
+    pub ends_at: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub radius: Option<f64>,
+}
+
+impl NewSharingRule {
+    fn trim(&mut self) -> &Self {
+        self.name = self.name.trim().to_string();
+        self
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty() {
+            return Err("rule name must not be empty".to_owned());
+        }
+        let schedule = (self.starts_at.as_deref(), self.ends_at.as_deref());
+        let zone = (self.latitude, self.longitude, self.radius);
+        match (schedule, zone) {
+            ((Some(starts_at), Some(ends_at)), (None, None, None)) => {
+                for t in [starts_at, ends_at] {
+                    if parse_minutes(t).is_none() {
+                        return Err(format!("schedule times must be formatted as HH:MM: {}", t));
+                    }
+                }
+                Ok(())
+            }
+            ((None, None), (Some(latitude), Some(longitude), Some(radius))) => {
+                if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+                    return Err("zone center is out of range".to_owned());
+                }
+                if radius.is_nan() || radius <= 0.0 {
+                    return Err("zone radius must be positive".to_owned());
+                }
+                Ok(())
+            }
+            _ => Err(
+                "a rule must have either starts_at and ends_at, or latitude, longitude and radius"
+                    .to_owned(),
+            ),
+        }
+    }
+}
+
+impl SharingRule {
+    // Get the status to serve instead of a position, if the rule hides it
+    pub fn hides(&self, position: &Position, now: i64, timezone: Tz) -> Option<&'static str> {
+        if let (Some(starts_at), Some(ends_at)) = (&self.starts_at, &self.ends_at) {
+            let (starts_at, ends_at) = (parse_minutes(starts_at)?, parse_minutes(ends_at)?);
+            // nothing is shared during the window, and what was recorded during it stays hidden
+            let during = |time| {
+                minute_of_day(time, timezone)
+                    .is_some_and(|minute| in_window(starts_at, ends_at, minute))
+            };
+            if during(now) || during(position.time) {
+                return Some("hidden by schedule");
+            }
+        }
+        if let (Some(latitude), Some(longitude), Some(radius)) =
+            (self.latitude, self.longitude, self.radius)
+            && distance(latitude, longitude, position.latitude, position.longitude) <= radius
+        {
+            return Some("hidden by zone");
+        }
+        None
+    }
+}
+
+fn parse_minutes(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.split_once(':')?;
+    let (hours, minutes) = (hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?);
+    (hours < 24 && minutes < 60 && time.len() == 5).then_some(hours * 60 + minutes)
+}
+
+fn minute_of_day(time: i64, timezone: Tz) -> Option<u32> {
+    let local = DateTime::from_timestamp_millis(time)?.with_timezone(&timezone);
+    Some(local.hour() * 60 + local.minute())
+}
+
+fn in_window(starts_at: u32, ends_at: u32, minute: u32) -> bool {
+    if starts_at <= ends_at {
+        starts_at <= minute && minute < ends_at
+    } else {
+        minute >= starts_at || minute < ends_at
+    }
+}
+
+// Great circle distance in meters
+pub(crate) fn distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let d_lat = lat2 - lat1;
+    let d_lon = (lon2 - lon1).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_METERS * a.sqrt().asin()
+}
+
+#[get("/{uid}/rules")]
+pub async fn read_all(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    uid: web::Path<i32>,
+) -> Result<HttpResponse, ServerError> {
+    let uid = *uid;
+    if let Some(resp) = check_owner(&req, uid) {
+        return Ok(resp);
+    }
+    let mut conn = pool.get()?;
+    let rules = web::block(move || {
+        sharing_rules::table
+            .filter(sharing_rules::user_id.eq(uid))
+            .order(sharing_rules::id.asc())
+            .load::<SharingRule>(&mut conn)
+    })
+    .await??;
+    Ok(HttpResponse::Ok().json(rules))
+}
+
+#[post("/{uid}/rules")]
+pub async fn create(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    uid: web::Path<i32>,
+    o: web::Json<NewSharingRule>,
+) -> Result<HttpResponse, ServerError> {
+    let uid = *uid;
+    if let Some(resp) = check_owner(&req, uid) {
+        return Ok(resp);
+    }
+    let mut o = o.into_inner();
+    o.user_id = uid;
+    o.trim();
+    if let Err(e) = o.validate() {
+        return Ok(HttpResponse::BadRequest().body(e));
+    }
+    let audit = NewAuditEntry::new(&req, "sharing_rules.create");
+    let mut conn = pool.get()?;
+    let rule = web::block(move || -> Result<SharingRule, ServerError> {
+        crate::schema::users::table
+            .find(uid)
+            .first::<User>(&mut conn)?;
+        diesel::insert_into(sharing_rules::table)
+            .values(&o)
+            .execute(&mut conn)?;
+        let rule = sharing_rules::table
+            .order(sharing_rules::id.desc())
+            .first::<SharingRule>(&mut conn)?;
+        audit
+            .target(format!("sharing_rules/{}", rule.id))
+            .details(&rule)
+            .insert(&mut conn)?;
+        Ok(rule)
+    })
+    .await??;
+    Ok(HttpResponse::Created().json(rule))
+}
+
+#[delete("/{uid}/rules/{rid}")]
+pub async fn delete(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    path: web::Path<(i32, i32)>,
+) -> Result<HttpResponse, ServerError> {
+    let (uid, rid) = path.into_inner();
+    if let Some(resp) = check_owner(&req, uid) {
+        return Ok(resp);
+    }
+    let audit = NewAuditEntry::new(&req, "sharing_rules.delete");
+    let mut conn = pool.get()?;
+    web::block(move || -> Result<(), ServerError> {
+        let deleted = diesel::delete(
+            sharing_rules::table
+                .filter(sharing_rules::id.eq(rid))
+                .filter(sharing_rules::user_id.eq(uid)),
+        )
+        .execute(&mut conn)?;
+        if deleted == 0 {
+            return Err(ServerError::DieselNotFound);
+        }
+        audit
+            .target(format!("sharing_rules/{}", rid))
+            .insert(&mut conn)?;
+        Ok(())
+    })
+    .await??;
+    Ok(HttpResponse::Ok().body(format!("Deleted object with id: {}", rid)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule() -> SharingRule {
+        SharingRule {
+            id: 1,
+            user_id: 1,
+            name: "night".to_owned(),
+            starts_at: Some("22:00".to_owned()),
+            ends_at: Some("07:00".to_owned()),
+            latitude: None,
+            longitude: None,
+            radius: None,
+        }
+    }
+
+    fn position(time: i64) -> Position {
+        Position {
+            id: 1,
+            user_id: 1,
+            latitude: 45.74846,
+            longitude: 4.84671,
+            source: "GPS".to_owned(),
+            battery_level: 50,
+            sport_mode: false,
+            time,
+            flagged: None,
+        }
+    }
+
+    #[test]
+    fn test_schedule() {
+        // 2026-01-01 at 12:00, 23:00 and 06:00 UTC
+        let (noon, night, morning) = (1767268800000, 1767308400000, 1767247200000);
+        let utc = chrono_tz::UTC;
+        assert_eq!(rule().hides(&position(noon), noon, utc), None);
+        assert_eq!(
+            rule().hides(&position(noon), night, utc),
+            Some("hidden by schedule")
+        );
+        assert_eq!(
+            rule().hides(&position(morning), noon, utc),
+            Some("hidden by schedule")
+        );
+        // Paris is one hour ahead in winter, so 06:30 UTC is after 07:00 there
+        let paris = chrono_tz::Europe::Paris;
+        assert!(
+            rule()
+                .hides(&position(noon), morning + 1800000, utc)
+                .is_some()
+        );
+        assert!(
+            rule()
+                .hides(&position(noon), morning + 1800000 + 3600000, paris)
+                .is_none()
+        );
+        assert!(in_window(540, 1020, 540) && !in_window(540, 1020, 1020));
+    }
+
+    #[test]
+    fn test_zone() {
+        let zone = SharingRule {
+            starts_at: None,
+            ends_at: None,
+            latitude: Some(45.75),
+            longitude: Some(4.85),
+            radius: Some(500.0),
+            ..rule()
+        };
+        assert_eq!(
+            zone.hides(&position(0), 0, chrono_tz::UTC),
+            Some("hidden by zone")
+        );
+        let far = Position {
+            latitude: 45.76,
+            ..position(0)
+        };
+        assert_eq!(zone.hides(&far, 0, chrono_tz::UTC), None);
+        // one degree of latitude is about 111 km
+        assert!((distance(45.0, 4.0, 46.0, 4.0) - 111_195.0).abs() < 100.0);
+    }
+
+    #[test]
+    fn test_validate() {
+        let new_rule = |starts_at: Option<&str>, radius: Option<f64>| NewSharingRule {
+            user_id: 1,
+            name: "rule".to_owned(),
+            starts_at: starts_at.map(str::to_owned),
+            ends_at: starts_at.map(|_| "07:00".to_owned()),
+            latitude: radius.map(|_| 45.0),
+            longitude: radius.map(|_| 4.0),
+            radius,
+        };
+        assert!(new_rule(Some("22:00"), None).validate().is_ok());
+        assert!(new_rule(None, Some(300.0)).validate().is_ok());
+        assert!(new_rule(Some("24:00"), None).validate().is_err());
+        assert!(new_rule(Some("7:00"), None).validate().is_err());
+        assert!(new_rule(None, Some(-1.0)).validate().is_err());
+        assert!(new_rule(Some("22:00"), Some(300.0)).validate().is_err());
+        assert!(new_rule(None, None).validate().is_err());
+    }
+}
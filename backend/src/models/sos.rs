@@ -0,0 +1,199 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::{HttpRequest, HttpResponse, delete, post, web};
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use serde::Serialize;
+
+use crate::{
+    app::AppConfig,
+    db::DbConnection,
+    errors::ServerError,
+    models::{
+        alert::{self, Alert, SOS},
+        audit::NewAuditEntry,
+        privacy::check_owner,
+        sport_mode::request_sport_mode,
+        user::User,
+    },
+    notifiers::Notification,
+    positions_server::PositionsServerHandle,
+    schema::{alerts, positions, users},
+    token::{self, Claims},
+    utils::now,
+};
+
+type DbPool = r2d2::Pool<ConnectionManager<DbConnection>>;
+
+// An emergency, with the link to follow the user when the public url is known
+#[derive(Debug, Serialize)]
+pub struct Emergency {
+    pub alert: Alert,
+    pub share_token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub share_link: Option<String>,
+}
+
+// Whether the device of the user is tracking in sport mode, according to its last position
+fn sport_mode(conn: &mut DbConnection, uid: i32) -> QueryResult<bool> {
+    Ok(positions::table
+        .filter(positions::user_id.eq(uid))
+        .filter(positions::flagged.is_null())
+        .order(positions::time.desc())
+        .select(positions::sport_mode)
+        .first::<bool>(conn)
+        .optional()?
+        .unwrap_or(false))
+}
+
+// Share the positions of the user with the emergency contacts, for longer than a share token.
+// The link is only built from the public url, the configuration requires it when the emergency
+// contacts are notified.
+fn share_link(
+    cfg: &AppConfig,
+    uid: i32,
+    expires_at: u64,
+) -> Result<(String, Option<String>), ServerError> {
+    let share_token = token::seal(
+        &cfg.share_keys,
+        &Claims::Emergency {
+            expires_at,
+            user_id: uid.try_into()?,
+        },
+    )?;
+    let share_link = cfg.public_url.as_deref().map(|public_url| {
+        format!(
+            "{}/?token={}&user={}",
+            public_url.trim_end_matches('/'),
+            urlencoding::encode(&share_token),
+            uid
+        )
+    });
+    Ok((share_token, share_link))
+}
+
+// Whether an emergency share token is still in force : it is given up when the emergency is cleared
+pub fn is_in_force(conn: &mut DbConnection, uid: i32, expires_at: u64) -> QueryResult<bool> {
+    let Ok(expires_at) = i64::try_from(expires_at) else {
+        return Ok(false);
+    };
+    let active = alerts::table
+        .filter(alerts::user_id.eq(uid))
+        .filter(alerts::kind.eq(SOS))
+        .filter(alerts::cleared_at.is_null())
+        .filter(alerts::share_expires_at.eq(expires_at))
+        .count()
+        .get_result::<i64>(conn)?;
+    Ok(active > 0)
+}
+
+// Put a user in emergency : track it closely, and warn everyone following it
+#[post("/{uid}")]
+pub async fn trigger(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    cfg: web::Data<AppConfig>,
+    ws_data: web::Data<PositionsServerHandle>,
+    uid: web::Path<i32>,
+) -> Result<HttpResponse, ServerError> {
+    let uid = *uid;
+    if let Some(resp) = check_owner(&req, uid) {
+        return Ok(resp);
+    }
+    let expires_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()
+        + cfg.config.emergency_share_token_duration;
+    let (share_token, share_link) = share_link(&cfg, uid, expires_at)?;
+    let audit = NewAuditEntry::new(&req, "sos.trigger");
+    let mut conn = pool.get()?;
+    let raised = web::block(move || -> Result<_, ServerError> {
+        users::table.find(uid).first::<User>(&mut conn)?;
+        let message = format!("{} is in emergency", alert::user_name(&mut conn, uid)?);
+        let Some(mut alert) = alert::raise(&mut conn, uid, SOS, &message, now())? else {
+            return Ok(None);
+        };
+        // The share token is valid as long as this alert is active
+        alert.share_expires_at = Some(i64::try_from(expires_at)?);
+        diesel::update(alerts::table.find(alert.id))
+            .set(alerts::share_expires_at.eq(alert.share_expires_at))
+            .execute(&mut conn)?;
+        audit.target(format!("users/{}", uid)).insert(&mut conn)?;
+        Ok(Some((
+            alert,
+            sport_mode(&mut conn, uid)?,
+            alert::notifier_targets(&mut conn, uid)?,
+        )))
+    })
+    .await??;
+    let Some((alert, current_sport_mode, targets)) = raised else {
+        return Ok(HttpResponse::Conflict().body("user is already in emergency"));
+    };
+    // High frequency tracking, delivered to the device as the sport mode toggle
+    request_sport_mode(&cfg, uid, current_sport_mode, true).await;
+    let emergency = Emergency {
+        alert,
+        share_token,
+        share_link,
+    };
+    // Only the user, the admins and the emergency contacts get the share link, the others
+    // following the user get the emergency without it
+    ws_data
+        .send_private_message(
+            uid.try_into()?,
+            serde_json::to_string(&serde_json::json!({ "sos": emergency }))?,
+            Some(serde_json::to_string(
+                &serde_json::json!({ "sos": { "alert": emergency.alert } }),
+            )?),
+        )
+        .await;
+    let notification = Notification {
+        user_id: uid,
+        title: "Emergency".to_owned(),
+        message: emergency.alert.message.clone(),
+        payload: serde_json::json!({ "alert": emergency.alert }),
+    };
+    cfg.notifiers.only(targets.as_deref()).dispatch_private(
+        Notification {
+            message: match &emergency.share_link {
+                Some(share_link) => format!(
+                    "{}, follow their position at {}",
+                    emergency.alert.message, share_link
+                ),
+                None => emergency.alert.message.clone(),
+            },
+            payload: serde_json::to_value(&emergency)?,
+            ..notification.clone()
+        },
+        notification,
+    );
+    Ok(HttpResponse::Created().json(emergency))
+}
+
+#[delete("/{uid}")]
+pub async fn clear(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    cfg: web::Data<AppConfig>,
+    ws_data: web::Data<PositionsServerHandle>,
+    uid: web::Path<i32>,
+) -> Result<HttpResponse, ServerError> {
+    let uid = *uid;
+    if let Some(resp) = check_owner(&req, uid) {
+        return Ok(resp);
+    }
+    let audit = NewAuditEntry::new(&req, "sos.clear");
+    let mut conn = pool.get()?;
+    let cleared = web::block(move || -> Result<_, ServerError> {
+        let Some(alert) = alert::clear(&mut conn, uid, SOS, now())? else {
+            return Ok(None);
+        };
+        audit.target(format!("users/{}", uid)).insert(&mut conn)?;
+        Ok(Some((alert, sport_mode(&mut conn, uid)?)))
+    })
+    .await??;
+    let Some((alert, current_sport_mode)) = cleared else {
+        return Ok(HttpResponse::NotFound().body("user is not in emergency"));
+    };
+    request_sport_mode(&cfg, uid, current_sport_mode, false).await;
+    alert::deliver(vec![alert.clone()], &pool, &cfg.notifiers, &ws_data).await?;
+    Ok(HttpResponse::Ok().json(alert))
+}
@@ -0,0 +1,310 @@
+use std::sync::{Arc, Mutex};
+
+use actix_web::{App, HttpResponse, HttpServer, web};
+use futures_util::future::BoxFuture;
+use tokio::sync::mpsc;
+
+use crate::{
+    app::{AppConfig, Role},
+    create_app,
+    notifiers::{Notification, Notifier, Notifiers, Webhook},
+    positions_server::PositionsServerHandle,
+    token::{self, Claims},
+    utils::now,
+};
+
+type Received = Arc<Mutex<Vec<String>>>;
+
+// A notifier that is not an emergency contact, keeping the messages
+struct Recorder(Received);
+
+impl Notifier for Recorder {
+    fn name(&self) -> &'static str {
+        "recorder"
+    }
+
+    fn notify<'a>(&'a self, notification: &'a Notification) -> BoxFuture<'a, Result<(), String>> {
+        self.0.lock().unwrap().push(notification.message.clone());
+        Box::pin(async { Ok(()) })
+    }
+}
+
+pub async fn sos_test(
+    pool: &r2d2::Pool<diesel::r2d2::ConnectionManager<crate::db::DbConnection>>,
+    position_server_handle: &PositionsServerHandle,
+) {
+    use crate::{do_test, do_test_extract_id};
+    use actix_web::{
+        http::{Method, StatusCode},
+        test,
+    };
+
+    // Mock webhook
+    let received: Received = Arc::default();
+    let http_received = web::Data::new(received.clone());
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let webhook_url = format!("http://{}/webhook", listener.local_addr().unwrap());
+    let http_server = HttpServer::new(move || {
+        App::new().app_data(http_received.clone()).route(
+            "/webhook",
+            web::post().to(|received: web::Data<Received>, body: String| async move {
+                received.lock().unwrap().push(body);
+                HttpResponse::Ok().finish()
+            }),
+        )
+    })
+    .workers(1)
+    .listen(listener)
+    .unwrap()
+    .run();
+    let http_server_handle = http_server.handle();
+    actix_web::rt::spawn(http_server);
+
+    let recorded: Received = Arc::default();
+    let notifiers: Vec<Box<dyn Notifier>> = vec![
+        Box::new(Webhook { url: webhook_url }),
+        Box::new(Recorder(recorded.clone())),
+    ];
+    let app_config = web::Data::new(
+        AppConfig::new("0101".to_string(), None)
+            .with_notifiers(
+                Notifiers::new(notifiers).with_emergency_contacts(vec!["webhook".to_owned()]),
+            )
+            .with_public_url("https://tesou.example.com/".to_owned()),
+    );
+    let mut app = test::init_service(create_app!(pool, &app_config, position_server_handle)).await;
+
+    // Send a request with a given token
+    macro_rules! call_with {
+        ($token:expr, $method:expr, $uri:expr, $payload:expr, $expected_status_code:expr) => {{
+            let req = test::TestRequest::default()
+                .method($method)
+                .insert_header(("Authorization", format!("Bearer {}", $token)))
+                .insert_header(("Content-Type", "application/json"))
+                .uri(&$uri)
+                .set_payload($payload)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), $expected_status_code);
+            let body = test::read_body(resp).await;
+            std::str::from_utf8(&body).unwrap().to_string()
+        }};
+    }
+
+    let ivy = do_test_extract_id!(
+        app,
+        Method::POST,
+        "/api/users",
+        r#"{"name":"Ivy","surname":"Emergency"}"#,
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    let jack = do_test_extract_id!(
+        app,
+        Method::POST,
+        "/api/users",
+        r#"{"name":"Jack","surname":"Bystander"}"#,
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    let session = |user_id| {
+        token::seal(
+            &app_config.share_keys,
+            &Claims::Session {
+                expires_at: u64::MAX / 2,
+                user_id: Some(user_id),
+                role: Role::Viewer,
+            },
+        )
+        .unwrap()
+    };
+    let ivy_session = session(ivy);
+    let jack_session = session(jack);
+    let post_position = |sport_mode: bool, time: i64| {
+        format!(
+            r#"[{{"user_id":{},"latitude":45.74846,"longitude":4.84671,"source":"GPS","battery_level":50,"sport_mode":{},"time":{}}}]"#,
+            ivy, sport_mode, time
+        )
+    };
+    do_test!(
+        app,
+        Method::POST,
+        "/api/positions",
+        &post_position(false, now() - 60000),
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    let (ws_tx, mut ws_rx) = mpsc::unbounded_channel();
+    let conn_id = position_server_handle
+        .connect(ws_tx, ivy.try_into().unwrap(), false)
+        .await;
+    let (exact_ws_tx, mut exact_ws_rx) = mpsc::unbounded_channel();
+    let exact_conn_id = position_server_handle
+        .connect(exact_ws_tx, ivy.try_into().unwrap(), true)
+        .await;
+    let sos_uri = format!("/api/sos/{}", ivy);
+
+    // Only the user or an admin can trigger an emergency
+    let body = call_with!(
+        jack_session,
+        Method::POST,
+        sos_uri,
+        "",
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(body, "only the user or an admin can do that");
+
+    // The emergency is broadcast to the followers of the user, and notified, the share link going
+    // to the exact sessions and the emergency contacts only
+    let body = call_with!(ivy_session, Method::POST, sos_uri, "", StatusCode::CREATED);
+    let emergency: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(emergency["alert"]["kind"], "sos");
+    assert_eq!(
+        emergency["alert"]["message"],
+        "Ivy Emergency is in emergency"
+    );
+    let share_link = emergency["share_link"].as_str().unwrap();
+    assert!(share_link.starts_with("https://tesou.example.com/?token="));
+    assert!(share_link.ends_with(&format!("&user={}", ivy)));
+    call_with!(ivy_session, Method::POST, sos_uri, "", StatusCode::CONFLICT);
+    let ws_message = ws_rx.try_recv().unwrap();
+    assert!(ws_message.starts_with(r#"{"sos":{"alert":"#));
+    assert!(!ws_message.contains("share_"));
+    let ws_message = exact_ws_rx.try_recv().unwrap();
+    assert!(ws_message.starts_with(r#"{"sos":{"alert":"#));
+    assert!(ws_message.contains(share_link));
+    for _ in 0..100 {
+        if !received.lock().unwrap().is_empty() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    let notifications = received.lock().unwrap().clone();
+    assert_eq!(notifications.len(), 1);
+    assert!(notifications[0].contains(r#""title":"Emergency""#));
+    assert!(notifications[0].contains(&format!(
+        "Ivy Emergency is in emergency, follow their position at {}",
+        share_link
+    )));
+    assert!(!notifications[0].contains(r#""share_expires_at""#));
+    assert_eq!(
+        *recorded.lock().unwrap(),
+        vec!["Ivy Emergency is in emergency".to_owned()]
+    );
+
+    // The emergency share link gives access to the positions of the user only
+    let share_token = emergency["share_token"].as_str().unwrap();
+    call_with!(
+        share_token,
+        Method::GET,
+        format!("/api/positions?user_id={}", ivy),
+        "",
+        StatusCode::OK
+    );
+    call_with!(
+        share_token,
+        Method::GET,
+        format!("/api/positions?user_id={}", jack),
+        "",
+        StatusCode::FORBIDDEN
+    );
+    call_with!(
+        share_token,
+        Method::DELETE,
+        sos_uri,
+        "",
+        StatusCode::FORBIDDEN
+    );
+
+    // The device is asked to track in sport mode
+    do_test!(
+        app,
+        Method::GET,
+        &format!("/api/users/{}", ivy),
+        "",
+        StatusCode::OK,
+        format!(
+            r#"{{"id":{},"name":"Ivy","surname":"Emergency","switching_mode":true"#,
+            ivy
+        )
+    );
+    do_test!(
+        app,
+        Method::POST,
+        "/api/positions",
+        &post_position(false, now() - 30000),
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    let body = do_test!(
+        app,
+        Method::GET,
+        &format!("/api/positions?user_id={}", ivy),
+        "",
+        StatusCode::OK,
+        "["
+    );
+    assert_eq!(body.matches(r#""sport_mode":true"#).count(), 1);
+
+    // Only the user or an admin clears the emergency, which ends the sport mode
+    call_with!(
+        jack_session,
+        Method::DELETE,
+        sos_uri,
+        "",
+        StatusCode::FORBIDDEN
+    );
+    let body = do_test!(app, Method::DELETE, &sos_uri, "", StatusCode::OK, "{\"id\"");
+    assert!(!body.contains(r#""cleared_at":null"#));
+    // The emergency share link is given up with the emergency
+    let body = call_with!(
+        share_token,
+        Method::GET,
+        format!("/api/positions?user_id={}", ivy),
+        "",
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(body, "the emergency is over");
+    do_test!(
+        app,
+        Method::DELETE,
+        &sos_uri,
+        "",
+        StatusCode::NOT_FOUND,
+        "user is not in emergency"
+    );
+    do_test!(
+        app,
+        Method::GET,
+        &format!("/api/users/{}", ivy),
+        "",
+        StatusCode::OK,
+        format!(
+            r#"{{"id":{},"name":"Ivy","surname":"Emergency","switching_mode":true"#,
+            ivy
+        )
+    );
+    let mut ws_alerts = Vec::new();
+    while let Ok(msg) = ws_rx.try_recv() {
+        if msg.starts_with(r#"{"alert":"#) {
+            ws_alerts.push(msg);
+        }
+    }
+    assert_eq!(ws_alerts.len(), 1);
+    assert!(ws_alerts[0].contains(r#""kind":"sos""#));
+
+    // Clean up
+    position_server_handle.disconnect(conn_id);
+    position_server_handle.disconnect(exact_conn_id);
+    for uid in [ivy, jack] {
+        do_test!(
+            app,
+            Method::DELETE,
+            &format!("/api/users/{}?cascade=true", uid),
+            "",
+            StatusCode::OK,
+            ""
+        );
+    }
+    http_server_handle.stop(true).await;
+}
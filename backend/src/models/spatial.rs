@@ -0,0 +1,392 @@
+use std::collections::BTreeMap;
+
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, get, web};
+use diesel::dsl::sql;
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use diesel::sql_types::{Bool, Double};
+use serde::Deserialize;
+
+use crate::{
+    app::Credential,
+    db::DbConnection,
+    errors::ServerError,
+    models::{group::request_visibility, position::Position, privacy, sharing_rule::distance},
+    schema::positions,
+    utils::now,
+};
+
+type DbPool = r2d2::Pool<ConnectionManager<DbConnection>>;
+
+const METERS_PER_DEGREE: f64 = 111_320.0;
+const DEFAULT_LIMIT: i64 = 1000;
+const MAX_LIMIT: i64 = 10_000;
+
+// Bounding box, in degrees
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingBox {
+    pub min_lat: f64,
+    pub min_lon: f64,
+    pub max_lat: f64,
+    pub max_lon: f64,
+}
+
+impl BoundingBox {
+    // Get the box grown by a distance on every side
+    pub fn widen(&self, meters: f64) -> BoundingBox {
+        let d_lat = meters / METERS_PER_DEGREE;
+        let min_lat = (self.min_lat - d_lat).max(-90.0);
+        let max_lat = (self.max_lat + d_lat).min(90.0);
+        // the meridians are the closest at the latitude the farthest from the equator
+        let d_lon = d_lat
+            / min_lat
+                .abs()
+                .max(max_lat.abs())
+                .to_radians()
+                .cos()
+                .max(0.01);
+        BoundingBox {
+            min_lat,
+            min_lon: (self.min_lon - d_lon).max(-180.0),
+            max_lat,
+            max_lon: (self.max_lon + d_lon).min(180.0),
+        }
+    }
+}
+
+// Users whose positions are looked for
+#[derive(Debug, Clone, Copy)]
+pub enum Users<'a> {
+    Only(&'a [i32]),
+    Except(&'a [i32]),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Area {
+    Box(BoundingBox),
+    // center and radius in meters
+    Circle { lat: f64, lon: f64, radius: f64 },
+}
+
+impl Area {
+    pub fn bounding_box(&self) -> BoundingBox {
+        match *self {
+            Area::Box(bbox) => bbox,
+            Area::Circle { lat, lon, radius } => {
+                let d_lat = radius / METERS_PER_DEGREE;
+                // the meridians get closer towards the poles
+                let d_lon = d_lat / lat.to_radians().cos().max(0.01);
+                BoundingBox {
+                    min_lat: (lat - d_lat).max(-90.0),
+                    min_lon: (lon - d_lon).max(-180.0),
+                    max_lat: (lat + d_lat).min(90.0),
+                    max_lon: (lon + d_lon).min(180.0),
+                }
+            }
+        }
+    }
+
+    pub fn contains(&self, latitude: f64, longitude: f64) -> bool {
+        match *self {
+            Area::Box(b) => {
+                (b.min_lat..=b.max_lat).contains(&latitude)
+                    && (b.min_lon..=b.max_lon).contains(&longitude)
+            }
+            Area::Circle { lat, lon, radius } => distance(lat, lon, latitude, longitude) <= radius,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct AreaParams {
+    min_lat: Option<f64>,
+    min_lon: Option<f64>,
+    max_lat: Option<f64>,
+    max_lon: Option<f64>,
+    lat: Option<f64>,
+    lon: Option<f64>,
+    radius: Option<f64>,
+    // time range, in milliseconds since the epoch
+    from: Option<i64>,
+    to: Option<i64>,
+    user_id: Option<i32>,
+    // only the latest position of each user
+    #[serde(default)]
+    latest: bool,
+    // maximum number of positions, and the id of the last one of the previous page
+    limit: Option<i64>,
+    before_id: Option<i32>,
+}
+
+impl AreaParams {
+    pub fn area(&self) -> Result<Area, String> {
+        let valid_lat = |v: f64| (-90.0..=90.0).contains(&v);
+        let valid_lon = |v: f64| (-180.0..=180.0).contains(&v);
+        match *self {
+            AreaParams {
+                min_lat: Some(min_lat),
+                min_lon: Some(min_lon),
+                max_lat: Some(max_lat),
+                max_lon: Some(max_lon),
+                lat: None,
+                lon: None,
+                radius: None,
+                ..
+            } => {
+                if !(valid_lat(min_lat) && valid_lat(max_lat) && min_lat <= max_lat) {
+                    return Err("min_lat and max_lat must be ordered latitudes".to_owned());
+                }
+                if !(valid_lon(min_lon) && valid_lon(max_lon) && min_lon <= max_lon) {
+                    return Err("min_lon and max_lon must be ordered longitudes".to_owned());
+                }
+                Ok(Area::Box(BoundingBox {
+                    min_lat,
+                    min_lon,
+                    max_lat,
+                    max_lon,
+                }))
+            }
+            AreaParams {
+                min_lat: None,
+                min_lon: None,
+                max_lat: None,
+                max_lon: None,
+                lat: Some(lat),
+                lon: Some(lon),
+                radius: Some(radius),
+                ..
+            } => {
+                if !(valid_lat(lat) && valid_lon(lon)) {
+                    return Err("lat and lon must be a latitude and a longitude".to_owned());
+                }
+                if !(radius > 0.0 && radius.is_finite()) {
+                    return Err("radius must be a positive number of meters".to_owned());
+                }
+                Ok(Area::Circle { lat, lon, radius })
+            }
+            _ => Err(
+                "the area must be given either by min_lat, min_lon, max_lat and max_lon, or by lat, lon and radius"
+                    .to_owned(),
+            ),
+        }
+    }
+}
+
+// Plausible positions inside a bounding box over a time range, most recent first, before a (time,
+// id) cursor if any: the spatial index selects the candidates, the exact coordinates being checked
+// afterwards
+pub fn load_in_box(
+    conn: &mut DbConnection,
+    bbox: BoundingBox,
+    from: Option<i64>,
+    before: Option<(i64, i32)>,
+    users: Users,
+    limit: i64,
+) -> QueryResult<Vec<Position>> {
+    let mut query = positions::table
+        .filter(positions::latitude.between(bbox.min_lat, bbox.max_lat))
+        .filter(positions::longitude.between(bbox.min_lon, bbox.max_lon))
+        .filter(positions::flagged.is_null())
+        .order((positions::time.desc(), positions::id.desc()))
+        .limit(limit)
+        .into_boxed();
+    query = match conn {
+        // R*Tree virtual table, maintained by triggers
+        DbConnection::Sqlite(_) => query.filter(
+            sql::<Bool>("positions.id IN (SELECT id FROM positions_rtree WHERE max_lat >= ")
+                .bind::<Double, _>(bbox.min_lat)
+                .sql(" AND min_lat <= ")
+                .bind::<Double, _>(bbox.max_lat)
+                .sql(" AND max_lon >= ")
+                .bind::<Double, _>(bbox.min_lon)
+                .sql(" AND min_lon <= ")
+                .bind::<Double, _>(bbox.max_lon)
+                .sql(")"),
+        ),
+        // GiST index on the location point
+        DbConnection::Postgresql(_) => query.filter(
+            sql::<Bool>("point(positions.longitude, positions.latitude) <@ box(point(")
+                .bind::<Double, _>(bbox.min_lon)
+                .sql(", ")
+                .bind::<Double, _>(bbox.min_lat)
+                .sql("), point(")
+                .bind::<Double, _>(bbox.max_lon)
+                .sql(", ")
+                .bind::<Double, _>(bbox.max_lat)
+                .sql("))"),
+        ),
+    };
+    if let Some(from) = from {
+        query = query.filter(positions::time.ge(from));
+    }
+    if let Some((time, id)) = before {
+        query = query.filter(
+            positions::time
+                .lt(time)
+                .or(positions::time.eq(time).and(positions::id.lt(id))),
+        );
+    }
+    query = match users {
+        Users::Only(uids) => query.filter(positions::user_id.eq_any(uids)),
+        Users::Except(uids) => query.filter(positions::user_id.ne_all(uids)),
+    };
+    query.load::<Position>(conn)
+}
+
+// Positions inside an area, given by a bounding box or by a center and a radius, over an optional
+// time range. The positions are served as shared by the privacy policies of their users, so a
+// blurred position is returned if its blurred location is inside the area. At most `limit`
+// positions are returned, the next ones are got by giving the time and the id of the last one as
+// `to` and `before_id`.
+#[get("/area")]
+pub async fn read_area(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    params: web::Query<AreaParams>,
+) -> Result<HttpResponse, ServerError> {
+    let mut params = params.into_inner();
+    let area = match params.area() {
+        Ok(area) => area,
+        Err(e) => return Ok(HttpResponse::BadRequest().body(e)),
+    };
+    let visibility = request_visibility(&req, &pool).await?;
+    let credential = req.extensions().get::<Credential>().copied();
+    // A share token gives access to its own user only
+    if let Some(Credential::Share(id)) = credential {
+        params.user_id = Some(i32::from(id));
+    }
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let mut conn = pool.get()?;
+    let objects = web::block(move || {
+        let policies = privacy::load_all(&mut conn)?;
+        // The blurred positions are matched on their blurred coordinates only, so their exact
+        // ones are looked for in a box grown by the blur
+        let mut blurred: BTreeMap<u64, Vec<i32>> = BTreeMap::new();
+        for (uid, policy) in &policies {
+            if let Some(cell_km) = policy.blur_km()
+                && !credential.is_some_and(|c| c.owns(*uid))
+                && params.user_id.is_none_or(|id| id == *uid)
+            {
+                blurred.entry(cell_km.to_bits()).or_default().push(*uid);
+            }
+        }
+        let all_blurred: Vec<i32> = blurred.values().flatten().copied().collect();
+        let bbox = area.bounding_box();
+        let mut queries = vec![(
+            bbox,
+            match &params.user_id {
+                Some(uid) if all_blurred.contains(uid) => Users::Only(&[]),
+                Some(uid) => Users::Only(std::slice::from_ref(uid)),
+                None => Users::Except(&all_blurred),
+            },
+        )];
+        for (cell_km, uids) in &blurred {
+            queries.push((
+                bbox.widen(f64::from_bits(*cell_km) * 1000.0),
+                Users::Only(uids),
+            ));
+        }
+        // Load the candidates a page at a time, until enough of them are served inside the area
+        let now = now();
+        let mut cursor = params
+            .to
+            .map(|to| (to, params.before_id.unwrap_or(i32::MAX)));
+        let mut seen_users = std::collections::HashSet::new();
+        let mut objects = Vec::new();
+        loop {
+            let mut candidates = Vec::new();
+            for (bbox, users) in &queries {
+                candidates.extend(load_in_box(
+                    &mut conn,
+                    *bbox,
+                    params.from,
+                    cursor,
+                    *users,
+                    limit,
+                )?);
+            }
+            candidates.sort_by_key(|o| std::cmp::Reverse((o.time, o.id)));
+            let exhausted = candidates.len() < limit as usize;
+            candidates.truncate(limit as usize);
+            cursor = candidates.last().map(|o| (o.time, o.id));
+            objects.extend(
+                candidates
+                    .into_iter()
+                    .filter(|o| visibility.allows(o.user_id))
+                    .filter_map(|o| {
+                        privacy::serve(credential.as_ref(), policies.get(&o.user_id), o, now)
+                            .position()
+                    })
+                    .filter(|o| area.contains(o.latitude, o.longitude))
+                    .filter(|o| !params.latest || seen_users.insert(o.user_id)),
+            );
+            if exhausted || objects.len() >= limit as usize {
+                break;
+            }
+        }
+        objects.truncate(limit as usize);
+        QueryResult::Ok(objects)
+    })
+    .await??;
+    Ok(HttpResponse::Ok().json(objects))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_area() {
+        let params = |query: &str| {
+            web::Query::<AreaParams>::from_query(query)
+                .unwrap()
+                .into_inner()
+                .area()
+        };
+        let area = params("min_lat=45&min_lon=4&max_lat=46&max_lon=5").unwrap();
+        assert!(area.contains(45.5, 4.5));
+        assert!(!area.contains(45.5, 5.5));
+        assert_eq!(
+            area.bounding_box(),
+            BoundingBox {
+                min_lat: 45.0,
+                min_lon: 4.0,
+                max_lat: 46.0,
+                max_lon: 5.0
+            }
+        );
+
+        // The bounding box of a circle contains it, and is wider than high away from the equator
+        let area = params("lat=45&lon=4&radius=1000").unwrap();
+        let bbox = area.bounding_box();
+        assert!((bbox.max_lat - 45.0 - 1000.0 / METERS_PER_DEGREE).abs() < 1e-9);
+        assert!(bbox.max_lon - 4.0 > bbox.max_lat - 45.0);
+        assert!(area.contains(45.0 + 900.0 / METERS_PER_DEGREE, 4.0));
+        assert!(!area.contains(45.0 + 1100.0 / METERS_PER_DEGREE, 4.0));
+        // the corners of the bounding box are outside of the circle
+        assert!(!area.contains(bbox.max_lat, bbox.max_lon));
+        // widening it by the radius again doubles it
+        let widened = bbox.widen(1000.0);
+        assert!((widened.max_lat - 45.0 - 2000.0 / METERS_PER_DEGREE).abs() < 1e-9);
+        assert!(widened.max_lon - 4.0 > 2.0 * (bbox.max_lon - 4.0));
+
+        assert_eq!(
+            params("min_lat=46&min_lon=4&max_lat=45&max_lon=5").unwrap_err(),
+            "min_lat and max_lat must be ordered latitudes"
+        );
+        assert_eq!(
+            params("lat=45&lon=4&radius=-1").unwrap_err(),
+            "radius must be a positive number of meters"
+        );
+        assert!(
+            params("lat=45&lon=4")
+                .unwrap_err()
+                .starts_with("the area must be given")
+        );
+        assert!(
+            params("lat=45&lon=4&radius=10&max_lat=46")
+                .unwrap_err()
+                .starts_with("the area must be given")
+        );
+    }
+}
@@ -1,9 +1,14 @@
 use crate::app::AppConfig;
 use crate::errors::ServerError;
-use actix_web::{HttpResponse, post, web};
+use crate::models::audit::NewAuditEntry;
+use actix_web::{HttpRequest, HttpResponse, post, web};
+
+type DbPool = r2d2::Pool<diesel::r2d2::ConnectionManager<crate::db::DbConnection>>;
 
 #[post("/toggle/{user_id}")]
 pub async fn toggle_sport_mode(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
     cfg: web::Data<AppConfig>,
     user_id: web::Path<i32>,
 ) -> Result<HttpResponse, ServerError> {
@@ -12,5 +17,22 @@ pub async fn toggle_sport_mode(
     if !sport_mode_toggle_users.contains(&uid) {
         sport_mode_toggle_users.push(uid);
     }
+    NewAuditEntry::new(&req, "sport_mode.toggle")
+        .target(format!("users/{}", uid))
+        .record(&pool)
+        .await?;
     Ok(HttpResponse::Ok().body(format!("User {} added to sport mode toggle list", uid)))
 }
+
+// Request the device of a user to switch its sport mode on or off, with the next position it sends
+pub async fn request_sport_mode(cfg: &AppConfig, uid: i32, current: bool, wanted: bool) {
+    let mut sport_mode_toggle_users = cfg.sport_mode_toggle_users.lock().await;
+    let pending = sport_mode_toggle_users.contains(&uid);
+    // a pending toggle inverts the current mode
+    if (current != pending) != wanted {
+        match pending {
+            true => sport_mode_toggle_users.retain(|&x| x != uid),
+            false => sport_mode_toggle_users.push(uid),
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use crate::{app::AppConfig, create_app, positions_server::PositionsServerHandle};
 
 pub async fn toggle_sport_mode_test(
-    pool: &r2d2::Pool<diesel::r2d2::ConnectionManager<diesel::SqliteConnection>>,
+    pool: &r2d2::Pool<diesel::r2d2::ConnectionManager<crate::db::DbConnection>>,
     app_config: &actix_web::web::Data<AppConfig>,
     position_server_handle: &PositionsServerHandle,
 ) {
@@ -17,7 +17,7 @@ pub async fn toggle_sport_mode_test(
     do_test!(
         app,
         Method::DELETE,
-        "/api/users",
+        "/api/users?confirm=users",
         "",
         StatusCode::OK,
         "Deleted all objects"
@@ -130,7 +130,7 @@ pub async fn toggle_sport_mode_test(
     do_test!(
         app,
         Method::DELETE,
-        "/api/positions",
+        "/api/positions?confirm=positions",
         "",
         StatusCode::OK,
         "Deleted all objects"
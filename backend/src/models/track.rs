@@ -0,0 +1,469 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
+
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, get, web};
+use chrono::DateTime;
+use chrono_tz::Tz;
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    app::{AppConfig, Credential},
+    config::Config,
+    db::DbConnection,
+    errors::ServerError,
+    models::{
+        group::request_visibility, position::Position, privacy, sharing_rule::EARTH_RADIUS_METERS,
+    },
+    schema::{daily_tracks, positions},
+    utils::now,
+};
+
+type DbPool = r2d2::Pool<ConnectionManager<DbConnection>>;
+
+// Reduction of a track, the options apply in turn and are all optional
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+pub struct Simplification {
+    // Douglas-Peucker tolerance, in meters
+    pub tolerance: Option<f64>,
+    // keep the first position of each bucket of that many seconds
+    pub bucket: Option<u64>,
+    // keep the most significant positions only
+    pub max_points: Option<usize>,
+}
+
+impl Simplification {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.tolerance.is_some_and(|t| !(t >= 0.0 && t.is_finite())) {
+            return Err("tolerance must be a positive number of meters".to_owned());
+        }
+        if self.bucket == Some(0) {
+            return Err("bucket must be a positive number of seconds".to_owned());
+        }
+        if self.max_points.is_some_and(|m| m < 2) {
+            return Err("max_points must be at least 2".to_owned());
+        }
+        Ok(())
+    }
+
+    pub fn is_none(&self) -> bool {
+        self.tolerance.is_none() && self.bucket.is_none() && self.max_points.is_none()
+    }
+
+    // Simplify a track, the positions are returned in chronological order
+    pub fn apply(&self, mut track: Vec<Position>) -> Vec<Position> {
+        if self.is_none() {
+            return track;
+        }
+        track.sort_by_key(|p| (p.time, p.id));
+        if let Some(bucket) = self.bucket {
+            let bucket = i64::try_from(bucket.saturating_mul(1000)).unwrap_or(i64::MAX);
+            let mut last_bucket = None;
+            track.retain(|p| {
+                let b = p.time.div_euclid(bucket);
+                last_bucket.replace(b) != Some(b)
+            });
+        }
+        if self.tolerance.is_none() && self.max_points.is_none_or(|m| track.len() <= m) {
+            return track;
+        }
+        let significance = significance(&track);
+        let mut threshold = self.tolerance.unwrap_or(-1.0);
+        if let Some(max_points) = self.max_points
+            && track.len() > max_points
+        {
+            let mut sorted = significance.clone();
+            sorted.sort_by(|a, b| b.total_cmp(a));
+            // the positions as significant as the last one kept are dropped with it
+            threshold = threshold.max(sorted[max_points]);
+        }
+        track
+            .into_iter()
+            .zip(significance)
+            .filter(|(_, s)| *s > threshold)
+            .map(|(p, _)| p)
+            .collect()
+    }
+}
+
+// Significance of each position of a track for the Douglas-Peucker algorithm: a position is kept
+// with a tolerance if and only if its significance is greater, the ends are always kept
+fn significance(track: &[Position]) -> Vec<f64> {
+    let mut significance = vec![0.0; track.len()];
+    if let (Some(first), Some(last)) = (significance.first_mut(), track.len().checked_sub(1)) {
+        *first = f64::INFINITY;
+        significance[last] = f64::INFINITY;
+    }
+    // the segments to split, with the significance of the position they were split at
+    let mut segments = vec![(0, track.len().saturating_sub(1), f64::INFINITY)];
+    while let Some((start, end, parent)) = segments.pop() {
+        if end <= start + 1 {
+            continue;
+        }
+        let (farthest, max) = (start + 1..end)
+            .map(|i| (i, segment_distance(&track[i], &track[start], &track[end])))
+            .fold((start + 1, f64::NEG_INFINITY), |acc, x| {
+                if x.1 > acc.1 { x } else { acc }
+            });
+        // the algorithm stops splitting at a segment, whatever is farther inside
+        let s = max.min(parent);
+        significance[farthest] = s;
+        segments.push((start, farthest, s));
+        segments.push((farthest, end, s));
+    }
+    significance
+}
+
+// Distance in meters from a position to a segment, on the plane tangent at its start
+fn segment_distance(p: &Position, a: &Position, b: &Position) -> f64 {
+    let scale = a.latitude.to_radians().cos();
+    let project = |q: &Position| {
+        (
+            (q.longitude - a.longitude).to_radians() * scale * EARTH_RADIUS_METERS,
+            (q.latitude - a.latitude).to_radians() * EARTH_RADIUS_METERS,
+        )
+    };
+    let ((px, py), (bx, by)) = (project(p), project(b));
+    let length = bx * bx + by * by;
+    let t = if length > 0.0 {
+        ((px * bx + py * by) / length).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    (px - t * bx).hypot(py - t * by)
+}
+
+// Simplified track of a user for a day of their timezone
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, Insertable, AsChangeset)]
+#[diesel(table_name = daily_tracks)]
+pub struct DailyTrack {
+    pub user_id: i32,
+    // local date, as YYYY-MM-DD
+    pub day: String,
+    // JSON array of the positions kept
+    pub positions: String,
+    // how many positions were recorded
+    pub raw_count: i32,
+    // time of the last position taken into account
+    pub last_time: i64,
+}
+
+fn local_day(time: i64, timezone: Tz) -> String {
+    DateTime::from_timestamp_millis(time)
+        .unwrap_or_default()
+        .with_timezone(&timezone)
+        .format("%Y-%m-%d")
+        .to_string()
+}
+
+// Add the positions recorded since the last update to the daily tracks, returns how many were
+// added. The positions older than the last one taken into account for a user are left out.
+pub fn update_tracks(conn: &mut DbConnection, tolerance: f64) -> QueryResult<usize> {
+    let policies = privacy::load_all(conn)?;
+    let simplification = Simplification {
+        tolerance: Some(tolerance),
+        ..Default::default()
+    };
+    let mut added = 0;
+    for (uid, policy) in policies {
+        added += conn.transaction(|conn| {
+            let last_time = daily_tracks::table
+                .filter(daily_tracks::user_id.eq(uid))
+                .select(diesel::dsl::max(daily_tracks::last_time))
+                .first::<Option<i64>>(conn)?
+                .unwrap_or(i64::MIN);
+            let recorded = positions::table
+                .filter(positions::user_id.eq(uid))
+                .filter(positions::time.gt(last_time))
+                .filter(positions::flagged.is_null())
+                .order((positions::time.asc(), positions::id.asc()))
+                .load::<Position>(conn)?;
+            let count = recorded.len();
+            let mut days: BTreeMap<String, Vec<Position>> = BTreeMap::new();
+            for position in recorded {
+                days.entry(local_day(position.time, policy.timezone))
+                    .or_default()
+                    .push(position);
+            }
+            for (day, recorded) in days {
+                let previous = daily_tracks::table
+                    .find((uid, &day))
+                    .first::<DailyTrack>(conn)
+                    .optional()?;
+                let (mut track, raw_count) = match previous {
+                    Some(previous) => (
+                        serde_json::from_str::<Vec<Position>>(&previous.positions)
+                            .unwrap_or_default(),
+                        previous.raw_count,
+                    ),
+                    None => (Vec::new(), 0),
+                };
+                let raw_count = raw_count + recorded.len() as i32;
+                let last_time = recorded.last().map(|p| p.time).unwrap_or_default();
+                track.extend(recorded);
+                let track = DailyTrack {
+                    user_id: uid,
+                    day: day.clone(),
+                    positions: serde_json::to_string(&simplification.apply(track))
+                        .expect("the positions are always serializable"),
+                    raw_count,
+                    last_time,
+                };
+                crate::replace_into!(
+                    conn,
+                    daily_tracks::table,
+                    daily_tracks::user_id
+                        .eq(uid)
+                        .and(daily_tracks::day.eq(&day)),
+                    &track
+                )?;
+            }
+            QueryResult::Ok(count)
+        })?;
+    }
+    Ok(added)
+}
+
+// Remove deleted positions from the daily tracks they were added to, the tracks left empty are
+// deleted
+pub fn forget(conn: &mut DbConnection, deleted: &[Position]) -> QueryResult<()> {
+    let mut by_user: HashMap<i32, Vec<&Position>> = HashMap::new();
+    for position in deleted {
+        by_user.entry(position.user_id).or_default().push(position);
+    }
+    for (uid, deleted) in by_user {
+        let timezone = privacy::load(conn, uid)?.timezone;
+        let mut days: BTreeMap<String, Vec<&Position>> = BTreeMap::new();
+        for position in deleted {
+            days.entry(local_day(position.time, timezone))
+                .or_default()
+                .push(position);
+        }
+        for (day, deleted) in days {
+            let Some(mut track) = daily_tracks::table
+                .find((uid, &day))
+                .first::<DailyTrack>(conn)
+                .optional()?
+            else {
+                continue;
+            };
+            // only the plausible positions already taken into account were counted
+            let counted = deleted
+                .iter()
+                .filter(|p| p.flagged.is_none() && p.time <= track.last_time)
+                .count();
+            let ids: HashSet<i32> = deleted.iter().map(|p| p.id).collect();
+            let mut positions =
+                serde_json::from_str::<Vec<Position>>(&track.positions).unwrap_or_default();
+            positions.retain(|p| !ids.contains(&p.id));
+            track.raw_count -= counted as i32;
+            let target = daily_tracks::table.find((uid, &day));
+            if positions.is_empty() || track.raw_count <= 0 {
+                diesel::delete(target).execute(conn)?;
+                continue;
+            }
+            track.positions =
+                serde_json::to_string(&positions).expect("the positions are always serializable");
+            diesel::update(target).set(&track).execute(conn)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn purge_expired(conn: &mut DbConnection, retention: Duration) -> QueryResult<usize> {
+    if retention.is_zero() {
+        return Ok(0);
+    }
+    let limit = now() - i64::try_from(retention.as_millis()).unwrap_or(i64::MAX / 2);
+    diesel::delete(daily_tracks::table)
+        .filter(daily_tracks::last_time.lt(limit))
+        .execute(conn)
+}
+
+// Keep the daily tracks up to date, far more often than the positions are purged
+pub async fn watch(pool: DbPool, config: Config) {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.track_interval));
+    loop {
+        interval.tick().await;
+        let (pool, tolerance, retention) = (
+            pool.clone(),
+            config.track_tolerance,
+            config.track_retention(),
+        );
+        let update = web::block(move || -> Result<usize, ServerError> {
+            let mut conn = pool.get()?;
+            purge_expired(&mut conn, retention)?;
+            Ok(update_tracks(&mut conn, tolerance)?)
+        })
+        .await;
+        match update.map_err(ServerError::from).and_then(|u| u) {
+            Ok(added) => log::debug!("{} positions added to the daily tracks", added),
+            Err(e) => log::error!("daily tracks update failed: {}", e),
+        }
+    }
+}
+
+// Daily track as served, with the positions shared by the privacy policy of the user
+#[derive(Debug, Serialize)]
+pub struct ServedTrack {
+    pub user_id: i32,
+    pub day: String,
+    pub raw_count: i32,
+    pub positions: Vec<Position>,
+}
+
+#[derive(Deserialize)]
+pub struct TracksParams {
+    user_id: i32,
+    // first and last days, as YYYY-MM-DD
+    from: Option<String>,
+    to: Option<String>,
+}
+
+#[get("/tracks")]
+pub async fn read_tracks(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    cfg: web::Data<AppConfig>,
+    params: web::Query<TracksParams>,
+    simplification: web::Query<Simplification>,
+) -> Result<HttpResponse, ServerError> {
+    let (params, simplification) = (params.into_inner(), simplification.into_inner());
+    if let Err(e) = simplification.validate() {
+        return Ok(HttpResponse::BadRequest().body(e));
+    }
+    if !request_visibility(&req, &pool)
+        .await?
+        .allows(params.user_id)
+    {
+        return Ok(HttpResponse::Forbidden().body("user is outside of your groups"));
+    }
+    let credential = req.extensions().get::<Credential>().copied();
+    let retention = cfg.config.track_retention();
+    let mut conn = pool.get()?;
+    let (tracks, policy) = web::block(move || {
+        purge_expired(&mut conn, retention)?;
+        let mut query = daily_tracks::table
+            .filter(daily_tracks::user_id.eq(params.user_id))
+            .order(daily_tracks::day.asc())
+            .into_boxed();
+        if let Some(from) = params.from {
+            query = query.filter(daily_tracks::day.ge(from));
+        }
+        if let Some(to) = params.to {
+            query = query.filter(daily_tracks::day.le(to));
+        }
+        let tracks = query.load::<DailyTrack>(&mut conn)?;
+        let policy = privacy::load(&mut conn, params.user_id)?;
+        QueryResult::Ok((tracks, policy))
+    })
+    .await??;
+    let now = now();
+    let tracks: Vec<ServedTrack> = tracks
+        .into_iter()
+        .map(|track| {
+            let positions = serde_json::from_str::<Vec<Position>>(&track.positions)?
+                .into_iter()
+                .filter_map(|o| {
+                    privacy::serve(credential.as_ref(), Some(&policy), o, now).position()
+                })
+                .collect();
+            Ok(ServedTrack {
+                user_id: track.user_id,
+                day: track.day,
+                raw_count: track.raw_count,
+                positions: simplification.apply(positions),
+            })
+        })
+        .collect::<Result<_, serde_json::Error>>()?;
+    Ok(HttpResponse::Ok().json(tracks))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // positions one second apart, along a line going north with a detour to the east
+    fn track() -> Vec<Position> {
+        [
+            (45.0, 4.0),
+            (45.0001, 4.0),
+            (45.0002, 4.00005),
+            (45.0003, 4.0),
+            (45.0004, 4.0),
+            (45.0005, 4.001),
+            (45.0006, 4.0),
+            (45.0007, 4.0),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, (latitude, longitude))| Position {
+            id: i as i32,
+            user_id: 1,
+            latitude,
+            longitude,
+            source: "GPS".to_owned(),
+            battery_level: 50,
+            sport_mode: true,
+            time: i as i64 * 1000,
+            flagged: None,
+        })
+        .collect()
+    }
+
+    fn ids(track: Vec<Position>) -> Vec<i32> {
+        track.into_iter().map(|p| p.id).collect()
+    }
+
+    #[test]
+    fn test_simplify() {
+        let simplify = |tolerance, bucket, max_points| {
+            ids(Simplification {
+                tolerance,
+                bucket,
+                max_points,
+            }
+            .apply(track()))
+        };
+        assert_eq!(simplify(None, None, None), (0..8).collect::<Vec<_>>());
+        // the detour is about 80 m wide, the small one 4 m wide
+        assert_eq!(simplify(Some(2.5), None, None), vec![0, 2, 4, 5, 6, 7]);
+        assert_eq!(simplify(Some(10.0), None, None), vec![0, 4, 5, 6, 7]);
+        assert_eq!(simplify(Some(20.0), None, None), vec![0, 4, 5, 7]);
+        assert_eq!(simplify(Some(100.0), None, None), vec![0, 7]);
+        assert_eq!(simplify(None, Some(3), None), vec![0, 3, 6]);
+        assert_eq!(simplify(None, None, Some(3)), vec![0, 5, 7]);
+        assert_eq!(simplify(None, None, Some(2)), vec![0, 7]);
+        assert_eq!(simplify(None, Some(2), Some(3)), vec![0, 2, 6]);
+        // the positions are put in chronological order
+        let mut reversed = track();
+        reversed.reverse();
+        assert_eq!(
+            ids(Simplification {
+                max_points: Some(3),
+                ..Default::default()
+            }
+            .apply(reversed)),
+            vec![0, 5, 7]
+        );
+
+        assert!(
+            Simplification {
+                max_points: Some(1),
+                ..Default::default()
+            }
+            .validate()
+            .is_err()
+        );
+        assert!(
+            Simplification {
+                tolerance: Some(f64::NAN),
+                ..Default::default()
+            }
+            .validate()
+            .is_err()
+        );
+        assert!(segment_distance(&track()[5], &track()[4], &track()[6]) > 75.0);
+    }
+}
@@ -0,0 +1,246 @@
+use std::time::Duration;
+
+use crate::{
+    app::AppConfig,
+    create_app,
+    models::track::{purge_expired, update_tracks},
+    positions_server::PositionsServerHandle,
+};
+
+pub async fn track_test(
+    pool: &r2d2::Pool<diesel::r2d2::ConnectionManager<crate::db::DbConnection>>,
+    app_config: &actix_web::web::Data<AppConfig>,
+    position_server_handle: &PositionsServerHandle,
+) {
+    use crate::{do_test, do_test_extract_id};
+    use actix_web::{
+        http::{Method, StatusCode},
+        test,
+    };
+
+    let mut app = test::init_service(create_app!(pool, app_config, position_server_handle)).await;
+
+    let user_id = do_test_extract_id!(
+        app,
+        Method::POST,
+        "/api/users",
+        r#"{"name":"Tracked","surname":"User"}"#,
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+
+    // A straight line going north, one position per second, with an 80 m detour to the east
+    let hour = 3600 * 1000;
+    let start = (crate::utils::now() - 2 * hour) / hour * hour;
+    let positions = |from: i64, count: i64| {
+        (from..from + count)
+            .map(|i| {
+                format!(
+                    r#"{{"user_id":{},"latitude":{},"longitude":{},"source":"GPS","battery_level":50,"sport_mode":true,"time":{}}}"#,
+                    user_id,
+                    45.0 + i as f64 * 0.0001,
+                    if i == 5 { 4.001 } else { 4.0 },
+                    start + i * 1000
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+    do_test!(
+        app,
+        Method::POST,
+        "/api/positions",
+        &format!("[{}]", positions(0, 10)),
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+
+    // The tracks are simplified on demand
+    let count = |body: String| body.matches(r#""id":"#).count();
+    let uri = |query: &str| format!("/api/positions?user_id={}{}", user_id, query);
+    let body = do_test!(app, Method::GET, &uri(""), "", StatusCode::OK, "[");
+    assert_eq!(count(body), 10);
+    let body = do_test!(
+        app,
+        Method::GET,
+        &uri("&tolerance=20"),
+        "",
+        StatusCode::OK,
+        "["
+    );
+    assert_eq!(count(body), 5);
+    let body = do_test!(
+        app,
+        Method::GET,
+        &uri("&tolerance=100"),
+        "",
+        StatusCode::OK,
+        "["
+    );
+    assert_eq!(count(body), 2);
+    let body = do_test!(app, Method::GET, &uri("&bucket=4"), "", StatusCode::OK, "[");
+    assert_eq!(count(body), 3);
+    let body = do_test!(
+        app,
+        Method::GET,
+        &uri("&max_points=3"),
+        "",
+        StatusCode::OK,
+        "["
+    );
+    assert!(body.contains(r#""longitude":4.001"#));
+    assert_eq!(count(body), 3);
+    do_test!(
+        app,
+        Method::GET,
+        &uri("&max_points=1"),
+        "",
+        StatusCode::BAD_REQUEST,
+        "max_points must be at least 2"
+    );
+    do_test!(
+        app,
+        Method::GET,
+        &uri("&tolerance=far"),
+        "",
+        StatusCode::BAD_REQUEST,
+        "Query deserialize error"
+    );
+
+    // The daily tracks are simplified as the positions come
+    let mut conn = pool.get().unwrap();
+    assert!(update_tracks(&mut conn, 10.0).unwrap() >= 10);
+    assert_eq!(update_tracks(&mut conn, 10.0).unwrap(), 0);
+    do_test!(
+        app,
+        Method::POST,
+        "/api/positions",
+        &format!("[{}]", positions(10, 10)),
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    assert!(update_tracks(&mut conn, 10.0).unwrap() >= 10);
+    let day = chrono::DateTime::from_timestamp_millis(start)
+        .unwrap()
+        .format("%Y-%m-%d")
+        .to_string();
+    let body = do_test!(
+        app,
+        Method::GET,
+        &format!("/api/positions/tracks?user_id={}", user_id),
+        "",
+        StatusCode::OK,
+        format!(
+            r#"[{{"user_id":{},"day":"{}","raw_count":20,"positions":["#,
+            user_id, day
+        )
+    );
+    assert_eq!(count(body), 5);
+    let body = do_test!(
+        app,
+        Method::GET,
+        &format!("/api/positions/tracks?user_id={}&max_points=2", user_id),
+        "",
+        StatusCode::OK,
+        "["
+    );
+    assert_eq!(count(body), 2);
+    do_test!(
+        app,
+        Method::GET,
+        &format!("/api/positions/tracks?user_id={}&to=2000-01-01", user_id),
+        "",
+        StatusCode::OK,
+        "[]"
+    );
+
+    // The deleted positions are removed from the daily tracks
+    let body = do_test!(app, Method::GET, &uri(""), "", StatusCode::OK, "[");
+    let detour = serde_json::from_str::<Vec<serde_json::Value>>(&body)
+        .unwrap()
+        .into_iter()
+        .find(|p| p["longitude"] == 4.001)
+        .unwrap()["id"]
+        .clone();
+    do_test!(
+        app,
+        Method::DELETE,
+        &format!("/api/positions/{}", detour),
+        "",
+        StatusCode::OK,
+        "Deleted object"
+    );
+    let tracks_uri = format!("/api/positions/tracks?user_id={}", user_id);
+    let body = do_test!(
+        app,
+        Method::GET,
+        &tracks_uri,
+        "",
+        StatusCode::OK,
+        format!(
+            r#"[{{"user_id":{},"day":"{}","raw_count":19,"positions":["#,
+            user_id, day
+        )
+    );
+    assert!(!body.contains(r#""longitude":4.001"#));
+
+    // The daily tracks go with their user
+    do_test!(
+        app,
+        Method::DELETE,
+        &format!("/api/users/{}", user_id),
+        "",
+        StatusCode::CONFLICT,
+        r#"{"dependents":{"daily_tracks":1"#
+    );
+
+    // They are kept for their own retention, beyond the positions
+    assert_eq!(purge_expired(&mut conn, Duration::ZERO).unwrap(), 0);
+    purge_expired(&mut conn, Duration::from_secs(86400)).unwrap();
+    do_test!(
+        app,
+        Method::GET,
+        &tracks_uri,
+        "",
+        StatusCode::OK,
+        format!(r#"[{{"user_id":{},"day":"{}""#, user_id, day)
+    );
+    assert!(purge_expired(&mut conn, Duration::from_secs(3600)).unwrap() >= 1);
+    do_test!(app, Method::GET, &tracks_uri, "", StatusCode::OK, "[]");
+
+    // Deleting all the positions deletes the daily tracks made of them
+    assert!(update_tracks(&mut conn, 10.0).unwrap() >= 19);
+    do_test!(
+        app,
+        Method::GET,
+        &tracks_uri,
+        "",
+        StatusCode::OK,
+        format!(r#"[{{"user_id":{},"day":"{}""#, user_id, day)
+    );
+    do_test!(
+        app,
+        Method::DELETE,
+        "/api/positions?confirm=positions",
+        "",
+        StatusCode::OK,
+        "Deleted all objects"
+    );
+    do_test!(app, Method::GET, &tracks_uri, "", StatusCode::OK, "[]");
+    do_test!(
+        app,
+        Method::DELETE,
+        &format!("/api/users/{}?cascade=true", user_id),
+        "",
+        StatusCode::OK,
+        "Deleted object"
+    );
+    do_test!(
+        app,
+        Method::GET,
+        &format!("/api/positions/tracks?user_id={}", user_id),
+        "",
+        StatusCode::OK,
+        "[]"
+    );
+}
@@ -0,0 +1,259 @@
+use std::{collections::HashSet, time::Duration};
+
+use actix_web::{HttpRequest, HttpResponse, delete, get, post, web};
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    app::AppConfig,
+    config::Config,
+    db::DbConnection,
+    errors::ServerError,
+    models::{
+        alert::{Alert, AlertSettings},
+        audit::NewAuditEntry,
+        device_status::DeviceStatus,
+        group::{Group, GroupMember},
+        position::Position,
+        privacy::PrivacySettings,
+        push_subscription::PushSubscription,
+        sharing_rule::SharingRule,
+        track::DailyTrack,
+        user::{self, User},
+    },
+    schema::{
+        alert_settings, alerts, daily_tracks, device_status, group_members, positions,
+        privacy_settings, push_subscriptions, sharing_rules, trash, user_groups, users,
+    },
+    utils::now,
+};
+
+type DbPool = r2d2::Pool<ConnectionManager<DbConnection>>;
+
+// Deleted objects, kept by table name until they are restored or purged
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable)]
+pub struct TrashEntry {
+    pub id: i32,
+    pub time: i64,
+    pub actor: String,
+    pub target: String,
+    pub content: String,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = trash)]
+struct NewTrashEntry<'a> {
+    time: i64,
+    actor: &'a str,
+    target: &'a str,
+    content: String,
+}
+
+// Put deleted objects in the trash, returns the trash entry id, or none if the trash is disabled
+pub fn put(
+    conn: &mut DbConnection,
+    purge_delay: Duration,
+    actor: &str,
+    target: &str,
+    content: &serde_json::Value,
+) -> Result<Option<i32>, ServerError> {
+    if purge_delay.is_zero() {
+        return Ok(None);
+    }
+    purge_expired(conn, purge_delay)?;
+    diesel::insert_into(trash::table)
+        .values(&NewTrashEntry {
+            time: now(),
+            actor,
+            target,
+            content: serde_json::to_string(content)?,
+        })
+        .execute(conn)?;
+    let trash_id = trash::table
+        .select(trash::id)
+        .order(trash::id.desc())
+        .first::<i32>(conn)?;
+    Ok(Some(trash_id))
+}
+
+pub fn purge_expired(conn: &mut DbConnection, purge_delay: Duration) -> QueryResult<usize> {
+    let limit = now() - i64::try_from(purge_delay.as_millis()).unwrap_or(i64::MAX / 2);
+    diesel::delete(trash::table)
+        .filter(trash::time.lt(limit))
+        .execute(conn)
+}
+
+// Insert the objects of a trash entry back, with their original ids
+fn restore_content(
+    conn: &mut DbConnection,
+    config: &Config,
+    content: &str,
+) -> Result<usize, ServerError> {
+    let content: serde_json::Value = serde_json::from_str(content)?;
+    let mut restored = 0;
+    let mut avatars = Vec::new();
+    // Parents first
+    if let Some(v) = content.get("users") {
+        let mut objects: Vec<User> = serde_json::from_value(v.clone())?;
+        // the avatars were moved aside along with their users, unless they were purged since
+        for user in &mut objects {
+            if !user::has_trashed_avatar(config, user.id) {
+                user.avatar_updated_at = None;
+            }
+        }
+        restored += crate::insert_batch!(conn, users::table, &objects)?;
+        avatars.extend(
+            objects
+                .iter()
+                .filter(|u| u.avatar_updated_at.is_some())
+                .map(|u| u.id),
+        );
+    }
+    if let Some(v) = content.get("user_groups") {
+        let objects: Vec<Group> = serde_json::from_value(v.clone())?;
+        restored += crate::insert_batch!(conn, user_groups::table, &objects)?;
+    }
+    if let Some(v) = content.get("group_members") {
+        let objects: Vec<GroupMember> = serde_json::from_value(v.clone())?;
+        restored += crate::insert_batch!(conn, group_members::table, &objects)?;
+    }
+    if let Some(v) = content.get("privacy_settings") {
+        let objects: Vec<PrivacySettings> = serde_json::from_value(v.clone())?;
+        restored += crate::insert_batch!(conn, privacy_settings::table, &objects)?;
+    }
+    if let Some(v) = content.get("sharing_rules") {
+        let objects: Vec<SharingRule> = serde_json::from_value(v.clone())?;
+        restored += crate::insert_batch!(conn, sharing_rules::table, &objects)?;
+    }
+    if let Some(v) = content.get("alert_settings") {
+        let objects: Vec<AlertSettings> = serde_json::from_value(v.clone())?;
+        restored += crate::insert_batch!(conn, alert_settings::table, &objects)?;
+    }
+    if let Some(v) = content.get("alerts") {
+        let objects: Vec<Alert> = serde_json::from_value(v.clone())?;
+        restored += crate::insert_batch!(conn, alerts::table, &objects)?;
+    }
+    if let Some(v) = content.get("push_subscriptions") {
+        let objects: Vec<PushSubscription> = serde_json::from_value(v.clone())?;
+        restored += crate::insert_batch!(conn, push_subscriptions::table, &objects)?;
+    }
+    if let Some(v) = content.get("device_status") {
+        let objects: Vec<DeviceStatus> = serde_json::from_value(v.clone())?;
+        restored += crate::insert_batch!(conn, device_status::table, &objects)?;
+    }
+    if let Some(v) = content.get("daily_tracks") {
+        let objects: Vec<DailyTrack> = serde_json::from_value(v.clone())?;
+        restored += crate::insert_batch!(conn, daily_tracks::table, &objects)?;
+    }
+    if let Some(v) = content.get("positions") {
+        let objects: Vec<Position> = serde_json::from_value(v.clone())?;
+        restored += crate::insert_batch!(conn, positions::table, &objects)?;
+    }
+    // the files are moved back once every object is restored
+    for uid in avatars {
+        user::restore_avatar(config, uid)?;
+    }
+    Ok(restored)
+}
+
+#[derive(Deserialize)]
+struct TrashedUser {
+    id: i32,
+}
+
+#[derive(Deserialize)]
+struct TrashedUsers {
+    #[serde(default)]
+    users: Vec<TrashedUser>,
+}
+
+// Remove the avatars of the users whose trash entry was purged, returns the number of files removed
+pub fn purge_avatars(conn: &mut DbConnection, config: &Config) -> Result<usize, ServerError> {
+    let contents = trash::table
+        .filter(trash::target.eq("users").or(trash::target.like("users/%")))
+        .select(trash::content)
+        .load::<String>(conn)?;
+    let mut trashed_users = HashSet::new();
+    for content in contents {
+        let content: TrashedUsers = serde_json::from_str(&content)?;
+        trashed_users.extend(content.users.iter().map(|u| u.id));
+    }
+    Ok(user::purge_trashed_avatars(config, &trashed_users)?)
+}
+
+#[get("")]
+pub async fn read_all(
+    pool: web::Data<DbPool>,
+    cfg: web::Data<AppConfig>,
+) -> Result<HttpResponse, ServerError> {
+    let mut conn = pool.get()?;
+    let purge_delay = cfg.trash_purge_delay;
+    let entries = web::block(move || -> Result<Vec<TrashEntry>, ServerError> {
+        purge_expired(&mut conn, purge_delay)?;
+        purge_avatars(&mut conn, &cfg.config)?;
+        Ok(trash::table
+            .order(trash::id.desc())
+            .load::<TrashEntry>(&mut conn)?)
+    })
+    .await??;
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+#[post("/{oid}/restore")]
+pub async fn restore(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    cfg: web::Data<AppConfig>,
+    oid: web::Path<i32>,
+) -> Result<HttpResponse, ServerError> {
+    let audit = NewAuditEntry::new(&req, "trash.restore");
+    let mut conn = pool.get()?;
+    let oid = *oid;
+    let restored = web::block(move || {
+        conn.transaction::<_, ServerError, _>(|conn| {
+            let entry = trash::table.find(oid).first::<TrashEntry>(conn)?;
+            let restored = restore_content(conn, &cfg.config, &entry.content)?;
+            diesel::delete(trash::table.find(oid)).execute(conn)?;
+            audit
+                .target(entry.target)
+                .details(&serde_json::json!({ "trash_id": oid, "restored": restored }))
+                .insert(conn)?;
+            Ok(restored)
+        })
+    })
+    .await?;
+    match restored {
+        Ok(restored) => Ok(HttpResponse::Ok().body(format!("Restored {} objects", restored))),
+        Err(ServerError::DieselDatabaseError(m)) => Ok(HttpResponse::Conflict().body(format!(
+            "some objects of the trash entry already exist: {}",
+            m
+        ))),
+        Err(e) => Err(e),
+    }
+}
+
+#[delete("/{oid}")]
+pub async fn purge(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    cfg: web::Data<AppConfig>,
+    oid: web::Path<i32>,
+) -> Result<HttpResponse, ServerError> {
+    let audit = NewAuditEntry::new(&req, "trash.purge");
+    let mut conn = pool.get()?;
+    let oid = *oid;
+    web::block(move || {
+        let deleted = diesel::delete(trash::table.find(oid)).execute(&mut conn)?;
+        match deleted {
+            0 => Err(ServerError::DieselNotFound),
+            _ => {
+                audit.target(format!("trash/{}", oid)).insert(&mut conn)?;
+                purge_avatars(&mut conn, &cfg.config)?;
+                Ok(deleted)
+            }
+        }
+    })
+    .await??;
+    Ok(HttpResponse::Ok().body(format!("Purged trash entry with id: {}", oid)))
+}
@@ -0,0 +1,227 @@
+use std::time::Duration;
+
+use crate::{
+    app::AppConfig, config::Config, create_app, models::trash::TrashEntry,
+    positions_server::PositionsServerHandle,
+};
+
+pub async fn trash_test(
+    pool: &r2d2::Pool<diesel::r2d2::ConnectionManager<crate::db::DbConnection>>,
+    app_config: &actix_web::web::Data<AppConfig>,
+    position_server_handle: &PositionsServerHandle,
+) {
+    use crate::{do_test, do_test_extract_id};
+    use actix_web::{
+        http::{Method, StatusCode},
+        test, web,
+    };
+
+    let mut app = test::init_service(create_app!(pool, app_config, position_server_handle)).await;
+
+    // Create a user with a position
+    let user_id = do_test_extract_id!(
+        app,
+        Method::POST,
+        "/api/users",
+        r#"{"name":"Trashed","surname":"User"}"#,
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    do_test!(
+        app,
+        Method::POST,
+        "/api/positions",
+        &format!(
+            r#"[{{"user_id":{},"latitude":45.12345,"longitude":4.84671,"source":"GPS","battery_level":50,"sport_mode":false}}]"#,
+            user_id
+        ),
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+
+    // Deleting all the users must be confirmed
+    do_test!(
+        app,
+        Method::DELETE,
+        "/api/users",
+        "",
+        StatusCode::BAD_REQUEST,
+        "deleting all users must be confirmed"
+    );
+    do_test!(
+        app,
+        Method::DELETE,
+        "/api/users?confirm=positions",
+        "",
+        StatusCode::BAD_REQUEST,
+        "deleting all users must be confirmed"
+    );
+
+    // Delete the user, it goes to the trash with its positions and device status
+    do_test!(
+        app,
+        Method::DELETE,
+        &format!("/api/users/{}?cascade=true", user_id),
+        "",
+        StatusCode::OK,
+        format!("Deleted object with id: {}", user_id)
+    );
+    do_test!(
+        app,
+        Method::GET,
+        &format!("/api/users/{}", user_id),
+        "",
+        StatusCode::NOT_FOUND,
+        "Item not found"
+    );
+    let body = do_test!(app, Method::GET, "/api/trash", "", StatusCode::OK, "[");
+    let entries: Vec<TrashEntry> = serde_json::from_str(&body).unwrap();
+    assert_eq!(entries[0].target, format!("users/{}", user_id));
+    assert_eq!(entries[0].actor, "main token");
+    assert!(entries[0].content.contains("45.12345"));
+
+    // Restore it
+    do_test!(
+        app,
+        Method::POST,
+        &format!("/api/trash/{}/restore", entries[0].id),
+        "",
+        StatusCode::OK,
+        "Restored 3 objects"
+    );
+    do_test!(
+        app,
+        Method::GET,
+        &format!("/api/users/{}", user_id),
+        "",
+        StatusCode::OK,
+        format!("{{\"id\":{},\"name\":\"Trashed\"", user_id)
+    );
+    let body = do_test!(
+        app,
+        Method::GET,
+        &format!("/api/positions?user_id={}", user_id),
+        "",
+        StatusCode::OK,
+        "["
+    );
+    assert!(body.contains("45.12345"));
+    do_test!(
+        app,
+        Method::POST,
+        &format!("/api/trash/{}/restore", entries[0].id),
+        "",
+        StatusCode::NOT_FOUND,
+        "Item not found"
+    );
+
+    // Delete all the users, confirming with a header, and restore them
+    let req = test::TestRequest::delete()
+        .insert_header(("Authorization", "Bearer 0101"))
+        .insert_header(("X-Confirm-Delete", "users"))
+        .uri("/api/users")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    do_test!(app, Method::GET, "/api/users", "", StatusCode::OK, "[]");
+    let body = do_test!(app, Method::GET, "/api/trash", "", StatusCode::OK, "[");
+    let entries: Vec<TrashEntry> = serde_json::from_str(&body).unwrap();
+    assert_eq!(entries[0].target, "users");
+    do_test!(
+        app,
+        Method::POST,
+        &format!("/api/trash/{}/restore", entries[0].id),
+        "",
+        StatusCode::OK,
+        "Restored"
+    );
+    let body = do_test!(app, Method::GET, "/api/users", "", StatusCode::OK, "[");
+    assert!(body.contains("Trashed"));
+
+    // Delete the user for good
+    do_test!(
+        app,
+        Method::DELETE,
+        &format!("/api/users/{}?cascade=true", user_id),
+        "",
+        StatusCode::OK,
+        format!("Deleted object with id: {}", user_id)
+    );
+    let body = do_test!(app, Method::GET, "/api/trash", "", StatusCode::OK, "[");
+    let entries: Vec<TrashEntry> = serde_json::from_str(&body).unwrap();
+    do_test!(
+        app,
+        Method::DELETE,
+        &format!("/api/trash/{}", entries[0].id),
+        "",
+        StatusCode::OK,
+        format!("Purged trash entry with id: {}", entries[0].id)
+    );
+    do_test!(
+        app,
+        Method::POST,
+        &format!("/api/trash/{}/restore", entries[0].id),
+        "",
+        StatusCode::NOT_FOUND,
+        "Item not found"
+    );
+
+    // Trash entries are purged after the configured delay
+    let short_config = web::Data::new(AppConfig::new("0101".to_string(), None).with_config(
+        Config {
+            trash_purge_delay: 1,
+            ..Config::default()
+        },
+    ));
+    let mut app =
+        test::init_service(create_app!(pool, &short_config, position_server_handle)).await;
+    let user_id = do_test_extract_id!(
+        app,
+        Method::POST,
+        "/api/users",
+        r#"{"name":"Purged","surname":"User"}"#,
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    do_test!(
+        app,
+        Method::DELETE,
+        &format!("/api/users/{}?cascade=true", user_id),
+        "",
+        StatusCode::OK,
+        ""
+    );
+    let body = do_test!(app, Method::GET, "/api/trash", "", StatusCode::OK, "[");
+    assert!(body.contains("Purged"));
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let body = do_test!(app, Method::GET, "/api/trash", "", StatusCode::OK, "[");
+    assert!(!body.contains("Purged"));
+
+    // ... and a zero delay disables the trash
+    let no_trash_config = web::Data::new(AppConfig::new("0101".to_string(), None).with_config(
+        Config {
+            trash_purge_delay: 0,
+            ..Config::default()
+        },
+    ));
+    let mut app =
+        test::init_service(create_app!(pool, &no_trash_config, position_server_handle)).await;
+    let user_id = do_test_extract_id!(
+        app,
+        Method::POST,
+        "/api/users",
+        r#"{"name":"Not trashed","surname":"User"}"#,
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    do_test!(
+        app,
+        Method::DELETE,
+        &format!("/api/users/{}?cascade=true", user_id),
+        "",
+        StatusCode::OK,
+        ""
+    );
+    let body = do_test!(app, Method::GET, "/api/trash", "", StatusCode::OK, "[");
+    assert!(!body.contains("Not trashed"));
+}
@@ -1,41 +1,178 @@
+use std::{
+    collections::HashSet,
+    io::Cursor,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use actix_files::NamedFile;
+use actix_web::http::header::CONTENT_TYPE;
+use futures_util::StreamExt;
+use image::{DynamicImage, ImageFormat, ImageReader, Limits, imageops::FilterType};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    crud_create, crud_delete, crud_delete_all, crud_update, crud_use, errors::ServerError,
+    app::AppConfig,
+    config::Config,
+    crud_create, crud_delete, crud_delete_all, crud_update, crud_use,
+    errors::ServerError,
+    models::{
+        alert::{Alert, AlertSettings},
+        audit::NewAuditEntry,
+        device_status::DeviceStatus,
+        group::{GroupMember, request_visibility},
+        position::Position,
+        privacy::PrivacySettings,
+        push_subscription::PushSubscription,
+        sharing_rule::SharingRule,
+        track::DailyTrack,
+    },
     schema::users,
+    utils::now,
 };
 
-macro_rules! trim {
-    () => {
-        fn trim(&mut self) -> &Self {
-            self.name = self.name.trim().to_string();
-            self.surname = self.surname.trim().to_string();
-            self
-        }
-    };
+// Time after which an avatar moved aside belongs to a committed deletion
+const PENDING_TRASH_DELAY: Duration = Duration::from_secs(60);
+const MAX_AVATAR_BYTES: usize = 5 * 1024 * 1024;
+const MAX_AVATAR_DIMENSION: u32 = 8192;
+const AVATAR_SIZE: u32 = 512;
+const THUMBNAIL_SIZE: u32 = 96;
+
+// Trim an optional field, an empty value meaning no value
+fn trim_option(value: &mut Option<String>) {
+    *value = value
+        .as_deref()
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(str::to_owned);
 }
 
-#[derive(
-    Debug, Clone, Serialize, Deserialize, Queryable, Insertable, AsChangeset, Identifiable,
-)]
+fn validate_profile(
+    color: Option<&str>,
+    phone: Option<&str>,
+    timezone: Option<&str>,
+) -> Result<(), String> {
+    if let Some(color) = color
+        && !(color.len() == 7
+            && color.starts_with('#')
+            && color[1..].chars().all(|c| c.is_ascii_hexdigit()))
+    {
+        return Err(format!("color must be formatted as #rrggbb: {}", color));
+    }
+    if let Some(phone) = phone
+        && !(phone.len() <= 32
+            && phone.chars().any(|c| c.is_ascii_digit())
+            && phone
+                .chars()
+                .all(|c| c.is_ascii_digit() || " +-.()".contains(c)))
+    {
+        return Err(format!("phone is not a valid phone number: {}", phone));
+    }
+    if let Some(timezone) = timezone
+        && timezone.parse::<chrono_tz::Tz>().is_err()
+    {
+        return Err(format!(
+            "timezone is not a known IANA timezone: {}",
+            timezone
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, Insertable, Identifiable)]
 #[diesel(table_name = users)]
 pub struct User {
     pub id: i32,
     pub name: String,
     pub surname: String,
+    // marker color on the map, as #rrggbb
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phone: Option<String>,
+    // IANA timezone used to display times, as Europe/Paris
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    // last avatar upload time, only changed through the avatar endpoints
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avatar_updated_at: Option<i64>,
+}
+
+// Tell a field set to null from a missing one, which is left unchanged
+fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
+}
+
+// Changes to a user : the profile fields not sent by older clients are left unchanged, they are
+// cleared when null or empty
+#[derive(Debug, Clone, Deserialize, AsChangeset)]
+#[diesel(table_name = users)]
+pub struct UserUpdate {
+    pub name: String,
+    pub surname: String,
+    #[serde(default, deserialize_with = "nullable")]
+    pub color: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub phone: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub timezone: Option<Option<String>>,
 }
-impl User {
-    trim!();
+impl UserUpdate {
+    pub(crate) fn trim(&mut self) -> &Self {
+        self.name = self.name.trim().to_string();
+        self.surname = self.surname.trim().to_string();
+        for value in [&mut self.color, &mut self.phone, &mut self.timezone]
+            .into_iter()
+            .flatten()
+        {
+            trim_option(value);
+        }
+        if let Some(Some(color)) = &mut self.color {
+            *color = color.to_lowercase();
+        }
+        self
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        validate_profile(
+            self.color.clone().flatten().as_deref(),
+            self.phone.clone().flatten().as_deref(),
+            self.timezone.clone().flatten().as_deref(),
+        )
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Insertable)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Insertable)]
 #[diesel(table_name = users)]
 pub struct NewUser {
     pub name: String,
     pub surname: String,
+    pub color: Option<String>,
+    pub phone: Option<String>,
+    pub timezone: Option<String>,
 }
 impl NewUser {
-    trim!();
+    pub(crate) fn trim(&mut self) -> &Self {
+        self.name = self.name.trim().to_string();
+        self.surname = self.surname.trim().to_string();
+        trim_option(&mut self.color);
+        trim_option(&mut self.phone);
+        trim_option(&mut self.timezone);
+        self.color = self.color.as_ref().map(|c| c.to_lowercase());
+        self
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        validate_profile(
+            self.color.as_deref(),
+            self.phone.as_deref(),
+            self.timezone.as_deref(),
+        )
+    }
 }
 
 crud_use!();
@@ -50,9 +187,11 @@ struct ReturnedUser {
 
 #[get("")]
 pub async fn read_all(
+    req: HttpRequest,
     pool: web::Data<DbPool>,
     cfg: web::Data<crate::app::AppConfig>,
 ) -> Result<HttpResponse, ServerError> {
+    let visibility = request_visibility(&req, &pool).await?;
     let mut conn = pool.get()?;
     let users = web::block(move || {
         use crate::schema::users::dsl::*;
@@ -63,6 +202,7 @@ pub async fn read_all(
     let sport_mode_toggle_users = cfg.sport_mode_toggle_users.lock().await;
     let returned_users: Vec<ReturnedUser> = users
         .into_iter()
+        .filter(|user| visibility.allows(user.id))
         .map(|user| {
             let uid = user.id;
             ReturnedUser {
@@ -77,10 +217,14 @@ pub async fn read_all(
 
 #[get("/{oid}")]
 pub async fn read(
+    req: HttpRequest,
     pool: web::Data<DbPool>,
     oid: web::Path<i32>,
     cfg: web::Data<crate::app::AppConfig>,
 ) -> Result<HttpResponse, ServerError> {
+    if !request_visibility(&req, &pool).await?.allows(*oid) {
+        return Err(ServerError::DieselNotFound);
+    }
     let mut conn = pool.get()?;
     let user = web::block(move || {
         use crate::schema::users::dsl::*;
@@ -96,6 +240,333 @@ pub async fn read(
     Ok(HttpResponse::Ok().json(returned_user))
 }
 
-crud_update!(User, users,);
-crud_delete!(User, users);
-crud_delete_all!(User, users);
+crud_update!(UserUpdate => User, users,);
+// The avatars of the deleted users go to the trash too
+crud_delete!(
+    User,
+    users,
+    Position,
+    positions,
+    user_id,
+    GroupMember,
+    group_members,
+    user_id,
+    PrivacySettings,
+    privacy_settings,
+    user_id,
+    SharingRule,
+    sharing_rules,
+    user_id,
+    AlertSettings,
+    alert_settings,
+    user_id,
+    Alert,
+    alerts,
+    user_id,
+    PushSubscription,
+    push_subscriptions,
+    user_id,
+    DeviceStatus,
+    device_status,
+    user_id,
+    DailyTrack,
+    daily_tracks,
+    user_id;
+    trash_avatars
+);
+crud_delete_all!(
+    User,
+    users,
+    Position,
+    positions,
+    GroupMember,
+    group_members,
+    PrivacySettings,
+    privacy_settings,
+    SharingRule,
+    sharing_rules,
+    AlertSettings,
+    alert_settings,
+    Alert,
+    alerts,
+    PushSubscription,
+    push_subscriptions,
+    DeviceStatus,
+    device_status,
+    DailyTrack,
+    daily_tracks;
+    trash_avatars
+);
+
+fn avatar_path(config: &Config, uid: i32, thumbnail: bool) -> PathBuf {
+    avatar_path_in(Path::new(&config.avatars_dir), uid, thumbnail)
+}
+
+fn avatar_path_in(dir: &Path, uid: i32, thumbnail: bool) -> PathBuf {
+    match thumbnail {
+        true => dir.join(format!("{}_thumbnail.png", uid)),
+        false => dir.join(format!("{}.png", uid)),
+    }
+}
+
+fn trashed_avatars_dir(config: &Config) -> PathBuf {
+    Path::new(&config.avatars_dir).join("trash")
+}
+
+fn remove_avatar_files(config: &Config, uid: i32) -> std::io::Result<()> {
+    for thumbnail in [false, true] {
+        match std::fs::remove_file(avatar_path(config, uid, thumbnail)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// Move the avatar files of a user to another folder, those missing are skipped
+fn move_avatar_files(uid: i32, from: &Path, to: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(to)?;
+    for thumbnail in [false, true] {
+        let target = avatar_path_in(to, uid, thumbnail);
+        match std::fs::rename(avatar_path_in(from, uid, thumbnail), &target) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            Err(_) => {}
+            // the move time tells the files of the deletions that may not be committed yet
+            Ok(()) => std::fs::OpenOptions::new()
+                .write(true)
+                .open(&target)?
+                .set_modified(SystemTime::now())?,
+        }
+    }
+    Ok(())
+}
+
+// The avatars of the deleted users are moved aside, until their trash entry is restored or purged
+fn trash_avatars(
+    _: &mut crate::db::DbConnection,
+    config: &Config,
+    deleted: &[User],
+) -> std::io::Result<()> {
+    deleted
+        .iter()
+        .filter(|u| u.avatar_updated_at.is_some())
+        .try_for_each(|u| {
+            move_avatar_files(
+                u.id,
+                Path::new(&config.avatars_dir),
+                &trashed_avatars_dir(config),
+            )
+        })
+}
+
+pub fn has_trashed_avatar(config: &Config, uid: i32) -> bool {
+    avatar_path_in(&trashed_avatars_dir(config), uid, false).exists()
+}
+
+pub fn restore_avatar(config: &Config, uid: i32) -> std::io::Result<()> {
+    move_avatar_files(
+        uid,
+        &trashed_avatars_dir(config),
+        Path::new(&config.avatars_dir),
+    )
+}
+
+// Remove the avatars moved aside for the users that are not in the trash anymore, returns the
+// number of files removed
+pub fn purge_trashed_avatars(
+    config: &Config,
+    trashed_users: &HashSet<i32>,
+) -> std::io::Result<usize> {
+    let entries = match std::fs::read_dir(trashed_avatars_dir(config)) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        entries => entries?,
+    };
+    let mut removed = 0;
+    for entry in entries {
+        let entry = entry?;
+        let uid = entry
+            .file_name()
+            .to_string_lossy()
+            .split(['.', '_'])
+            .next()
+            .and_then(|uid| uid.parse::<i32>().ok());
+        let recent = entry
+            .metadata()?
+            .modified()?
+            .elapsed()
+            .is_ok_and(|age| age < PENDING_TRASH_DELAY);
+        if uid.is_some_and(|uid| !trashed_users.contains(&uid)) && !recent {
+            std::fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+// Decode an uploaded image, and get the avatar and its square thumbnail
+fn process_avatar(
+    data: &[u8],
+    format: ImageFormat,
+) -> Result<(DynamicImage, DynamicImage), image::ImageError> {
+    let mut reader = ImageReader::with_format(Cursor::new(data), format);
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_AVATAR_DIMENSION);
+    limits.max_image_height = Some(MAX_AVATAR_DIMENSION);
+    reader.limits(limits);
+    let img = reader.decode()?;
+    let avatar = if img.width() > AVATAR_SIZE || img.height() > AVATAR_SIZE {
+        img.resize(AVATAR_SIZE, AVATAR_SIZE, FilterType::Lanczos3)
+    } else {
+        img.clone()
+    };
+    let thumbnail = img.resize_to_fill(THUMBNAIL_SIZE, THUMBNAIL_SIZE, FilterType::Triangle);
+    Ok((avatar, thumbnail))
+}
+
+#[put("/{oid}/avatar")]
+pub async fn upload_avatar(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    cfg: web::Data<AppConfig>,
+    oid: web::Path<i32>,
+    mut payload: web::Payload,
+) -> Result<HttpResponse, ServerError> {
+    let format = match req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some("image/png") => ImageFormat::Png,
+        Some("image/jpeg") => ImageFormat::Jpeg,
+        Some("image/webp") => ImageFormat::WebP,
+        _ => {
+            return Ok(HttpResponse::UnsupportedMediaType()
+                .body("avatar must be a png, jpeg or webp image"));
+        }
+    };
+    let mut data = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if data.len() + chunk.len() > MAX_AVATAR_BYTES {
+            return Ok(HttpResponse::PayloadTooLarge().body(format!(
+                "avatar must not be larger than {} bytes",
+                MAX_AVATAR_BYTES
+            )));
+        }
+        data.extend_from_slice(&chunk);
+    }
+    let (avatar, thumbnail) = match web::block(move || process_avatar(&data, format)).await? {
+        Ok(images) => images,
+        Err(e) => return Ok(HttpResponse::BadRequest().body(format!("invalid image: {}", e))),
+    };
+
+    let audit = NewAuditEntry::new(&req, "users.avatar_update");
+    let mut conn = pool.get()?;
+    let oid = *oid;
+    web::block(move || -> Result<(), ServerError> {
+        use crate::schema::users::dsl::*;
+        users.find(oid).first::<User>(&mut conn)?;
+        std::fs::create_dir_all(&cfg.config.avatars_dir)?;
+        avatar
+            .save_with_format(avatar_path(&cfg.config, oid, false), ImageFormat::Png)
+            .map_err(|e| ServerError::Image(e.to_string()))?;
+        thumbnail
+            .save_with_format(avatar_path(&cfg.config, oid, true), ImageFormat::Png)
+            .map_err(|e| ServerError::Image(e.to_string()))?;
+        diesel::update(users.find(oid))
+            .set(avatar_updated_at.eq(now()))
+            .execute(&mut conn)?;
+        audit.target(format!("users/{}", oid)).insert(&mut conn)?;
+        Ok(())
+    })
+    .await??;
+    Ok(HttpResponse::Ok().body(format!("Avatar updated for user with id: {}", oid)))
+}
+
+#[derive(Deserialize)]
+pub struct AvatarQuery {
+    #[serde(default)]
+    thumbnail: bool,
+}
+
+#[get("/{oid}/avatar")]
+pub async fn read_avatar(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    cfg: web::Data<AppConfig>,
+    oid: web::Path<i32>,
+    query: web::Query<AvatarQuery>,
+) -> Result<HttpResponse, ServerError> {
+    if !request_visibility(&req, &pool).await?.allows(*oid) {
+        return Err(ServerError::DieselNotFound);
+    }
+    match NamedFile::open_async(avatar_path(&cfg.config, *oid, query.thumbnail)).await {
+        Ok(file) => Ok(file.into_response(&req)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(ServerError::DieselNotFound),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[delete("/{oid}/avatar")]
+pub async fn delete_avatar(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    cfg: web::Data<AppConfig>,
+    oid: web::Path<i32>,
+) -> Result<HttpResponse, ServerError> {
+    let audit = NewAuditEntry::new(&req, "users.avatar_delete");
+    let mut conn = pool.get()?;
+    let oid = *oid;
+    web::block(move || -> Result<(), ServerError> {
+        use crate::schema::users::dsl::*;
+        let updated = diesel::update(users.find(oid).filter(avatar_updated_at.is_not_null()))
+            .set(avatar_updated_at.eq(None::<i64>))
+            .execute(&mut conn)?;
+        if updated == 0 {
+            return Err(ServerError::DieselNotFound);
+        }
+        remove_avatar_files(&cfg.config, oid)?;
+        audit.target(format!("users/{}", oid)).insert(&mut conn)?;
+        Ok(())
+    })
+    .await??;
+    Ok(HttpResponse::Ok().body(format!("Avatar deleted for user with id: {}", oid)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_profile() {
+        assert!(validate_profile(None, None, None).is_ok());
+        assert!(
+            validate_profile(
+                Some("#a1b2c3"),
+                Some("+33 (0)6 12-34.56"),
+                Some("Europe/Paris")
+            )
+            .is_ok()
+        );
+        assert!(validate_profile(Some("a1b2c3"), None, None).is_err());
+        assert!(validate_profile(Some("#a1b2cg"), None, None).is_err());
+        assert!(validate_profile(None, Some("call me"), None).is_err());
+        assert!(validate_profile(None, Some("+-"), None).is_err());
+        assert!(validate_profile(None, None, Some("Europe/Atlantis")).is_err());
+    }
+
+    #[test]
+    fn test_process_avatar() {
+        let mut png = Vec::new();
+        DynamicImage::new_rgb8(1024, 512)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        let (avatar, thumbnail) = process_avatar(&png, ImageFormat::Png).unwrap();
+        assert_eq!((avatar.width(), avatar.height()), (512, 256));
+        assert_eq!((thumbnail.width(), thumbnail.height()), (96, 96));
+
+        // The declared format must match the content
+        assert!(process_avatar(&png, ImageFormat::Jpeg).is_err());
+        assert!(process_avatar(b"not an image", ImageFormat::Png).is_err());
+    }
+}
@@ -1,7 +1,7 @@
 use crate::{app::AppConfig, create_app, positions_server::PositionsServerHandle};
 
 pub async fn user_test(
-    pool: &r2d2::Pool<diesel::r2d2::ConnectionManager<diesel::SqliteConnection>>,
+    pool: &r2d2::Pool<diesel::r2d2::ConnectionManager<crate::db::DbConnection>>,
     app_config: &actix_web::web::Data<AppConfig>,
     position_server_handle: &PositionsServerHandle,
 ) {
@@ -16,7 +16,7 @@ pub async fn user_test(
     // Delete all the users
     let req = test::TestRequest::delete()
         .insert_header(("Authorization", "Bearer 0101"))
-        .uri("/api/users")
+        .uri("/api/users?confirm=users")
         .to_request();
     test::call_service(&app, req).await;
 
@@ -86,10 +86,79 @@ pub async fn user_test(
         "Item not found"
     );
 
+    // A user with positions is not deleted unless cascading, and its dependents are listed
+    let id = do_test_extract_id!(
+        app,
+        Method::POST,
+        "/api/users",
+        r#"{"name":"Parent","surname":"User"}"#,
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    do_test!(
+        app,
+        Method::POST,
+        "/api/positions",
+        &format!(
+            r#"[{{"user_id":{},"latitude":45.74846,"longitude":4.84671,"source":"GPS","battery_level":50,"sport_mode":false}}]"#,
+            id
+        ),
+        StatusCode::CREATED,
+        "{\"id\""
+    );
+    do_test!(
+        app,
+        Method::DELETE,
+        &format!("/api/users/{}", id),
+        "",
+        StatusCode::CONFLICT,
+        r#"{"dependents":{"device_status":1,"positions":1},"message":"#
+    );
+    do_test!(
+        app,
+        Method::GET,
+        &format!("/api/positions?user_id={}", id),
+        "",
+        StatusCode::OK,
+        "[{\"id\""
+    );
+
+    // ... and cascading deletes them along with the user
+    do_test!(
+        app,
+        Method::DELETE,
+        &format!("/api/users/{}?cascade=true", id),
+        "",
+        StatusCode::OK,
+        format!("Deleted object with id: {}", id)
+    );
+    do_test!(
+        app,
+        Method::GET,
+        &format!("/api/positions?user_id={}", id),
+        "",
+        StatusCode::OK,
+        "[]"
+    );
+
+    // The foreign keys are enforced, so that no orphan can be left
+    {
+        use crate::schema::positions;
+        use diesel::prelude::*;
+        let orphan = diesel::insert_into(positions::table)
+            .values(&crate::models::position::NewPosition {
+                user_id: id,
+                source: "GPS".to_owned(),
+                ..Default::default()
+            })
+            .execute(&mut pool.get().unwrap());
+        assert!(orphan.is_err());
+    }
+
     // Delete all the users
     let req = test::TestRequest::delete()
         .insert_header(("Authorization", "Bearer 0101"))
-        .uri("/api/users")
+        .uri("/api/users?confirm=users")
         .to_request();
     test::call_service(&app, req).await;
 
@@ -126,9 +195,229 @@ pub async fn user_test(
     do_test!(
         app,
         Method::DELETE,
-        "/api/users",
+        "/api/users?confirm=users",
         "",
         StatusCode::OK,
         "Deleted all objects"
     );
+
+    // Create a user with a profile
+    let id = do_test_extract_id!(
+        app,
+        Method::POST,
+        "/api/users",
+        r##"{"name":"Profile","surname":"User","color":" #A1B2C3 ","phone":"+33 6 12 34 56 78","timezone":"Europe/Paris"}"##,
+        StatusCode::CREATED,
+        r##"{"id""##
+    );
+    do_test!(
+        app,
+        Method::GET,
+        &format!("/api/users/{}", id),
+        "",
+        StatusCode::OK,
+        format!(
+            r##"{{"id":{},"name":"Profile","surname":"User","color":"#a1b2c3","phone":"+33 6 12 34 56 78","timezone":"Europe/Paris","switching_mode":false}}"##,
+            id
+        )
+    );
+
+    // Invalid profile fields are rejected
+    do_test!(
+        app,
+        Method::POST,
+        "/api/users",
+        r#"{"name":"Profile","surname":"User","color":"red"}"#,
+        StatusCode::BAD_REQUEST,
+        "color must be formatted as #rrggbb"
+    );
+    do_test!(
+        app,
+        Method::PUT,
+        &format!("/api/users/{}", id),
+        &format!(
+            r#"{{"id":{},"name":"Profile","surname":"User","timezone":"Mars/Olympus"}}"#,
+            id
+        ),
+        StatusCode::BAD_REQUEST,
+        "timezone is not a known IANA timezone"
+    );
+
+    // Upload an avatar
+    let mut png = Vec::new();
+    image::DynamicImage::new_rgb8(1024, 512)
+        .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+        .unwrap();
+    let upload = |content_type: &str, data: Vec<u8>| {
+        test::TestRequest::put()
+            .insert_header(("Authorization", "Bearer 0101"))
+            .insert_header(("content-type", content_type))
+            .uri(&format!("/api/users/{}/avatar", id))
+            .set_payload(data)
+            .to_request()
+    };
+    let resp = test::call_service(&app, upload("image/gif", png.clone())).await;
+    assert_eq!(resp.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    let resp = test::call_service(&app, upload("image/jpeg", png.clone())).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let resp = test::call_service(&app, upload("image/png", vec![0; 5 * 1024 * 1024 + 1])).await;
+    assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let resp = test::call_service(&app, upload("image/png", png.clone())).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = do_test!(
+        app,
+        Method::GET,
+        &format!("/api/users/{}", id),
+        "",
+        StatusCode::OK,
+        "{"
+    );
+    assert!(body.contains("\"avatar_updated_at\":"));
+
+    // Get the avatar and its thumbnail
+    for (uri, size) in [
+        (format!("/api/users/{}/avatar", id), (512, 256)),
+        (format!("/api/users/{}/avatar?thumbnail=true", id), (96, 96)),
+    ] {
+        let req = test::TestRequest::get()
+            .insert_header(("Authorization", "Bearer 0101"))
+            .uri(&uri)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("content-type").unwrap(), "image/png");
+        let img = image::load_from_memory(&test::read_body(resp).await).unwrap();
+        assert_eq!((img.width(), img.height()), size);
+    }
+
+    // Updating the user keeps the avatar, and the profile fields that are not sent
+    do_test!(
+        app,
+        Method::PUT,
+        &format!("/api/users/{}", id),
+        &format!(r#"{{"id":{},"name":"Profile","surname":"User"}}"#, id),
+        StatusCode::OK,
+        format!(
+            r##"{{"id":{},"name":"Profile","surname":"User","color":"#a1b2c3","phone":"+33 6 12 34 56 78","timezone":"Europe/Paris","avatar_updated_at":"##,
+            id
+        )
+    );
+
+    // The profile fields are cleared when null or empty
+    do_test!(
+        app,
+        Method::PUT,
+        &format!("/api/users/{}", id),
+        &format!(
+            r#"{{"id":{},"name":"Profile","surname":"User","color":null,"phone":" "}}"#,
+            id
+        ),
+        StatusCode::OK,
+        format!(
+            r#"{{"id":{},"name":"Profile","surname":"User","timezone":"Europe/Paris","avatar_updated_at":"#,
+            id
+        )
+    );
+
+    // Delete the avatar
+    do_test!(
+        app,
+        Method::DELETE,
+        &format!("/api/users/{}/avatar", id),
+        "",
+        StatusCode::OK,
+        format!("Avatar deleted for user with id: {}", id)
+    );
+    do_test!(
+        app,
+        Method::GET,
+        &format!("/api/users/{}/avatar", id),
+        "",
+        StatusCode::NOT_FOUND,
+        "Item not found"
+    );
+
+    // Deleting the user moves its avatar to the trash, restoring it brings the avatar back
+    let resp = test::call_service(&app, upload("image/png", png.clone())).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let avatar = std::path::Path::new("db/avatars").join(format!("{}.png", id));
+    let trashed_avatar = std::path::Path::new("db/avatars/trash").join(format!("{}.png", id));
+    let trashed_thumbnail =
+        std::path::Path::new("db/avatars/trash").join(format!("{}_thumbnail.png", id));
+    assert!(avatar.exists());
+    let trash_id = |trash: &str| {
+        let entries: serde_json::Value = serde_json::from_str(trash).unwrap();
+        entries
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|e| e["target"] == format!("users/{}", id))
+            .unwrap()["id"]
+            .as_i64()
+            .unwrap()
+    };
+    for _ in 0..2 {
+        do_test!(
+            app,
+            Method::DELETE,
+            &format!("/api/users/{}", id),
+            "",
+            StatusCode::OK,
+            format!("Deleted object with id: {}", id)
+        );
+        assert!(!avatar.exists());
+        assert!(trashed_avatar.exists() && trashed_thumbnail.exists());
+        let trash = do_test!(app, Method::GET, "/api/trash", "", StatusCode::OK, "[");
+        do_test!(
+            app,
+            Method::POST,
+            &format!("/api/trash/{}/restore", trash_id(&trash)),
+            "",
+            StatusCode::OK,
+            "Restored"
+        );
+        assert!(avatar.exists());
+        assert!(!trashed_avatar.exists());
+        let body = do_test!(
+            app,
+            Method::GET,
+            &format!("/api/users/{}", id),
+            "",
+            StatusCode::OK,
+            "{"
+        );
+        assert!(body.contains("\"avatar_updated_at\":"));
+    }
+
+    // Purging the trash entry deletes the avatar
+    do_test!(
+        app,
+        Method::DELETE,
+        &format!("/api/users/{}", id),
+        "",
+        StatusCode::OK,
+        format!("Deleted object with id: {}", id)
+    );
+    let trash = do_test!(app, Method::GET, "/api/trash", "", StatusCode::OK, "[");
+    do_test!(
+        app,
+        Method::DELETE,
+        &format!("/api/trash/{}", trash_id(&trash)),
+        "",
+        StatusCode::OK,
+        "Purged trash entry"
+    );
+    // the files moved aside in the last minute may belong to a deletion not committed yet
+    assert!(trashed_avatar.exists());
+    for path in [&trashed_avatar, &trashed_thumbnail] {
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() - std::time::Duration::from_secs(120))
+            .unwrap();
+    }
+    do_test!(app, Method::GET, "/api/trash", "", StatusCode::OK, "[");
+    assert!(!trashed_avatar.exists() && !trashed_thumbnail.exists());
+    assert!(!avatar.exists());
 }
@@ -0,0 +1,302 @@
+//! Delivery of notifications outside of the application.
+//!
+//! A notifier sends a notification to an external service. The available ones are a JSON webhook,
+//! an ntfy-compatible HTTP endpoint, and plain SMTP to a local relay (no authentication nor TLS,
+//! the relay being trusted to forward the mails). Notifications are sent in the background, and
+//! failures are only logged.
+//!
+//! The emergency share links are only sent to the notifiers named in `alert_emergency_contacts`,
+//! which must reach trusted people only, the others get the emergencies without them.
+//!
+//! Every user chooses in their alert settings the notifiers their alerts are sent through.
+
+use std::{collections::HashSet, sync::Arc};
+
+use futures_util::future::BoxFuture;
+use serde::Serialize;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+use crate::config::Config;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification {
+    // the user the notification is about
+    pub user_id: i32,
+    pub title: String,
+    pub message: String,
+    // the object the notification is about, sent as is to the webhooks
+    pub payload: serde_json::Value,
+}
+
+pub trait Notifier: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn notify<'a>(&'a self, notification: &'a Notification) -> BoxFuture<'a, Result<(), String>>;
+}
+
+// POST the notification as JSON
+pub struct Webhook {
+    pub url: String,
+}
+
+impl Notifier for Webhook {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    fn notify<'a>(&'a self, notification: &'a Notification) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            reqwest::Client::new()
+                .post(&self.url)
+                .json(notification)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map_err(|e| e.to_string())?;
+            Ok(())
+        })
+    }
+}
+
+// POST the message to a topic url, with the title as a header
+pub struct Ntfy {
+    pub url: String,
+    pub token: Option<String>,
+}
+
+impl Notifier for Ntfy {
+    fn name(&self) -> &'static str {
+        "ntfy"
+    }
+
+    fn notify<'a>(&'a self, notification: &'a Notification) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let mut request = reqwest::Client::new()
+                .post(&self.url)
+                .header("Title", &notification.title)
+                .header("Tags", "warning")
+                .body(notification.message.clone());
+            if let Some(token) = &self.token {
+                request = request.bearer_auth(token);
+            }
+            request
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map_err(|e| e.to_string())?;
+            Ok(())
+        })
+    }
+}
+
+// Send a mail through an SMTP relay
+pub struct Smtp {
+    // host:port of the relay
+    pub relay: String,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+impl Notifier for Smtp {
+    fn name(&self) -> &'static str {
+        "smtp"
+    }
+
+    fn notify<'a>(&'a self, notification: &'a Notification) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move { self.send(notification).await.map_err(|e| e.to_string()) })
+    }
+}
+
+impl Smtp {
+    async fn send(&self, notification: &Notification) -> std::io::Result<()> {
+        let stream = TcpStream::connect(&self.relay).await?;
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        smtp_reply(&mut reader, 220).await?;
+        smtp_command(&mut writer, &mut reader, "EHLO tesou", 250).await?;
+        smtp_command(
+            &mut writer,
+            &mut reader,
+            &format!("MAIL FROM:<{}>", self.from),
+            250,
+        )
+        .await?;
+        for to in &self.to {
+            smtp_command(&mut writer, &mut reader, &format!("RCPT TO:<{}>", to), 250).await?;
+        }
+        smtp_command(&mut writer, &mut reader, "DATA", 354).await?;
+        let mut data = format!(
+            "From: <{}>\r\nTo: {}\r\nSubject: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n",
+            self.from,
+            self.to
+                .iter()
+                .map(|to| format!("<{}>", to))
+                .collect::<Vec<_>>()
+                .join(", "),
+            notification.title.replace(['\r', '\n'], " ")
+        );
+        for line in notification.message.lines() {
+            // dot stuffing, as a single dot ends the data
+            if line.starts_with('.') {
+                data.push('.');
+            }
+            data.push_str(line);
+            data.push_str("\r\n");
+        }
+        data.push('.');
+        smtp_command(&mut writer, &mut reader, &data, 250).await?;
+        smtp_command(&mut writer, &mut reader, "QUIT", 221).await
+    }
+}
+
+async fn smtp_command<W, R>(
+    writer: &mut W,
+    reader: &mut R,
+    command: &str,
+    expected: u16,
+) -> std::io::Result<()>
+where
+    W: AsyncWriteExt + Unpin,
+    R: AsyncBufReadExt + Unpin,
+{
+    writer
+        .write_all(format!("{}\r\n", command).as_bytes())
+        .await?;
+    smtp_reply(reader, expected).await
+}
+
+// Read a reply, which may span several lines as 250-..., and check its code
+async fn smtp_reply<R: AsyncBufReadExt + Unpin>(
+    reader: &mut R,
+    expected: u16,
+) -> std::io::Result<()> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "the SMTP relay closed the connection",
+            ));
+        }
+        if line.len() < 4 || !line.is_char_boundary(3) {
+            return Err(std::io::Error::other(format!(
+                "invalid SMTP reply: {}",
+                line
+            )));
+        }
+        if line.as_bytes()[3] == b'-' {
+            continue;
+        }
+        return match line[..3].parse::<u16>() {
+            Ok(code) if code == expected => Ok(()),
+            _ => Err(std::io::Error::other(format!(
+                "unexpected SMTP reply: {}",
+                line.trim_end()
+            ))),
+        };
+    }
+}
+
+// The names of a comma separated list
+pub fn split_names(names: &str) -> impl Iterator<Item = String> {
+    names
+        .split(',')
+        .map(|s| s.trim().to_owned())
+        .filter(|s| !s.is_empty())
+}
+
+// The configured notifiers, cheap to clone
+#[derive(Clone, Default)]
+pub struct Notifiers {
+    notifiers: Arc<Vec<Box<dyn Notifier>>>,
+    // names of the notifiers reaching the emergency contacts
+    emergency_contacts: Arc<HashSet<String>>,
+    // names of the notifiers used, all of them if none
+    targets: Option<Arc<HashSet<String>>>,
+}
+
+impl Notifiers {
+    pub fn new(notifiers: Vec<Box<dyn Notifier>>) -> Self {
+        Notifiers {
+            notifiers: Arc::new(notifiers),
+            emergency_contacts: Arc::default(),
+            targets: None,
+        }
+    }
+
+    pub fn with_emergency_contacts(mut self, names: impl IntoIterator<Item = String>) -> Self {
+        self.emergency_contacts = Arc::new(names.into_iter().collect());
+        self
+    }
+
+    // The notifiers of the configuration, the web push one is added by the caller
+    pub fn from_config(config: &Config) -> Vec<Box<dyn Notifier>> {
+        let mut notifiers: Vec<Box<dyn Notifier>> = Vec::new();
+        if let Some(url) = &config.alert_webhook_url {
+            notifiers.push(Box::new(Webhook { url: url.clone() }));
+        }
+        if let Some(url) = &config.alert_ntfy_url {
+            notifiers.push(Box::new(Ntfy {
+                url: url.clone(),
+                token: config.alert_ntfy_token.clone(),
+            }));
+        }
+        if let (Some(relay), Some(from), Some(to)) = (
+            &config.alert_smtp_relay,
+            &config.alert_smtp_from,
+            &config.alert_smtp_to,
+        ) {
+            notifiers.push(Box::new(Smtp {
+                relay: relay.clone(),
+                from: from.clone(),
+                to: split_names(to).collect(),
+            }));
+        }
+        notifiers
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.notifiers.iter().map(|n| n.name()).collect()
+    }
+
+    // Use only the notifiers of a comma separated list of names, all of them if none
+    pub fn only(&self, names: Option<&str>) -> Self {
+        Notifiers {
+            targets: names.map(|names| Arc::new(split_names(names).collect())),
+            ..self.clone()
+        }
+    }
+
+    // Send a notification through every notifier, in the background
+    pub fn dispatch(&self, notification: Notification) {
+        self.dispatch_private(notification.clone(), notification);
+    }
+
+    // Send a notification through the emergency contacts notifiers, and its public version through
+    // the others, in the background
+    pub fn dispatch_private(&self, private: Notification, public: Notification) {
+        if self.notifiers.is_empty() {
+            return;
+        }
+        let notifiers = self.clone();
+        tokio::spawn(async move {
+            for notifier in notifiers.notifiers.iter().filter(|n| {
+                notifiers
+                    .targets
+                    .as_ref()
+                    .is_none_or(|targets| targets.contains(n.name()))
+            }) {
+                let notification = match notifiers.emergency_contacts.contains(notifier.name()) {
+                    true => &private,
+                    false => &public,
+                };
+                if let Err(e) = notifier.notify(notification).await {
+                    log::error!("{} notification failed: {}", notifier.name(), e);
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,757 @@
+//! OpenID Connect login for the web frontend.
+//!
+//! Implements the authorization code flow with PKCE (S256). Once the identity provider has
+//! authenticated someone, its subject is mapped to a tesou user and role (see the `oidc_subjects`
+//! table), and a session token is issued. Session tokens are sealed with the share keys, so that
+//! the validators accept them alongside the main token and the share tokens.
+//!
+//! The signature of the ID token is verified with the keys published by the identity provider
+//! (its JWKS), which are fetched again when a token is signed with an unknown key. RS256 and ES256
+//! are supported.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{HttpRequest, HttpResponse, get, http::header::LOCATION, web};
+use base64ct::{Base64UrlUnpadded, Encoding};
+use ring::signature::{
+    ECDSA_P256_SHA256_FIXED, RSA_PKCS1_2048_8192_SHA256, RsaPublicKeyComponents, UnparsedPublicKey,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use urlencoding::encode;
+
+use crate::{
+    app::{AppConfig, Credential, Role},
+    config::Config,
+    errors::ServerError,
+    models::{audit::NewAuditEntry, oidc_subject},
+    notifiers::split_names,
+    token::{self, Claims},
+    utils::random_string,
+};
+
+type DbPool = r2d2::Pool<diesel::r2d2::ConnectionManager<crate::db::DbConnection>>;
+
+// how long a login can take on the identity provider side
+const PENDING_LOGIN_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+// logins started at once, the oldest are given up beyond
+const MAX_PENDING_LOGINS: usize = 1000;
+// minimum delay between two fetches of the identity provider keys
+const JWKS_REFRESH_DELAY: Duration = Duration::from_secs(60);
+
+pub struct OidcConfig {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub redirect_uri: String,
+    pub post_login_url: String,
+    pub session_duration: Duration,
+    // subjects granted the admin role without being mapped in the database
+    pub admin_subjects: Vec<String>,
+}
+
+impl OidcConfig {
+    // The provider of the configuration, if login is enabled
+    pub fn from_config(config: &Config) -> Option<Self> {
+        Some(OidcConfig {
+            issuer: config.oidc_issuer.clone()?,
+            client_id: config.oidc_client_id.clone()?,
+            client_secret: config.oidc_client_secret.clone(),
+            redirect_uri: config.oidc_redirect_uri.clone()?,
+            post_login_url: config.oidc_post_login_url.clone(),
+            session_duration: config.oidc_session_duration(),
+            admin_subjects: split_names(&config.oidc_admin_subjects).collect(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+// A public key of the identity provider, RSA or P-256
+#[derive(Debug, Clone, Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    crv: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct IdTokenHeader {
+    alg: String,
+    kid: Option<String>,
+}
+
+fn decode_part(part: &str) -> Result<Vec<u8>, &'static str> {
+    Base64UrlUnpadded::decode_vec(part.trim_end_matches('=')).map_err(|_| "malformed id token")
+}
+
+impl Jwk {
+    // Verify a signature made with this key, with the given JWS algorithm
+    fn verify(&self, alg: &str, message: &[u8], signature: &[u8]) -> Result<(), &'static str> {
+        let decode = |v: &Option<String>| decode_part(v.as_deref().unwrap_or_default());
+        let verified = match (alg, self.kty.as_str()) {
+            ("RS256", "RSA") => RsaPublicKeyComponents {
+                n: decode(&self.n)?,
+                e: decode(&self.e)?,
+            }
+            .verify(&RSA_PKCS1_2048_8192_SHA256, message, signature),
+            ("ES256", "EC") if self.crv.as_deref() == Some("P-256") => {
+                // uncompressed point
+                let mut point = vec![0x04];
+                point.extend(decode(&self.x)?);
+                point.extend(decode(&self.y)?);
+                UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, point).verify(message, signature)
+            }
+            _ => return Err("unsupported id token algorithm"),
+        };
+        verified.map_err(|_| "invalid id token signature")
+    }
+}
+
+struct PendingLogin {
+    verifier: String,
+    nonce: String,
+    created: Instant,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+struct IdTokenClaims {
+    iss: String,
+    sub: String,
+    aud: Audience,
+    exp: u64,
+    nonce: Option<String>,
+}
+
+pub struct Oidc {
+    config: OidcConfig,
+    client: reqwest::Client,
+    discovery: tokio::sync::OnceCell<Discovery>,
+    // the identity provider keys, with when they were fetched
+    jwks: tokio::sync::Mutex<Option<(Instant, Vec<Jwk>)>>,
+    pending: Mutex<HashMap<String, PendingLogin>>,
+}
+
+impl Oidc {
+    pub fn new(config: OidcConfig) -> Self {
+        Oidc {
+            config,
+            client: reqwest::Client::new(),
+            discovery: tokio::sync::OnceCell::new(),
+            jwks: tokio::sync::Mutex::new(None),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Get the provider endpoints, fetched once from its discovery document
+    async fn discovery(&self) -> Result<&Discovery, ServerError> {
+        self.discovery
+            .get_or_try_init(|| async {
+                let url = format!(
+                    "{}/.well-known/openid-configuration",
+                    self.config.issuer.trim_end_matches('/')
+                );
+                let discovery = self
+                    .client
+                    .get(url)
+                    .send()
+                    .await
+                    .map_err(|e| {
+                        ServerError::Other(format!("identity provider did not respond: {}", e))
+                    })?
+                    .json::<Discovery>()
+                    .await
+                    .map_err(|e| {
+                        ServerError::Other(format!("invalid identity provider discovery: {}", e))
+                    })?;
+                if discovery.issuer != self.config.issuer {
+                    return Err(ServerError::Other(
+                        "identity provider issuer does not match the configuration".to_owned(),
+                    ));
+                }
+                Ok(discovery)
+            })
+            .await
+    }
+
+    // Get the keys of the identity provider, fetched again if the ID token is signed with another
+    async fn signing_keys(&self, id_token: &str) -> Result<Vec<Jwk>, ServerError> {
+        let kid = id_token
+            .split('.')
+            .next()
+            .and_then(|h| decode_part(h).ok())
+            .and_then(|h| serde_json::from_slice::<IdTokenHeader>(&h).ok())
+            .and_then(|h| h.kid);
+        let mut jwks = self.jwks.lock().await;
+        if let Some((fetched, keys)) = &*jwks
+            && (fetched.elapsed() < JWKS_REFRESH_DELAY
+                || kid.is_none()
+                || keys.iter().any(|k| k.kid == kid))
+        {
+            return Ok(keys.clone());
+        }
+        let discovery = self.discovery().await?;
+        let keys = self
+            .client
+            .get(&discovery.jwks_uri)
+            .send()
+            .await
+            .map_err(|e| ServerError::Other(format!("identity provider did not respond: {}", e)))?
+            .json::<Jwks>()
+            .await
+            .map_err(|e| ServerError::Other(format!("invalid identity provider keys: {}", e)))?
+            .keys;
+        *jwks = Some((Instant::now(), keys.clone()));
+        Ok(keys)
+    }
+
+    // Remember a login until the identity provider calls back
+    fn add_pending(&self, state: String, started: PendingLogin) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, p| p.created.elapsed() < PENDING_LOGIN_TIMEOUT);
+        while pending.len() >= MAX_PENDING_LOGINS {
+            let Some(oldest) = pending
+                .iter()
+                .min_by_key(|(_, p)| p.created)
+                .map(|(state, _)| state.clone())
+            else {
+                break;
+            };
+            pending.remove(&oldest);
+        }
+        pending.insert(state, started);
+    }
+
+    fn take_pending(&self, state: &str) -> Option<PendingLogin> {
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, p| p.created.elapsed() < PENDING_LOGIN_TIMEOUT);
+        pending.remove(state)
+    }
+
+    // Exchange an authorization code for an ID token
+    async fn exchange(&self, code: &str, verifier: &str) -> Result<String, ServerError> {
+        let discovery = self.discovery().await?;
+        let mut body = format!(
+            "grant_type=authorization_code&code={}&redirect_uri={}&client_id={}&code_verifier={}",
+            encode(code),
+            encode(&self.config.redirect_uri),
+            encode(&self.config.client_id),
+            encode(verifier)
+        );
+        if let Some(secret) = &self.config.client_secret {
+            body.push_str(&format!("&client_secret={}", encode(secret)));
+        }
+        let resp = self
+            .client
+            .post(&discovery.token_endpoint)
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| ServerError::Other(format!("identity provider did not respond: {}", e)))?;
+        if !resp.status().is_success() {
+            return Err(ServerError::Other(format!(
+                "identity provider refused the authorization code: {}",
+                resp.status()
+            )));
+        }
+        let resp = resp.json::<TokenResponse>().await.map_err(|e| {
+            ServerError::Other(format!("invalid identity provider token response: {}", e))
+        })?;
+        Ok(resp.id_token)
+    }
+
+    fn validate(
+        &self,
+        id_token: &str,
+        keys: &[Jwk],
+        nonce: &str,
+    ) -> Result<IdTokenClaims, &'static str> {
+        // the signature covers the header and the payload
+        let (message, signature) = id_token.rsplit_once('.').ok_or("malformed id token")?;
+        let (header, payload) = message.split_once('.').ok_or("malformed id token")?;
+        let header: IdTokenHeader =
+            serde_json::from_slice(&decode_part(header)?).map_err(|_| "malformed id token")?;
+        let signature = decode_part(signature)?;
+        let mut candidates = keys
+            .iter()
+            .filter(|k| header.kid.is_none() || k.kid == header.kid)
+            .peekable();
+        if candidates.peek().is_none() {
+            return Err("unknown id token key");
+        }
+        // without key id, any of the keys may have signed
+        let mut verified = Err("invalid id token signature");
+        for key in candidates {
+            verified = key.verify(&header.alg, message.as_bytes(), &signature);
+            if verified.is_ok() {
+                break;
+            }
+        }
+        verified?;
+        let payload = decode_part(payload)?;
+        let claims: IdTokenClaims =
+            serde_json::from_slice(&payload).map_err(|_| "malformed id token")?;
+        if claims.iss != self.config.issuer {
+            return Err("wrong id token issuer");
+        }
+        let audience_ok = match &claims.aud {
+            Audience::One(aud) => *aud == self.config.client_id,
+            Audience::Many(auds) => auds.contains(&self.config.client_id),
+        };
+        if !audience_ok {
+            return Err("wrong id token audience");
+        }
+        if claims.exp < unix_time() {
+            return Err("id token is expired");
+        }
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err("wrong id token nonce");
+        }
+        Ok(claims)
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// Start a login : redirect to the identity provider
+#[get("/login")]
+pub async fn login(cfg: web::Data<AppConfig>) -> Result<HttpResponse, ServerError> {
+    let Some(oidc) = &cfg.oidc else {
+        return Ok(HttpResponse::NotFound().body("OpenID Connect is not configured"));
+    };
+    let discovery = oidc.discovery().await?;
+    let state = random_string();
+    let nonce = random_string();
+    let verifier = random_string();
+    let challenge = Base64UrlUnpadded::encode_string(&Sha256::digest(verifier.as_bytes()));
+    let url = format!(
+        "{}{}response_type=code&scope=openid&client_id={}&redirect_uri={}&state={}&nonce={}&code_challenge={}&code_challenge_method=S256",
+        discovery.authorization_endpoint,
+        if discovery.authorization_endpoint.contains('?') {
+            '&'
+        } else {
+            '?'
+        },
+        encode(&oidc.config.client_id),
+        encode(&oidc.config.redirect_uri),
+        state,
+        nonce,
+        challenge
+    );
+    oidc.add_pending(
+        state,
+        PendingLogin {
+            verifier,
+            nonce,
+            created: Instant::now(),
+        },
+    );
+    Ok(HttpResponse::Found()
+        .insert_header((LOCATION, url))
+        .finish())
+}
+
+#[derive(Deserialize)]
+pub struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+// Finish a login : exchange the code, map the subject and issue a session token
+#[get("/callback")]
+pub async fn callback(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    cfg: web::Data<AppConfig>,
+    query: web::Query<CallbackQuery>,
+) -> Result<HttpResponse, ServerError> {
+    let Some(oidc) = &cfg.oidc else {
+        return Ok(HttpResponse::NotFound().body("OpenID Connect is not configured"));
+    };
+    if let Some(error) = &query.error {
+        return Ok(HttpResponse::BadRequest().body(format!("identity provider error: {}", error)));
+    }
+    let (Some(code), Some(state)) = (&query.code, &query.state) else {
+        return Ok(HttpResponse::BadRequest().body("missing code or state"));
+    };
+    let Some(pending) = oidc.take_pending(state) else {
+        return Ok(HttpResponse::BadRequest().body("unknown or expired login state"));
+    };
+    let id_token = oidc.exchange(code, &pending.verifier).await?;
+    let keys = oidc.signing_keys(&id_token).await?;
+    let claims = match oidc.validate(&id_token, &keys, &pending.nonce) {
+        Ok(claims) => claims,
+        Err(reason) => return Ok(HttpResponse::Forbidden().body(reason)),
+    };
+
+    // Map the subject to a user and a role
+    let mut conn = pool.get()?;
+    let sub = claims.sub.clone();
+    let mapping = web::block(move || oidc_subject::find(&mut conn, &sub)).await??;
+    let (user_id, role) = match mapping {
+        Some(m) => (
+            m.user_id,
+            m.role().map_err(|e| ServerError::Other(e.to_owned()))?,
+        ),
+        None if oidc.config.admin_subjects.contains(&claims.sub) => (None, Role::Admin),
+        None => return Ok(HttpResponse::Forbidden().body("subject is not allowed")),
+    };
+
+    // Issue the session token
+    let expires_at = unix_time() + oidc.config.session_duration.as_secs();
+    let session_token = token::seal(
+        &cfg.share_keys,
+        &Claims::Session {
+            expires_at,
+            user_id,
+            role,
+        },
+    )?;
+    let mut audit = NewAuditEntry::new(&req, "oidc.login")
+        .target(format!("oidc_subjects/{}", claims.sub))
+        .details(&serde_json::json!({ "expires_at": expires_at }));
+    audit.actor = Credential::Session { user_id, role }.to_string();
+    audit.record(&pool).await?;
+
+    // Hand the token to the frontend in the fragment, so that it is not sent back to any server
+    Ok(HttpResponse::Found()
+        .insert_header((
+            LOCATION,
+            format!(
+                "{}#token={}",
+                oidc.config.post_login_url,
+                encode(&session_token)
+            ),
+        ))
+        .finish())
+}
+
+#[cfg(test)]
+pub async fn oidc_test(
+    pool: &DbPool,
+    position_server_handle: &crate::positions_server::PositionsServerHandle,
+) {
+    use crate::app::query_string_to_hashmap;
+    use crate::do_test;
+    use actix_web::{
+        App, HttpServer,
+        http::{Method, StatusCode},
+        test,
+    };
+
+    use ring::{
+        rand::SystemRandom,
+        signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, KeyPair},
+    };
+
+    // Mock identity provider : it authorizes whoever the test wants, and checks the PKCE verifier
+    struct MockIdp {
+        issuer: String,
+        key: EcdsaKeyPair,
+        challenge: Mutex<String>,
+        nonce: Mutex<String>,
+        subject: Mutex<String>,
+        // sign the ID tokens with another key
+        forge: Mutex<bool>,
+    }
+
+    async fn mock_discovery(idp: web::Data<MockIdp>) -> HttpResponse {
+        HttpResponse::Ok().json(serde_json::json!({
+            "issuer": idp.issuer,
+            "authorization_endpoint": format!("{}/authorize", idp.issuer),
+            "token_endpoint": format!("{}/token", idp.issuer),
+            "jwks_uri": format!("{}/jwks", idp.issuer),
+        }))
+    }
+
+    async fn mock_jwks(idp: web::Data<MockIdp>) -> HttpResponse {
+        let point = idp.key.public_key().as_ref();
+        HttpResponse::Ok().json(serde_json::json!({ "keys": [{
+            "kty": "EC",
+            "kid": "mock",
+            "crv": "P-256",
+            "x": Base64UrlUnpadded::encode_string(&point[1..33]),
+            "y": Base64UrlUnpadded::encode_string(&point[33..]),
+        }]}))
+    }
+
+    fn generate_key() -> EcdsaKeyPair {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng).unwrap()
+    }
+
+    async fn mock_token(idp: web::Data<MockIdp>, body: String) -> HttpResponse {
+        let params = query_string_to_hashmap(&body);
+        let verifier = urlencoding::decode(&params["code_verifier"])
+            .unwrap()
+            .into_owned();
+        let challenge = Base64UrlUnpadded::encode_string(&Sha256::digest(verifier.as_bytes()));
+        if params["grant_type"] != "authorization_code"
+            || params["code"] != "mock-code"
+            || params["client_secret"] != "secret"
+            || challenge != *idp.challenge.lock().unwrap()
+        {
+            return HttpResponse::BadRequest().finish();
+        }
+        let claims = serde_json::json!({
+            "iss": idp.issuer,
+            "sub": *idp.subject.lock().unwrap(),
+            "aud": ["tesou", "other client"],
+            "exp": unix_time() + 60,
+            "nonce": *idp.nonce.lock().unwrap(),
+        });
+        let message = format!(
+            "{}.{}",
+            Base64UrlUnpadded::encode_string(br#"{"alg":"ES256","kid":"mock"}"#),
+            Base64UrlUnpadded::encode_string(claims.to_string().as_bytes())
+        );
+        let key = match *idp.forge.lock().unwrap() {
+            true => &generate_key(),
+            false => &idp.key,
+        };
+        let signature = key.sign(&SystemRandom::new(), message.as_bytes()).unwrap();
+        let id_token = format!(
+            "{}.{}",
+            message,
+            Base64UrlUnpadded::encode_string(signature.as_ref())
+        );
+        HttpResponse::Ok().json(serde_json::json!({
+            "access_token": "mock",
+            "token_type": "Bearer",
+            "id_token": id_token,
+        }))
+    }
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let issuer = format!("http://{}", listener.local_addr().unwrap());
+    let idp = web::Data::new(MockIdp {
+        issuer: issuer.clone(),
+        key: generate_key(),
+        challenge: Mutex::default(),
+        nonce: Mutex::default(),
+        subject: Mutex::default(),
+        forge: Mutex::new(false),
+    });
+    let idp_data = idp.clone();
+    let idp_server = HttpServer::new(move || {
+        App::new()
+            .app_data(idp_data.clone())
+            .route(
+                "/.well-known/openid-configuration",
+                web::get().to(mock_discovery),
+            )
+            .route("/jwks", web::get().to(mock_jwks))
+            .route("/token", web::post().to(mock_token))
+    })
+    .workers(1)
+    .listen(listener)
+    .unwrap()
+    .run();
+    let idp_server_handle = idp_server.handle();
+    actix_web::rt::spawn(idp_server);
+
+    // Without configuration, the login is not available
+    let app_config = web::Data::new(AppConfig::new("0101".to_string(), None));
+    let app = test::init_service(crate::create_app!(
+        pool,
+        &app_config,
+        position_server_handle
+    ))
+    .await;
+    let req = test::TestRequest::get().uri("/api/oidc/login").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 404);
+
+    let app_config = web::Data::new(
+        AppConfig::new("0101".to_string(), None).with_oidc(Oidc::new(OidcConfig {
+            issuer: issuer.clone(),
+            client_id: "tesou".to_owned(),
+            client_secret: Some("secret".to_owned()),
+            redirect_uri: "http://localhost:8080/api/oidc/callback".to_owned(),
+            post_login_url: "/".to_owned(),
+            session_duration: Duration::from_secs(60 * 60),
+            admin_subjects: vec!["bob".to_owned()],
+        })),
+    );
+    let mut app = test::init_service(crate::create_app!(
+        pool,
+        &app_config,
+        position_server_handle
+    ))
+    .await;
+
+    // Log in as a subject, returns the callback response
+    macro_rules! oidc_login {
+        ($subject:expr, $nonce_override:expr) => {{
+            let req = test::TestRequest::get().uri("/api/oidc/login").to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), 302);
+            let location = resp
+                .headers()
+                .get(LOCATION)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_owned();
+            assert!(location.starts_with(&format!("{}/authorize?", issuer)));
+            let params = query_string_to_hashmap(location.split_once('?').unwrap().1);
+            assert_eq!(params["code_challenge_method"], "S256");
+            assert_eq!(params["client_id"], "tesou");
+            *idp.challenge.lock().unwrap() = params["code_challenge"].clone();
+            *idp.nonce.lock().unwrap() = $nonce_override.unwrap_or(params["nonce"].clone());
+            *idp.subject.lock().unwrap() = $subject.to_owned();
+            let req = test::TestRequest::get()
+                .uri(&format!(
+                    "/api/oidc/callback?code=mock-code&state={}",
+                    params["state"]
+                ))
+                .to_request();
+            test::call_service(&app, req).await
+        }};
+    }
+
+    // A callback with an unknown state is rejected
+    let req = test::TestRequest::get()
+        .uri("/api/oidc/callback?code=mock-code&state=unknown")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 400);
+
+    // An unmapped subject is rejected
+    let resp = oidc_login!("alice", None::<String>);
+    assert_eq!(resp.status(), 403);
+
+    // A wrong nonce is rejected
+    do_test!(
+        app,
+        Method::POST,
+        "/api/oidc/subjects",
+        r#"{"subject":"alice","user_id":null,"role":"Viewer"}"#,
+        StatusCode::CREATED,
+        r#"{"subject":"alice","user_id":null,"role":"viewer"}"#
+    );
+    let resp = oidc_login!("alice", Some("wrong nonce".to_owned()));
+    assert_eq!(resp.status(), 403);
+
+    // An ID token that the identity provider did not sign is rejected
+    *idp.forge.lock().unwrap() = true;
+    let resp = oidc_login!("alice", None::<String>);
+    assert_eq!(resp.status(), 403);
+    let body = test::read_body(resp).await;
+    assert_eq!(body, "invalid id token signature");
+    *idp.forge.lock().unwrap() = false;
+
+    // A viewer can read but not alter data
+    let resp = oidc_login!("alice", None::<String>);
+    assert_eq!(resp.status(), 302);
+    let location = resp.headers().get(LOCATION).unwrap().to_str().unwrap();
+    let session_token = urlencoding::decode(location.strip_prefix("/#token=").unwrap())
+        .unwrap()
+        .into_owned();
+    let req = test::TestRequest::get()
+        .insert_header(("Authorization", format!("Bearer {session_token}")))
+        .uri("/api/users")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    let req = test::TestRequest::delete()
+        .insert_header(("Authorization", format!("Bearer {session_token}")))
+        .uri("/api/users")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 403);
+    let req = test::TestRequest::get()
+        .insert_header(("Authorization", format!("Bearer {session_token}")))
+        .uri("/api/audit")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 403);
+
+    // An admin subject gets admin rights, and the logins are audited
+    let resp = oidc_login!("bob", None::<String>);
+    assert_eq!(resp.status(), 302);
+    let location = resp.headers().get(LOCATION).unwrap().to_str().unwrap();
+    let session_token = urlencoding::decode(location.strip_prefix("/#token=").unwrap())
+        .unwrap()
+        .into_owned();
+    let req = test::TestRequest::get()
+        .insert_header(("Authorization", format!("Bearer {session_token}")))
+        .uri("/api/audit?action=oidc.login&limit=2")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    let entries: Vec<crate::models::audit::AuditEntry> = test::read_body_json(resp).await;
+    assert_eq!(entries[0].actor, "admin session");
+    assert_eq!(entries[0].target, "oidc_subjects/bob");
+    assert_eq!(entries[1].actor, "viewer session");
+
+    // The logins started and never finished are bounded
+    let oidc = app_config.oidc.as_ref().unwrap();
+    for i in 0..MAX_PENDING_LOGINS + 10 {
+        oidc.add_pending(
+            i.to_string(),
+            PendingLogin {
+                verifier: String::new(),
+                nonce: String::new(),
+                created: Instant::now(),
+            },
+        );
+    }
+    assert_eq!(oidc.pending.lock().unwrap().len(), MAX_PENDING_LOGINS);
+    assert!(oidc.take_pending("0").is_none());
+    assert!(
+        oidc.take_pending(&(MAX_PENDING_LOGINS + 9).to_string())
+            .is_some()
+    );
+
+    // Remove the subject mapping
+    do_test!(
+        app,
+        Method::DELETE,
+        "/api/oidc/subjects/alice",
+        "",
+        StatusCode::OK,
+        "Deleted subject: alice"
+    );
+
+    idp_server_handle.stop(true).await;
+}
@@ -1,12 +1,10 @@
 use std::{
-    env,
     pin::pin,
-    sync::LazyLock,
     time::{Duration, Instant},
 };
 
 use actix_web::Error;
-use actix_web::{HttpRequest, HttpResponse, web};
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, web};
 use actix_web::{
     Responder,
     error::{self},
@@ -19,22 +17,17 @@ use futures_util::{
 use tokio::{sync::mpsc, task::spawn_local, time::interval};
 
 use crate::{
-    app::query_string_to_hashmap,
+    app::{AppConfig, Credential, authenticate, check_share_scope, query_string_to_hashmap},
+    models::group::request_visibility,
     positions_server::{PositionsServerHandle, UserId},
 };
 
-// how often heartbeat pings are sent
-static HEARTBEAT_INTERVAL: LazyLock<Duration> = LazyLock::new(|| {
-    Duration::from_secs(
-        env::var("HEARTBEAT_INTERVAL")
-            .unwrap_or("60".to_owned())
-            .parse::<u64>()
-            .unwrap_or(60),
-    )
-});
+type DbPool = r2d2::Pool<diesel::r2d2::ConnectionManager<crate::db::DbConnection>>;
 
 // how long before lack of client response causes a timeout
-pub static CLIENT_TIMEOUT: LazyLock<Duration> = LazyLock::new(|| *HEARTBEAT_INTERVAL * 2);
+pub fn client_timeout(heartbeat_interval: Duration) -> Duration {
+    heartbeat_interval * 2
+}
 
 // Echo text & binary messages received from the client, respond to ping messages, and monitor connection health to detect network issues and free up resources.
 pub async fn positions_ws(
@@ -42,16 +35,19 @@ pub async fn positions_ws(
     mut session: actix_ws::Session,
     msg_stream: actix_ws::MessageStream,
     user_id: UserId,
+    exact: bool,
+    heartbeat_interval: Duration,
 ) {
     log::info!("new endpoint connection");
 
     let mut last_heartbeat = Instant::now();
-    let mut interval = interval(*HEARTBEAT_INTERVAL);
+    let mut interval = interval(heartbeat_interval);
+    let client_timeout = client_timeout(heartbeat_interval);
 
     let (conn_tx, mut conn_rx) = mpsc::unbounded_channel();
 
     // unwrap: positions server is not dropped before the HTTP server
-    let conn_id = positions_server.connect(conn_tx, user_id).await;
+    let conn_id = positions_server.connect(conn_tx, user_id, exact).await;
 
     let msg_stream = msg_stream
         .max_frame_size(128 * 1024)
@@ -117,10 +113,10 @@ pub async fn positions_ws(
             // heartbeat internal tick
             Either::Right((_inst, _)) => {
                 // if no heartbeat ping/pong received recently, close the connection
-                if Instant::now().duration_since(last_heartbeat) > *CLIENT_TIMEOUT {
+                if Instant::now().duration_since(last_heartbeat) > client_timeout {
                     log::info!(
                         "client has not sent heartbeat in over {:?}; disconnecting",
-                        *CLIENT_TIMEOUT
+                        client_timeout
                     );
                     break None;
                 }
@@ -150,22 +146,53 @@ async fn process_text_msg(
 pub async fn positions_ws_handler(
     req: HttpRequest,
     stream: web::Payload,
+    pool: web::Data<DbPool>,
+    cfg: web::Data<AppConfig>,
     chat_server: web::Data<PositionsServerHandle>,
 ) -> Result<HttpResponse, Error> {
-    let (res, session, msg_stream) = actix_ws::handle(&req, stream)?;
+    // browsers can't set headers on websockets, the token comes in the query string
+    let params = query_string_to_hashmap(req.query_string());
+    let query_token = params
+        .get("token")
+        .ok_or(error::ErrorUnauthorized("could not parse query"))?;
+    let query_token = urlencoding::decode(query_token).unwrap_or_default();
+    let credential = authenticate(&req, &query_token)
+        .await
+        .map_err(|e| e.into_error(error::ErrorUnauthorized))?;
+    if let Credential::Share(id) = credential {
+        check_share_scope(id, params.get("user_id")).map_err(error::ErrorUnauthorized)?;
+    }
+    req.extensions_mut().insert(credential);
 
     // get user id from request
-    let user_id = query_string_to_hashmap(req.query_string())
+    let user_id = params
         .get("user_id")
         .ok_or(error::ErrorBadRequest("no user_id must in query"))?
         .parse::<u16>()
         .map_err(|_| error::ErrorBadRequest("the user_id must be a number"))?;
+    // positions stay private outside the groups
+    if !request_visibility(&req, &pool)
+        .await?
+        .allows(user_id.into())
+    {
+        return Err(error::ErrorForbidden("user is outside of your groups"));
+    }
+
+    // the owner and the admins get the exact positions
+    let exact = req
+        .extensions()
+        .get::<Credential>()
+        .is_some_and(|c| c.owns(user_id.into()));
+
+    let (res, session, msg_stream) = actix_ws::handle(&req, stream)?;
     // spawn websocket handler (and don't await it) so that the response is returned immediately
     spawn_local(positions_ws(
         (**chat_server).clone(),
         session,
         msg_stream,
         user_id,
+        exact,
+        cfg.config.heartbeat_interval(),
     ));
 
     Ok(res)
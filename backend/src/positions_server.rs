@@ -12,6 +12,8 @@ use std::{
 use rand::{Rng, rng};
 use tokio::sync::{mpsc, oneshot};
 
+use crate::pubsub::{InMemory, PubSub, Published};
+
 // connection ID
 pub type ConnId = u64;
 
@@ -26,6 +28,7 @@ pub type Msg = String;
 enum Command {
     Connect {
         user: UserId,
+        exact: bool,
         conn_tx: mpsc::UnboundedSender<Msg>,
         res_tx: oneshot::Sender<ConnId>,
     },
@@ -36,6 +39,7 @@ enum Command {
 
     Message {
         msg: Msg,
+        shared_msg: Option<Msg>,
         user_id: UserId,
         res_tx: oneshot::Sender<()>,
     },
@@ -55,6 +59,9 @@ pub struct PositionsServer {
     // map of user id to participant IDs listening to that user positions updates
     users: HashMap<UserId, HashSet<ConnId>>,
 
+    // connections that get the exact positions (their owner or an admin), the others get the shared ones
+    exact_sessions: HashSet<ConnId>,
+
     // tracks total number of historical connections established.
     visitor_count: Arc<AtomicUsize>,
 
@@ -72,17 +79,27 @@ impl PositionsServer {
             Self {
                 sessions: HashMap::new(),
                 users,
+                exact_sessions: HashSet::new(),
                 visitor_count: Arc::new(AtomicUsize::new(0)),
                 cmd_rx,
             },
-            PositionsServerHandle { cmd_tx },
+            PositionsServerHandle {
+                cmd_tx,
+                pubsub: Arc::new(InMemory),
+            },
         )
     }
 
-    async fn send_message(&self, user: &UserId, msg: impl Into<Msg>) {
+    async fn send_message(&self, user: &UserId, msg: Msg, shared_msg: Option<Msg>) {
         if let Some(sessions) = self.users.get(user) {
-            let msg = msg.into();
             for conn_id in sessions {
+                let msg = match self.exact_sessions.contains(conn_id) {
+                    true => &msg,
+                    false => match &shared_msg {
+                        Some(shared_msg) => shared_msg,
+                        None => continue,
+                    },
+                };
                 if let Some(tx) = self.sessions.get(conn_id) {
                     // errors if client disconnected abruptly and hasn't been timed-out yet
                     let _ = tx.send(msg.clone());
@@ -92,12 +109,20 @@ impl PositionsServer {
     }
 
     //Register new session and assign unique ID to this session
-    async fn connect(&mut self, tx: mpsc::UnboundedSender<Msg>, user_id: UserId) -> ConnId {
+    async fn connect(
+        &mut self,
+        tx: mpsc::UnboundedSender<Msg>,
+        user_id: UserId,
+        exact: bool,
+    ) -> ConnId {
         log::info!("endpoint connected");
 
         // register session with random connection ID
         let id = rng().random::<ConnId>();
         self.sessions.insert(id, tx);
+        if exact {
+            self.exact_sessions.insert(id);
+        }
 
         // Join the endpoints listening to the target user
         self.users.entry(user_id).or_default().insert(id);
@@ -114,6 +139,7 @@ impl PositionsServer {
 
         // remove sender
         self.sessions.remove(&conn_id);
+        self.exact_sessions.remove(&conn_id);
         // remove session from all users
         for sessions in self.users.values_mut() {
             sessions.remove(&conn_id);
@@ -128,9 +154,10 @@ impl PositionsServer {
                 Command::Connect {
                     conn_tx,
                     user,
+                    exact,
                     res_tx,
                 } => {
-                    let conn_id = self.connect(conn_tx, user).await;
+                    let conn_id = self.connect(conn_tx, user, exact).await;
                     let _ = res_tx.send(conn_id);
                 }
 
@@ -141,9 +168,10 @@ impl PositionsServer {
                 Command::Message {
                     user_id,
                     msg,
+                    shared_msg,
                     res_tx,
                 } => {
-                    self.send_message(&user_id, msg).await;
+                    self.send_message(&user_id, msg, shared_msg).await;
                     let _ = res_tx.send(());
                 }
 
@@ -159,14 +187,32 @@ impl PositionsServer {
 }
 
 // handle and command sender for positions server
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct PositionsServerHandle {
     cmd_tx: mpsc::UnboundedSender<Command>,
+
+    // backend broadcasting the messages to every instance
+    pubsub: Arc<dyn PubSub>,
 }
 
 impl PositionsServerHandle {
+    // Broadcast the messages through another pub/sub backend
+    pub fn with_pubsub(mut self, pubsub: Arc<dyn PubSub>) -> Self {
+        self.pubsub = pubsub;
+        self
+    }
+
+    pub fn pubsub_name(&self) -> &'static str {
+        self.pubsub.name()
+    }
+
     //Register client message sender and obtain connection ID.
-    pub async fn connect(&self, conn_tx: mpsc::UnboundedSender<Msg>, user: UserId) -> ConnId {
+    pub async fn connect(
+        &self,
+        conn_tx: mpsc::UnboundedSender<Msg>,
+        user: UserId,
+        exact: bool,
+    ) -> ConnId {
         let (res_tx, res_rx) = oneshot::channel();
 
         // unwrap: positions server should not have been dropped
@@ -175,6 +221,7 @@ impl PositionsServerHandle {
                 conn_tx,
                 res_tx,
                 user,
+                exact,
             })
             .unwrap();
 
@@ -184,13 +231,40 @@ impl PositionsServerHandle {
 
     // broadcast message
     pub async fn send_message(&self, user_id: UserId, msg: impl Into<Msg>) {
+        let msg = msg.into();
+        self.send_private_message(user_id, msg.clone(), Some(msg))
+            .await;
+    }
+
+    // broadcast a message to the exact listeners, and its shared version (if any) to the others
+    pub async fn send_private_message(
+        &self,
+        user_id: UserId,
+        msg: impl Into<Msg>,
+        shared_msg: Option<Msg>,
+    ) {
+        let message = Published {
+            user_id,
+            msg: msg.into(),
+            shared_msg,
+        };
+        if let Err(e) = self.pubsub.publish(self, message.clone()).await {
+            // the local listeners get the message anyway
+            log::error!("{} publication failed: {}", self.pubsub.name(), e);
+            self.deliver(message).await;
+        }
+    }
+
+    // deliver a published message to the local sessions
+    pub async fn deliver(&self, message: Published) {
         let (res_tx, res_rx) = oneshot::channel();
 
         // unwrap: positions server should not have been dropped
         self.cmd_tx
             .send(Command::Message {
-                user_id,
-                msg: msg.into(),
+                user_id: message.user_id,
+                msg: message.msg,
+                shared_msg: message.shared_msg,
                 res_tx,
             })
             .unwrap();
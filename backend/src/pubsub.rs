@@ -0,0 +1,274 @@
+//! Broadcast of the positions server messages between the instances.
+//!
+//! Every instance keeps its own WebSocket sessions, and publishes the messages for them through a
+//! pub/sub backend : a message published by any instance is then delivered by all of them to their
+//! local sessions. The in-memory backend serves a single instance, the PostgreSQL one relies on
+//! LISTEN/NOTIFY so that several instances behind a load balancer share their broadcasts.
+//!
+//! The PostgreSQL connection uses TLS as set by the sslmode of its URL, and is opened again when
+//! lost : the messages published by the other instances in the meantime are missed.
+
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use futures_util::{StreamExt, future::BoxFuture, stream};
+use rustls::ClientConfig;
+use rustls_platform_verifier::BuilderVerifierExt;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use tokio_postgres::{AsyncMessage, Client};
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+use crate::positions_server::{Msg, PositionsServerHandle, UserId};
+
+// channel of the PostgreSQL notifications
+const CHANNEL: &str = "tesou_positions";
+// PostgreSQL refuses the notification payloads of 8000 bytes or more
+const MAX_PAYLOAD_BYTES: usize = 7999;
+// delays between the attempts to connect again, doubled up to the maximum
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+// A message for the sessions listening to a user, as sent to every instance
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Published {
+    pub user_id: UserId,
+    // the message for the exact listeners
+    pub msg: Msg,
+    // the message for the others, if any
+    pub shared_msg: Option<Msg>,
+}
+
+pub trait PubSub: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // Publish a message to every instance, the local one being given for the local delivery
+    fn publish<'a>(
+        &'a self,
+        local: &'a PositionsServerHandle,
+        message: Published,
+    ) -> BoxFuture<'a, Result<(), String>>;
+}
+
+// Deliver the messages to the local sessions only
+pub struct InMemory;
+
+impl PubSub for InMemory {
+    fn name(&self) -> &'static str {
+        "in-memory"
+    }
+
+    fn publish<'a>(
+        &'a self,
+        local: &'a PositionsServerHandle,
+        message: Published,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            local.deliver(message).await;
+            Ok(())
+        })
+    }
+}
+
+// Share the messages through PostgreSQL notifications, the instances deliver the ones they receive
+pub struct Postgres {
+    // none while the connection is lost
+    client: Arc<RwLock<Option<Arc<Client>>>>,
+}
+
+impl Postgres {
+    // Connect, and deliver the received notifications to the local server, connecting again when
+    // the connection is lost
+    pub async fn connect(url: &str, local: PositionsServerHandle) -> Result<Self, String> {
+        let tls = tls_connector()?;
+        let (client, mut connection) = listen(url, &tls, &local).await?;
+        let client = Arc::new(RwLock::new(Some(client)));
+        let shared = client.clone();
+        let url = url.to_owned();
+        tokio::spawn(async move {
+            loop {
+                let _ = connection.await;
+                *shared.write().unwrap() = None;
+                log::error!(
+                    "pub/sub connection lost, the broadcasts of the other instances are missed until it is back"
+                );
+                let mut delay = RECONNECT_DELAY;
+                loop {
+                    tokio::time::sleep(delay).await;
+                    match listen(&url, &tls, &local).await {
+                        Ok((c, conn)) => {
+                            *shared.write().unwrap() = Some(c);
+                            connection = conn;
+                            log::info!("pub/sub connection back");
+                            break;
+                        }
+                        Err(e) => {
+                            log::error!("pub/sub connection failed: {}", e);
+                            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                        }
+                    }
+                }
+            }
+        });
+        Ok(Postgres { client })
+    }
+}
+
+// Verify the server certificates with the platform trust store
+fn tls_connector() -> Result<MakeRustlsConnect, String> {
+    let config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .and_then(|builder| builder.with_platform_verifier())
+            .map_err(|e| e.to_string())?
+            .with_no_client_auth();
+    Ok(MakeRustlsConnect::new(config))
+}
+
+// Open a connection listening to the channel, its task ends when the connection does
+async fn listen(
+    url: &str,
+    tls: &MakeRustlsConnect,
+    local: &PositionsServerHandle,
+) -> Result<(Arc<Client>, JoinHandle<()>), String> {
+    let (client, mut connection) = tokio_postgres::connect(url, tls.clone())
+        .await
+        .map_err(|e| e.to_string())?;
+    let local = local.clone();
+    let mut messages = stream::poll_fn(move |cx| connection.poll_message(cx));
+    let connection = tokio::spawn(async move {
+        while let Some(message) = messages.next().await {
+            match message {
+                Ok(AsyncMessage::Notification(n)) => {
+                    match serde_json::from_str::<Published>(n.payload()) {
+                        Ok(message) => local.deliver(message).await,
+                        Err(e) => log::error!("invalid published message: {}", e),
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    log::error!("pub/sub connection failed: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+    if let Err(e) = client.batch_execute(&format!("LISTEN {}", CHANNEL)).await {
+        connection.abort();
+        return Err(e.to_string());
+    }
+    Ok((Arc::new(client), connection))
+}
+
+impl PubSub for Postgres {
+    fn name(&self) -> &'static str {
+        "postgres"
+    }
+
+    fn publish<'a>(
+        &'a self,
+        _local: &'a PositionsServerHandle,
+        message: Published,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let payload = serde_json::to_string(&message).map_err(|e| e.to_string())?;
+            if payload.len() > MAX_PAYLOAD_BYTES {
+                return Err(format!(
+                    "message of {} bytes too large for a notification",
+                    payload.len()
+                ));
+            }
+            let client = self.client.read().unwrap().clone();
+            let Some(client) = client else {
+                return Err("pub/sub connection lost".to_owned());
+            };
+            client
+                .execute("SELECT pg_notify($1, $2)", &[&CHANNEL, &payload])
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::positions_server::PositionsServer;
+
+    // Two instances sharing a PostgreSQL database, run with TEST_PUBSUB_URL=... cargo test -- --ignored
+    #[actix_rt::test]
+    #[ignore = "needs a PostgreSQL database given by TEST_PUBSUB_URL"]
+    async fn test_postgres() {
+        let url = std::env::var("TEST_PUBSUB_URL").expect("TEST_PUBSUB_URL must be set");
+        let mut instances = Vec::new();
+        for _ in 0..2 {
+            let (server, handle) = PositionsServer::new();
+            tokio::spawn(server.run());
+            let pubsub = Postgres::connect(&url, handle.clone()).await.unwrap();
+            instances.push(handle.with_pubsub(Arc::new(pubsub)));
+        }
+        let (exact_tx, mut exact_rx) = mpsc::unbounded_channel();
+        let (shared_tx, mut shared_rx) = mpsc::unbounded_channel();
+        instances[1].connect(exact_tx, 7, true).await;
+        instances[1].connect(shared_tx, 7, false).await;
+
+        // A message published by an instance reaches the sessions of the other
+        instances[0]
+            .send_private_message(7, "exact", Some("shared".to_owned()))
+            .await;
+        let exact = tokio::time::timeout(Duration::from_secs(5), exact_rx.recv()).await;
+        assert_eq!(exact.unwrap().as_deref(), Some("exact"));
+        let shared = tokio::time::timeout(Duration::from_secs(5), shared_rx.recv()).await;
+        assert_eq!(shared.unwrap().as_deref(), Some("shared"));
+        instances[0].send_private_message(7, "private", None).await;
+        let exact = tokio::time::timeout(Duration::from_secs(5), exact_rx.recv()).await;
+        assert_eq!(exact.unwrap().as_deref(), Some("private"));
+        assert!(shared_rx.try_recv().is_err());
+
+        // A message too large for a notification is delivered to the local sessions only
+        let (local_tx, mut local_rx) = mpsc::unbounded_channel();
+        instances[0].connect(local_tx, 7, true).await;
+        instances[0]
+            .send_private_message(7, "x".repeat(MAX_PAYLOAD_BYTES), None)
+            .await;
+        let local = tokio::time::timeout(Duration::from_secs(5), local_rx.recv()).await;
+        assert_eq!(local.unwrap().unwrap().len(), MAX_PAYLOAD_BYTES);
+        assert!(
+            tokio::time::timeout(Duration::from_secs(1), exact_rx.recv())
+                .await
+                .is_err()
+        );
+
+        // The instances connect again when their connections are lost
+        let (client, connection) = tokio_postgres::connect(&url, tokio_postgres::NoTls)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+        client
+            .batch_execute(&format!(
+                "SELECT pg_terminate_backend(pid) FROM pg_stat_activity WHERE pid <> pg_backend_pid() AND (query LIKE 'LISTEN {}' OR query LIKE '%pg_notify%')",
+                CHANNEL
+            ))
+            .await
+            .unwrap();
+        let mut received = None;
+        for _ in 0..20 {
+            instances[0]
+                .send_private_message(7, "reconnected", None)
+                .await;
+            if let Ok(msg) = tokio::time::timeout(Duration::from_millis(500), exact_rx.recv()).await
+            {
+                received = msg;
+                break;
+            }
+        }
+        assert_eq!(received.as_deref(), Some("reconnected"));
+    }
+}
@@ -0,0 +1,407 @@
+//! Brute-force protection for the authentication endpoints.
+//!
+//! Failed attempts are counted per client IP and per presented credential. Once a key reaches
+//! the maximum number of failures, it is locked out for a duration that doubles with each further
+//! failure. Every failed attempt is also recorded in the `auth_failures` table, as well as the
+//! first attempt refused during each lockout, so that a client hammering a locked key does not
+//! fill the table. The recorded failures are purged after a retention.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::{HttpRequest, web};
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use sha2::{Digest, Sha256};
+
+use crate::db::DbConnection;
+
+type DbPool = r2d2::Pool<ConnectionManager<DbConnection>>;
+
+// forget about a key that has not failed for this long
+const FORGET_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+// keep the attempts map bounded, the keys that failed the longest ago are forgotten first
+const MAX_TRACKED_KEYS: usize = 10_000;
+// reason recorded for the attempts refused during a lockout
+pub const LOCKED_OUT: &str = "locked out after too many failed attempts";
+
+#[derive(Debug, Clone, Copy)]
+struct Attempts {
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+    // a refused attempt was recorded during the current lockout
+    refusal_recorded: bool,
+}
+
+#[derive(Debug)]
+pub struct AuthLimiter {
+    max_failures: u32,
+    base_lockout: Duration,
+    max_lockout: Duration,
+    attempts: Mutex<HashMap<String, Attempts>>,
+}
+
+impl Default for AuthLimiter {
+    fn default() -> Self {
+        AuthLimiter::new(5, Duration::from_secs(30), Duration::from_secs(60 * 60))
+    }
+}
+
+impl AuthLimiter {
+    pub fn new(max_failures: u32, base_lockout: Duration, max_lockout: Duration) -> Self {
+        AuthLimiter {
+            max_failures,
+            base_lockout,
+            max_lockout,
+            attempts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Check that none of the keys is locked out, returns the remaining lockout otherwise
+    pub fn check(&self, keys: &[&str]) -> Result<(), Duration> {
+        let attempts = self.attempts.lock().unwrap();
+        let now = Instant::now();
+        let remaining = keys
+            .iter()
+            .filter_map(|k| attempts.get(*k)?.locked_until)
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+            .max();
+        match remaining {
+            Some(remaining) => Err(remaining),
+            None => Ok(()),
+        }
+    }
+
+    // Note an attempt refused during a lockout, returns whether it is the first one refused on
+    // any of the locked keys since they were locked, and so has to be recorded
+    pub fn refusal(&self, keys: &[&str]) -> bool {
+        let mut attempts = self.attempts.lock().unwrap();
+        let now = Instant::now();
+        let mut first = false;
+        for key in keys {
+            if let Some(a) = attempts.get_mut(*key)
+                && a.locked_until.is_some_and(|until| until > now)
+                && !a.refusal_recorded
+            {
+                a.refusal_recorded = true;
+                first = true;
+            }
+        }
+        first
+    }
+
+    pub fn failure(&self, keys: &[&str]) {
+        let mut attempts = self.attempts.lock().unwrap();
+        let now = Instant::now();
+        if attempts.len() + keys.len() > MAX_TRACKED_KEYS {
+            attempts.retain(|_, a| now.duration_since(a.last_failure) < FORGET_AFTER);
+            // evict a tenth of the keys at once, not to sort the map on each failure
+            let excess = (attempts.len() + keys.len()).saturating_sub(MAX_TRACKED_KEYS * 9 / 10);
+            if excess > 0 {
+                let mut oldest: Vec<(Instant, String)> = attempts
+                    .iter()
+                    .map(|(k, a)| (a.last_failure, k.clone()))
+                    .collect();
+                oldest.sort_unstable();
+                for (_, key) in oldest.into_iter().take(excess) {
+                    attempts.remove(&key);
+                }
+            }
+        }
+        for key in keys {
+            let a = attempts.entry(key.to_string()).or_insert(Attempts {
+                failures: 0,
+                last_failure: now,
+                locked_until: None,
+                refusal_recorded: false,
+            });
+            if now.duration_since(a.last_failure) >= FORGET_AFTER {
+                a.failures = 0;
+            }
+            a.failures += 1;
+            a.last_failure = now;
+            if a.failures >= self.max_failures {
+                a.locked_until = Some(now + self.lockout(a.failures));
+                a.refusal_recorded = false;
+            }
+        }
+    }
+
+    pub fn success(&self, keys: &[&str]) {
+        let mut attempts = self.attempts.lock().unwrap();
+        for key in keys {
+            attempts.remove(*key);
+        }
+    }
+
+    // Lockout duration, doubling with each failure above the maximum
+    fn lockout(&self, failures: u32) -> Duration {
+        let exponent = failures.saturating_sub(self.max_failures).min(31);
+        self.base_lockout
+            .saturating_mul(1 << exponent)
+            .min(self.max_lockout)
+    }
+}
+
+// Limiter keys for a request : its client IP and a digest of the presented credential
+pub fn limiter_keys(req: &HttpRequest, token: &str) -> (String, String) {
+    (
+        format!("ip:{}", client_ip(req)),
+        format!("credential:{}", credential_digest(token)),
+    )
+}
+
+pub fn client_ip(req: &HttpRequest) -> String {
+    req.peer_addr()
+        .map(|a| a.ip().to_string())
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+// Never keep the presented credentials, only a short digest of them
+pub fn credential_digest(token: &str) -> String {
+    let digest = Sha256::digest(token.as_bytes());
+    digest[..8].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::schema::auth_failures)]
+pub struct NewAuthFailure {
+    pub time: i64,
+    pub ip: String,
+    pub credential: String,
+    pub path: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Queryable)]
+#[allow(dead_code)]
+pub struct AuthFailure {
+    pub id: i32,
+    pub time: i64,
+    pub ip: String,
+    pub credential: String,
+    pub path: String,
+    pub reason: String,
+}
+
+// Record a failed attempt in the background, so as not to slow down the rejection
+pub fn record_failure(req: &HttpRequest, token: &str, reason: &str) {
+    log::warn!(
+        "failed authentication from {} on {}: {}",
+        client_ip(req),
+        req.path(),
+        reason
+    );
+    let Some(pool) = req.app_data::<web::Data<DbPool>>() else {
+        return;
+    };
+    let pool = pool.clone();
+    let failure = NewAuthFailure {
+        time: crate::utils::now(),
+        ip: client_ip(req),
+        credential: credential_digest(token),
+        path: req.path().to_owned(),
+        reason: reason.to_owned(),
+    };
+    actix_web::rt::spawn(async move {
+        let res = web::block(move || -> Result<usize, crate::errors::ServerError> {
+            let mut conn = pool.get()?;
+            Ok(diesel::insert_into(crate::schema::auth_failures::table)
+                .values(&failure)
+                .execute(&mut conn)?)
+        })
+        .await;
+        if !matches!(res, Ok(Ok(_))) {
+            log::error!("could not record failed authentication attempt");
+        }
+    });
+}
+
+// Delete the failed authentications recorded longer ago than the retention
+pub fn purge_expired(conn: &mut DbConnection, retention: Duration) -> QueryResult<usize> {
+    if retention.is_zero() {
+        return Ok(0);
+    }
+    let limit = crate::utils::now() - i64::try_from(retention.as_millis()).unwrap_or(i64::MAX / 2);
+    diesel::delete(crate::schema::auth_failures::table)
+        .filter(crate::schema::auth_failures::time.lt(limit))
+        .execute(conn)
+}
+
+#[cfg(test)]
+pub async fn rate_limit_test(
+    pool: &DbPool,
+    position_server_handle: &crate::positions_server::PositionsServerHandle,
+) {
+    use crate::app::AppConfig;
+    use actix_web::test;
+
+    // Use a dedicated configuration not to lock out the other tests
+    let app_config = web::Data::new(AppConfig::new("0101".to_string(), None).with_auth_limiter(
+        AuthLimiter::new(3, Duration::from_secs(60), Duration::from_secs(60)),
+    ));
+    let app = test::init_service(crate::create_app!(
+        pool,
+        &app_config,
+        position_server_handle
+    ))
+    .await;
+
+    let before: i64 = crate::schema::auth_failures::table
+        .count()
+        .get_result(&mut pool.get().unwrap())
+        .unwrap();
+
+    // Fail three times from the same IP
+    for _ in 0..3 {
+        let req = test::TestRequest::get()
+            .peer_addr("10.0.0.1:1234".parse().unwrap())
+            .insert_header(("Authorization", "Bearer 0102"))
+            .uri("/api/users")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 403);
+    }
+
+    // The IP is now locked out, even with the right token
+    let req = test::TestRequest::get()
+        .peer_addr("10.0.0.1:1234".parse().unwrap())
+        .insert_header(("Authorization", "Bearer 0101"))
+        .uri("/api/users")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 429);
+
+    // The wrong credential is locked out from another IP too
+    let req = test::TestRequest::get()
+        .peer_addr("10.0.0.2:1234".parse().unwrap())
+        .insert_header(("Authorization", "Bearer 0102"))
+        .uri("/api/users")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 429);
+
+    // ... as well as on the websocket endpoint
+    let req = test::TestRequest::get()
+        .peer_addr("10.0.0.2:1234".parse().unwrap())
+        .uri("/api/positions/ws?user_id=1&token=0102")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 429);
+
+    // Another IP with the right token is not affected
+    let req = test::TestRequest::get()
+        .peer_addr("10.0.0.2:1234".parse().unwrap())
+        .insert_header(("Authorization", "Bearer 0101"))
+        .uri("/api/users")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+
+    // The failed attempts were recorded, and so was the first attempt refused on each locked key
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let failures: Vec<AuthFailure> = crate::schema::auth_failures::table
+        .order(crate::schema::auth_failures::id.desc())
+        .limit(5)
+        .load(&mut pool.get().unwrap())
+        .unwrap();
+    let after: i64 = crate::schema::auth_failures::table
+        .count()
+        .get_result(&mut pool.get().unwrap())
+        .unwrap();
+    assert_eq!(after - before, 5);
+    let (refused, failed): (Vec<AuthFailure>, Vec<AuthFailure>) =
+        failures.into_iter().partition(|f| f.reason == LOCKED_OUT);
+    assert_eq!(failed.len(), 3);
+    assert!(failed.iter().all(|f| f.ip == "10.0.0.1"
+        && f.path == "/api/users"
+        && f.credential == credential_digest("0102")));
+    assert_eq!(refused.len(), 2);
+    assert!(refused.iter().any(|f| f.ip == "10.0.0.1"));
+    assert!(
+        refused
+            .iter()
+            .any(|f| f.ip == "10.0.0.2" && f.credential == credential_digest("0102"))
+    );
+
+    // The failures recorded longer ago than the retention are purged
+    let mut conn = pool.get().unwrap();
+    diesel::insert_into(crate::schema::auth_failures::table)
+        .values(&NewAuthFailure {
+            time: crate::utils::now() - 2 * 60 * 60 * 1000,
+            ip: "10.0.0.3".to_owned(),
+            credential: credential_digest("0103"),
+            path: "/api/users".to_owned(),
+            reason: "expired".to_owned(),
+        })
+        .execute(&mut conn)
+        .unwrap();
+    assert_eq!(
+        purge_expired(&mut conn, Duration::from_secs(60 * 60)).unwrap(),
+        1
+    );
+    assert_eq!(purge_expired(&mut conn, Duration::ZERO).unwrap(), 0);
+    let after_purge: i64 = crate::schema::auth_failures::table
+        .count()
+        .get_result(&mut conn)
+        .unwrap();
+    assert_eq!(after_purge, after);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exponential_lockout() {
+        let limiter = AuthLimiter::new(3, Duration::from_secs(10), Duration::from_secs(60));
+        assert_eq!(limiter.lockout(3), Duration::from_secs(10));
+        assert_eq!(limiter.lockout(4), Duration::from_secs(20));
+        assert_eq!(limiter.lockout(5), Duration::from_secs(40));
+        assert_eq!(limiter.lockout(6), Duration::from_secs(60));
+        assert_eq!(limiter.lockout(100), Duration::from_secs(60));
+
+        let keys = ["ip:1.2.3.4", "credential:abcd"];
+        limiter.failure(&keys);
+        limiter.failure(&keys);
+        assert!(limiter.check(&keys).is_ok());
+        limiter.failure(&keys);
+        assert!(limiter.check(&keys).is_err());
+        assert!(limiter.check(&["ip:1.2.3.4"]).is_err());
+        assert!(limiter.check(&["ip:5.6.7.8"]).is_ok());
+
+        // Only the first refusal of a lockout is to be recorded
+        assert!(limiter.refusal(&keys));
+        assert!(!limiter.refusal(&keys));
+        assert!(!limiter.refusal(&["ip:5.6.7.8"]));
+        // ... until a new lockout
+        limiter.failure(&keys);
+        assert!(limiter.refusal(&["credential:abcd"]));
+
+        // A success clears the keys
+        limiter.success(&keys);
+        assert!(limiter.check(&keys).is_ok());
+    }
+
+    #[test]
+    fn test_bounded_keys() {
+        let limiter = AuthLimiter::new(1, Duration::from_secs(10), Duration::from_secs(60));
+        for i in 0..MAX_TRACKED_KEYS + 10 {
+            limiter.failure(&[&format!("ip:{}", i)]);
+        }
+        let len = limiter.attempts.lock().unwrap().len();
+        assert!(len <= MAX_TRACKED_KEYS);
+        // the oldest keys were evicted, not the latest
+        assert!(limiter.check(&["ip:0"]).is_ok());
+        assert!(
+            limiter
+                .check(&[&format!("ip:{}", MAX_TRACKED_KEYS + 9)])
+                .is_err()
+        );
+    }
+}
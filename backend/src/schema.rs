@@ -1,3 +1,97 @@
+table! {
+    alert_settings (user_id) {
+        user_id -> Integer,
+        battery_threshold -> Nullable<Integer>,
+        offline_after -> Nullable<Integer>,
+        notifiers -> Nullable<Text>,
+    }
+}
+
+table! {
+    alerts (id) {
+        id -> Integer,
+        user_id -> Integer,
+        kind -> Text,
+        message -> Text,
+        raised_at -> BigInt,
+        cleared_at -> Nullable<BigInt>,
+        share_expires_at -> Nullable<BigInt>,
+    }
+}
+
+table! {
+    audit_log (id) {
+        id -> Integer,
+        time -> BigInt,
+        actor -> Text,
+        ip -> Text,
+        device -> Text,
+        action -> Text,
+        target -> Text,
+        details -> Text,
+    }
+}
+
+table! {
+    auth_failures (id) {
+        id -> Integer,
+        time -> BigInt,
+        ip -> Text,
+        credential -> Text,
+        path -> Text,
+        reason -> Text,
+    }
+}
+
+table! {
+    cell_towers (mcc, mnc, lac, cid) {
+        mcc -> Integer,
+        mnc -> Integer,
+        lac -> Integer,
+        cid -> BigInt,
+        radio -> Text,
+        latitude -> Double,
+        longitude -> Double,
+        radius -> Integer,
+        expires_at -> Nullable<BigInt>,
+    }
+}
+
+table! {
+    daily_tracks (user_id, day) {
+        user_id -> Integer,
+        day -> Text,
+        positions -> Text,
+        raw_count -> Integer,
+        last_time -> BigInt,
+    }
+}
+
+table! {
+    device_status (user_id) {
+        user_id -> Integer,
+        last_fix -> BigInt,
+        battery_level -> Integer,
+        source -> Text,
+        online -> Bool,
+    }
+}
+
+table! {
+    group_members (group_id, user_id) {
+        group_id -> Integer,
+        user_id -> Integer,
+    }
+}
+
+table! {
+    oidc_subjects (subject) {
+        subject -> Text,
+        user_id -> Nullable<Integer>,
+        role -> Text,
+    }
+}
+
 table! {
     positions (id) {
         id -> Integer,
@@ -8,6 +102,58 @@ table! {
         battery_level -> Integer,
         sport_mode -> Bool,
         time -> BigInt,
+        flagged -> Nullable<Text>,
+    }
+}
+
+table! {
+    privacy_settings (user_id) {
+        user_id -> Integer,
+        precision -> Text,
+        paused_since -> Nullable<BigInt>,
+        paused_until -> Nullable<BigInt>,
+    }
+}
+
+table! {
+    push_subscriptions (id) {
+        id -> Integer,
+        user_id -> Integer,
+        credential -> Text,
+        endpoint -> Text,
+        p256dh -> Text,
+        auth -> Text,
+        created_at -> BigInt,
+    }
+}
+
+table! {
+    sharing_rules (id) {
+        id -> Integer,
+        user_id -> Integer,
+        name -> Text,
+        starts_at -> Nullable<Text>,
+        ends_at -> Nullable<Text>,
+        latitude -> Nullable<Double>,
+        longitude -> Nullable<Double>,
+        radius -> Nullable<Double>,
+    }
+}
+
+table! {
+    trash (id) {
+        id -> Integer,
+        time -> BigInt,
+        actor -> Text,
+        target -> Text,
+        content -> Text,
+    }
+}
+
+table! {
+    user_groups (id) {
+        id -> Integer,
+        name -> Text,
     }
 }
 
@@ -16,9 +162,40 @@ table! {
         id -> Integer,
         name -> Text,
         surname -> Text,
+        color -> Nullable<Text>,
+        phone -> Nullable<Text>,
+        timezone -> Nullable<Text>,
+        avatar_updated_at -> Nullable<BigInt>,
     }
 }
 
+joinable!(alert_settings -> users (user_id));
+joinable!(alerts -> users (user_id));
+joinable!(daily_tracks -> users (user_id));
+joinable!(device_status -> users (user_id));
+joinable!(group_members -> user_groups (group_id));
+joinable!(group_members -> users (user_id));
+joinable!(oidc_subjects -> users (user_id));
 joinable!(positions -> users (user_id));
+joinable!(privacy_settings -> users (user_id));
+joinable!(push_subscriptions -> users (user_id));
+joinable!(sharing_rules -> users (user_id));
 
-allow_tables_to_appear_in_same_query!(positions, users,);
+allow_tables_to_appear_in_same_query!(
+    alert_settings,
+    alerts,
+    audit_log,
+    auth_failures,
+    cell_towers,
+    daily_tracks,
+    device_status,
+    group_members,
+    oidc_subjects,
+    positions,
+    privacy_settings,
+    push_subscriptions,
+    sharing_rules,
+    trash,
+    user_groups,
+    users,
+);
@@ -1,44 +1,77 @@
 use actix_web::web::Data;
-use diesel_migrations::{EmbeddedMigrations, MigrationHarness};
 use tokio::spawn;
 
 use crate::{
     app::AppConfig,
+    config::Config,
+    db::DbConnection,
+    ingester::Ingester,
     models::{
-        position_tests::position_test, position_ws_tests::position_ws_test, sport_mode_tests::toggle_sport_mode_test, user_tests::user_test
+        alert_tests::alert_test, audit_tests::audit_test, backup_tests::backup_test, group_tests::group_test, position_tests::position_test, position_ws_tests::position_ws_test, privacy_tests::privacy_test, push_tests::push_test, sos_tests::sos_test, sport_mode_tests::toggle_sport_mode_test, track_tests::track_test, trash_tests::trash_test, user_tests::user_test
     },
     positions_server::PositionsServer,
+    oidc::oidc_test,
+    rate_limit::rate_limit_test,
     token::token_test,
 };
 #[actix_rt::test]
 async fn test_models() {
-    use diesel::SqliteConnection;
-    use diesel::r2d2::{self, ConnectionManager};
     // TODO: Audit that the environment access only happens in single-threaded code.
     unsafe { std::env::set_var("RUST_LOG", "debug") };
     env_logger::init();
 
+    test_database("db/test_db.sqlite").await;
+    // Run the same tests against PostgreSQL if a database is given, it is wiped beforehand
+    if let Ok(url) = std::env::var("TEST_DATABASE_URL") {
+        test_database(&url).await;
+    }
+}
+
+async fn test_database(database_url: &str) {
+    use diesel::connection::SimpleConnection;
+    use diesel::r2d2::{self, ConnectionManager};
+
     // set up database connection pool
-    let manager = ConnectionManager::<SqliteConnection>::new("db/test_db.sqlite");
+    let manager = ConnectionManager::<DbConnection>::new(database_url);
     let pool = r2d2::Pool::builder()
+        .connection_customizer(Box::new(crate::db_options::ConnectionOptions::from_config(
+            &Config::default(),
+        )))
         .build(manager)
         .expect("Failed to create pool.");
-    pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("db/migrations");
-    pool.get()
-        .expect("couldn't get db connection from pool")
-        .run_pending_migrations(MIGRATIONS)
-        .expect("couldn't run migrations");
+    let mut conn = pool.get().expect("couldn't get db connection from pool");
+    if let DbConnection::Postgresql(_) = *conn {
+        conn.batch_execute("DROP SCHEMA public CASCADE; CREATE SCHEMA public;")
+            .expect("couldn't wipe the database");
+    }
+    conn.run_migrations().expect("couldn't run migrations");
+    drop(conn);
+
+    // Batch the positions writes
+    let (ingester, ingester_handle) = Ingester::new(pool.clone(), &Config::default());
+    spawn(ingester.run());
 
     // Set up authorization token
-    let app_config = AppConfig::new("0101".to_string(), None);
+    let app_config = AppConfig::new("0101".to_string(), None).with_ingester(ingester_handle);
     let app_data = Data::new(app_config);
     let (positions_server, server_tx) = PositionsServer::new();
     let positions_server = spawn(positions_server.run());
 
     user_test(&pool, &app_data, &server_tx).await;
     position_test(&pool, &app_data, &server_tx).await;
+    track_test(&pool, &app_data, &server_tx).await;
     token_test(&pool, &app_data, &server_tx).await;
     toggle_sport_mode_test(&pool, &app_data, &server_tx).await;
+    rate_limit_test(&pool, &server_tx).await;
+    audit_test(&pool, &app_data, &server_tx).await;
+    trash_test(&pool, &app_data, &server_tx).await;
+    backup_test(&pool, &server_tx).await;
+    group_test(&pool, &app_data, &server_tx).await;
+    privacy_test(&pool, &app_data, &server_tx).await;
+    alert_test(&pool, &server_tx).await;
+    push_test(&pool, &server_tx).await;
+    sos_test(&pool, &server_tx).await;
+    oidc_test(&pool, &server_tx).await;
     tokio::select! {
         _ = position_ws_test(&pool, &app_data, &server_tx) => {}
         _ = positions_server => {}
@@ -1,14 +1,206 @@
-use actix_web::{HttpResponse, Responder, Result, get, web};
+use actix_web::{HttpRequest, HttpResponse, Responder, Result, get, web};
 use base64ct::{Base64, Encoding};
-use chacha20poly1305::{
-    ChaCha20Poly1305,
-    aead::{Aead, AeadCore, KeyInit, OsRng},
-};
 use log::debug;
-use sha2::{Digest, Sha256};
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::{app::AppConfig, errors::ServerError};
+use crate::{
+    app::{AppConfig, Role},
+    errors::ServerError,
+    keys::ShareKeySet,
+    models::audit::NewAuditEntry,
+};
+
+type DbPool = r2d2::Pool<diesel::r2d2::ConnectionManager<crate::db::DbConnection>>;
+
+// Claims carried by the tokens sealed with the share keys
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Claims {
+    // read only access to the positions of a user, for the share token duration after its issuance
+    Share {
+        issued_at: u64,
+        user_id: u16,
+    },
+    // read only access to the positions of a user until it expires, handed out in emergencies (see
+    // the sos module)
+    Emergency {
+        expires_at: u64,
+        user_id: u16,
+    },
+    // read only access to the positions of a user until it expires, issued with a custom validity
+    // from the command line
+    Expiring {
+        expires_at: u64,
+        user_id: u16,
+    },
+    // login session (see the oidc module), until it expires
+    Session {
+        expires_at: u64,
+        user_id: Option<i32>,
+        role: Role,
+    },
+}
+
+const SHARE_CLAIMS_LEN: usize = 10;
+const SESSION_CLAIMS_KIND: u8 = 1;
+const SESSION_CLAIMS_LEN: usize = 14;
+const EMERGENCY_CLAIMS_KIND: u8 = 2;
+const EMERGENCY_CLAIMS_LEN: usize = 11;
+const EXPIRING_CLAIMS_KIND: u8 = 3;
+
+impl Claims {
+    // Share claims : time (8) | user id (2), session claims : kind (1) | time (8) | user id (4) | role (1),
+    // emergency and expiring claims : kind (1) | time (8) | user id (2)
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(SESSION_CLAIMS_LEN);
+        match self {
+            Claims::Share { issued_at, user_id } => {
+                data.extend_from_slice(&issued_at.to_le_bytes());
+                data.extend_from_slice(&user_id.to_le_bytes());
+            }
+            Claims::Emergency {
+                expires_at,
+                user_id,
+            } => {
+                data.push(EMERGENCY_CLAIMS_KIND);
+                data.extend_from_slice(&expires_at.to_le_bytes());
+                data.extend_from_slice(&user_id.to_le_bytes());
+            }
+            Claims::Expiring {
+                expires_at,
+                user_id,
+            } => {
+                data.push(EXPIRING_CLAIMS_KIND);
+                data.extend_from_slice(&expires_at.to_le_bytes());
+                data.extend_from_slice(&user_id.to_le_bytes());
+            }
+            Claims::Session {
+                expires_at,
+                user_id,
+                role,
+            } => {
+                data.push(SESSION_CLAIMS_KIND);
+                data.extend_from_slice(&expires_at.to_le_bytes());
+                data.extend_from_slice(&user_id.unwrap_or(0).to_le_bytes());
+                data.push(*role as u8);
+            }
+        }
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, &'static str> {
+        match data.len() {
+            SHARE_CLAIMS_LEN => {
+                let (time_data, id) = data.split_at(8);
+                Ok(Claims::Share {
+                    issued_at: u64::from_le_bytes(
+                        time_data
+                            .try_into()
+                            .map_err(|_| "could not extract time from data")?,
+                    ),
+                    user_id: u16::from_le_bytes(
+                        id.try_into()
+                            .map_err(|_| "could not extract user id from data")?,
+                    ),
+                })
+            }
+            EMERGENCY_CLAIMS_LEN
+                if data[0] == EMERGENCY_CLAIMS_KIND || data[0] == EXPIRING_CLAIMS_KIND =>
+            {
+                let expires_at = u64::from_le_bytes(
+                    data[1..9]
+                        .try_into()
+                        .map_err(|_| "could not extract time from data")?,
+                );
+                let user_id = u16::from_le_bytes(
+                    data[9..11]
+                        .try_into()
+                        .map_err(|_| "could not extract user id from data")?,
+                );
+                Ok(match data[0] {
+                    EMERGENCY_CLAIMS_KIND => Claims::Emergency {
+                        expires_at,
+                        user_id,
+                    },
+                    _ => Claims::Expiring {
+                        expires_at,
+                        user_id,
+                    },
+                })
+            }
+            SESSION_CLAIMS_LEN if data[0] == SESSION_CLAIMS_KIND => {
+                let expires_at = u64::from_le_bytes(
+                    data[1..9]
+                        .try_into()
+                        .map_err(|_| "could not extract time from data")?,
+                );
+                let user_id = i32::from_le_bytes(
+                    data[9..13]
+                        .try_into()
+                        .map_err(|_| "could not extract user id from data")?,
+                );
+                Ok(Claims::Session {
+                    expires_at,
+                    user_id: (user_id != 0).then_some(user_id),
+                    role: Role::try_from(data[13])?,
+                })
+            }
+            _ => Err("could not extract claims from data"),
+        }
+    }
+
+    fn expires_at(&self, share_token_duration: u64) -> u64 {
+        match self {
+            // saturating, as the issuance time comes from the token
+            Claims::Share { issued_at, .. } => issued_at.saturating_add(share_token_duration),
+            Claims::Emergency { expires_at, .. }
+            | Claims::Expiring { expires_at, .. }
+            | Claims::Session { expires_at, .. } => *expires_at,
+        }
+    }
+}
+
+// Seal claims with the current share key, as a base 64 token
+pub fn seal(share_keys: &ShareKeySet, claims: &Claims) -> Result<String, ServerError> {
+    // Encrypt message with the current share key (the key id and nonce are prepended)
+    let ciphered = share_keys.seal(&claims.encode())?;
+    debug!("Creating token, binary token = {:?}", ciphered);
+    let encoded = Base64::encode_string(&ciphered);
+    debug!("Creating token, base64 token = {:?}", encoded);
+    Ok(encoded)
+}
+
+// Open a base 64 token, returning its claims if they are not expired
+pub fn open(
+    share_keys: &ShareKeySet,
+    share_token_duration: u64,
+    base64_token: &str,
+) -> Result<Claims, &'static str> {
+    // TRY TO DECRYPT THE TOKEN
+    // Get the token as base64
+    debug!("Getting token, base64 token = {:?}", base64_token);
+    // Convert to &[u8]
+    let binary_token = match Base64::decode_vec(base64_token) {
+        Ok(val) => val,
+        Err(_) => {
+            return Err("could not decode share token as base 64");
+        }
+    };
+    debug!("Getting token, binary token = {:?}", binary_token);
+    // Decipher the value of the token with the share key it was sealed with
+    let claims = Claims::decode(&share_keys.open(&binary_token)?)?;
+    // Get the current time
+    let time = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(val) => val.as_secs(),
+        Err(_) => {
+            return Err("could not get system time");
+        }
+    };
+    // Check that the token has not expired
+    if time > claims.expires_at(share_token_duration) {
+        return Err("token is expired");
+    }
+    Ok(claims)
+}
 
 #[derive(serde::Deserialize)]
 pub struct Info {
@@ -17,36 +209,27 @@ pub struct Info {
 
 #[get("")]
 pub async fn get(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
     cfg: web::Data<AppConfig>,
     info: web::Query<Info>,
 ) -> Result<impl Responder, ServerError> {
     // Get the current time
     let time = SystemTime::now().duration_since(UNIX_EPOCH)?;
     debug!("Creating token, time = {:?}", time);
-    let mut data = Vec::with_capacity(9);
-    data.extend_from_slice(&time.as_secs().to_le_bytes());
-    data.extend_from_slice(&info.user_id.to_le_bytes());
-
-    // Get the main token
-    let token = &cfg.bearer_token;
-
-    // Derive it as a key
-    let mut hasher = Sha256::new();
-    hasher.update(token);
-    let key: [u8; 32] = hasher.finalize().into();
-
-    // Encrypt message
-    let cipher = ChaCha20Poly1305::new(&key.into());
-    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng); // 96-bits; unique per message
-    debug!("Creating token, nonce = {:?}", nonce);
-    let mut ciphertext = cipher.encrypt(&nonce, data.as_ref())?;
-    debug!("Creating token, ciphertext = {:?}", ciphertext);
-    // Prepend message with nonce
-    let mut ciphered = nonce.to_vec();
-    ciphered.append(&mut ciphertext);
-    debug!("Creating token, binary token = {:?}", ciphered);
-    let encoded = Base64::encode_string(&ciphered);
-    debug!("Creating token, base64 token = {:?}", encoded);
+    let encoded = seal(
+        &cfg.share_keys,
+        &Claims::Share {
+            issued_at: time.as_secs(),
+            user_id: info.user_id,
+        },
+    )?;
+
+    NewAuditEntry::new(&req, "token.issue")
+        .target(format!("users/{}", info.user_id))
+        .details(&serde_json::json!({ "key_id": format!("{:02x?}", cfg.share_keys.current_id()) }))
+        .record(&pool)
+        .await?;
 
     // Respond
     Ok(HttpResponse::Ok().body(encoded))
@@ -54,7 +237,7 @@ pub async fn get(
 
 #[cfg(test)]
 pub async fn token_test(
-    pool: &r2d2::Pool<diesel::r2d2::ConnectionManager<diesel::SqliteConnection>>,
+    pool: &r2d2::Pool<diesel::r2d2::ConnectionManager<crate::db::DbConnection>>,
     app_config: &actix_web::web::Data<AppConfig>,
     position_server_handle: &crate::positions_server::PositionsServerHandle,
 ) {
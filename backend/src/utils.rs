@@ -7,3 +7,13 @@ pub fn random_string() -> std::string::String {
         .map(char::from)
         .collect()
 }
+
+// Current unix time in milliseconds
+pub fn now() -> i64 {
+    match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+        Ok(n) => std::time::Duration::as_millis(&n)
+            .try_into()
+            .unwrap_or_default(),
+        Err(_) => 0,
+    }
+}
@@ -0,0 +1,415 @@
+//! Web Push notifications, so that the alerts reach a closed browser tab.
+//!
+//! Messages are encrypted for the browser with the `aes128gcm` content coding (RFC 8291), in a
+//! single record, and the push services authenticate the application server with VAPID
+//! (RFC 8292). The VAPID key is generated on first start and kept in the db folder, unless given
+//! with `vapid_private_key`. The subscriptions the push service reports as gone (404 or 410) are
+//! removed, and the push services are only called at public addresses.
+
+use std::{path::Path, sync::Arc};
+
+use aes_gcm::{Aes128Gcm, KeyInit, Nonce, aead::Aead};
+use base64ct::{Base64UrlUnpadded, Encoding};
+use diesel::prelude::*;
+use futures_util::future::BoxFuture;
+use hkdf::Hkdf;
+use p256::{
+    PublicKey, SecretKey,
+    ecdsa::{Signature, SigningKey, signature::Signer},
+    elliptic_curve::sec1::ToEncodedPoint,
+};
+use rand::{Rng, rng};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use sha2::Sha256;
+
+use crate::{
+    models::push_subscription::{PushSubscription, is_public},
+    notifiers::{Notification, Notifier},
+    schema::push_subscriptions,
+};
+
+type DbPool = r2d2::Pool<diesel::r2d2::ConnectionManager<crate::db::DbConnection>>;
+
+// size of the single record of a message, the payload must fit in it
+const RECORD_SIZE: u32 = 4096;
+// how long the push service keeps a message for an offline browser, in seconds
+const TTL: u32 = 24 * 60 * 60;
+// validity of the VAPID tokens, in seconds (at most 24 hours)
+const VAPID_TOKEN_DURATION: u64 = 12 * 60 * 60;
+
+fn random_secret() -> SecretKey {
+    // almost every 32 bytes string is a valid scalar
+    loop {
+        if let Ok(secret) = SecretKey::from_slice(&rng().random::<[u8; 32]>()) {
+            return secret;
+        }
+    }
+}
+
+fn uncompressed(key: &PublicKey) -> Vec<u8> {
+    key.to_encoded_point(false).as_bytes().to_vec()
+}
+
+// The application server key pair
+pub struct VapidKeys {
+    secret: SecretKey,
+}
+
+impl VapidKeys {
+    pub fn generate() -> Self {
+        VapidKeys {
+            secret: random_secret(),
+        }
+    }
+
+    pub fn from_base64(key: &str) -> Result<Self, String> {
+        let bytes = Base64UrlUnpadded::decode_vec(key.trim().trim_end_matches('='))
+            .map_err(|_| "the VAPID private key is not base64url encoded")?;
+        let secret =
+            SecretKey::from_slice(&bytes).map_err(|_| "the VAPID private key is invalid")?;
+        Ok(VapidKeys { secret })
+    }
+
+    pub fn to_base64(&self) -> String {
+        Base64UrlUnpadded::encode_string(&self.secret.to_bytes())
+    }
+
+    // Load the key from a file, generating it the first time
+    pub fn load_or_generate(path: &Path) -> Result<Self, String> {
+        match std::fs::read_to_string(path) {
+            Ok(key) => VapidKeys::from_base64(&key),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let keys = VapidKeys::generate();
+                std::fs::write(path, keys.to_base64()).map_err(|e| e.to_string())?;
+                Ok(keys)
+            }
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    // The key given to the browsers as applicationServerKey
+    pub fn public_key(&self) -> String {
+        Base64UrlUnpadded::encode_string(&uncompressed(&self.secret.public_key()))
+    }
+
+    // Authorization header value for a push service (RFC 8292, section 3)
+    fn authorization(
+        &self,
+        endpoint: &str,
+        subject: &str,
+        expires_at: u64,
+    ) -> Result<String, String> {
+        let url = reqwest::Url::parse(endpoint).map_err(|e| e.to_string())?;
+        let header = Base64UrlUnpadded::encode_string(br#"{"typ":"JWT","alg":"ES256"}"#);
+        let claims = serde_json::json!({
+            "aud": url.origin().ascii_serialization(),
+            "exp": expires_at,
+            "sub": subject,
+        });
+        let claims = Base64UrlUnpadded::encode_string(claims.to_string().as_bytes());
+        let signing_input = format!("{}.{}", header, claims);
+        let signature: Signature = SigningKey::from(&self.secret).sign(signing_input.as_bytes());
+        Ok(format!(
+            "vapid t={}.{}, k={}",
+            signing_input,
+            Base64UrlUnpadded::encode_string(&signature.to_bytes()),
+            self.public_key()
+        ))
+    }
+}
+
+// Encrypt a message for a browser (RFC 8291)
+pub fn encrypt(ua_public: &[u8], auth_secret: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, String> {
+    encrypt_with(
+        ua_public,
+        auth_secret,
+        plaintext,
+        &random_secret(),
+        &rng().random::<[u8; 16]>(),
+    )
+}
+
+fn encrypt_with(
+    ua_public: &[u8],
+    auth_secret: &[u8],
+    plaintext: &[u8],
+    as_secret: &SecretKey,
+    salt: &[u8; 16],
+) -> Result<Vec<u8>, String> {
+    let ua_key =
+        PublicKey::from_sec1_bytes(ua_public).map_err(|_| "the browser public key is invalid")?;
+    let ua_public = uncompressed(&ua_key);
+    let as_public = uncompressed(&as_secret.public_key());
+    let ecdh_secret = p256::ecdh::diffie_hellman(as_secret.to_nonzero_scalar(), ua_key.as_affine());
+    // combine the shared secret with the authentication secret
+    let key_info = [b"WebPush: info\0".as_slice(), &ua_public, &as_public].concat();
+    let mut ikm = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(auth_secret), ecdh_secret.raw_secret_bytes())
+        .expand(&key_info, &mut ikm)
+        .map_err(|e| e.to_string())?;
+    // derive the content encryption key and nonce (RFC 8188)
+    let hkdf = Hkdf::<Sha256>::new(Some(salt), &ikm);
+    let mut cek = [0u8; 16];
+    let mut nonce = [0u8; 12];
+    hkdf.expand(b"Content-Encoding: aes128gcm\0", &mut cek)
+        .and_then(|_| hkdf.expand(b"Content-Encoding: nonce\0", &mut nonce))
+        .map_err(|e| e.to_string())?;
+    // the padding delimiter of the last (and only) record
+    let record = [plaintext, &[2]].concat();
+    if record.len() + 16 > RECORD_SIZE as usize {
+        return Err("the message is too large".to_owned());
+    }
+    let ciphertext = Aes128Gcm::new(&cek.into())
+        .encrypt(Nonce::from_slice(&nonce), record.as_slice())
+        .map_err(|e| e.to_string())?;
+    Ok([
+        salt.as_slice(),
+        &RECORD_SIZE.to_be_bytes(),
+        &[as_public.len() as u8],
+        &as_public,
+        &ciphertext,
+    ]
+    .concat())
+}
+
+// Sends the notifications to the push subscriptions of their user
+// Resolve the push services names to their public addresses only, so that a name pointing to the
+// server network is not called
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_owned();
+        Box::pin(async move {
+            let addrs: Vec<_> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|a| is_public(a.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", host).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct WebPush {
+    keys: Arc<VapidKeys>,
+    // contact of the application server operator, as mailto: or https:
+    subject: String,
+    pool: DbPool,
+    client: reqwest::Client,
+}
+
+impl WebPush {
+    pub fn new(keys: VapidKeys, subject: String, pool: DbPool) -> Self {
+        WebPush {
+            keys: Arc::new(keys),
+            subject,
+            pool,
+            // unwrap: reqwest::Client::new panics the same way
+            client: reqwest::Client::builder()
+                .dns_resolver(Arc::new(PublicResolver))
+                .build()
+                .unwrap(),
+        }
+    }
+
+    pub fn public_key(&self) -> String {
+        self.keys.public_key()
+    }
+
+    // Push a message, returns false if the subscription is gone
+    async fn push(&self, subscription: &PushSubscription, message: &[u8]) -> Result<bool, String> {
+        let decode = |v: &str| Base64UrlUnpadded::decode_vec(v.trim_end_matches('=')).ok();
+        let (Some(p256dh), Some(auth)) = (decode(&subscription.p256dh), decode(&subscription.auth))
+        else {
+            return Err("the subscription keys are not base64url encoded".to_owned());
+        };
+        let body = encrypt(&p256dh, &auth, message)?;
+        let expires_at = crate::utils::now() as u64 / 1000 + VAPID_TOKEN_DURATION;
+        let authorization =
+            self.keys
+                .authorization(&subscription.endpoint, &self.subject, expires_at)?;
+        let response = self
+            .client
+            .post(&subscription.endpoint)
+            .header("Authorization", authorization)
+            .header("TTL", TTL.to_string())
+            .header("Urgency", "high")
+            .header("Content-Encoding", "aes128gcm")
+            .header("Content-Type", "application/octet-stream")
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        match response.status().as_u16() {
+            404 | 410 => Ok(false),
+            s if (200..300).contains(&s) => Ok(true),
+            s => Err(format!("the push service answered {}", s)),
+        }
+    }
+
+    pub async fn push_to_user(&self, uid: i32, message: &[u8]) -> Result<(), String> {
+        let mut conn = self.pool.get().map_err(|e| e.to_string())?;
+        let subscriptions = actix_web::web::block(move || {
+            push_subscriptions::table
+                .filter(push_subscriptions::user_id.eq(uid))
+                .load::<PushSubscription>(&mut conn)
+        })
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())?;
+        let mut gone = Vec::new();
+        let mut errors = Vec::new();
+        for subscription in &subscriptions {
+            match self.push(subscription, message).await {
+                Ok(true) => {}
+                Ok(false) => gone.push(subscription.id),
+                Err(e) => errors.push(format!("{}: {}", subscription.endpoint, e)),
+            }
+        }
+        if !gone.is_empty() {
+            let mut conn = self.pool.get().map_err(|e| e.to_string())?;
+            actix_web::web::block(move || {
+                diesel::delete(push_subscriptions::table)
+                    .filter(push_subscriptions::id.eq_any(gone))
+                    .execute(&mut conn)
+            })
+            .await
+            .map_err(|e| e.to_string())?
+            .map_err(|e| e.to_string())?;
+        }
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors.join(", ")),
+        }
+    }
+}
+
+impl Notifier for WebPush {
+    fn name(&self) -> &'static str {
+        "web push"
+    }
+
+    fn notify<'a>(&'a self, notification: &'a Notification) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let message = serde_json::to_vec(notification).map_err(|e| e.to_string())?;
+            self.push_to_user(notification.user_id, &message).await
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use aes_gcm::aead::Aead;
+    use p256::ecdsa::{VerifyingKey, signature::Verifier};
+
+    fn decode(v: &str) -> Vec<u8> {
+        Base64UrlUnpadded::decode_vec(v).unwrap()
+    }
+
+    // Decrypt a message as a browser would
+    pub fn decrypt(ua_secret: &SecretKey, auth_secret: &[u8], body: &[u8]) -> Vec<u8> {
+        let (salt, rest) = body.split_at(16);
+        let id_len = rest[4] as usize;
+        let (as_public, ciphertext) = rest[5..].split_at(id_len);
+        let as_key = PublicKey::from_sec1_bytes(as_public).unwrap();
+        let ecdh_secret =
+            p256::ecdh::diffie_hellman(ua_secret.to_nonzero_scalar(), as_key.as_affine());
+        let key_info = [
+            b"WebPush: info\0".as_slice(),
+            &uncompressed(&ua_secret.public_key()),
+            as_public,
+        ]
+        .concat();
+        let mut ikm = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(auth_secret), ecdh_secret.raw_secret_bytes())
+            .expand(&key_info, &mut ikm)
+            .unwrap();
+        let hkdf = Hkdf::<Sha256>::new(Some(salt), &ikm);
+        let (mut cek, mut nonce) = ([0u8; 16], [0u8; 12]);
+        hkdf.expand(b"Content-Encoding: aes128gcm\0", &mut cek)
+            .unwrap();
+        hkdf.expand(b"Content-Encoding: nonce\0", &mut nonce)
+            .unwrap();
+        let mut record = Aes128Gcm::new(&cek.into())
+            .decrypt(Nonce::from_slice(&nonce), ciphertext)
+            .unwrap();
+        assert_eq!(record.pop(), Some(2));
+        record
+    }
+
+    // Check a VAPID authorization header, returns its claims
+    pub fn verify_authorization(authorization: &str, public_key: &str) -> serde_json::Value {
+        let (token, key) = authorization
+            .strip_prefix("vapid t=")
+            .and_then(|a| a.split_once(", k="))
+            .unwrap();
+        assert_eq!(key, public_key);
+        let (signing_input, signature) = token.rsplit_once('.').unwrap();
+        let key = VerifyingKey::from_sec1_bytes(&decode(key)).unwrap();
+        let signature = Signature::from_slice(&decode(signature)).unwrap();
+        key.verify(signing_input.as_bytes(), &signature).unwrap();
+        let claims = signing_input.split_once('.').unwrap().1;
+        serde_json::from_slice(&decode(claims)).unwrap()
+    }
+
+    #[test]
+    fn test_encrypt() {
+        // RFC 8291, appendix A
+        let as_secret =
+            SecretKey::from_slice(&decode("yfWPiYE-n46HLnH0KqZOF1fJJU3MYrct3AELtAQ-oRw")).unwrap();
+        let ua_secret =
+            SecretKey::from_slice(&decode("q1dXpw3UpT5VOmu_cf_v6ih07Aems3njxI-JWgLcM94")).unwrap();
+        let ua_public = decode(
+            "BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4",
+        );
+        assert_eq!(uncompressed(&ua_secret.public_key()), ua_public);
+        let auth_secret = decode("BTBZMqHH6r4Tts7J_aSIgg");
+        let salt: [u8; 16] = decode("DGv6ra1nlYgDCS1FRnbzlw").try_into().unwrap();
+        let plaintext = b"When I grow up, I want to be a watermelon";
+        let body = encrypt_with(&ua_public, &auth_secret, plaintext, &as_secret, &salt).unwrap();
+        assert_eq!(
+            Base64UrlUnpadded::encode_string(&body),
+            "DGv6ra1nlYgDCS1FRnbzlwAAEABBBP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A_yl95bQpu6cVPTpK4Mqgkf1CXztLVBSt2Ks3oZwbuwXPXLWyouBWLVWGNWQexSgSxsj_Qulcy4a-fN"
+        );
+        assert_eq!(decrypt(&ua_secret, &auth_secret, &body), plaintext);
+
+        // With random keys and salt
+        let body = encrypt(&ua_public, &auth_secret, plaintext).unwrap();
+        assert_eq!(decrypt(&ua_secret, &auth_secret, &body), plaintext);
+        assert!(encrypt(&ua_public, &auth_secret, &[0; 4096]).is_err());
+        assert!(encrypt(&ua_public[1..], &auth_secret, plaintext).is_err());
+    }
+
+    #[test]
+    fn test_vapid() {
+        let keys = VapidKeys::generate();
+        assert_eq!(
+            VapidKeys::from_base64(&keys.to_base64())
+                .unwrap()
+                .public_key(),
+            keys.public_key()
+        );
+        assert!(VapidKeys::from_base64("not a key").is_err());
+        let authorization = keys
+            .authorization(
+                "https://push.example.net:8443/send/abc",
+                "mailto:admin@example.net",
+                1_800_000_000,
+            )
+            .unwrap();
+        let claims = verify_authorization(&authorization, &keys.public_key());
+        assert_eq!(
+            claims,
+            serde_json::json!({
+                "aud": "https://push.example.net:8443",
+                "exp": 1_800_000_000,
+                "sub": "mailto:admin@example.net",
+            })
+        );
+    }
+}
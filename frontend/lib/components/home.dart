@@ -559,7 +559,12 @@ class HomeState extends State<Home>
           "/api/positions/ws?user_id=$displayedUser&token=${Uri.encodeComponent(App().prefs.token)}";
       wsChannel = WebSocketChannel.connect(Uri.parse(websocketUrl));
       wsChannel?.stream.listen((message) async {
-        Position pos = Position.fromJson(json.decode((message)));
+        var data = json.decode((message));
+        // alerts and hidden positions statuses are not positions
+        if (data is! Map<String, dynamic> || !data.containsKey("latitude")) {
+          return;
+        }
+        Position pos = Position.fromJson(data);
         var itms = await positions;
         itms.insert(0, pos);
         if (itms.isNotEmpty) {
@@ -30,11 +30,28 @@ class NewEditUserState extends State<NewEditUser> {
                   IconButton(
                       icon: const Icon(Icons.delete_forever),
                       onPressed: () async {
-                        widget.crud.delete(widget.user.id);
+                        var msg = tr(context, "user_deleted");
+                        try {
+                          await widget.crud.delete(widget.user.id);
+                        } on DependentsException catch (e) {
+                          // Delete the dependents only once confirmed
+                          if (!context.mounted ||
+                              !await confirmCascade(context, e.dependents)) {
+                            return;
+                          }
+                          try {
+                            await widget.crud
+                                .delete(widget.user.id, cascade: true);
+                          } catch (error) {
+                            msg = error.toString();
+                          }
+                        } catch (e) {
+                          msg = e.toString();
+                        }
                         if (!context.mounted) return;
                         Navigator.pop(context);
-                        ScaffoldMessenger.of(context).showSnackBar(SnackBar(
-                            content: Text(tr(context, "user_deleted"))));
+                        ScaffoldMessenger.of(context)
+                            .showSnackBar(SnackBar(content: Text(msg)));
                       })
                 ]
               : null,
@@ -125,3 +142,27 @@ class NewEditUserState extends State<NewEditUser> {
             )));
   }
 }
+
+Future<bool> confirmCascade(
+    BuildContext context, Map<String, int> dependents) async {
+  final confirmed = await showDialog<bool>(
+    context: context,
+    builder: (BuildContext context) => AlertDialog(
+      title: Text(tr(context, "delete_dependents")),
+      content: Text(dependents.entries
+          .map((e) => "${e.key}: ${e.value}")
+          .join("\n")),
+      actions: <Widget>[
+        TextButton(
+          onPressed: () => Navigator.pop(context, false),
+          child: Text(tr(context, "cancel")),
+        ),
+        TextButton(
+          onPressed: () => Navigator.pop(context, true),
+          child: Text(tr(context, "delete")),
+        ),
+      ],
+    ),
+  );
+  return confirmed ?? false;
+}
@@ -19,6 +19,10 @@ class MyLocalizations {
   static final Map<String, Map<String, String>> _localizedValues = {
     'en': {
       "active_user": "Active user",
+      "cancel": "Cancel",
+      "delete": "Delete",
+      "delete_dependents":
+          "This user has data, that will be deleted along with it",
       "edit_user": "Edit user",
       "enable_log": "Enable Logging",
       "get_latest_release": "Get latest release from GitHub",
@@ -44,6 +48,10 @@ class MyLocalizations {
     },
     'fr': {
       "active_user": "Utilisateur actif",
+      "cancel": "Annuler",
+      "delete": "Supprimer",
+      "delete_dependents":
+          "Cet utilisateur a des données, qui seront supprimées avec lui",
       "edit_user": "Éditer utilisateur",
       "enable_log": "Activer le journal",
       "get_latest_release": "Récupérer la dernière version sur GitHub",
@@ -38,6 +38,16 @@ abstract class Serialisable {
   Map<String, dynamic> toJson();
 }
 
+// The object has dependents, that are deleted along with it only when cascading
+class DependentsException implements Exception {
+  final Map<String, int> dependents;
+  DependentsException(this.dependents);
+
+  @override
+  String toString() =>
+      dependents.entries.map((e) => "${e.key}: ${e.value}").join(", ");
+}
+
 abstract class Crud<T extends Serialisable> {
   create(T val) {}
 
@@ -47,7 +57,7 @@ abstract class Crud<T extends Serialisable> {
 
   update(T val) {}
 
-  delete(int id) {}
+  delete(int id, {bool cascade = false}) {}
 }
 
 class APICrud<T extends Serialisable> extends Crud<T> {
@@ -166,12 +176,19 @@ class APICrud<T extends Serialisable> extends Crud<T> {
   }
 
   @override
-  delete(int id) async {
+  delete(int id, {bool cascade = false}) async {
     try {
       final response = await client.delete(
-        Uri.parse('$base/$route/$id'),
+        cascade
+            ? Uri.parse('$base/$route/$id?cascade=true')
+            : Uri.parse('$base/$route/$id'),
         headers: <String, String>{'Authorization': "Bearer $token"},
       );
+      if (response.statusCode == 409) {
+        final Map<String, dynamic> body =
+            json.decode(utf8.decode(response.bodyBytes));
+        throw DependentsException(Map<String, int>.from(body["dependents"]));
+      }
       if (response.statusCode != 200) {
         throw Exception(response.body.toString());
       }